@@ -0,0 +1,247 @@
+//! Read options from a TOML file, see `--config`. The file holds the long
+//! options without their dashes, for example
+//!
+//! ```toml
+//! messages = true
+//! format = "jsonl"
+//! port = [50000, 50001]
+//! listen = "50000"
+//! forward = "localhost:50001"
+//! ```
+//!
+//! They are put before the options on the command line, so those take
+//! precedence.
+//!
+//! On SIGUSR1 the file is read again, see [option_names].
+//!
+//! Arguments can also be stored in a plain file with one per line, which is
+//! read where `@FILE` appears on the command line, see [expand_arg_files].
+
+use std::{
+    collections::HashSet,
+    env,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result as AResult};
+use toml::{Table, Value};
+
+/// The options read from a configuration file
+#[derive(Debug, Default)]
+pub struct Config {
+    /// The options as they would be written on the command line
+    pub args: Vec<OsString>,
+    /// LISTEN_ADDR, if not given on the command line
+    pub listen_addr: Option<OsString>,
+    /// FORWARD_ADDR, if not given on the command line
+    pub forward_addr: Option<OsString>,
+}
+
+impl Config {
+    /// Read `path`, or the default file if it exists and no path is given.
+    pub fn load(path: Option<&Path>) -> AResult<Config> {
+        let path = match path {
+            Some(path) => path.to_owned(),
+            None => match default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Config::default()),
+            },
+        };
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("Could not read config file {}", path.display()))?;
+        Config::parse(&text).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    fn parse(text: &str) -> AResult<Config> {
+        let table: Table = text.parse()?;
+        let mut config = Config::default();
+        for (key, value) in table {
+            match key.as_str() {
+                "listen" => config.listen_addr = Some(address(&key, value)?),
+                "forward" => config.forward_addr = Some(address(&key, value)?),
+                _ => add_option(&mut config.args, &key, value)?,
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Find the file given with `--config` among the command line arguments,
+/// before they are parsed for real.
+pub fn config_arg(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        } else if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        } else if let Some(path) = arg.to_str().and_then(|a| a.strip_prefix("--config=")) {
+            return Some(path.into());
+        }
+    }
+    None
+}
+
+/// The long names of the options given on the command line, such as
+/// `output` for `-o FILE`. When the config file is read again on SIGUSR1,
+/// these are left alone because the command line takes precedence.
+pub fn option_names(args: &[OsString]) -> HashSet<String> {
+    let mut names = HashSet::new();
+    for arg in args.iter().filter_map(|a| a.to_str()) {
+        if arg == "--" {
+            break;
+        } else if let Some(long) = arg.strip_prefix("--") {
+            let name = long.split_once('=').map_or(long, |(name, _)| name);
+            names.insert(name.to_string());
+        } else if let Some(short) = arg.strip_prefix('-') {
+            for c in short.chars() {
+                let name = match c {
+                    'm' => "messages",
+                    'b' => "blocks",
+                    'r' => "raw",
+                    'B' => "binary",
+                    // The rest is the file name
+                    'o' => {
+                        names.insert("output".to_string());
+                        break;
+                    }
+                    _ => continue,
+                };
+                names.insert(name.to_string());
+            }
+        }
+    }
+    names
+}
+
+/// Replace every argument `@FILE` by the lines of FILE, each of which is
+/// an argument as it is. Empty lines are skipped. Arguments after `--` are
+/// left alone.
+pub fn expand_arg_files(args: impl IntoIterator<Item = OsString>) -> AResult<Vec<OsString>> {
+    let mut expanded = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            expanded.push(arg);
+            expanded.extend(args);
+            break;
+        }
+        let Some(path) = arg.to_str().and_then(|a| a.strip_prefix('@')) else {
+            expanded.push(arg);
+            continue;
+        };
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Could not read argument file {path}"))?;
+        expanded.extend(arg_file_lines(&text));
+    }
+    Ok(expanded)
+}
+
+fn arg_file_lines(text: &str) -> impl Iterator<Item = OsString> + '_ {
+    text.lines()
+        .filter(|line| !line.is_empty())
+        .map(OsString::from)
+}
+
+/// `$XDG_CONFIG_HOME/mapiproxy.toml`, by default in `~/.config`
+fn default_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("mapiproxy.toml"))
+}
+
+fn address(key: &str, value: Value) -> AResult<OsString> {
+    match value {
+        Value::String(s) => Ok(s.into()),
+        Value::Integer(n) => Ok(n.to_string().into()),
+        _ => bail!("{key}: expected an address such as 50000 or localhost:50000"),
+    }
+}
+
+/// Add `--key`, `--key=value` or, for a list, `--key=value` for each of
+/// its items. False leaves the option out.
+fn add_option(args: &mut Vec<OsString>, key: &str, value: Value) -> AResult<()> {
+    let value = match value {
+        Value::Boolean(true) => {
+            args.push(format!("--{key}").into());
+            return Ok(());
+        }
+        Value::Boolean(false) => return Ok(()),
+        Value::Array(items) => {
+            for item in items {
+                add_option(args, key, item)?;
+            }
+            return Ok(());
+        }
+        Value::String(s) => s,
+        Value::Integer(n) => n.to_string(),
+        Value::Float(x) => x.to_string(),
+        Value::Datetime(t) => t.to_string(),
+        Value::Table(_) => bail!("{key}: expected a value or a list of values"),
+    };
+    args.push(format!("--{key}={value}").into());
+    Ok(())
+}
+
+#[test]
+fn test_parse_config() {
+    let text = r#"
+        messages = true
+        binary = false
+        format = "jsonl"
+        port = [50000, 50001]
+        listen = 50000
+        forward = "localhost:50001"
+    "#;
+    let config = Config::parse(text).unwrap();
+    let mut args = config.args.clone();
+    args.sort();
+    assert_eq!(
+        args,
+        [
+            "--format=jsonl",
+            "--messages",
+            "--port=50000",
+            "--port=50001"
+        ]
+    );
+    assert_eq!(config.listen_addr, Some("50000".into()));
+    assert_eq!(config.forward_addr, Some("localhost:50001".into()));
+    assert!(Config::parse("theme = { frame = 35 }").is_err());
+
+    let args: Vec<OsString> = ["-m", "--config=a.toml", "--config", "b.toml"]
+        .iter()
+        .map(OsString::from)
+        .collect();
+    assert_eq!(config_arg(&args), Some("a.toml".into()));
+}
+
+#[test]
+fn test_arg_file_lines() {
+    let lines: Vec<OsString> =
+        arg_file_lines("-m\r\n\n--grep=SELECT 1\n--pcap\nfile.pcap").collect();
+    assert_eq!(lines, ["-m", "--grep=SELECT 1", "--pcap", "file.pcap"]);
+}
+
+#[test]
+fn test_option_names() {
+    let args: Vec<OsString> = [
+        "-mB",
+        "-o/tmp/raw.log",
+        "--grep=x",
+        "--conn",
+        "12",
+        "--",
+        "--sender",
+    ]
+    .iter()
+    .map(OsString::from)
+    .collect();
+    let mut names: Vec<String> = option_names(&args).into_iter().collect();
+    names.sort();
+    assert_eq!(names, ["binary", "conn", "grep", "messages", "output"]);
+}
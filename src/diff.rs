@@ -0,0 +1,339 @@
+//! Compare the statements and responses in two captures, see
+//! `mapiproxy diff`.
+
+use std::{collections::HashMap, fs::File, path::Path};
+
+use anyhow::{bail, Context, Result as AResult};
+use tracing::warn;
+
+use crate::{
+    grep::{is_records_file, read_records},
+    mapi::{read_frame, Structured},
+    pcap::{self, Tracker},
+    proxy::event::{Direction, MapiEvent},
+    render::Renderer,
+    Format, Level,
+};
+
+/// Statements are cut off after this many characters in the report
+const MAX_STATEMENT_LEN: usize = 60;
+
+/// Connections with more pairs of statements than this are compared in
+/// order rather than by looking for the longest common sequence, which
+/// takes memory for every pair
+const MAX_LCS_PAIRS: usize = 10_000_000;
+
+/// The statements sent on the connections in a capture, each with the
+/// response of the server, in the order the connections came in
+#[derive(Debug, Default)]
+pub struct Capture {
+    conns: Vec<Conn>,
+    /// Position in [Capture::conns] by connection id
+    index: HashMap<usize, usize>,
+}
+
+#[derive(Debug)]
+struct Conn {
+    id: usize,
+    exchanges: Vec<Exchange>,
+    /// Whether the last message of the client was a statement, so the
+    /// messages of the server answer it. The login is skipped.
+    answering: bool,
+}
+
+#[derive(Debug)]
+struct Exchange {
+    statement: String,
+    /// The kind of the first response, such as &1, or the error message.
+    /// None if the server did not answer.
+    status: Option<String>,
+    /// Total size of the responses, without the header lines of the
+    /// result sets because they include timings, see [response_size]
+    size: usize,
+}
+
+impl Capture {
+    /// Read a capture, or the records in a .jsonl file written with
+    /// `-m --format=jsonl`.
+    pub fn read(path: &Path, options: &pcap::Options) -> AResult<Capture> {
+        let mut capture = Capture::default();
+        if is_records_file(path) {
+            let mut messages = 0;
+            read_records(path, |record, _| {
+                match read_frame(record) {
+                    Some((id, direction, data)) if record["event"] == "message" => {
+                        messages += 1;
+                        capture.add_message(id, direction, &data)
+                    }
+                    _ if record["event"] == "incoming" => {
+                        capture.add_conn(record["conn"].as_u64().unwrap_or_default() as usize);
+                    }
+                    _ => {}
+                }
+                Ok(())
+            })?;
+            if messages == 0 {
+                warn!(
+                    "{} has no message records, was it written with -m --format=jsonl?",
+                    path.display()
+                );
+            }
+            return Ok(capture);
+        }
+
+        let file = File::open(path)
+            .with_context(|| format!("Could not open pcap file {}", path.display()))?;
+        let mut structured = Structured::new(Format::JsonLines, Level::Messages, false, false);
+        let handler = |ev: MapiEvent| {
+            if let MapiEvent::Incoming { id, .. } = ev {
+                capture.add_conn(id.number());
+            }
+            for record in structured.records(&ev) {
+                if let (Some(data), "message", Some(id), Some(direction)) =
+                    (&record.data, record.kind, record.id, record.direction)
+                {
+                    capture.add_message(id.number(), direction, data);
+                }
+            }
+            Ok(())
+        };
+        let mut tracker = Tracker::new(options.clone(), handler);
+        pcap::parse_pcap_file(file, &mut tracker)?;
+        drop(tracker);
+        Ok(capture)
+    }
+
+    fn add_conn(&mut self, id: usize) -> &mut Conn {
+        let pos = *self.index.entry(id).or_insert_with(|| {
+            self.conns.push(Conn {
+                id,
+                exchanges: vec![],
+                answering: false,
+            });
+            self.conns.len() - 1
+        });
+        &mut self.conns[pos]
+    }
+
+    fn add_message(&mut self, id: usize, direction: Direction, data: &[u8]) {
+        let conn = self.add_conn(id);
+        match direction {
+            Direction::Upstream => {
+                // SQL statements and commands such as Xreply_size
+                conn.answering = matches!(data.first(), Some(b's' | b'X'));
+                if conn.answering {
+                    conn.exchanges.push(Exchange {
+                        statement: statement_text(data),
+                        status: None,
+                        size: 0,
+                    });
+                }
+            }
+            Direction::Downstream => {
+                let Some(exchange) = conn.exchanges.last_mut().filter(|_| conn.answering) else {
+                    return;
+                };
+                exchange.status.get_or_insert_with(|| status(data));
+                exchange.size += response_size(data);
+            }
+        }
+    }
+}
+
+/// The statement on a single line, without the leading 's' that marks SQL
+fn statement_text(data: &[u8]) -> String {
+    let text = String::from_utf8_lossy(data);
+    let text = text.strip_prefix('s').unwrap_or(&text);
+    let words: Vec<&str> = text.split_whitespace().collect();
+    words.join(" ").trim_end_matches(';').trim_end().to_string()
+}
+
+/// The first word of the response, or the error message
+fn status(response: &[u8]) -> String {
+    let text = String::from_utf8_lossy(response);
+    let first = text.lines().next().unwrap_or_default();
+    if first.is_empty() {
+        "empty".to_string()
+    } else if first.starts_with('&') {
+        first.split(' ').next().unwrap_or_default().to_string()
+    } else {
+        first.to_string()
+    }
+}
+
+/// The size of a response without the header line of a result set or
+/// other &-response, which holds the time the server took
+fn response_size(response: &[u8]) -> usize {
+    if !response.starts_with(b"&") {
+        return response.len();
+    }
+    match response.iter().position(|&b| b == b'\n') {
+        Some(pos) => response.len() - pos - 1,
+        None => 0,
+    }
+}
+
+fn shorten(text: &str) -> String {
+    match text.char_indices().nth(MAX_STATEMENT_LEN) {
+        Some((pos, _)) => format!("{}...", &text[..pos]),
+        None => text.to_string(),
+    }
+}
+
+/// Describe the differences between the captures, a line for each.
+/// Connections are paired in the order they came in, their statements by
+/// looking for the longest common sequence.
+pub fn diff(a: &Capture, b: &Capture) -> Vec<String> {
+    let mut lines = vec![];
+    for i in 0..a.conns.len().max(b.conns.len()) {
+        match (a.conns.get(i), b.conns.get(i)) {
+            (Some(ca), Some(cb)) => diff_conn(ca, cb, &mut lines),
+            (Some(c), None) | (None, Some(c)) => {
+                let side = if i < a.conns.len() { "A" } else { "B" };
+                let n = c.exchanges.len();
+                let s = if n == 1 { "" } else { "s" };
+                lines.push(format!("#{}: only in {side}, {n} statement{s}", c.id));
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+    lines
+}
+
+fn diff_conn(a: &Conn, b: &Conn, lines: &mut Vec<String>) {
+    let label = if a.id == b.id {
+        format!("#{}", a.id)
+    } else {
+        format!("#{} vs #{}", a.id, b.id)
+    };
+    let (ea, eb) = (&a.exchanges, &b.exchanges);
+
+    // lcs[i][j] is the length of the longest common sequence of the
+    // statements from ea[i] and eb[j] on
+    let mut lcs = vec![];
+    if ea.len().saturating_mul(eb.len()) <= MAX_LCS_PAIRS {
+        lcs = vec![vec![0usize; eb.len() + 1]; ea.len() + 1];
+        for i in (0..ea.len()).rev() {
+            for j in (0..eb.len()).rev() {
+                lcs[i][j] = if ea[i].statement == eb[j].statement {
+                    lcs[i + 1][j + 1] + 1
+                } else {
+                    lcs[i + 1][j].max(lcs[i][j + 1])
+                };
+            }
+        }
+    }
+    // Whether skipping the statement of A keeps the longest common sequence
+    let skip_a = |i: usize, j: usize| lcs.is_empty() || lcs[i + 1][j] >= lcs[i][j + 1];
+
+    let (mut i, mut j) = (0, 0);
+    while i < ea.len() || j < eb.len() {
+        if i < ea.len() && j < eb.len() && ea[i].statement == eb[j].statement {
+            let statement = shorten(&ea[i].statement);
+            let none = || "no response".to_string();
+            let status_a = ea[i].status.clone().unwrap_or_else(none);
+            let status_b = eb[j].status.clone().unwrap_or_else(none);
+            if status_a != status_b {
+                lines.push(format!(
+                    "{label}: {statement}: status {status_a} vs {status_b}"
+                ));
+            } else if ea[i].size != eb[j].size {
+                let (size_a, size_b) = (ea[i].size, eb[j].size);
+                lines.push(format!(
+                    "{label}: {statement}: response {size_a} vs {size_b} bytes"
+                ));
+            }
+            i += 1;
+            j += 1;
+        } else if j == eb.len() || i < ea.len() && skip_a(i, j) {
+            let statement = shorten(&ea[i].statement);
+            lines.push(format!("{label}: only in A: {statement}"));
+            i += 1;
+        } else {
+            let statement = shorten(&eb[j].statement);
+            lines.push(format!("{label}: only in B: {statement}"));
+            j += 1;
+        }
+    }
+}
+
+/// Compare captures `a` and `b` and write the differences. Fails if there
+/// are any, like diff(1).
+pub fn run_diff(
+    a: &Path,
+    b: &Path,
+    options: &pcap::Options,
+    renderer: &mut Renderer,
+) -> AResult<()> {
+    let capture_a = Capture::read(a, options)?;
+    let capture_b = Capture::read(b, options)?;
+    let lines = diff(&capture_a, &capture_b);
+    for line in &lines {
+        renderer.write_raw(format!("{line}\n").as_bytes())?;
+    }
+    renderer.flush()?;
+    if !lines.is_empty() {
+        let n = lines.len();
+        let s = if n == 1 { "" } else { "s" };
+        bail!("{} and {} have {n} difference{s}", a.display(), b.display());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_diff() {
+    let mut a = Capture::default();
+    let mut b = Capture::default();
+    for (capture, id, messages) in [
+        (
+            &mut a,
+            10,
+            &[
+                "BIG:monetdb:{SHA512}1234:sql:demo:",
+                "sselect 1;\n",
+                "sselect * from t;\n",
+                "sdelete from t;\n",
+                "sdrop table t;\n",
+            ][..],
+        ),
+        (
+            &mut b,
+            20,
+            &[
+                "BIG:monetdb:{SHA512}5678:sql:demo:",
+                "sselect 1;\n",
+                "sselect * from t;\n",
+                "sdelete from t;\n",
+                "screate table u (i int);\n",
+            ],
+        ),
+    ] {
+        capture.add_message(id, Direction::Downstream, b"challenge:mserver:9:");
+        for message in messages {
+            capture.add_message(id, Direction::Upstream, message.as_bytes());
+            let response: &[u8] = match (id, *message) {
+                (10, "sselect * from t;\n") => b"&1 0 2 1 2\n[ 1\t]\n[ 2\t]\n",
+                (20, "sselect * from t;\n") => b"!42S02!SELECT: no such table 't'\n",
+                (10, "sselect 1;\n") => b"&1 0 1 1 1 9 7 1 1\n[ 1\t]\n",
+                (20, "sselect 1;\n") => b"&1 0 1 1 1 12 10 1 1\n[ 10\t]\n",
+                // only the timings in the header differ
+                (10, "sdelete from t;\n") => b"&2 2 -1 8 1\n",
+                (20, "sdelete from t;\n") => b"&2 2 -1 12 1\n",
+                _ => b"",
+            };
+            capture.add_message(id, Direction::Downstream, response);
+        }
+    }
+    b.add_conn(21);
+
+    assert_eq!(
+        diff(&a, &b),
+        [
+            "#10 vs #20: select 1: response 6 vs 7 bytes",
+            "#10 vs #20: select * from t: status &1 vs !42S02!SELECT: no such table 't'",
+            "#10 vs #20: only in A: drop table t",
+            "#10 vs #20: only in B: create table u (i int)",
+            "#21: only in B, 0 statements",
+        ]
+    );
+}
@@ -0,0 +1,117 @@
+//! Search the records in files written by `--format=jsonl`, see
+//! `mapiproxy grep`. Captures are searched by decoding them as usual with
+//! a [Filter] in place.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    ops::RangeInclusive,
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result as AResult};
+use serde_json::Value;
+
+use crate::{
+    mapi::{self, read_frame, Filter},
+    proxy::event::ConnectionId,
+    render::Renderer,
+};
+
+/// Whether `path` holds records rather than a capture.
+pub fn is_records_file(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "jsonl")
+}
+
+/// Read the records in a file written by `--format=jsonl`, calling
+/// `handle` with each record and the line it was read from.
+pub fn read_records(
+    path: &Path,
+    mut handle: impl FnMut(&Value, String) -> AResult<()>,
+) -> AResult<()> {
+    let file = File::open(path)
+        .with_context(|| format!("Could not open records file {}", path.display()))?;
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line =
+            line.with_context(|| format!("Could not read records file {}", path.display()))?;
+        let Ok(record) = serde_json::from_str::<Value>(&line) else {
+            bail!("{}:{}: not a JSON object", path.display(), n + 1);
+        };
+        handle(&record, line)?;
+    }
+    Ok(())
+}
+
+/// Show the messages, blocks and chunks of data in the files that the
+/// filter wants, rendered with the settings of `state`. If `state` writes
+/// JSON Lines, the lines are written as they are instead, after the schema
+/// record written by [mapi::State::start], so the result can be read like
+/// the original.
+pub fn grep_records(
+    paths: &[impl AsRef<Path>],
+    filter: &Filter,
+    conns: &[RangeInclusive<usize>],
+    state: &mapi::State,
+    renderer: &mut Renderer,
+) -> AResult<()> {
+    for path in paths {
+        // Per connection, see Filter::wants_in_conn
+        let mut excluded = HashMap::new();
+        read_records(path.as_ref(), |record, line| {
+            if !wants(record, filter, conns, &mut excluded) {
+                return Ok(());
+            }
+            if state.is_structured() {
+                renderer.write_raw(format!("{line}\n").as_bytes())?;
+            } else if let Some((conn, direction, data)) = read_frame(record) {
+                let kind = record["event"].as_str().unwrap_or_default();
+                state.show_frame(ConnectionId::new(conn), direction, kind, &data, renderer)?;
+            }
+            Ok(())
+        })?;
+    }
+    renderer.flush()?;
+    Ok(())
+}
+
+fn wants(
+    record: &Value,
+    filter: &Filter,
+    conns: &[RangeInclusive<usize>],
+    excluded: &mut HashMap<usize, bool>,
+) -> bool {
+    let Some((conn, direction, data)) = read_frame(record) else {
+        return false;
+    };
+    if !conns.is_empty() && !conns.iter().any(|r| r.contains(&conn)) {
+        return false;
+    }
+    let excluded = excluded.entry(conn).or_default();
+    filter.wants_in_conn(excluded, direction, &data)
+}
+
+#[test]
+fn test_wants() {
+    use lazy_regex::BytesRegex;
+    use serde_json::json;
+
+    let filter = Filter {
+        grep: Some(BytesRegex::new("SELECT").unwrap()),
+        sender: None,
+        exclude: vec![],
+    };
+    let mut excluded = HashMap::new();
+    let record = json!({ "event": "message", "conn": 12, "direction": "upstream", "size": 9, "text": "sSELECT 1" });
+    assert!(wants(&record, &filter, &[], &mut excluded));
+    assert!(wants(&record, &filter, &[10..=12], &mut excluded));
+    assert!(!wants(&record, &filter, &[13..=13], &mut excluded));
+
+    let record = json!({ "event": "message", "conn": 12, "direction": "downstream", "size": 6, "base64": "U0VMRUNU" });
+    assert!(wants(&record, &filter, &[], &mut excluded));
+    let record = json!({ "event": "message", "conn": 12, "direction": "downstream", "size": 2, "text": "=1" });
+    assert!(!wants(&record, &filter, &[], &mut excluded));
+    let record =
+        json!({ "event": "incoming", "conn": 12, "local": "[::1]:50000", "peer": "[::1]:41394" });
+    assert!(!wants(&record, &filter, &[], &mut excluded));
+}
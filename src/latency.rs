@@ -0,0 +1,165 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    time::Duration,
+};
+
+use crate::{
+    proxy::{
+        counter::Counter,
+        event::{CaptureTime, ConnectionId, Direction, MapiEvent},
+    },
+    render::Renderer,
+};
+
+/// Measures how long the server takes to answer each request, see
+/// `--latency` and `--stats`. Every message of the server except the login
+/// challenge is the response to the oldest unanswered message of the client.
+/// The round trip is measured from the last packet of the request to the
+/// last packet of the response.
+#[derive(Debug, Default)]
+pub struct Latency {
+    /// Data without a capture time happens now. Otherwise, such as with
+    /// `--streams`, there is no time to measure.
+    live: bool,
+    connections: HashMap<ConnectionId, ConnLatency>,
+    /// Round trip times of all requests seen so far
+    samples: Vec<Duration>,
+}
+
+#[derive(Debug)]
+struct ConnLatency {
+    upstream: Counter,
+    downstream: Counter,
+    /// Capture times of the requests that have not been answered yet
+    unanswered: VecDeque<Duration>,
+}
+
+impl Latency {
+    pub fn new(live: bool) -> Self {
+        Latency {
+            live,
+            ..Latency::default()
+        }
+    }
+
+    /// Pair the requests and responses. If a renderer is given, report the
+    /// round trip time of each response as it completes.
+    pub fn handle(&mut self, event: &MapiEvent, renderer: Option<&mut Renderer>) -> io::Result<()> {
+        match event {
+            MapiEvent::Incoming { id, .. } => {
+                let conn = ConnLatency {
+                    upstream: Counter::new(false),
+                    downstream: Counter::new(false),
+                    unanswered: VecDeque::new(),
+                };
+                self.connections.insert(*id, conn);
+            }
+            MapiEvent::Data {
+                id,
+                direction,
+                data,
+                timestamp,
+            } => {
+                let Some(conn) = self.connections.get_mut(id) else {
+                    return Ok(());
+                };
+                // Live traffic has no capture times
+                let timestamp = timestamp.or_else(|| self.live.then(CaptureTime::now));
+                let Some(CaptureTime(now)) = timestamp else {
+                    return Ok(());
+                };
+                let mut round_trips = vec![];
+                let mut data = &data[..];
+                while !data.is_empty() {
+                    let counter = match direction {
+                        Direction::Upstream => &mut conn.upstream,
+                        Direction::Downstream => &mut conn.downstream,
+                    };
+                    let before = counter.messages;
+                    let n = counter.advance(data);
+                    data = &data[n..];
+                    if counter.messages == before {
+                        continue;
+                    }
+                    match direction {
+                        Direction::Upstream => conn.unanswered.push_back(now),
+                        // Responses without a request, such as the login
+                        // challenge, are skipped
+                        Direction::Downstream => {
+                            if let Some(sent) = conn.unanswered.pop_front() {
+                                round_trips.push(now.saturating_sub(sent));
+                            }
+                        }
+                    }
+                }
+                if let Some(renderer) = renderer {
+                    for rtt in &round_trips {
+                        renderer.message(
+                            Some(*id),
+                            Some(*direction),
+                            format_args!("response after {}", millis(*rtt)),
+                        )?;
+                    }
+                }
+                self.samples.extend(round_trips);
+            }
+            MapiEvent::DataMissing { id, .. } => {
+                // We can no longer tell which response belongs to which
+                // request
+                if let Some(conn) = self.connections.get_mut(id) {
+                    conn.upstream.resync();
+                    conn.downstream.resync();
+                    conn.unanswered.clear();
+                }
+            }
+            MapiEvent::End { id, .. } | MapiEvent::Aborted { id, .. } => {
+                self.connections.remove(id);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The round trip times seen so far, sorted.
+    pub fn samples(&mut self) -> &[Duration] {
+        self.samples.sort();
+        &self.samples
+    }
+
+    /// Print the distribution of the round trip times.
+    pub fn report(&mut self, renderer: &mut Renderer) -> io::Result<()> {
+        self.samples.sort();
+        let n = self.samples.len();
+        let s = if n == 1 { "" } else { "s" };
+        let mut summary = format!("LATENCY: {n} request{s}");
+        if n > 0 {
+            for (label, p) in [("min", 0.0), ("median", 50.0), ("p90", 90.0), ("p99", 99.0)] {
+                let value = percentile(&self.samples, p);
+                summary += &format!(", {label} {}", millis(value));
+            }
+            summary += &format!(", max {}", millis(self.samples[n - 1]));
+        }
+        renderer.message(None, None, summary)?;
+        renderer.flush()
+    }
+}
+
+/// Nearest-rank percentile of sorted, non-empty samples
+pub fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+pub fn millis(d: Duration) -> String {
+    format!("{:.3}ms", d.as_secs_f64() * 1000.0)
+}
+
+#[test]
+fn test_percentile() {
+    let samples: Vec<Duration> = (1..=10).map(Duration::from_millis).collect();
+    assert_eq!(percentile(&samples, 0.0), Duration::from_millis(1));
+    assert_eq!(percentile(&samples, 50.0), Duration::from_millis(5));
+    assert_eq!(percentile(&samples, 90.0), Duration::from_millis(9));
+    assert_eq!(percentile(&samples, 99.0), Duration::from_millis(10));
+}
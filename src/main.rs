@@ -1,28 +1,81 @@
 #![doc = include_str!("../README.md")]
 
+mod config;
+mod diff;
+mod grep;
+mod latency;
+mod man;
 mod mapi;
+mod output;
+mod parallel;
+mod parquet;
 mod pcap;
+mod progress;
 mod proxy;
 mod render;
+mod replay;
+mod split;
+mod sqlite;
+mod stats;
+mod statsd;
+mod streams;
+mod summarize;
+mod tee;
+mod web;
 
-use std::fs::File;
-use std::panic::PanicInfo;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::ops::RangeInclusive;
+use std::panic::PanicHookInfo;
 use std::path::{Path, PathBuf};
 use std::process::ExitCode;
-use std::{io, panic, process, thread};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{env, io, panic, process, thread};
 
 use anyhow::{bail, Context, Result as AResult};
 use argsplitter::{ArgError, ArgSplitter};
-use pcap::Tracker;
-use proxy::event::MapiEvent;
-use proxy::network::MonetAddr;
+use lazy_regex::BytesRegex;
+use pcap::{Recorder, Selection, SplitRecorder, TimeBound, Tracker};
+use proxy::event::{CaptureTime, ConnectionId, Direction, MapiEvent, TimeZone};
+use proxy::network::{Addr, MonetAddr, ResolvePolicy, Subnet};
+use replay::Replayer;
+use tracing::{info, warn};
+use tracing_subscriber::{prelude::*, reload, EnvFilter, Registry};
 
-use crate::{proxy::Proxy, render::Renderer};
+use crate::{
+    config::Config,
+    latency::Latency,
+    mapi::{Anonymizer, Check, Filter},
+    output::Output,
+    parallel::Parallel,
+    parquet::ParquetLog,
+    progress::Progress,
+    proxy::{KillAfter, Options as ProxyOptions, Proxy},
+    render::{Renderer, Theme, Timestamps},
+    split::SplitOutput,
+    sqlite::SqliteLog,
+    stats::{ByteLimit, Stats, Totals},
+    statsd::Statsd,
+    tee::Tee,
+    web::Web,
+};
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+pub const DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
+
 pub const USAGE: &str = include_str!("usage.txt");
 
+/// Width for `--side-by-side` and `--wrap` when it's not given and the
+/// output is not a terminal
+const DEFAULT_WIDTH: usize = 160;
+const MIN_WIDTH: usize = 20;
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 enum Level {
     Raw,
@@ -30,13 +83,49 @@ enum Level {
     Messages,
 }
 
+/// How the events are written, see `--format`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Format {
+    Text,
+    JsonLines,
+    /// The bodies of the messages, each preceded by a small header
+    Raw,
+    /// The same records as [Format::JsonLines], encoded as CBOR
+    Cbor,
+    /// The same records as [Format::JsonLines] as plain lines that are easy
+    /// to grep and compare
+    PlainStable,
+}
+
 #[derive(Debug)]
 enum Source {
     Proxy {
         listen_addr: MonetAddr,
         forward_addr: MonetAddr,
     },
-    Pcap(PathBuf),
+    Pcap(Vec<PathBuf>),
+    /// Files written by `--format=jsonl`, searched by `mapiproxy grep`
+    Records(Vec<PathBuf>),
+    /// Two captures or files of records, compared by `mapiproxy diff`
+    Diff {
+        a: PathBuf,
+        b: PathBuf,
+    },
+    /// A capture to rewrite into another by `mapiproxy anonymize`
+    Anonymize {
+        input: PathBuf,
+        output: PathBuf,
+    },
+    /// A capture to give an overview of by `mapiproxy summarize`
+    Summarize(PathBuf),
+    Replay {
+        path: PathBuf,
+        forward_addr: MonetAddr,
+    },
+    Streams {
+        client: PathBuf,
+        server: PathBuf,
+    },
 }
 
 fn main() -> ExitCode {
@@ -46,19 +135,299 @@ fn main() -> ExitCode {
 fn mymain() -> AResult<()> {
     install_panic_hook();
 
-    let mut pcap_file: Option<PathBuf> = None;
+    // `mapiproxy @args.txt` reads the arguments from args.txt
+    let mut argv = config::expand_arg_files(env::args_os())?;
+    if argv.get(1).is_some_and(|arg| arg == "man") {
+        print!("{}", man::man_page(USAGE, VERSION, DESCRIPTION));
+        return Ok(());
+    }
+    // `mapiproxy grep PATTERN FILE...` searches captures and records,
+    // `mapiproxy diff FILE_A FILE_B` compares them and `mapiproxy anonymize
+    // IN OUT` makes a capture fit for sharing. `mapiproxy summarize FILE`
+    // lists the most frequent, slowest and largest queries
+    let command = argv.get(1).and_then(|arg| {
+        ["grep", "diff", "anonymize", "summarize"]
+            .into_iter()
+            .find(|c| arg == *c)
+    });
+
+    let mut pcap_files: Vec<PathBuf> = vec![];
+    let mut records_files: Vec<PathBuf> = vec![];
+    let mut diff_files: Option<(PathBuf, PathBuf)> = None;
+    let mut anonymize_files: Option<(PathBuf, PathBuf)> = None;
+    let mut anonymize_strings = false;
+    let mut summarize_file: Option<PathBuf> = None;
+    let mut top = None;
+    let mut replay_file: Option<PathBuf> = None;
+    let mut replay_password: Option<String> = None;
+    let mut streams_file: Option<PathBuf> = None;
     let mut level = None;
+    let mut quiet = false;
+    let mut show_peers = false;
+    let mut max_lines = None;
+    let mut escape = false;
+    let mut table = false;
+    let mut hide_empty = false;
+    let mut quiet_connections = false;
+    let mut side_by_side: Option<Option<usize>> = None;
+    let mut wrap: Option<Option<usize>> = None;
+    let mut idle_gap = None;
     let mut force_binary = false;
+    let mut format = Format::Text;
     let mut colored = None;
+    let mut theme = None;
+    let mut ascii = false;
+    let mut color_connections = false;
+    let mut frame_chars = None;
+    let mut output_file: Option<PathBuf> = None;
+    let mut rotate_size = None;
+    let mut keep = None;
+    let mut limit_bytes = None;
+    let mut limit_exit = false;
+    let mut record_pcap: Option<PathBuf> = None;
+    let mut record_pcap_dir: Option<PathBuf> = None;
+    let mut tee_dir: Option<PathBuf> = None;
+    let mut split_output: Option<PathBuf> = None;
+    let mut sqlite_file: Option<PathBuf> = None;
+    let mut parquet_file: Option<PathBuf> = None;
+    let mut statsd_addr: Option<String> = None;
+    let mut web_addr: Option<String> = None;
+    let mut proxy_options = ProxyOptions::default();
+    let mut pcap_options = pcap::Options::default();
+    let mut pcap_pace = false;
+    let mut pcap_stats = false;
+    let mut latency = false;
+    let mut totals = false;
+    let mut check_exit_code = false;
+    let mut progress = false;
+    let mut jobs = None;
+    let mut time_zone = None;
+    let mut timestamps = None;
+    let mut timestamp_format = None;
+    let mut from: Option<String> = None;
+    let mut to: Option<String> = None;
+    let mut keylog_files: Vec<PathBuf> = vec![];
+    let mut conns: Vec<RangeInclusive<usize>> = vec![];
+    let mut clients = vec![];
+    let mut filter = Filter::default();
+    let mut speed = None;
+    let mut log_level = None;
+    let mut dry_run = false;
 
-    let mut args = ArgSplitter::from_env();
+    // Options from the config file go first so the command line overrides
+    // them
+    if command.is_some() {
+        argv.remove(1);
+    }
+    let config_path = config::config_arg(&argv[1..]);
+    let command_line_options = config::option_names(&argv[1..]);
+    let config = Config::load(config_path.as_deref())?;
+    argv.splice(1..1, config.args);
+    let mut args = ArgSplitter::from(argv);
     while let Some(flag) = args.flag()? {
         match flag {
-            "--pcap" => pcap_file = Some(args.param_os()?.into()),
+            "--config" => {
+                // Already read
+                args.param_os()?;
+            }
+            "--pcap" => pcap_files.push(args.param_os()?.into()),
+            "--replay" => replay_file = Some(args.param_os()?.into()),
+            "--replay-password" => replay_password = Some(args.param()?),
+            "--streams" => streams_file = Some(args.param_os()?.into()),
+            "--port" => pcap_options.ports.extend(parse_ports(&args.param()?)?),
+            "--mid-stream" => pcap_options.mid_stream = true,
+            "--allow-truncated" => pcap_options.allow_truncated = true,
+            "--reorder-limit" => {
+                let text = args.param()?;
+                let Some(limit) = parse_size(&text).and_then(|n| usize::try_from(n).ok()) else {
+                    bail!("--reorder-limit={text}: invalid size");
+                };
+                pcap_options.reorder_limit = Some(limit);
+            }
+            "--decapsulate" => pcap_options.decapsulate = true,
+            "--detect-mapi" => pcap_options.detect_mapi = true,
+            "--tcp-events" => pcap_options.tcp_events = true,
+            "--keylog" => keylog_files.push(args.param_os()?.into()),
+            "--select" => pcap_options.select = Some(parse_select(&args.param()?)?),
+            "--first-id" => {
+                let text = args.param()?;
+                let Ok(n) = text.parse() else {
+                    bail!("--first-id={text}: expected a number");
+                };
+                proxy_options.first_id = Some(n);
+                pcap_options.first_id = Some(n);
+            }
+            "--conn" => conns.extend(parse_conns(&args.param()?)?),
+            "--client" => clients.push(parse_client(&args.param()?)?),
+            "--anonymize-strings" => anonymize_strings = true,
+            "--top" => {
+                let text = args.param()?;
+                let Some(n) = text.parse().ok().filter(|&n: &usize| n > 0) else {
+                    bail!("--top={text}: expected a number of queries");
+                };
+                top = Some(n);
+            }
+            "--grep" => filter.grep = Some(parse_grep("--grep", &args.param()?)?),
+            "--exclude-grep" => filter
+                .exclude
+                .push(parse_grep("--exclude-grep", &args.param()?)?),
+            "--sender" => filter.sender = Some(parse_sender(&args.param()?)?),
+            "--from" => from = Some(args.param()?),
+            "--to" => to = Some(args.param()?),
+            "--pcap-pace" => pcap_pace = true,
+            "--pcap-stats" => pcap_stats = true,
+            "--latency" => latency = true,
+            "--stats" => totals = true,
+            "--progress" => progress = true,
+            "--jobs" => {
+                let text = args.param()?;
+                let Some(n) = text.parse().ok().filter(|&n| n > 0) else {
+                    bail!("--jobs={text}: expected a number of threads");
+                };
+                jobs = Some(n);
+            }
+            "--tz" => time_zone = Some(parse_time_zone(&args.param()?)?),
+            "--timestamp-format" => {
+                timestamp_format = Some(parse_timestamp_format(&args.param()?)?)
+            }
+            "--timestamps" => {
+                timestamps = match args.param()?.as_str() {
+                    "none" => Some(Timestamps::None),
+                    "absolute" => Some(Timestamps::Absolute),
+                    "relative" => Some(Timestamps::Relative),
+                    "delta" => Some(Timestamps::Delta),
+                    other => bail!(
+                        "--timestamps={other}: must be 'none', 'absolute', 'relative' or 'delta'"
+                    ),
+                }
+            }
+            "--speed" => speed = Some(parse_speed(&args.param()?)?),
             "-m" | "--messages" => level = Some(Level::Messages),
             "-b" | "--blocks" => level = Some(Level::Blocks),
             "-r" | "--raw" => level = Some(Level::Raw),
             "-B" | "--binary" => force_binary = true,
+            "--format" => {
+                format = match args.param()?.as_str() {
+                    "text" => Format::Text,
+                    "jsonl" => Format::JsonLines,
+                    "raw" => Format::Raw,
+                    "cbor" => Format::Cbor,
+                    "plain-stable" => Format::PlainStable,
+                    other => bail!(
+                        "--format={other}: must be 'text', 'jsonl', 'cbor', 'plain-stable' or 'raw'"
+                    ),
+                }
+            }
+            "-o" | "--output" => output_file = Some(args.param_os()?.into()),
+            "--rotate-size" => {
+                let text = args.param()?;
+                let Some(size) = parse_size(&text).filter(|&n| n > 0) else {
+                    bail!("--rotate-size={text}: invalid size");
+                };
+                rotate_size = Some(size);
+            }
+            "--limit-bytes" => {
+                let text = args.param()?;
+                let Some(size) = parse_size(&text).filter(|&n| n > 0) else {
+                    bail!("--limit-bytes={text}: invalid size");
+                };
+                limit_bytes = Some(size);
+            }
+            "--limit-exit" => limit_exit = true,
+            "--keep" => {
+                let text = args.param()?;
+                let Ok(n) = text.parse() else {
+                    bail!("--keep={text}: expected a number of files");
+                };
+                keep = Some(n);
+            }
+            "--record-pcap" => record_pcap = Some(args.param_os()?.into()),
+            "--record-pcap-dir" => record_pcap_dir = Some(args.param_os()?.into()),
+            "--tee" => tee_dir = Some(args.param_os()?.into()),
+            "--split-output" => split_output = Some(args.param_os()?.into()),
+            "--sqlite" => sqlite_file = Some(args.param_os()?.into()),
+            "--parquet" => parquet_file = Some(args.param_os()?.into()),
+            "--statsd" => statsd_addr = Some(args.param()?),
+            "--web" => web_addr = Some(args.param()?),
+            "--quiet-data" => proxy_options.pass_through = true,
+            "--quiet" => quiet = true,
+            "--retry" => proxy_options.retry = Some(parse_retry(&args.param()?)?),
+            "--connect-timeout" => {
+                let text = args.param()?;
+                let Some(timeout) = parse_duration(&text) else {
+                    bail!("--connect-timeout={text}: invalid duration");
+                };
+                proxy_options.connect_timeout = Some(timeout);
+            }
+            "--idle-gap" => {
+                let text = args.param()?;
+                let Some(gap) = parse_duration(&text) else {
+                    bail!("--idle-gap={text}: invalid duration");
+                };
+                idle_gap = Some(gap);
+            }
+            "--check-exit-code" => check_exit_code = true,
+            "--status-interval" => {
+                let text = args.param()?;
+                let Some(interval) = parse_duration(&text).filter(|d| !d.is_zero()) else {
+                    bail!("--status-interval={text}: invalid duration");
+                };
+                proxy_options.status_interval = Some(interval);
+            }
+            "--duration" => {
+                let text = args.param()?;
+                let Some(duration) = parse_duration(&text).filter(|d| !d.is_zero()) else {
+                    bail!("--duration={text}: invalid duration");
+                };
+                proxy_options.duration = Some(duration);
+            }
+            "--max-total-connections" => {
+                let text = args.param()?;
+                let Some(n) = text.parse().ok().filter(|&n| n > 0) else {
+                    bail!("--max-total-connections={text}: expected a positive number");
+                };
+                proxy_options.max_total_connections = Some(n);
+            }
+            "--happy-eyeballs" => proxy_options.happy_eyeballs = true,
+            "--resolve" => proxy_options.resolve = parse_resolve(&args.param()?)?,
+            "--transparent" => {
+                if !cfg!(target_os = "linux") {
+                    bail!("--transparent is only supported on Linux");
+                }
+                proxy_options.transparent = true;
+            }
+            "--mirror" => proxy_options.mirror = Some(args.param_os()?.try_into()?),
+            "--reuseport" => proxy_options.reuse_port = true,
+            "--v6only" => {
+                proxy_options.v6only = match args.param()?.to_lowercase().as_str() {
+                    "yes" => Some(true),
+                    "no" => Some(false),
+                    other => bail!("--v6only={other}: must be 'yes' or 'no'"),
+                }
+            }
+            "--kill-after" => proxy_options.kill_after = parse_kill_after(&args.param()?)?,
+            "--first-byte-delay" => {
+                let Ok(ms) = args.param()?.parse() else {
+                    bail!("--first-byte-delay: expected a number of milliseconds");
+                };
+                proxy_options.first_byte_delay = Some(Duration::from_millis(ms));
+            }
+            "--no-nodelay" => {
+                proxy_options.nagle_client = true;
+                proxy_options.nagle_server = true;
+            }
+            "--no-nodelay-client" => proxy_options.nagle_client = true,
+            "--no-nodelay-server" => proxy_options.nagle_server = true,
+            "--route" => proxy_options
+                .sni_routes
+                .extend(parse_routes(&args.param()?)?),
+            "--route-wait" => {
+                let text = args.param()?;
+                let Some(wait) = parse_duration(&text) else {
+                    bail!("--route-wait={text}: invalid duration");
+                };
+                proxy_options.sni_wait = Some(wait);
+            }
             "--color" => {
                 colored = match args.param()?.to_lowercase().as_str() {
                     "always" => Some(true),
@@ -67,6 +436,42 @@ fn mymain() -> AResult<()> {
                     other => bail!("--color={other}: must be 'always', 'auto' or 'never'"),
                 }
             }
+            "--theme" => {
+                let text = args.param()?;
+                let Some(t) = Theme::parse(&text) else {
+                    bail!("--theme={text}: must be 'light', 'dark' and/or STYLE=CODE, for example frame=36");
+                };
+                theme = Some(t);
+            }
+            "--ascii" => ascii = true,
+            "--color-connections" => color_connections = true,
+            "--frame-chars" => frame_chars = Some(args.param()?),
+            "--show-peers" => show_peers = true,
+            "--escape" => escape = true,
+            "--table" => table = true,
+            "--hide-empty" => hide_empty = true,
+            "--quiet-connections" => quiet_connections = true,
+            "--side-by-side" => {
+                side_by_side = Some(None);
+                if args.has_param_attached() {
+                    side_by_side = Some(Some(parse_width("--side-by-side", &args.param()?)?));
+                }
+            }
+            "--wrap" => {
+                wrap = Some(None);
+                if args.has_param_attached() {
+                    wrap = Some(Some(parse_width("--wrap", &args.param()?)?));
+                }
+            }
+            "--max-lines" => {
+                let text = args.param()?;
+                let Ok(n) = text.parse() else {
+                    bail!("--max-lines={text}: expected a number of lines");
+                };
+                max_lines = Some(n);
+            }
+            "--log-level" => log_level = Some(args.param()?),
+            "--dry-run" => dry_run = true,
             "--help" => {
                 println!("Mapiproxy version {VERSION}");
                 println!();
@@ -77,98 +482,1415 @@ fn mymain() -> AResult<()> {
                 println!("Mapiproxy version {VERSION}");
                 return Ok(());
             }
+            "--print-schema" => {
+                print!("{}", mapi::SCHEMA);
+                return Ok(());
+            }
             _ => return Err(ArgError::unknown_flag(flag).into()),
         }
     }
-    let Some(level) = level else {
-        return Err(ArgError::message("Please set the mode using -r, -b or -m").into());
+    let log_filter = install_logger(log_level.as_deref())?;
+    if command == Some("grep") {
+        if filter.grep.is_some() {
+            bail!("--grep cannot be used with mapiproxy grep");
+        }
+        filter.grep = Some(parse_grep("PATTERN", &args.stashed("PATTERN")?)?);
+        for path in args.stashed_args_os(1, "FILE")?.map(PathBuf::from) {
+            if grep::is_records_file(&path) {
+                records_files.push(path);
+            } else {
+                pcap_files.push(path);
+            }
+        }
+        if !records_files.is_empty() && !pcap_files.is_empty() {
+            bail!("mapiproxy grep cannot search .jsonl files and captures at the same time");
+        }
+        if !records_files.is_empty() && ![Format::Text, Format::JsonLines].contains(&format) {
+            bail!("mapiproxy grep shows the records in .jsonl files as text or, with --format=jsonl, as they are");
+        }
+        level.get_or_insert(Level::Messages);
+    }
+    if command == Some("diff") {
+        if !pcap_files.is_empty() {
+            bail!("--pcap cannot be used with mapiproxy diff");
+        }
+        if format != Format::Text {
+            bail!("mapiproxy diff writes a line per difference, --format cannot be used");
+        }
+        let a = args.stashed_os("FILE_A")?.into();
+        let b = args.stashed_os("FILE_B")?.into();
+        diff_files = Some((a, b));
+        level.get_or_insert(Level::Messages);
+    }
+    if command == Some("anonymize") {
+        if !pcap_files.is_empty() {
+            bail!("--pcap cannot be used with mapiproxy anonymize");
+        }
+        let input = args.stashed_os("IN")?.into();
+        let output = args.stashed_os("OUT")?.into();
+        anonymize_files = Some((input, output));
+        // Doesn't matter, nothing is dumped
+        level.get_or_insert(Level::Messages);
+    } else if anonymize_strings {
+        bail!("--anonymize-strings can only be used with mapiproxy anonymize");
+    }
+    if command == Some("summarize") {
+        if !pcap_files.is_empty() {
+            bail!("--pcap cannot be used with mapiproxy summarize");
+        }
+        if format != Format::Text {
+            bail!("mapiproxy summarize writes a report, --format cannot be used");
+        }
+        summarize_file = Some(args.stashed_os("FILE")?.into());
+        // Doesn't matter, nothing is dumped
+        level.get_or_insert(Level::Messages);
+    } else if top.is_some() {
+        bail!("--top can only be used with mapiproxy summarize");
+    }
+    let level = match level {
+        Some(level) => level,
+        // doesn't matter, nothing is dumped
+        None if pcap_stats => Level::Messages,
+        None if quiet => Level::Messages,
+        None => return Err(ArgError::message("Please set the mode using -r, -b or -m").into()),
     };
 
-    let source = if let Some(path) = pcap_file {
-        Source::Pcap(path)
+    if !pcap_files.is_empty() && replay_file.is_some() {
+        bail!("--pcap and --replay cannot be combined");
+    }
+    if rotate_size.is_some() && output_file.is_none() {
+        bail!("--rotate-size can only be used with --output");
+    }
+    if keep.is_some() && rotate_size.is_none() {
+        bail!("--keep can only be used with --rotate-size");
+    }
+    if streams_file.is_some() && (!pcap_files.is_empty() || replay_file.is_some()) {
+        bail!("--streams cannot be combined with --pcap or --replay");
+    }
+    if replay_password.is_some() && replay_file.is_none() {
+        bail!("--replay-password can only be used with --replay");
+    }
+    if pcap_stats && pcap_files.is_empty() {
+        bail!("--pcap-stats can only be used with --pcap");
+    }
+    if latency && pcap_files.is_empty() {
+        bail!("--latency can only be used with --pcap");
+    }
+    if progress && pcap_files.is_empty() {
+        bail!("--progress can only be used with --pcap");
+    }
+    let templated = [&output_file, &record_pcap, &sqlite_file, &parquet_file]
+        .into_iter()
+        .flatten()
+        .any(|p| p.to_string_lossy().contains('%'));
+    if time_zone.is_some()
+        && pcap_files.is_empty()
+        && timestamps != Some(Timestamps::Absolute)
+        && timestamp_format.is_none()
+        && !templated
+    {
+        bail!(
+            "--tz can only be used with --pcap, --timestamps=absolute, --timestamp-format or placeholders in --output"
+        );
+    }
+    if jobs.is_some() && pcap_files.is_empty() {
+        bail!("--jobs can only be used with --pcap");
+    }
+    if jobs.is_some() && (pcap_stats || latency) {
+        bail!("--jobs cannot be combined with --pcap-stats or --latency");
+    }
+    if format != Format::Text && (pcap_stats || latency) {
+        bail!("--format cannot be combined with --pcap-stats or --latency");
+    }
+    if totals && format != Format::Text {
+        bail!("--stats can only be used with --format=text");
+    }
+    if quiet && level == Level::Raw {
+        bail!("--quiet cannot be combined with --raw");
+    }
+    if show_peers && format != Format::Text {
+        bail!("--show-peers can only be used with --format=text");
+    }
+    if table && format != Format::Text {
+        bail!("--table can only be used with --format=text");
+    }
+    if hide_empty && format != Format::Text {
+        bail!("--hide-empty can only be used with --format=text");
+    }
+    if quiet_connections && format != Format::Text {
+        bail!("--quiet-connections can only be used with --format=text");
+    }
+    if wrap.is_some() && format != Format::Text {
+        bail!("--wrap can only be used with --format=text");
+    }
+    if side_by_side.is_some() && format != Format::Text {
+        bail!("--side-by-side can only be used with --format=text");
+    }
+    if max_lines.is_some() && format != Format::Text {
+        bail!("--max-lines can only be used with --format=text");
+    }
+    if quiet && format != Format::Text {
+        bail!("--quiet can only be used with --format=text");
+    }
+    if timestamps.is_some() && format != Format::Text {
+        bail!("--timestamps can only be used with --format=text");
+    }
+    if timestamps.is_some() && streams_file.is_some() {
+        bail!("--timestamps cannot be combined with --streams");
+    }
+    if limit_exit && limit_bytes.is_none() {
+        bail!("--limit-exit can only be used with --limit-bytes");
+    }
+
+    let source = if let Some((a, b)) = diff_files {
+        Source::Diff { a, b }
+    } else if let Some((input, output)) = anonymize_files {
+        Source::Anonymize { input, output }
+    } else if let Some(path) = summarize_file {
+        Source::Summarize(path)
+    } else if !records_files.is_empty() {
+        Source::Records(records_files)
+    } else if !pcap_files.is_empty() {
+        if tee_dir.is_some() {
+            bail!("--tee can only be used when proxying");
+        }
+        if limit_exit {
+            bail!("--limit-exit can only be used when proxying");
+        }
+        if pcap_pace {
+            pcap_options.pace = Some(speed.unwrap_or(1.0));
+        } else if speed.is_some() {
+            bail!("--speed can only be used with --pcap-pace");
+        }
+        Source::Pcap(pcap_files)
+    } else if let Some(path) = replay_file {
+        if pcap_pace || speed.is_some() {
+            bail!("--pcap-pace and --speed can only be used with --pcap");
+        }
+        if limit_exit {
+            bail!("--limit-exit can only be used when proxying");
+        }
+        let forward_addr = args
+            .stashed_os("FORWARD_ADDR")
+            .or_else(|e| config.forward_addr.clone().ok_or(e))?
+            .try_into()?;
+        Source::Replay { path, forward_addr }
     } else {
-        let listen_addr = args.stashed_os("LISTEN_ADDR")?.try_into()?;
-        let forward_addr = args.stashed_os("FORWARD_ADDR")?.try_into()?;
-        Source::Proxy {
-            listen_addr,
-            forward_addr,
+        if !pcap_options.ports.is_empty() {
+            bail!("--port can only be used with --pcap or --replay");
+        }
+        if pcap_options.mid_stream {
+            bail!("--mid-stream can only be used with --pcap or --replay");
+        }
+        if pcap_options.allow_truncated {
+            bail!("--allow-truncated can only be used with --pcap or --replay");
+        }
+        if pcap_options.reorder_limit.is_some() {
+            bail!("--reorder-limit can only be used with --pcap or --replay");
+        }
+        if pcap_options.decapsulate {
+            bail!("--decapsulate can only be used with --pcap or --replay");
+        }
+        if pcap_options.select.is_some() {
+            bail!("--select can only be used with --pcap or --replay");
+        }
+        if pcap_options.detect_mapi {
+            bail!("--detect-mapi can only be used with --pcap or --replay");
+        }
+        if pcap_options.tcp_events {
+            bail!("--tcp-events can only be used with --pcap or --replay");
+        }
+        if !keylog_files.is_empty() {
+            bail!("--keylog can only be used with --pcap or --replay");
+        }
+        if from.is_some() || to.is_some() {
+            bail!("--from and --to can only be used with --pcap or --replay");
+        }
+        if pcap_pace || speed.is_some() {
+            bail!("--pcap-pace and --speed can only be used with --pcap");
+        }
+        if let Some(client) = streams_file {
+            if limit_exit {
+                bail!("--limit-exit can only be used when proxying");
+            }
+            let server = args.stashed_os("SERVER_FILE")?.into();
+            Source::Streams { client, server }
+        } else {
+            let listen_addr = args
+                .stashed_os("LISTEN_ADDR")
+                .or_else(|e| config.listen_addr.clone().ok_or(e))?
+                .try_into()?;
+            let forward_addr = args
+                .stashed_os("FORWARD_ADDR")
+                .or_else(|e| config.forward_addr.clone().ok_or(e))?
+                .try_into()?;
+            Source::Proxy {
+                listen_addr,
+                forward_addr,
+            }
         }
     };
 
     args.no_more_stashed()?;
 
-    let out = io::stdout();
-    let colored = colored.unwrap_or_else(|| is_terminal::is_terminal(&out));
+    // Capture times without an offset are in the zone the output uses
+    let zone = time_zone.unwrap_or_default();
+    if let Some(text) = &from {
+        pcap_options.from = Some(parse_time_bound("--from", text, zone)?);
+    }
+    if let Some(text) = &to {
+        pcap_options.to = Some(parse_time_bound("--to", text, zone)?);
+    }
+    if let (Some(from), Some(to)) = (pcap_options.from, pcap_options.to) {
+        let empty = match (from, to) {
+            (TimeBound::At(from), TimeBound::At(to)) => from > to,
+            (TimeBound::After(from), TimeBound::After(to)) => from > to,
+            _ => false,
+        };
+        if empty {
+            bail!("--from must not be later than --to");
+        }
+    }
+
+    // Placeholders such as %Y in the output paths, in the same zone. The
+    // output file expands them again when it is rotated or reopened.
+    let output_template = output_file.clone();
+    let now = CaptureTime::now().in_zone(zone);
+    for path in [
+        &mut output_file,
+        &mut record_pcap,
+        &mut sqlite_file,
+        &mut parquet_file,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        *path = output::expand_path(path, &now);
+    }
+
+    for path in &keylog_files {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Could not read key log file {}", path.display()))?;
+        pcap_options.keylog.push_str(&text);
+    }
+
+    if dry_run {
+        let outputs = [
+            ("output", &output_file),
+            ("PCAP-NG capture", &record_pcap),
+            ("SQLite database", &sqlite_file),
+            ("Parquet file", &parquet_file),
+            ("split output", &split_output),
+        ];
+        let dirs = [
+            ("PCAP-NG directory", &record_pcap_dir),
+            ("tee directory", &tee_dir),
+        ];
+        let services = Services {
+            mirror: proxy_options.mirror.as_ref(),
+            statsd: statsd_addr.as_deref(),
+            web: web_addr.as_deref(),
+        };
+        return report_dry_run(&source, &outputs, &dirs, &services);
+    }
+
+    let out = if let (Some(path), Some(template)) = (&output_file, &output_template) {
+        Output::create(path)
+            .with_context(|| format!("Could not create output file {}", path.display()))?
+            .with_template(template, zone)
+    } else {
+        Output::stdout()
+    };
+    if matches!(format, Format::Raw | Format::Cbor) && out.is_terminal() {
+        let name = if format == Format::Raw { "raw" } else { "cbor" };
+        bail!("--format={name} writes binary data, use --output or a pipe");
+    }
+    // See https://no-color.org
+    let no_color = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    // The files of --split-output are only colored on request
+    let split_colored = colored == Some(true);
+    let colored = colored.unwrap_or_else(|| !no_color && out.is_terminal());
+    let out_width = out.terminal_width();
     let mut renderer = Renderer::new(colored, out);
+    if let Some(theme) = theme {
+        renderer.set_theme(theme);
+    }
+    renderer.set_ascii(ascii);
+    if let Some(text) = &frame_chars {
+        let Some(glyphs) = renderer.glyphs().with_frame_chars(text) else {
+            bail!("--frame-chars={text}: must be 'unicode', 'ascii' and/or NAME=CHARS, for example line=|");
+        };
+        renderer.set_glyphs(glyphs);
+    }
+    renderer.set_color_connections(color_connections);
+    if let Some(max_lines) = max_lines {
+        renderer.set_max_lines(max_lines);
+    }
+    if let Some(width) = side_by_side {
+        let width = width.or(out_width).unwrap_or(DEFAULT_WIDTH);
+        renderer.set_side_by_side(width);
+    }
+    if let Some(gap) = idle_gap {
+        renderer.set_idle_gap(Some(gap).filter(|g| !g.is_zero()));
+    }
+    if let Some(width) = wrap {
+        let width = width.or(out_width).unwrap_or(DEFAULT_WIDTH);
+        renderer.set_wrap(width);
+    }
+    if let Some(time_zone) = time_zone {
+        renderer.set_time_zone(time_zone);
+    }
+    if let Some(format) = &timestamp_format {
+        renderer.set_time_format(format.clone());
+    }
+    if let Some(size) = rotate_size {
+        renderer.set_rotate(size, keep.unwrap_or(5));
+    }
+
+    // When writing to a file, SIGHUP means the file has been rotated away
+    let reopen = Arc::new(AtomicBool::new(false));
+    if output_file.is_some() {
+        install_sighup_handler(&reopen)?;
+    }
+    // On SIGUSR1 the proxy reads the config file again
+    let reload = Reload {
+        path: config_path,
+        fixed: command_line_options,
+        zone,
+        log_filter,
+    };
 
-    let mapi_state = mapi::State::new(level, force_binary);
+    let live = matches!(source, Source::Proxy { .. } | Source::Replay { .. });
+    let mut taps = Taps::default();
+    if let Some(path) = &record_pcap {
+        let recorder = Recorder::create(path)
+            .with_context(|| format!("Could not create pcap file {}", path.display()))?;
+        taps.recorder = Some(recorder);
+    }
+    if let Some(dir) = &record_pcap_dir {
+        let recorder = SplitRecorder::create(dir)
+            .with_context(|| format!("Could not create pcap directory {}", dir.display()))?;
+        taps.split_recorder = Some(recorder);
+    }
+    if let Some(dir) = &tee_dir {
+        let tee = Tee::new(dir)
+            .with_context(|| format!("Could not create tee directory {}", dir.display()))?;
+        taps.tee = Some(tee);
+    }
+    if let Some(path) = &sqlite_file {
+        let sqlite = SqliteLog::create(path, live)
+            .with_context(|| format!("Could not create database {}", path.display()))?;
+        taps.sqlite = Some(sqlite);
+    }
+    if let Some(path) = &parquet_file {
+        let parquet = ParquetLog::create(path, live)
+            .with_context(|| format!("Could not create Parquet file {}", path.display()))?;
+        taps.parquet = Some(parquet);
+    }
+    if let Some(addr) = &statsd_addr {
+        let statsd = Statsd::new(addr)
+            .with_context(|| format!("Could not set up sending metrics to {addr}"))?;
+        taps.statsd = Some(statsd);
+    }
+    if let Some(addr) = &web_addr {
+        if !live {
+            bail!("--web can only be used when proxying or with --replay");
+        }
+        let web = addr
+            .to_socket_addrs()
+            .and_then(|mut addrs| addrs.next().ok_or(io::ErrorKind::NotFound.into()))
+            .and_then(|addr| Web::start(addr, level, force_binary))
+            .with_context(|| format!("Could not serve web page on {addr}"))?;
+        taps.web = Some(web);
+    }
+    if totals {
+        taps.totals = Some(Totals::new(live));
+    }
+    if check_exit_code {
+        taps.check = Some(Check::default());
+    }
+    taps.limit = limit_bytes.map(|size| ByteLimit::new(size, limit_exit));
+
+    let mut mapi_state = mapi::State::new(level, force_binary);
+    mapi_state.set_quiet(quiet);
+    mapi_state.set_show_peers(show_peers);
+    mapi_state.set_escape(escape);
+    mapi_state.set_table(table);
+    mapi_state.set_hide_empty(hide_empty);
+    mapi_state.set_quiet_connections(quiet_connections);
+    if !conns.is_empty() {
+        mapi_state.set_conns(conns.clone());
+    }
+    mapi_state.set_clients(clients);
+    mapi_state.set_filter(filter.clone());
+    if let Some(format) = timestamp_format {
+        mapi_state.set_time_format(format, zone);
+    }
+    mapi_state.set_format(format, live);
+    if let Some(timestamps) = timestamps {
+        renderer.set_timestamps(timestamps, live);
+    }
+    mapi_state.start(&mut renderer)?;
+    if let Some(template) = &split_output {
+        let split = SplitOutput::new(template, zone, split_colored, &mapi_state, &renderer);
+        taps.split_output = Some(split);
+    }
 
     match source {
         Source::Proxy {
             listen_addr,
             forward_addr,
-        } => run_proxy(listen_addr, forward_addr, mapi_state, &mut renderer),
-        Source::Pcap(path) => run_pcap(&path, mapi_state, &mut renderer),
+        } => run_proxy(
+            listen_addr,
+            forward_addr,
+            proxy_options,
+            mapi_state,
+            &mut renderer,
+            taps,
+            &reopen,
+            &reload,
+        ),
+        Source::Pcap(paths) => run_pcap(
+            &paths,
+            progress,
+            jobs.unwrap_or(1),
+            pcap_options,
+            mapi_state,
+            pcap_stats.then(Stats::default),
+            latency.then(Latency::default),
+            &mut renderer,
+            taps,
+            &reopen,
+        ),
+        Source::Records(paths) => {
+            grep::grep_records(&paths, &filter, &conns, &mapi_state, &mut renderer)
+        }
+        Source::Diff { a, b } => diff::run_diff(&a, &b, &pcap_options, &mut renderer),
+        Source::Anonymize { input, output } => {
+            run_anonymize(&input, &output, pcap_options, anonymize_strings)
+        }
+        Source::Summarize(path) => summarize::run_summarize(
+            &path,
+            top.unwrap_or(summarize::DEFAULT_TOP),
+            &pcap_options,
+            &mut renderer,
+        ),
+        Source::Replay { path, forward_addr } => run_replay(
+            &path,
+            &forward_addr,
+            pcap_options,
+            replay_password.as_deref(),
+            mapi_state,
+            &mut renderer,
+            taps,
+            &reopen,
+        ),
+        Source::Streams { client, server } => {
+            let id = ConnectionId::new(proxy_options.first_id.unwrap_or(ConnectionId::FIRST));
+            run_streams(
+                id,
+                &client,
+                &server,
+                mapi_state,
+                &mut renderer,
+                taps,
+                &reopen,
+            )
+        }
     }
 }
 
+/// The other servers a run talks to, for [report_dry_run]
+#[derive(Default)]
+struct Services<'a> {
+    mirror: Option<&'a MonetAddr>,
+    statsd: Option<&'a str>,
+    web: Option<&'a str>,
+}
+
+/// Check that the addresses resolve, that the files to read exist, that the
+/// files and directories to write can be created and that the `--web` port
+/// is free, then print what we would do, see `--dry-run`.
+fn report_dry_run(
+    source: &Source,
+    outputs: &[(&str, &Option<PathBuf>)],
+    dirs: &[(&str, &Option<PathBuf>)],
+    services: &Services,
+) -> AResult<()> {
+    let mut plan = vec![];
+    match source {
+        Source::Proxy {
+            listen_addr,
+            forward_addr,
+        } => {
+            let listen = resolve_for_dry_run("LISTEN_ADDR", listen_addr)?;
+            plan.push(format!("listen on {listen}"));
+            let forward = resolve_for_dry_run("FORWARD_ADDR", forward_addr)?;
+            plan.push(format!("forward to {forward}"));
+        }
+        Source::Pcap(paths) => {
+            for path in paths {
+                if path != Path::new("-") {
+                    check_input_file("pcap file", path)?;
+                }
+                plan.push(format!("read capture {}", path.display()));
+            }
+        }
+        Source::Records(paths) => {
+            for path in paths {
+                check_input_file("records file", path)?;
+                plan.push(format!("search records {}", path.display()));
+            }
+        }
+        Source::Diff { a, b } => {
+            check_input_file("file", a)?;
+            check_input_file("file", b)?;
+            plan.push(format!("compare {} and {}", a.display(), b.display()));
+        }
+        Source::Anonymize { input, output } => {
+            check_input_file("pcap file", input)?;
+            let (input, output) = (input.display(), output.display());
+            plan.push(format!("write an anonymized copy of {input} to {output}"));
+        }
+        Source::Summarize(path) => {
+            check_input_file("pcap file", path)?;
+            plan.push(format!("summarize capture {}", path.display()));
+        }
+        Source::Replay { path, forward_addr } => {
+            check_input_file("pcap file", path)?;
+            let forward = resolve_for_dry_run("FORWARD_ADDR", forward_addr)?;
+            plan.push(format!("replay {} to {forward}", path.display()));
+        }
+        Source::Streams { client, server } => {
+            check_input_file("stream file", client)?;
+            check_input_file("stream file", server)?;
+            let (client, server) = (client.display(), server.display());
+            plan.push(format!(
+                "decode client stream {client} and server stream {server}"
+            ));
+        }
+    }
+    for (what, path) in outputs {
+        let Some(path) = path else {
+            continue;
+        };
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            if !dir.is_dir() {
+                bail!(
+                    "Cannot create {what} {}: no directory {}",
+                    path.display(),
+                    dir.display()
+                );
+            }
+        }
+        plan.push(format!("write {what} {}", path.display()));
+    }
+    for (what, dir) in dirs {
+        let Some(dir) = dir else {
+            continue;
+        };
+        // Missing directories are created, but not below a file
+        let existing = dir.ancestors().find(|d| d.exists());
+        if let Some(file) = existing.filter(|d| !d.is_dir()) {
+            bail!(
+                "Cannot create {what} {}: {} is not a directory",
+                dir.display(),
+                file.display()
+            );
+        }
+        plan.push(format!("write {what} {}", dir.display()));
+    }
+    if let Some(mirror) = services.mirror {
+        let mirror = resolve_for_dry_run("--mirror", mirror)?;
+        plan.push(format!("mirror the client traffic to {mirror}"));
+    }
+    if let Some(addr) = services.statsd {
+        let target = addr
+            .to_socket_addrs()
+            .with_context(|| format!("--statsd={addr} does not resolve"))?
+            .next()
+            .with_context(|| format!("--statsd={addr} does not resolve to any addresses"))?;
+        plan.push(format!("send metrics to {target}"));
+    }
+    if let Some(addr) = services.web {
+        let addr = addr
+            .to_socket_addrs()
+            .with_context(|| format!("--web={addr} does not resolve"))?
+            .next()
+            .with_context(|| format!("--web={addr} does not resolve to any addresses"))?;
+        // Bind and let go, the port may be taken by the time we really run
+        TcpListener::bind(addr).with_context(|| format!("Cannot serve web page on {addr}"))?;
+        plan.push(format!("serve a web page on {addr}"));
+    }
+    for line in plan {
+        println!("Would {line}");
+    }
+    Ok(())
+}
+
+/// The addresses `addr` stands for, as a list for [report_dry_run]
+fn resolve_for_dry_run(name: &str, addr: &MonetAddr) -> AResult<String> {
+    let mut addrs = addr
+        .resolve_tcp()
+        .with_context(|| format!("{name} {addr} does not resolve"))?;
+    addrs.extend(addr.resolve_unix()?);
+    if addrs.is_empty() {
+        bail!("{name} {addr} does not resolve to any addresses");
+    }
+    let addrs: Vec<String> = addrs.iter().map(Addr::to_string).collect();
+    Ok(addrs.join(", "))
+}
+
+fn check_input_file(what: &str, path: &Path) -> AResult<()> {
+    File::open(path).with_context(|| format!("Could not open {what} {}", path.display()))?;
+    Ok(())
+}
+
+/// Parse the parameter of --retry: N[,DELAY]
+fn parse_retry(text: &str) -> AResult<(u32, Duration)> {
+    let (count, delay) = match text.split_once(',') {
+        Some((count, delay)) => (count, Some(delay)),
+        None => (text, None),
+    };
+    let Ok(count) = count.parse() else {
+        bail!("--retry={text}: invalid retry count {count:?}");
+    };
+    let delay = match delay {
+        None => Duration::from_secs(1),
+        Some(d) => match parse_duration(d) {
+            Some(d) => d,
+            None => bail!("--retry={text}: invalid delay {d:?}"),
+        },
+    };
+    Ok((count, delay))
+}
+
+/// Parse the parameter of --port: PORT[,PORT...]
+fn parse_ports(text: &str) -> AResult<Vec<u16>> {
+    let mut ports = vec![];
+    for port in text.split(',') {
+        let Ok(port) = port.parse() else {
+            bail!("--port={text}: invalid port number {port:?}");
+        };
+        ports.push(port);
+    }
+    Ok(ports)
+}
+
+/// Parse the parameter of --select: ID or ADDR:PORT-ADDR:PORT
+fn parse_select(text: &str) -> AResult<Selection> {
+    if let Ok(n) = text.strip_prefix('#').unwrap_or(text).parse() {
+        return Ok(Selection::Id(ConnectionId::new(n)));
+    }
+    if let Some((a, b)) = text.split_once('-') {
+        if let (Ok(a), Ok(b)) = (a.parse(), b.parse()) {
+            return Ok(Selection::Endpoints(a, b));
+        }
+    }
+    bail!("--select={text}: expected a connection id or ADDR:PORT-ADDR:PORT");
+}
+
+/// Parse the parameter of --conn: ids and ranges of ids such as 12,15-18
+fn parse_conns(text: &str) -> AResult<Vec<RangeInclusive<usize>>> {
+    let mut conns = vec![];
+    for item in text.split(',') {
+        let item = item.trim_start_matches('#');
+        let range = match item.split_once('-') {
+            Some((lo, hi)) => lo.parse().and_then(|lo| Ok(lo..=hi.parse()?)),
+            None => item.parse().map(|n| n..=n),
+        };
+        match range {
+            Ok(range) if !range.is_empty() => conns.push(range),
+            _ => bail!("--conn={text}: expected connection ids such as 12,15-18"),
+        }
+    }
+    Ok(conns)
+}
+
+/// Parse the parameter of --client, an IP address or subnet
+fn parse_client(text: &str) -> AResult<Subnet> {
+    match Subnet::parse(text) {
+        Some(subnet) => Ok(subnet),
+        None => bail!("--client={text}: expected an IP address or a subnet such as 10.1.2.0/24"),
+    }
+}
+
+fn parse_sender(text: &str) -> AResult<Direction> {
+    match text {
+        "client" => Ok(Direction::Upstream),
+        "server" => Ok(Direction::Downstream),
+        _ => bail!("--sender={text}: must be 'client' or 'server'"),
+    }
+}
+
+/// Parse the parameter of --grep or --exclude-grep or the PATTERN of
+/// `mapiproxy grep`, a regular expression
+fn parse_grep(name: &str, text: &str) -> AResult<BytesRegex> {
+    match BytesRegex::new(text) {
+        Ok(regex) => Ok(regex),
+        Err(e) if name.starts_with('-') => {
+            bail!("{name}={text}: invalid regular expression: {e}")
+        }
+        Err(e) => bail!("{name} {text}: invalid regular expression: {e}"),
+    }
+}
+
+/// Parse the parameter of --speed: a positive factor, optionally followed by 'x'
+fn parse_speed(text: &str) -> AResult<f64> {
+    let factor = text.strip_suffix('x').unwrap_or(text);
+    match factor.parse::<f64>() {
+        Ok(f) if f.is_finite() && f > 0.0 => Ok(f),
+        _ => bail!("--speed={text}: expected a positive factor such as 2x or 0.5x"),
+    }
+}
+
+/// Parse the parameter of --tz: local, utc or an offset such as +02:00
+fn parse_time_zone(text: &str) -> AResult<TimeZone> {
+    let Some(zone) = TimeZone::parse(text) else {
+        bail!("--tz={text}: must be 'local', 'utc' or an offset such as +02:00");
+    };
+    Ok(zone)
+}
+
+/// Parse the parameter of --timestamp-format, a format such as
+/// '%Y-%m-%dT%H:%M:%S.%3f%:z'.
+fn parse_timestamp_format(text: &str) -> AResult<Arc<str>> {
+    if let Some(placeholder) = proxy::event::unknown_placeholder(text) {
+        bail!("--timestamp-format={text}: unknown placeholder {placeholder}");
+    }
+    Ok(text.into())
+}
+
+/// Parse the parameter of --from or --to: a capture time such as
+/// '2024-03-05 14:02:01' or an offset from the start of the capture such
+/// as '+30s'
+fn parse_time_bound(flag: &str, text: &str, zone: TimeZone) -> AResult<TimeBound> {
+    let bound = if let Some(offset) = text.strip_prefix('+') {
+        parse_duration(offset).map(TimeBound::After)
+    } else {
+        CaptureTime::parse(text, zone).map(TimeBound::At)
+    };
+    let Some(bound) = bound else {
+        bail!("{flag}={text}: expected a time such as '2024-03-05 14:02:01' or an offset such as +30s");
+    };
+    Ok(bound)
+}
+
+/// Parse the parameter of --side-by-side or --wrap, a number of columns
+fn parse_width(flag: &str, text: &str) -> AResult<usize> {
+    let width = text.parse().ok().filter(|&w| w >= MIN_WIDTH);
+    let Some(width) = width else {
+        bail!("{flag}={text}: expected a width of at least {MIN_WIDTH} columns");
+    };
+    Ok(width)
+}
+
+/// Parse the parameter of --resolve: once, per-connection or ttl=SECS
+fn parse_resolve(text: &str) -> AResult<ResolvePolicy> {
+    let policy = match text {
+        "once" => ResolvePolicy::Once,
+        "per-connection" => ResolvePolicy::PerConnection,
+        _ => match text.strip_prefix("ttl=").and_then(parse_duration) {
+            Some(ttl) => ResolvePolicy::Ttl(ttl),
+            None => bail!("--resolve={text}: must be 'once', 'per-connection' or 'ttl=SECS'"),
+        },
+    };
+    Ok(policy)
+}
+
+/// Parse the parameter of --route: sni=HOST=ADDR[,sni=HOST=ADDR...]
+fn parse_routes(text: &str) -> AResult<Vec<(String, MonetAddr)>> {
+    let mut routes = vec![];
+    for route in text.split(',') {
+        let Some(route) = route.strip_prefix("sni=") else {
+            bail!("--route={text}: expected sni=HOST=ADDR, got {route:?}");
+        };
+        let Some((host, addr)) = route.split_once('=') else {
+            bail!("--route={text}: expected sni=HOST=ADDR, got {route:?}");
+        };
+        let addr = match MonetAddr::try_from(OsStr::new(addr)) {
+            Ok(addr) => addr,
+            Err(e) => bail!("--route={text}: {e}"),
+        };
+        routes.push((host.to_string(), addr));
+    }
+    Ok(routes)
+}
+
+/// Parse the parameter of --kill-after: bytes=N,messages=N,secs=N, each
+/// of them optional
+fn parse_kill_after(text: &str) -> AResult<KillAfter> {
+    let mut kill_after = KillAfter::default();
+    for item in text.split(',') {
+        let parsed = match item.split_once('=') {
+            Some(("bytes", n)) => parse_size(n).map(|n| kill_after.bytes = Some(n)),
+            Some(("messages", n)) => n.parse().ok().map(|n| kill_after.messages = Some(n)),
+            Some(("secs", n)) => parse_duration(n).map(|d| kill_after.duration = Some(d)),
+            _ => bail!("--kill-after={text}: expected bytes=N, messages=N or secs=N, got {item:?}"),
+        };
+        if parsed.is_none() {
+            bail!("--kill-after={text}: invalid number in {item:?}");
+        }
+    }
+    Ok(kill_after)
+}
+
+/// Parse a byte count such as '100', '64K', '1M' or '2G'.
+fn parse_size(text: &str) -> Option<u64> {
+    let (number, scale) = match text.char_indices().last()? {
+        (i, 'k' | 'K') => (&text[..i], 1 << 10),
+        (i, 'm' | 'M') => (&text[..i], 1 << 20),
+        (i, 'g' | 'G') => (&text[..i], 1 << 30),
+        _ => (text, 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(scale)
+}
+
+/// Parse a duration such as '2', '1.5s', '500ms', '10m' or '1h'. Without a
+/// unit, the number is interpreted as seconds.
+fn parse_duration(text: &str) -> Option<Duration> {
+    let (number, scale) = if let Some(ms) = text.strip_suffix("ms") {
+        (ms, 0.001)
+    } else if let Some(secs) = text.strip_suffix('s') {
+        (secs, 1.0)
+    } else if let Some(mins) = text.strip_suffix('m') {
+        (mins, 60.0)
+    } else if let Some(hours) = text.strip_suffix('h') {
+        (hours, 3600.0)
+    } else {
+        (text, 1.0)
+    };
+    let number: f64 = number.parse().ok()?;
+    Duration::try_from_secs_f64(number * scale).ok()
+}
+
+#[allow(clippy::too_many_arguments)]
 fn run_proxy(
     listen_addr: MonetAddr,
     forward_addr: MonetAddr,
+    proxy_options: ProxyOptions,
     mut mapi_state: mapi::State,
     renderer: &mut Renderer,
+    mut taps: Taps,
+    reopen: &AtomicBool,
+    reload: &Reload,
 ) -> AResult<()> {
     let (send_events, receive_events) = std::sync::mpsc::sync_channel(500);
+    let send_stopped = send_events.clone();
+    install_sigusr1_handler(send_events.clone())?;
     let handler = move |event| {
-        let _ = send_events.send(event);
+        let _ = send_events.send(Received::Event(event));
     };
-    let mut proxy = Proxy::new(listen_addr, forward_addr, handler)?;
+    let mut proxy = Proxy::new(listen_addr, forward_addr, proxy_options, handler)?;
+    if let Some(port) = proxy.ephemeral_port() {
+        // Machine readable, for test harnesses that let the OS pick the port.
+        // Not on stdout, that may be carrying --format=jsonl, cbor or raw.
+        eprintln!("LISTEN_PORT={port}");
+    }
     install_ctrl_c_handler(proxy.get_shutdown_trigger())?;
-    thread::spawn(move || proxy.run().unwrap());
+    if let Some(limit) = &mut taps.limit {
+        // Like --duration, the open connections get some time to end
+        limit.set_shutdown_trigger(proxy.get_stop_trigger());
+    }
+    let proxy_thread = thread::spawn(move || {
+        let result = proxy.run();
+        let _ = send_stopped.send(Received::Stopped);
+        result
+    });
 
-    while let Ok(ev) = receive_events.recv() {
+    // The proxy thread says when it has stopped. The events it sent before
+    // that are still processed.
+    while let Ok(received) = receive_events.recv() {
+        let ev = match received {
+            Received::Event(ev) => ev,
+            Received::Reload => {
+                reload_or_warn(reload, &mut mapi_state, renderer);
+                continue;
+            }
+            Received::Stopped => break,
+        };
+        if !taps.admits(&ev) {
+            continue;
+        }
+        check_reopen(reopen, renderer)?;
+        taps.handle(&ev)?;
         mapi_state.handle(&ev, renderer)?;
     }
+    mapi_state.finish(renderer)?;
+    taps.report(renderer)?;
+
+    proxy_thread.join().expect("proxy thread panicked")?;
     Ok(())
 }
 
-fn run_pcap(path: &Path, mut mapi_state: mapi::State, renderer: &mut Renderer) -> AResult<()> {
-    let mut owned_file;
-    let mut owned_stdin;
+/// Additional consumers of the proxy events, besides the renderer
+#[derive(Default)]
+struct Taps {
+    recorder: Option<Recorder>,
+    split_recorder: Option<SplitRecorder>,
+    tee: Option<Tee>,
+    split_output: Option<SplitOutput>,
+    sqlite: Option<SqliteLog>,
+    parquet: Option<ParquetLog>,
+    statsd: Option<Statsd>,
+    web: Option<Web>,
+    totals: Option<Totals>,
+    check: Option<Check>,
+    limit: Option<ByteLimit>,
+}
 
-    let reader: &mut dyn io::Read = if path == Path::new("-") {
-        owned_stdin = Some(io::stdin().lock());
-        owned_stdin.as_mut().unwrap()
-    } else {
-        let file = File::open(path)
-            .with_context(|| format!("Could not open pcap file {}", path.display()))?;
-        owned_file = Some(file);
-        owned_file.as_mut().unwrap()
+impl Taps {
+    /// Whether the event falls within `--limit-bytes`. If not, it should be
+    /// ignored altogether.
+    fn admits(&mut self, ev: &MapiEvent) -> bool {
+        self.limit.as_mut().is_none_or(|limit| limit.admits(ev))
+    }
+
+    fn handle(&mut self, ev: &MapiEvent) -> AResult<()> {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.handle(ev)?;
+        }
+        if let Some(recorder) = &mut self.split_recorder {
+            recorder.handle(ev)?;
+        }
+        if let Some(tee) = &mut self.tee {
+            tee.handle(ev)?;
+        }
+        if let Some(split) = &mut self.split_output {
+            split.handle(ev)?;
+        }
+        if let Some(sqlite) = &mut self.sqlite {
+            sqlite.handle(ev)?;
+        }
+        if let Some(parquet) = &mut self.parquet {
+            parquet.handle(ev)?;
+        }
+        if let Some(statsd) = &mut self.statsd {
+            statsd.handle(ev);
+        }
+        if let Some(web) = &mut self.web {
+            web.handle(ev);
+        }
+        if let Some(totals) = &mut self.totals {
+            totals.handle(ev);
+        }
+        if let Some(check) = &mut self.check {
+            check.handle(ev);
+        }
+        Ok(())
+    }
+
+    /// Called at the end of the run. Fails if `--check-exit-code` found
+    /// problems.
+    fn report(&mut self, renderer: &mut Renderer) -> AResult<()> {
+        if let Some(split) = &mut self.split_output {
+            split.finish()?;
+        }
+        if let Some(totals) = &mut self.totals {
+            totals.report(renderer)?;
+        }
+        if let Some(problems) = self.check.as_ref().and_then(Check::problems) {
+            bail!("--check-exit-code: observed {problems}");
+        }
+        Ok(())
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_pcap(
+    paths: &[PathBuf],
+    show_progress: bool,
+    jobs: usize,
+    pcap_options: pcap::Options,
+    mut mapi_state: mapi::State,
+    mut stats: Option<Stats>,
+    mut latency: Option<Latency>,
+    renderer: &mut Renderer,
+    mut taps: Taps,
+    reopen: &AtomicBool,
+) -> AResult<()> {
+    // Progress can only be computed for regular files, not for stdin or pipes
+    let sizes = paths.iter().map(|path| match fs::metadata(path) {
+        Ok(meta) if meta.is_file() && path != Path::new("-") => meta.len(),
+        _ => 0,
+    });
+    let progress = show_progress.then(|| Progress::new(sizes.clone().sum()));
+    let mut inputs = vec![];
+    for (path, size) in paths.iter().zip(sizes) {
+        let mut rd = open_pcap(path)?;
+        if let Some(progress) = progress.as_ref().filter(|_| size > 0) {
+            rd = progress.wrap(rd);
+        }
+        inputs.push((path.display().to_string(), rd));
+    }
+    let mut parallel = (jobs > 1).then(|| Parallel::new(jobs, &mapi_state, renderer));
+    let handler = |ev: MapiEvent| {
+        if !taps.admits(&ev) {
+            return Ok(());
+        }
+        check_reopen(reopen, renderer)?;
+        taps.handle(&ev)
+            .map_err(|e| io::Error::other(format!("{e:#}")))?;
+        if let Some(parallel) = &mut parallel {
+            // There are no stats or latencies to collect, see --jobs
+            return parallel.handle(ev, renderer);
+        }
+        match &mut stats {
+            Some(stats) => stats.handle(&ev),
+            None => mapi_state.handle(&ev, renderer)?,
+        }
+        if let Some(latency) = &mut latency {
+            // With --pcap-stats, only the summary is shown
+            let renderer = if stats.is_none() {
+                Some(&mut *renderer)
+            } else {
+                None
+            };
+            latency.handle(&ev, renderer)?;
+        }
+        Ok(())
+    };
+    let mut tracker = Tracker::new(pcap_options, handler);
+    let result = pcap::parse_pcap_files(inputs, &mut tracker);
+    drop(tracker);
+    // Also after an error, to show what happened before it
+    let finished = match parallel {
+        Some(parallel) => parallel.finish(&mut mapi_state, renderer),
+        None => Ok(()),
+    };
+    if let Some(progress) = &progress {
+        progress.finish();
+    }
+    result?;
+    finished?;
+
+    if stats.is_none() {
+        mapi_state.finish(renderer)?;
+    }
+
+    if let Some(stats) = &stats {
+        stats.report(renderer)?;
+    }
+    if let Some(latency) = &mut latency {
+        latency.report(renderer)?;
+    }
+    taps.report(renderer)?;
+    Ok(())
+}
+
+/// Write the traffic in capture `input` to `output` with the addresses,
+/// user names and password hashes replaced, see `mapiproxy anonymize`.
+fn run_anonymize(
+    input: &Path,
+    output: &Path,
+    pcap_options: pcap::Options,
+    strings: bool,
+) -> AResult<()> {
+    let mut recorder = Recorder::create(output)
+        .with_context(|| format!("Could not create pcap file {}", output.display()))?;
+    let mut anonymizer = Anonymizer::new(strings);
+    let handler = |ev: MapiEvent| {
+        for ev in anonymizer.handle(ev) {
+            recorder.handle(&ev)?;
+        }
+        Ok(())
+    };
+    let mut tracker = Tracker::new(pcap_options, handler);
+    pcap::parse_pcap_file(open_pcap(input)?, &mut tracker)?;
+    drop(tracker);
+    let lost = anonymizer.unanonymized();
+    if lost > 0 {
+        warn!("{lost} bytes could not be anonymized, they have been replaced by zero bytes");
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_replay(
+    path: &Path,
+    forward_addr: &MonetAddr,
+    pcap_options: pcap::Options,
+    password: Option<&str>,
+    mut mapi_state: mapi::State,
+    renderer: &mut Renderer,
+    mut taps: Taps,
+    reopen: &AtomicBool,
+) -> AResult<()> {
+    let replayer = Replayer::from_pcap(open_pcap(path)?, pcap_options)?;
+    let handler = |ev: MapiEvent| {
+        if !taps.admits(&ev) {
+            return Ok(());
+        }
+        check_reopen(reopen, renderer)?;
+        taps.handle(&ev)?;
+        mapi_state.handle(&ev, renderer)?;
+        Ok(())
+    };
+    replayer.run(forward_addr, password, handler)?;
+    mapi_state.finish(renderer)?;
+    taps.report(renderer)?;
+    Ok(())
+}
+
+fn run_streams(
+    id: ConnectionId,
+    client: &Path,
+    server: &Path,
+    mut mapi_state: mapi::State,
+    renderer: &mut Renderer,
+    mut taps: Taps,
+    reopen: &AtomicBool,
+) -> AResult<()> {
+    let read = |path: &Path| {
+        fs::read(path).with_context(|| format!("Could not read stream file {}", path.display()))
+    };
+    let (client_data, server_data) = (read(client)?, read(server)?);
+    let handler = |ev: MapiEvent| {
+        if !taps.admits(&ev) {
+            return Ok(());
+        }
+        check_reopen(reopen, renderer)?;
+        taps.handle(&ev)?;
+        mapi_state.handle(&ev, renderer)?;
+        Ok(())
     };
+    streams::decode_streams(id, (client, &client_data), (server, &server_data), handler)?;
+    taps.report(renderer)?;
+    renderer.flush()?;
+    Ok(())
+}
 
-    let handler = |ev: MapiEvent| mapi_state.handle(&ev, renderer);
-    let mut tracker = Tracker::new(handler);
-    pcap::parse_pcap_file(reader, &mut tracker)
+/// Open the pcap file, or stdin if the path is '-'
+fn open_pcap(path: &Path) -> AResult<Box<dyn io::Read>> {
+    if path == Path::new("-") {
+        return Ok(Box::new(io::stdin().lock()));
+    }
+    let file =
+        File::open(path).with_context(|| format!("Could not open pcap file {}", path.display()))?;
+    Ok(Box::new(file))
 }
 
 fn install_ctrl_c_handler(trigger: Box<dyn Fn() + Send + Sync>) -> AResult<()> {
-    let mut triggered = false;
+    // The first Control-C stops accepting new connections, the second aborts
+    // the remaining ones. If that doesn't work either, the third one exits.
+    let mut triggered = 0;
     let handler = move || {
-        if triggered {
+        if triggered == 2 {
             std::process::exit(1);
         }
-        triggered = true;
+        triggered += 1;
         trigger()
     };
     ctrlc::set_handler(handler).with_context(|| "cannot set Ctrl-C handler")?;
     Ok(())
 }
 
+/// Reopen the output file if a SIGHUP has been received since the last call.
+/// We only do this right before rendering the next event, nothing is written
+/// in between anyway.
+fn check_reopen(reopen: &AtomicBool, renderer: &mut Renderer) -> io::Result<()> {
+    if reopen.swap(false, Ordering::Relaxed) {
+        renderer.reopen()?;
+    }
+    Ok(())
+}
+
+/// What the main loop of [run_proxy] receives
+enum Received {
+    Event(MapiEvent),
+    /// A SIGUSR1, read the config file again
+    Reload,
+    /// The proxy thread has stopped, no more events will come
+    Stopped,
+}
+
+/// What is needed to read the config file again on SIGUSR1, see
+/// [reload_or_warn]
+struct Reload {
+    path: Option<PathBuf>,
+    /// The options given on the command line, these are left alone
+    fixed: HashSet<String>,
+    /// For the placeholders in `output`
+    zone: TimeZone,
+    /// To change `log-level`
+    log_filter: LogFilter,
+}
+
+/// Read the config file again after a SIGUSR1. Only the options that can
+/// change without disturbing the open connections are applied, see
+/// [reload_config]. If the file cannot be read, we carry on as before.
+fn reload_or_warn(reload: &Reload, mapi_state: &mut mapi::State, renderer: &mut Renderer) {
+    match reload_config(reload, mapi_state, renderer) {
+        Ok(()) => info!("reloaded the config file"),
+        Err(e) => warn!("not reloading the config file: {e:#}"),
+    }
+}
+
+/// Apply the level, `--conn` and `--client` to the connections that start
+/// from now on and the filters to all of them. If `output` has changed,
+/// continue in the new file, and `log-level` applies to the internal log
+/// right away. An option that is no longer in the file goes back to its
+/// default, except the level, `output` and `log-level` which stay as they
+/// are. The options of the proxy itself, such as `--first-byte-delay` and
+/// `--kill-after`, cannot be changed.
+fn reload_config(
+    reload: &Reload,
+    mapi_state: &mut mapi::State,
+    renderer: &mut Renderer,
+) -> AResult<()> {
+    let config = Config::load(reload.path.as_deref())?;
+    let fixed = |name: &str| reload.fixed.contains(name);
+    let level_fixed = ["messages", "blocks", "raw"].into_iter().any(fixed);
+
+    let mut level = None;
+    let mut filter = mapi_state.filter().clone();
+    if !fixed("grep") {
+        filter.grep = None;
+    }
+    if !fixed("exclude-grep") {
+        filter.exclude.clear();
+    }
+    if !fixed("sender") {
+        filter.sender = None;
+    }
+    let mut conns = vec![];
+    let mut clients = vec![];
+    let mut output = None;
+    let mut log_filter = None;
+    for arg in &config.args {
+        let arg = arg.to_string_lossy();
+        let arg = arg.trim_start_matches("--");
+        let (name, value) = arg.split_once('=').unwrap_or((arg, ""));
+        if fixed(name) {
+            continue;
+        }
+        match name {
+            "messages" | "blocks" | "raw" if level_fixed => {}
+            "messages" => level = Some(Level::Messages),
+            "blocks" => level = Some(Level::Blocks),
+            "raw" => level = Some(Level::Raw),
+            "grep" => filter.grep = Some(parse_grep("--grep", value)?),
+            "exclude-grep" => filter.exclude.push(parse_grep("--exclude-grep", value)?),
+            "sender" => filter.sender = Some(parse_sender(value)?),
+            "conn" => conns.extend(parse_conns(value)?),
+            "client" => clients.push(parse_client(value)?),
+            "output" => output = Some(PathBuf::from(value)),
+            "log-level" => {
+                let filter = EnvFilter::try_new(value)
+                    .with_context(|| format!("log-level={value}: invalid level or filter"))?;
+                log_filter = Some(filter);
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(template) = output {
+        let now = CaptureTime::now().in_zone(reload.zone);
+        let path = output::expand_path(&template, &now);
+        if renderer.output_path() != Some(&path) {
+            let out = Output::create(&path)
+                .with_context(|| format!("Could not create output file {}", path.display()))?
+                .with_template(&template, reload.zone);
+            renderer.set_output(out)?;
+        }
+    }
+    if let Some(level) = level {
+        mapi_state.set_level(level);
+    }
+    if !fixed("conn") {
+        mapi_state.set_conns(conns);
+    }
+    if !fixed("client") {
+        mapi_state.set_clients(clients);
+    }
+    mapi_state.set_filter(filter);
+    if let Some(filter) = log_filter {
+        reload.log_filter.reload(filter)?;
+    }
+    Ok(())
+}
+
+/// Wake up the main loop of [run_proxy] with [Received::Reload] on every
+/// SIGUSR1, so the config file is also read again when there is no traffic.
+#[cfg(unix)]
+fn install_sigusr1_handler(sender: SyncSender<Received>) -> AResult<()> {
+    use signal_hook::{consts::SIGUSR1, iterator::Signals};
+    let mut signals = Signals::new([SIGUSR1]).with_context(|| "cannot set SIGUSR1 handler")?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            if sender.send(Received::Reload).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn install_sigusr1_handler(_sender: SyncSender<Received>) -> AResult<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn install_sighup_handler(flag: &Arc<AtomicBool>) -> AResult<()> {
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(flag))
+        .with_context(|| "cannot set SIGHUP handler")?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn install_sighup_handler(_flag: &Arc<AtomicBool>) -> AResult<()> {
+    Ok(())
+}
+
+/// Changes the filter of the internal log, see [install_logger]
+type LogFilter = reload::Handle<EnvFilter, Registry>;
+
+/// Send the internal log to stderr. The filter is `--log-level`, RUST_LOG
+/// or by default 'warn', see [EnvFilter] for the syntax. It can be changed
+/// later through the returned handle.
+fn install_logger(log_level: Option<&str>) -> AResult<LogFilter> {
+    let filter = match log_level {
+        Some(text) => EnvFilter::try_new(text)
+            .with_context(|| format!("--log-level={text}: invalid level or filter"))?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn")),
+    };
+    let (filter, handle) = reload::Layer::new(filter);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().with_writer(io::stderr))
+        .init();
+    Ok(handle)
+}
+
 fn install_panic_hook() {
     let orig_hook = panic::take_hook();
-    let my_hook = Box::new(move |panic_info: &PanicInfo<'_>| {
+    let my_hook = Box::new(move |panic_info: &PanicHookInfo<'_>| {
         orig_hook(panic_info);
         process::exit(1);
     });
     panic::set_hook(my_hook);
 }
+
+#[test]
+fn test_dry_run_missing_directory() {
+    let source = Source::Pcap(vec![PathBuf::from("-")]);
+    let services = Services::default();
+
+    let output = Some(PathBuf::from("no/such/dir/out.txt"));
+    let err = report_dry_run(&source, &[("output", &output)], &[], &services).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Cannot create output no/such/dir/out.txt: no directory no/such/dir"
+    );
+
+    // Directories are created as needed, but not below a file
+    let tee = Some(PathBuf::from("no/such/dir"));
+    report_dry_run(&source, &[], &[("tee directory", &tee)], &services).unwrap();
+    let tee = Some(PathBuf::from("Cargo.toml/tee"));
+    let err = report_dry_run(&source, &[], &[("tee directory", &tee)], &services).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Cannot create tee directory Cargo.toml/tee: Cargo.toml is not a directory"
+    );
+}
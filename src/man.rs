@@ -0,0 +1,132 @@
+//! Generate a man page from the usage text, see `mapiproxy man`.
+
+/// Column where the descriptions of the options start in the usage text
+const DESCRIPTION_COLUMN: usize = 25;
+
+/// Turn the usage text into a man page in roff format. The synopsis comes
+/// from the 'Usage:' lines, every section of options becomes a section of
+/// the man page and the other sections are copied as they are.
+pub fn man_page(usage: &str, version: &str, description: &str) -> String {
+    let mut out = String::new();
+    out.push_str(&format!(
+        ".TH MAPIPROXY 1 \"\" \"mapiproxy {version}\" \"User Commands\"\n"
+    ));
+    out.push_str(".SH NAME\n");
+    out.push_str(&format!("mapiproxy \\- {}\n", escape(description)));
+
+    let mut in_options = false;
+    // Inside a block of lines that are shown as they are
+    let mut in_text = false;
+    let end_text = |out: &mut String, in_text: &mut bool| {
+        if *in_text {
+            out.push_str(".fi\n");
+            *in_text = false;
+        }
+    };
+    for line in usage.lines() {
+        if let Some(synopsis) = line.strip_prefix("Usage: ") {
+            out.push_str(".SH SYNOPSIS\n.nf\n");
+            out.push_str(&escape(synopsis));
+            out.push('\n');
+            in_text = true;
+        } else if line.is_empty() {
+            end_text(&mut out, &mut in_text);
+        } else if let Some(title) = line.strip_suffix(':').filter(|_| !line.starts_with(' ')) {
+            end_text(&mut out, &mut in_text);
+            out.push_str(&format!(".SH \"{}\"\n", escape(&title.to_uppercase())));
+            in_options = title.ends_with("ptions");
+        } else if in_options {
+            let (option, description) = split_option(line);
+            if let Some(option) = option {
+                out.push_str(&format!(".TP\n.B {}\n", escape(option)));
+            }
+            if let Some(description) = description {
+                out.push_str(&escape(description));
+                out.push('\n');
+            }
+        } else {
+            if !in_text {
+                out.push_str(".nf\n");
+                in_text = true;
+            }
+            out.push_str(&escape(line.trim()));
+            out.push('\n');
+        }
+    }
+    end_text(&mut out, &mut in_text);
+    out
+}
+
+/// Split a line of an options section into the option, if it starts one,
+/// and the description.
+fn split_option(line: &str) -> (Option<&str>, Option<&str>) {
+    let indent = line.len() - line.trim_start().len();
+    let option_start = indent < DESCRIPTION_COLUMN && line[indent..].starts_with(['-', '@']);
+    let (option, description) = match line.get(..DESCRIPTION_COLUMN) {
+        // The description follows on the same line
+        Some(head) if option_start && line.as_bytes()[DESCRIPTION_COLUMN - 1] == b' ' => {
+            (Some(head.trim()), Some(&line[DESCRIPTION_COLUMN..]))
+        }
+        // The option is too wide, the description starts on the next line
+        _ if option_start => (Some(line.trim()), None),
+        _ => (None, Some(line.trim())),
+    };
+    (option, description.map(str::trim).filter(|d| !d.is_empty()))
+}
+
+/// Escape the characters roff would interpret.
+fn escape(text: &str) -> String {
+    let escaped = text.replace('\\', "\\e").replace('-', "\\-");
+    if escaped.starts_with(['.', '\'']) {
+        format!("\\&{escaped}")
+    } else {
+        escaped
+    }
+}
+
+#[test]
+fn test_man_page() {
+    let usage = concat!(
+        "Usage: mapiproxy [OPTIONS] LISTEN_ADDR FORWARD_ADDR\n",
+        "       mapiproxy [OPTIONS] --pcap PCAP_FILE\n",
+        "\n",
+        "LISTEN_ADDR:\n",
+        "    PORT, for example, 50000\n",
+        "\n",
+        "Options:\n",
+        "    -m, --messages       Dump whole messages\n",
+        "    --escape             Show other bytes as \\xNN, see\n",
+        "                         --messages\n",
+        "    --side-by-side[=WIDTH]\n",
+        "                         Show what the server sends to the right\n",
+    );
+    let page = man_page(usage, "1.0", "A proxy");
+    assert_eq!(
+        page,
+        concat!(
+            ".TH MAPIPROXY 1 \"\" \"mapiproxy 1.0\" \"User Commands\"\n",
+            ".SH NAME\n",
+            "mapiproxy \\- A proxy\n",
+            ".SH SYNOPSIS\n",
+            ".nf\n",
+            "mapiproxy [OPTIONS] LISTEN_ADDR FORWARD_ADDR\n",
+            "mapiproxy [OPTIONS] \\-\\-pcap PCAP_FILE\n",
+            ".fi\n",
+            ".SH \"LISTEN_ADDR\"\n",
+            ".nf\n",
+            "PORT, for example, 50000\n",
+            ".fi\n",
+            ".SH \"OPTIONS\"\n",
+            ".TP\n",
+            ".B \\-m, \\-\\-messages\n",
+            "Dump whole messages\n",
+            ".TP\n",
+            ".B \\-\\-escape\n",
+            "Show other bytes as \\exNN, see\n",
+            "\\-\\-messages\n",
+            ".TP\n",
+            ".B \\-\\-side\\-by\\-side[=WIDTH]\n",
+            "Show what the server sends to the right\n",
+        )
+    );
+}
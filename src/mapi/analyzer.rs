@@ -1,3 +1,8 @@
+use std::mem;
+
+use tracing::trace;
+
+use super::MAX_BLOCK_SIZE;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Analyzer {
@@ -98,6 +103,9 @@ impl Analyzer {
 
             (Unix0, [_, ..]) => (1, Self::Error),
         };
+        if mem::discriminant(self) != mem::discriminant(&new_state) {
+            trace!(from = ?self, to = ?new_state, taken, "analyzer state");
+        }
         *self = new_state;
         Some(taken as usize)
     }
@@ -106,7 +114,7 @@ impl Analyzer {
         // little endian
         let n = *byte1 as u16 + 256 * *byte2 as u16;
         let len = n / 2;
-        if len <= 8190 {
+        if len as usize <= MAX_BLOCK_SIZE {
             let last = n & 1 > 0;
             Self::Body {
                 still_needed: len,
@@ -0,0 +1,406 @@
+//! Replace what should not leave the building by made-up values, see
+//! `mapiproxy anonymize`. A value always gets the same replacement so
+//! connections and statements can still be told apart and compared.
+
+use std::{
+    collections::HashMap,
+    mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use crate::proxy::{
+    event::{ConnectionId, Direction, MapiEvent},
+    network::Addr,
+};
+
+use super::{analyzer::Analyzer, frame};
+
+/// Rewrites the events of a capture. The IP addresses become addresses in
+/// 10.0.0.0/8 or fd00::/8, the user names in login responses become user1,
+/// user2 and so on and the password hashes become zeroes. Optionally the
+/// string literals in statements and in result sets become str1, str2 and
+/// so on.
+///
+/// Data that cannot be parsed as MAPI cannot be anonymized either, it is
+/// replaced by zero bytes, see [Anonymizer::unanonymized].
+#[derive(Debug, Default)]
+pub struct Anonymizer {
+    strings: bool,
+    ips: HashMap<IpAddr, IpAddr>,
+    users: HashMap<String, String>,
+    literals: HashMap<String, String>,
+    streams: HashMap<(ConnectionId, Direction), Stream>,
+    /// Number of bytes replaced by zero bytes
+    unanonymized: u64,
+}
+
+#[derive(Debug)]
+struct Stream {
+    analyzer: Analyzer,
+    /// The bytes of the message being received, as they came in, so they
+    /// can be passed on if it turns out not to be MAPI
+    pending: Vec<u8>,
+    /// The blocks of that message without their headers
+    payload: Vec<u8>,
+    /// We cannot tell where the messages start, for example after a gap in
+    /// the capture
+    lost: bool,
+    /// The other side has sent data since the stream was lost, so the next
+    /// data starts a new message
+    answered: bool,
+}
+
+impl Stream {
+    fn new(unix_client: bool) -> Self {
+        Stream {
+            analyzer: Analyzer::new(unix_client),
+            pending: vec![],
+            payload: vec![],
+            lost: false,
+            answered: false,
+        }
+    }
+}
+
+impl Anonymizer {
+    /// Also replace the string literals, see `--anonymize-strings`.
+    pub fn new(strings: bool) -> Self {
+        Anonymizer {
+            strings,
+            ..Anonymizer::default()
+        }
+    }
+
+    /// Number of bytes that could not be anonymized and have been replaced
+    /// by zero bytes.
+    pub fn unanonymized(&self) -> u64 {
+        self.unanonymized
+    }
+
+    /// The event with the sensitive parts replaced. Data is held back until
+    /// the message it belongs to is complete, so an event can turn into no
+    /// events or into several.
+    pub fn handle(&mut self, event: MapiEvent) -> Vec<MapiEvent> {
+        match event {
+            MapiEvent::Incoming { id, local, peer } => {
+                self.streams
+                    .insert((id, Direction::Upstream), Stream::new(peer.is_unix()));
+                self.streams
+                    .insert((id, Direction::Downstream), Stream::new(false));
+                let local = self.addr(local);
+                let peer = self.addr(peer);
+                vec![MapiEvent::Incoming { id, local, peer }]
+            }
+            MapiEvent::Connected { id, peer } => {
+                let peer = self.addr(peer);
+                vec![MapiEvent::Connected { id, peer }]
+            }
+            MapiEvent::Data {
+                id,
+                direction,
+                data,
+                timestamp,
+            } => {
+                let data = self.data(id, direction, &data);
+                if data.is_empty() {
+                    return vec![];
+                }
+                let data = data.into();
+                vec![MapiEvent::Data {
+                    id,
+                    direction,
+                    data,
+                    timestamp,
+                }]
+            }
+            MapiEvent::DataMissing { id, direction, .. }
+            | MapiEvent::ShutdownRead { id, direction } => {
+                let mut events: Vec<MapiEvent> = self.flush(id, direction).into_iter().collect();
+                if let MapiEvent::DataMissing { .. } = event {
+                    self.lose(id, direction);
+                }
+                events.push(event);
+                events
+            }
+            MapiEvent::JoinedMidStream { id } => {
+                self.lose(id, Direction::Upstream);
+                self.lose(id, Direction::Downstream);
+                vec![event]
+            }
+            MapiEvent::End { id, .. } | MapiEvent::Aborted { id, .. } => {
+                let mut events = vec![];
+                for direction in [Direction::Upstream, Direction::Downstream] {
+                    events.extend(self.flush(id, direction));
+                    self.streams.remove(&(id, direction));
+                }
+                events.push(event);
+                events
+            }
+            other => vec![other],
+        }
+    }
+
+    /// Pass on zero bytes for what we have of an incomplete message.
+    fn flush(&mut self, id: ConnectionId, direction: Direction) -> Option<MapiEvent> {
+        let stream = self.streams.get_mut(&(id, direction))?;
+        stream.payload.clear();
+        let data = mem::take(&mut stream.pending);
+        if data.is_empty() {
+            return None;
+        }
+        self.unanonymized += data.len() as u64;
+        Some(MapiEvent::Data {
+            id,
+            direction,
+            data: vec![0; data.len()].into(),
+            timestamp: None,
+        })
+    }
+
+    /// Stop trusting the analyzer of the stream until the other side has had
+    /// its turn.
+    fn lose(&mut self, id: ConnectionId, direction: Direction) {
+        if let Some(stream) = self.streams.get_mut(&(id, direction)) {
+            stream.lost = true;
+            stream.answered = false;
+        }
+    }
+
+    /// The rewritten messages completed by `data`.
+    fn data(&mut self, id: ConnectionId, direction: Direction, mut data: &[u8]) -> Vec<u8> {
+        let other = match direction {
+            Direction::Upstream => Direction::Downstream,
+            Direction::Downstream => Direction::Upstream,
+        };
+        if let Some(stream) = self.streams.get_mut(&(id, other)) {
+            stream.answered |= stream.lost;
+        }
+        let Some(stream) = self
+            .streams
+            .get_mut(&(id, direction))
+            .filter(|s| !s.lost || s.answered)
+        else {
+            self.unanonymized += data.len() as u64;
+            return vec![0; data.len()];
+        };
+        if stream.lost {
+            // MAPI is request and response, so this is the start of a message
+            *stream = Stream::new(false);
+        }
+        let mut out = vec![];
+        let mut messages = vec![];
+        while let Some(chunk) = stream.analyzer.split_chunk(&mut data) {
+            if stream.analyzer.was_error() {
+                // Not MAPI after all, we can no longer tell what is what
+                let n = stream.pending.len() + chunk.len() + data.len();
+                self.unanonymized += n as u64;
+                out.resize(out.len() + n, 0);
+                stream.pending.clear();
+                stream.payload.clear();
+                stream.lost = true;
+                break;
+            }
+            if !stream.analyzer.was_head() && !stream.analyzer.was_body() {
+                // The '0' Unix Domain clients start with
+                out.extend_from_slice(chunk);
+                continue;
+            }
+            stream.pending.extend_from_slice(chunk);
+            if stream.analyzer.was_body() {
+                stream.payload.extend_from_slice(chunk);
+            }
+            if stream.analyzer.was_message_boundary() {
+                stream.pending.clear();
+                messages.push((out.len(), mem::take(&mut stream.payload)));
+            }
+        }
+        // The messages go where they ended, among any data passed on as is
+        let mut result = vec![];
+        let mut copied = 0;
+        for (pos, payload) in messages {
+            result.extend_from_slice(&out[copied..pos]);
+            copied = pos;
+            let payload = match direction {
+                Direction::Upstream => self.request(payload),
+                Direction::Downstream => self.response(payload),
+            };
+            result.extend(frame(&payload));
+        }
+        result.extend_from_slice(&out[copied..]);
+        result
+    }
+
+    /// Replace the user name and password hash of a login response such as
+    /// `BIG:monetdb:{SHA512}0123abcd:sql:demo:` and, optionally, the string
+    /// literals of a statement.
+    fn request(&mut self, payload: Vec<u8>) -> Vec<u8> {
+        let text = match String::from_utf8(payload) {
+            Ok(text) => text,
+            // Binary data such as a file upload, nothing we know to replace
+            Err(e) => return e.into_bytes(),
+        };
+        let mut fields: Vec<String> = text.split(':').map(str::to_string).collect();
+        if fields.len() >= 5 && ["LIT", "BIG"].contains(&&*fields[0]) && fields[2].starts_with('{')
+        {
+            let n = self.users.len() + 1;
+            let user = self.users.entry(fields[1].clone());
+            fields[1] = user.or_insert_with(|| format!("user{n}")).clone();
+            if let Some((algo, hash)) = fields[2].split_once('}') {
+                fields[2] = format!("{algo}}}{}", "0".repeat(hash.len()));
+            }
+            return fields.join(":").into_bytes();
+        }
+        if self.strings && text.starts_with('s') {
+            return self.replace_literals(&text, '\'').into_bytes();
+        }
+        text.into_bytes()
+    }
+
+    /// Optionally replace the strings in the rows of result sets.
+    fn response(&mut self, payload: Vec<u8>) -> Vec<u8> {
+        if !self.strings {
+            return payload;
+        }
+        let text = match String::from_utf8(payload) {
+            Ok(text) => text,
+            Err(e) => return e.into_bytes(),
+        };
+        let mut result = String::new();
+        for line in text.split_inclusive('\n') {
+            if line.starts_with('[') {
+                result.push_str(&self.replace_literals(line, '"'));
+            } else {
+                result.push_str(line);
+            }
+        }
+        result.into_bytes()
+    }
+
+    /// Replace the contents of the strings between `quote`s in `text`.
+    /// Backslashes and doubled quotes escape the quote.
+    fn replace_literals(&mut self, text: &str, quote: char) -> String {
+        let mut result = String::new();
+        let mut rest = text;
+        while let Some(start) = rest.find(quote) {
+            result.push_str(&rest[..=start]);
+            rest = &rest[start + 1..];
+            let mut end = None;
+            let mut chars = rest.char_indices().peekable();
+            while let Some((i, c)) = chars.next() {
+                let doubled = c == quote && chars.peek().is_some_and(|(_, c)| *c == quote);
+                if c == '\\' || doubled {
+                    chars.next();
+                } else if c == quote {
+                    end = Some(i);
+                    break;
+                }
+            }
+            // An unterminated string is replaced up to the end
+            let end = end.unwrap_or(rest.len());
+            let n = self.literals.len() + 1;
+            let literal = self.literals.entry(rest[..end].to_string());
+            result.push_str(literal.or_insert_with(|| format!("str{n}")));
+            rest = &rest[end..];
+            if let Some(after) = rest.strip_prefix(quote) {
+                result.push(quote);
+                rest = after;
+            }
+        }
+        result.push_str(rest);
+        result
+    }
+
+    fn addr(&mut self, addr: Addr) -> Addr {
+        match addr {
+            Addr::Tcp(sock) => Addr::Tcp(SocketAddr::new(self.ip(sock.ip()), sock.port())),
+            unix => unix,
+        }
+    }
+
+    /// Loopback addresses are left alone, they don't give anything away.
+    fn ip(&mut self, ip: IpAddr) -> IpAddr {
+        if ip.is_loopback() {
+            return ip;
+        }
+        let n = self.ips.len() as u32 + 1;
+        *self.ips.entry(ip).or_insert_with(|| match ip {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + n)),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(0xfd00_u128 << 112 | n as u128)),
+        })
+    }
+}
+
+#[test]
+fn test_anonymize() {
+    let mut anonymizer = Anonymizer::new(true);
+    let id = ConnectionId::new(10);
+    let local = Addr::Tcp("192.168.1.5:50000".parse().unwrap());
+    let peer = Addr::Tcp("172.16.0.9:41394".parse().unwrap());
+    let events = anonymizer.handle(MapiEvent::Incoming { id, local, peer });
+    let [MapiEvent::Incoming { local, peer, .. }] = &events[..] else {
+        panic!("expected an incoming event");
+    };
+    assert_eq!(local.to_string(), "10.0.0.1:50000");
+    assert_eq!(peer.to_string(), "10.0.0.2:41394");
+
+    let mut rewrite = |direction, payload: &str| -> String {
+        let message = frame(payload.as_bytes());
+        // Split in the middle to check it is reassembled
+        let (a, b) = message.split_at(message.len() / 2);
+        let mut data = anonymizer.data(id, direction, a);
+        assert!(data.is_empty());
+        data.extend(anonymizer.data(id, direction, b));
+        String::from_utf8(data[2..].to_vec()).unwrap()
+    };
+    assert_eq!(
+        rewrite(Direction::Upstream, "BIG:alice:{SHA512}12ab:sql:demo:"),
+        "BIG:user1:{SHA512}0000:sql:demo:"
+    );
+    assert_eq!(
+        rewrite(
+            Direction::Upstream,
+            "sSELECT * FROM t WHERE a = 'it''s' OR b = 'x';\n"
+        ),
+        "sSELECT * FROM t WHERE a = 'str1' OR b = 'str2';\n"
+    );
+    assert_eq!(
+        rewrite(
+            Direction::Downstream,
+            "&1 0 1 1 1\n% t # name\n[ \"it's\",\t\"x\"\t]\n"
+        ),
+        "&1 0 1 1 1\n% t # name\n[ \"str3\",\t\"str2\"\t]\n"
+    );
+}
+
+#[test]
+fn test_anonymize_gap() {
+    let mut anonymizer = Anonymizer::new(false);
+    let id = ConnectionId::new(10);
+    let local = Addr::Tcp("[::1]:50000".parse().unwrap());
+    let peer = Addr::Tcp("[::1]:41394".parse().unwrap());
+    anonymizer.handle(MapiEvent::Incoming { id, local, peer });
+
+    // A gap in the middle of the login message
+    let login = frame(b"BIG:alice:{SHA512}12ab:sql:demo:");
+    assert!(anonymizer
+        .data(id, Direction::Upstream, &login[..8])
+        .is_empty());
+    let events = anonymizer.handle(MapiEvent::DataMissing {
+        id,
+        direction: Direction::Upstream,
+        bytes: 10,
+    });
+    let [MapiEvent::Data { data, .. }, MapiEvent::DataMissing { .. }] = &events[..] else {
+        panic!("expected the start of the login and the gap");
+    };
+    assert_eq!(data[..], [0; 8]);
+    let rest = &login[18..];
+    let data = anonymizer.data(id, Direction::Upstream, rest);
+    assert_eq!(data, vec![0; rest.len()]);
+    assert_eq!(anonymizer.unanonymized(), 8 + rest.len() as u64);
+
+    // Once the server has answered, the client starts a new message
+    anonymizer.data(id, Direction::Downstream, &frame(b"=OK\n"));
+    let query = frame(b"sSELECT 1;\n");
+    assert_eq!(anonymizer.data(id, Direction::Upstream, &query), query);
+}
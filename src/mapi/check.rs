@@ -0,0 +1,159 @@
+//! Keep track of what went wrong during the run, see `--check-exit-code`.
+
+use std::collections::HashMap;
+
+use crate::proxy::event::{ConnectionId, Direction, MapiEvent};
+
+use super::analyzer::Analyzer;
+
+/// Counts the protocol errors, aborted connections, skipped packets and error
+/// responses of the server, so we can exit with an error if there were any.
+#[derive(Debug, Default)]
+pub struct Check {
+    streams: HashMap<(ConnectionId, Direction), Stream>,
+    protocol_errors: u64,
+    aborted: u64,
+    skipped_packets: u64,
+    error_responses: u64,
+}
+
+#[derive(Debug)]
+struct Stream {
+    analyzer: Analyzer,
+    /// No body bytes of the current message have been seen yet
+    at_start: bool,
+}
+
+impl Stream {
+    fn new(unix_client: bool) -> Self {
+        Stream {
+            analyzer: Analyzer::new(unix_client),
+            at_start: true,
+        }
+    }
+}
+
+impl Check {
+    pub fn handle(&mut self, event: &MapiEvent) {
+        match event {
+            MapiEvent::Incoming { id, peer, .. } => {
+                for (direction, unix) in [
+                    (Direction::Upstream, peer.is_unix()),
+                    (Direction::Downstream, false),
+                ] {
+                    self.streams.insert((*id, direction), Stream::new(unix));
+                }
+            }
+            MapiEvent::Data {
+                id,
+                direction,
+                data,
+                ..
+            } => self.data(*id, *direction, data),
+            MapiEvent::DataMissing { id, direction, .. } => {
+                if let Some(stream) = self.streams.get_mut(&(*id, *direction)) {
+                    *stream = Stream::new(false);
+                }
+            }
+            MapiEvent::End { id, .. } | MapiEvent::Aborted { id, .. } => {
+                if let MapiEvent::Aborted { .. } = event {
+                    self.aborted += 1;
+                }
+                self.streams.remove(&(*id, Direction::Upstream));
+                self.streams.remove(&(*id, Direction::Downstream));
+            }
+            MapiEvent::SkippedPacket { .. } => self.skipped_packets += 1,
+            _ => {}
+        }
+    }
+
+    fn data(&mut self, id: ConnectionId, direction: Direction, mut data: &[u8]) {
+        let Some(stream) = self.streams.get_mut(&(id, direction)) else {
+            return;
+        };
+        if stream.analyzer.was_error() {
+            // Already counted, the analyzer swallows the rest
+            return;
+        }
+        while let Some(chunk) = stream.analyzer.split_chunk(&mut data) {
+            if stream.analyzer.was_error() {
+                self.protocol_errors += 1;
+                return;
+            }
+            if stream.analyzer.was_body() && !chunk.is_empty() && stream.at_start {
+                if direction == Direction::Downstream && chunk[0] == b'!' {
+                    self.error_responses += 1;
+                }
+                stream.at_start = false;
+            }
+            if stream.analyzer.was_message_boundary() {
+                stream.at_start = true;
+            }
+        }
+    }
+
+    /// Describe what went wrong, for example "1 aborted connection and 2
+    /// error responses". None if nothing did.
+    pub fn problems(&self) -> Option<String> {
+        let mut problems = vec![];
+        for (n, what) in [
+            (self.protocol_errors, "protocol error"),
+            (self.aborted, "aborted connection"),
+            (self.skipped_packets, "skipped packet"),
+            (self.error_responses, "error response"),
+        ] {
+            if n > 0 {
+                let s = if n == 1 { "" } else { "s" };
+                problems.push(format!("{n} {what}{s}"));
+            }
+        }
+        let last = problems.pop()?;
+        if problems.is_empty() {
+            Some(last)
+        } else {
+            Some(format!("{} and {last}", problems.join(", ")))
+        }
+    }
+}
+
+#[test]
+fn test_check() {
+    use crate::proxy::{event::Timing, event::Traffic, network::Addr, Error};
+
+    let mut check = Check::default();
+    assert_eq!(check.problems(), None);
+
+    let id = ConnectionId::new(10);
+    let addr = Addr::Tcp("127.0.0.1:50000".parse().unwrap());
+    check.handle(&MapiEvent::Incoming {
+        id,
+        local: addr.clone(),
+        peer: addr,
+    });
+    for (direction, data) in [
+        (Direction::Downstream, &b"\x09\x00&1 0"[..]),
+        (Direction::Downstream, b"\x03\x00!"),
+        (Direction::Downstream, b"\x05\x00"),
+        (Direction::Downstream, b"!x"),
+        (Direction::Upstream, b"\x05\x00!s"),
+    ] {
+        check.handle(&MapiEvent::Data {
+            id,
+            direction,
+            data: data.to_vec().into(),
+            timestamp: None,
+        });
+    }
+    assert_eq!(check.problems().as_deref(), Some("2 error responses"));
+
+    check.handle(&MapiEvent::Aborted {
+        id,
+        error: Error::Other("boom".to_string()),
+        traffic: Traffic::default(),
+        timing: Timing::default(),
+    });
+    assert_eq!(
+        check.problems().as_deref(),
+        Some("1 aborted connection and 2 error responses")
+    );
+}
@@ -0,0 +1,80 @@
+//! Only show some of the messages, see `--grep`, `--exclude-grep` and
+//! `--sender`.
+
+use lazy_regex::BytesRegex;
+
+use crate::proxy::event::Direction;
+
+/// Decides which messages, blocks or chunks of data are shown. The others
+/// are still counted in the summary. The responses to a request excluded by
+/// `--exclude-grep` are hidden too, see [Filter::wants_in_conn].
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// Only the frames that match, see `--grep`
+    pub grep: Option<BytesRegex>,
+    /// Not the frames that match any of these, see `--exclude-grep`
+    pub exclude: Vec<BytesRegex>,
+    /// Only the frames sent by this side, see `--sender`
+    pub sender: Option<Direction>,
+}
+
+impl Filter {
+    /// Whether a frame with `data` sent in `direction` is shown.
+    pub fn wants(&self, direction: Direction, data: &[u8]) -> bool {
+        if self.sender.is_some_and(|sender| sender != direction) {
+            return false;
+        }
+        if self.excludes(data) {
+            return false;
+        }
+        match &self.grep {
+            Some(regex) => regex.is_match(data),
+            None => true,
+        }
+    }
+
+    /// Like [Filter::wants], but frames from the server are also hidden if
+    /// the request they answer was excluded. `excluded` belongs to the
+    /// connection and remembers whether its last request was.
+    pub fn wants_in_conn(&self, excluded: &mut bool, direction: Direction, data: &[u8]) -> bool {
+        match direction {
+            Direction::Upstream => *excluded = self.excludes(data),
+            Direction::Downstream if *excluded => return false,
+            Direction::Downstream => {}
+        }
+        self.wants(direction, data)
+    }
+
+    fn excludes(&self, data: &[u8]) -> bool {
+        self.exclude.iter().any(|regex| regex.is_match(data))
+    }
+}
+
+#[test]
+fn test_filter() {
+    let mut filter = Filter::default();
+    assert!(filter.wants(Direction::Upstream, b"sSELECT 1;"));
+
+    filter.grep = Some(BytesRegex::new("(?i)select").unwrap());
+    assert!(filter.wants(Direction::Upstream, b"sSELECT 1;"));
+    assert!(filter.wants(Direction::Downstream, b"&1 0 1 1 1\n% select"));
+    assert!(!filter.wants(Direction::Upstream, b"sINSERT INTO t VALUES (1);"));
+
+    filter.sender = Some(Direction::Upstream);
+    assert!(filter.wants(Direction::Upstream, b"sSELECT 1;"));
+    assert!(!filter.wants(Direction::Downstream, b"&1 0 1 1 1\n% select"));
+
+    filter.exclude.push(BytesRegex::new("^sSELECT 1;").unwrap());
+    assert!(!filter.wants(Direction::Upstream, b"sSELECT 1;"));
+    assert!(filter.wants(Direction::Upstream, b"sSELECT 2;"));
+
+    // The response to an excluded request is hidden as well
+    filter.sender = None;
+    filter.grep = None;
+    let mut excluded = false;
+    let response = b"&1 0 1 1 1\n[ 1\t]\n";
+    assert!(!filter.wants_in_conn(&mut excluded, Direction::Upstream, b"sSELECT 1;"));
+    assert!(!filter.wants_in_conn(&mut excluded, Direction::Downstream, response));
+    assert!(filter.wants_in_conn(&mut excluded, Direction::Upstream, b"sSELECT 2;"));
+    assert!(filter.wants_in_conn(&mut excluded, Direction::Downstream, response));
+}
@@ -1,23 +1,118 @@
 mod analyzer;
+mod anonymize;
+mod check;
+mod filter;
+mod sizes;
+mod structured;
+mod table;
+mod version;
 
 use std::{
-    collections::HashMap,
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    fmt,
     io::{self, ErrorKind},
+    ops::RangeInclusive,
+    sync::Arc,
 };
 
+use smallvec::SmallVec;
+
 use crate::{
-    proxy::event::{ConnectionId, Direction, MapiEvent},
-    render::{Renderer, Style},
-    Level,
+    proxy::{
+        event::{CaptureTime, ConnectionId, Direction, MapiEvent, TcpEvent, TimeZone, Traffic},
+        network::{Addr, Subnet},
+    },
+    render::{Glyphs, Renderer, Style},
+    Format, Level,
 };
 
-use self::analyzer::Analyzer;
+use self::{sizes::Sizes, version::VersionCheck};
+
+pub use self::analyzer::Analyzer;
+pub use self::anonymize::Anonymizer;
+pub use self::check::Check;
+pub use self::filter::Filter;
+pub use self::structured::{direction_name, iso_time, read_frame, Record, Structured, SCHEMA};
 
 #[derive(Debug)]
 pub struct State {
     level: Level,
     force_binary: bool,
+    /// Show a line per message instead of the message, see `--quiet`
+    quiet: bool,
+    /// Include the addresses in the frame headers, see `--show-peers`
+    show_peers: bool,
+    /// Show mostly-text frames as text with escapes, see `--escape`
+    escape: bool,
+    /// Show result sets as tables, see `--table`
+    table: bool,
+    /// Don't show frames without data, see `--hide-empty`
+    hide_empty: bool,
+    /// Hold back the lines about a connection until it sends data, see
+    /// `--quiet-connections`
+    quiet_connections: bool,
+    /// What is held back per connection whose client has not sent data yet
+    held: HashMap<ConnectionId, Vec<Held>>,
+    /// Only show the connections with these ids, see `--conn`
+    conns: Option<Vec<RangeInclusive<usize>>>,
+    /// Only show the connections from clients in these subnets, see
+    /// `--client`
+    clients: Vec<Subnet>,
+    /// Connections that are only counted, not shown
+    hidden: HashSet<ConnectionId>,
+    /// Which frames are shown, see `--grep` and `--sender`
+    filter: Filter,
+    /// How the structured formats write times, see `--timestamp-format`
+    time_format: Option<(Arc<str>, TimeZone)>,
     accs: HashMap<ConnectionId, (Accumulator, Accumulator)>,
+    summary: Summary,
+    sizes: Sizes,
+    versions: VersionCheck,
+    /// Set if the events are written as structured records rather than
+    /// rendered
+    structured: Option<Structured>,
+}
+
+/// A line or data held back, see `--quiet-connections`. Each with the time
+/// it would have been shown at.
+#[derive(Debug)]
+enum Held {
+    Line(Option<Direction>, Option<CaptureTime>, String),
+    /// What the server sent before the client said anything
+    Data(SmallVec<[u8; 8]>, Option<CaptureTime>),
+}
+
+/// Totals over all connections, reported by [State::finish].
+#[derive(Debug, Default)]
+struct Summary {
+    connections: u64,
+    aborted: u64,
+    traffic: Traffic,
+    skipped_packets: u64,
+}
+
+impl Summary {
+    /// Count a connection that is not shown, see `--conn`.
+    fn count(&mut self, event: &MapiEvent) {
+        match event {
+            MapiEvent::Incoming { .. } => self.connections += 1,
+            MapiEvent::End { traffic, .. } => self.traffic = self.traffic + *traffic,
+            MapiEvent::Aborted { traffic, .. } => {
+                self.aborted += 1;
+                self.traffic = self.traffic + *traffic;
+            }
+            MapiEvent::SkippedPacket { .. } => self.skipped_packets += 1,
+            _ => {}
+        }
+    }
+
+    fn merge(&mut self, other: &Summary) {
+        self.connections += other.connections;
+        self.aborted += other.aborted;
+        self.traffic = self.traffic + other.traffic;
+        self.skipped_packets += other.skipped_packets;
+    }
 }
 
 impl State {
@@ -25,31 +120,364 @@ impl State {
         State {
             level,
             force_binary,
+            quiet: false,
+            show_peers: false,
+            escape: false,
+            table: false,
+            hide_empty: false,
+            quiet_connections: false,
+            held: HashMap::new(),
+            conns: None,
+            clients: vec![],
+            hidden: HashSet::new(),
+            filter: Filter::default(),
+            time_format: None,
             accs: Default::default(),
+            summary: Summary::default(),
+            sizes: Sizes::default(),
+            versions: VersionCheck::default(),
+            structured: None,
+        }
+    }
+
+    /// Choose how the events are written. If they happen `live` rather than
+    /// being read from a file, the structured formats include the current
+    /// time.
+    pub fn set_format(&mut self, format: Format, live: bool) {
+        self.structured = match format {
+            Format::Text => None,
+            Format::JsonLines | Format::Cbor | Format::PlainStable | Format::Raw => {
+                let mut structured = Structured::new(format, self.level, self.force_binary, live);
+                structured.set_filter(self.filter.clone());
+                if let Some((format, zone)) = &self.time_format {
+                    structured.set_time_format(format.clone(), *zone);
+                }
+                Some(structured)
+            }
+        };
+    }
+
+    /// Whether the events are written as structured records, which must
+    /// not be interrupted by the blank lines that mark pauses.
+    pub fn is_structured(&self) -> bool {
+        self.structured.is_some()
+    }
+
+    /// Summarize each message or block in a single line rather than
+    /// dumping it.
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+    }
+
+    /// Mention the client and server address in every frame header.
+    pub fn set_show_peers(&mut self, show_peers: bool) {
+        self.show_peers = show_peers;
+    }
+
+    /// Show frames that are mostly text as text, with the control characters
+    /// and invalid UTF-8 written as `\xNN`.
+    pub fn set_escape(&mut self, escape: bool) {
+        self.escape = escape;
+    }
+
+    /// Show the result sets in the responses of the server as aligned
+    /// tables.
+    pub fn set_table(&mut self, table: bool) {
+        self.table = table;
+    }
+
+    /// Leave out the messages or blocks that have no data, such as the
+    /// empty blocks clients send to flush.
+    pub fn set_hide_empty(&mut self, hide_empty: bool) {
+        self.hide_empty = hide_empty;
+    }
+
+    /// Don't mention connections until they send data, so connections
+    /// that are opened and closed again, for example by port scanners and
+    /// health checks, are not shown at all.
+    pub fn set_quiet_connections(&mut self, quiet_connections: bool) {
+        self.quiet_connections = quiet_connections;
+    }
+
+    /// Use `level` for the connections that start from now on. Those
+    /// already open keep theirs.
+    pub fn set_level(&mut self, level: Level) {
+        if let Some(structured) = &mut self.structured {
+            structured.set_level(level);
+        }
+        self.level = level;
+    }
+
+    /// Only show the connections whose ids fall in one of the ranges. The
+    /// others are counted in the summary but not shown. No ranges means all
+    /// connections are shown.
+    pub fn set_conns(&mut self, conns: Vec<RangeInclusive<usize>>) {
+        self.conns = Some(conns).filter(|c| !c.is_empty());
+    }
+
+    /// Only show the connections from clients in one of the subnets. The
+    /// others are counted in the summary but not shown.
+    pub fn set_clients(&mut self, clients: Vec<Subnet>) {
+        self.clients = clients;
+    }
+
+    /// Only show the messages, blocks or chunks of data the filter wants.
+    /// The others are counted in the summary but not shown. Also applies to
+    /// the connections that are already open.
+    pub fn set_filter(&mut self, filter: Filter) {
+        if let Some(structured) = &mut self.structured {
+            structured.set_filter(filter.clone());
+        }
+        for (upstream, downstream) in self.accs.values_mut() {
+            upstream.filter = filter.clone();
+            downstream.filter = filter.clone();
+        }
+        self.filter = filter;
+    }
+
+    pub fn filter(&self) -> &Filter {
+        &self.filter
+    }
+
+    /// Write the times of the records in `format` in `zone`, see
+    /// [Structured::set_time_format]. The renderer takes care of the text
+    /// format.
+    pub fn set_time_format(&mut self, format: Arc<str>, zone: TimeZone) {
+        if let Some(structured) = &mut self.structured {
+            structured.set_time_format(format.clone(), zone);
+        }
+        self.time_format = Some((format, zone));
+    }
+
+    /// Create a State with the same settings but without any connections,
+    /// for example to decode other connections on another thread.
+    pub fn fresh(&self) -> Self {
+        let mut state = State::new(self.level, self.force_binary);
+        state.quiet = self.quiet;
+        state.show_peers = self.show_peers;
+        state.escape = self.escape;
+        state.table = self.table;
+        state.hide_empty = self.hide_empty;
+        state.quiet_connections = self.quiet_connections;
+        state.conns = self.conns.clone();
+        state.clients = self.clients.clone();
+        state.filter = self.filter.clone();
+        state.time_format = self.time_format.clone();
+        state.structured = self.structured.as_ref().map(Structured::fresh);
+        state
+    }
+
+    /// Take over the open connections and the totals of `other`, the state
+    /// of a worker of `--jobs`, so [State::finish] reports on them too.
+    pub fn merge(&mut self, other: State) {
+        self.accs.extend(other.accs);
+        self.held.extend(other.held);
+        self.summary.merge(&other.summary);
+        self.sizes.merge(other.sizes);
+        if let (Some(structured), Some(other)) = (&mut self.structured, other.structured) {
+            structured.merge(other);
+        }
+    }
+
+    /// Called before the first event, see [Structured::start].
+    pub fn start(&mut self, renderer: &mut Renderer) -> io::Result<()> {
+        if let Some(structured) = &self.structured {
+            structured.start(renderer)?;
+        }
+        Ok(())
+    }
+
+    /// Called when no more events will arrive. Reports on the connections
+    /// that are still open and prints a summary.
+    pub fn finish(&mut self, renderer: &mut Renderer) -> io::Result<()> {
+        if let Some(structured) = &mut self.structured {
+            return structured.finish(renderer);
         }
+        let mut open: Vec<ConnectionId> = self
+            .accs
+            .keys()
+            .filter(|id| !self.held.contains_key(id))
+            .copied()
+            .collect();
+        open.sort();
+        for id in &open {
+            for direction in [Direction::Upstream, Direction::Downstream] {
+                self.check_incomplete(*id, direction, renderer)?;
+            }
+            renderer.message(Some(*id), None, "STILL OPEN")?;
+        }
+
+        let Summary {
+            connections,
+            aborted,
+            traffic,
+            skipped_packets,
+        } = &self.summary;
+        let s = if *connections == 1 { "" } else { "s" };
+        let still_open = open.len();
+        let skipped = match skipped_packets {
+            0 => String::new(),
+            1 => ", 1 packet skipped".to_string(),
+            n => format!(", {n} packets skipped"),
+        };
+        let client = traffic.upstream;
+        let server = traffic.downstream;
+        renderer.message(
+            None,
+            None,
+            format_args!("SUMMARY: {connections} connection{s}, {aborted} aborted, {still_open} still open{skipped}; client sent {client}, server sent {server}"),
+        )?;
+        self.sizes.report(renderer)?;
+        renderer.flush()
     }
 
     pub fn handle(&mut self, event: &MapiEvent, renderer: &mut Renderer) -> io::Result<()> {
+        self.versions.handle(event);
+        if self.is_hidden(event) {
+            match &mut self.structured {
+                Some(structured) => structured.count_only(event),
+                None => self.summary.count(event),
+            }
+            return Ok(());
+        }
+        if let Some(structured) = &mut self.structured {
+            return structured.handle(event, renderer);
+        }
+        if let Some(timestamp) = event.timestamp() {
+            renderer.advance_clock(timestamp);
+        }
+        if let Some(held) = event.id().and_then(|id| self.held.get_mut(&id)) {
+            match event {
+                MapiEvent::Data {
+                    direction: Direction::Downstream,
+                    data,
+                    timestamp,
+                    ..
+                } => {
+                    // A health check also gets the login challenge
+                    held.push(Held::Data(data.clone(), timestamp.or(renderer.now())));
+                    return Ok(());
+                }
+                // The client sent something, or something went wrong
+                MapiEvent::Data { id, .. }
+                | MapiEvent::ConnectFailed { id, .. }
+                | MapiEvent::ConnectCancelled { id, .. }
+                | MapiEvent::Aborted { id, .. }
+                | MapiEvent::MirrorFailed { id, .. }
+                | MapiEvent::RetransmissionDiffers { id, .. }
+                | MapiEvent::DataMissing { id, .. }
+                | MapiEvent::TlsNotDecrypted { id, .. } => self.release(*id, renderer)?,
+                // The rest is shown through conn_message, which holds it
+                _ => {}
+            }
+        }
         match event {
             MapiEvent::BoundPort(port) => {
                 renderer.message(None, None, format_args!("LISTEN on port {port}"))?;
             }
 
-            MapiEvent::Incoming { id, local, peer } => {
+            MapiEvent::Draining { connections } => {
+                let s = if *connections == 1 { "" } else { "s" };
                 renderer.message(
-                    Some(*id),
+                    None,
+                    None,
+                    format_args!("SHUTTING DOWN, waiting for {connections} connection{s} to end, press Control-C again to abort"),
+                )?;
+            }
+
+            MapiEvent::Ignored { connections, bytes } => {
+                let s = if *connections == 1 { "" } else { "s" };
+                renderer.message(
+                    None,
+                    None,
+                    format_args!("IGNORED {connections} connection{s} with {bytes} bytes that did not match --port or --detect-mapi"),
+                )?;
+            }
+
+            MapiEvent::SkippedPacket {
+                id,
+                timestamp,
+                error,
+            } => {
+                self.summary.skipped_packets += 1;
+                let timestamp = renderer.capture_time(*timestamp);
+                renderer.message(
+                    *id,
+                    None,
+                    format_args!("SKIPPED packet captured at {timestamp}: {error}"),
+                )?;
+            }
+
+            MapiEvent::Status {
+                connections,
+                bytes,
+                messages,
+                messages_per_sec,
+            } => {
+                let s = if *connections == 1 { "" } else { "s" };
+                renderer.message(
+                    None,
+                    None,
+                    format_args!("STATUS: {connections} connection{s} open, {bytes} bytes and {messages} messages forwarded, {messages_per_sec:.1} messages/s"),
+                )?;
+            }
+
+            MapiEvent::Incoming { id, local, peer } => {
+                if self.quiet_connections {
+                    self.held.insert(*id, vec![]);
+                }
+                self.conn_message(
+                    *id,
                     None,
                     format_args!("INCOMING on {local} from {peer}"),
+                    renderer,
+                )?;
+                self.add_connection(id, peer);
+                self.sizes.add_connection(*id, peer.is_unix());
+                self.summary.connections += 1;
+            }
+
+            MapiEvent::JoinedMidStream { id } => {
+                self.conn_message(*id, None, "JOINED MID-STREAM", renderer)?;
+            }
+
+            MapiEvent::TlsDecrypted { id, version } => {
+                self.conn_message(
+                    *id,
+                    None,
+                    format_args!("TLS {version}, DECRYPTED using key log"),
+                    renderer,
+                )?;
+            }
+
+            MapiEvent::TlsNotDecrypted {
+                id,
+                direction,
+                reason,
+            } => {
+                renderer.message(
+                    Some(*id),
+                    *direction,
+                    format_args!("TLS NOT DECRYPTED: {reason}"),
                 )?;
-                self.add_connection(id, peer.is_unix());
+            }
+
+            MapiEvent::ServerName { id, name } => {
+                self.conn_message(*id, None, format_args!("TLS SERVER NAME {name}"), renderer)?;
             }
 
             MapiEvent::Connecting { id, remote } => {
-                renderer.message(Some(*id), None, format_args!("CONNECTING to {remote}"))?;
+                self.conn_message(*id, None, format_args!("CONNECTING to {remote}"), renderer)?;
             }
 
-            MapiEvent::Connected { id, .. } => {
-                renderer.message(Some(*id), None, "CONNECTED")?;
+            MapiEvent::Connected { id, peer } => {
+                self.conn_message(*id, None, "CONNECTED", renderer)?;
+                if let Some((upstream, downstream)) =
+                    self.accs.get_mut(id).filter(|_| self.show_peers)
+                {
+                    upstream.server = Some(peer.to_string());
+                    downstream.server = Some(peer.to_string());
+                }
             }
 
             MapiEvent::ConnectFailed {
@@ -66,38 +494,137 @@ impl State {
                 )?;
             }
 
-            MapiEvent::End { id } => {
-                renderer.message(Some(*id), None, "ENDED")?;
+            MapiEvent::ConnectRetry {
+                id,
+                attempt,
+                retries,
+                delay,
+            } => {
+                self.conn_message(
+                    *id,
+                    None,
+                    format_args!("CONNECT RETRY {attempt}/{retries} in {delay:?}"),
+                    renderer,
+                )?;
+            }
+
+            MapiEvent::ConnectCancelled { id, remote } => {
+                renderer.message(Some(*id), None, format_args!("CONNECT CANCELLED: {remote}"))?;
+            }
+
+            MapiEvent::End {
+                id,
+                traffic,
+                timing,
+            } => {
+                let client = traffic.upstream;
+                let server = traffic.downstream;
+                // Without data there is nothing to show
+                if self.held.remove(id).is_none() {
+                    renderer.message(
+                        Some(*id),
+                        None,
+                        format_args!("ENDED {timing}: client sent {client}, server sent {server}"),
+                    )?;
+                }
+                self.summary.traffic = self.summary.traffic + *traffic;
                 self.remove_connection(id);
+                self.sizes.remove_connection(*id);
+                renderer.forget_connection(*id);
             }
 
-            MapiEvent::Aborted { id, error } => {
-                renderer.message(Some(*id), None, format_args!("ABORTED: {error}"))?;
+            MapiEvent::Aborted {
+                id,
+                error,
+                traffic,
+                timing,
+            } => {
+                renderer.message(Some(*id), None, format_args!("ABORTED {timing}: {error}"))?;
+                self.summary.aborted += 1;
+                self.summary.traffic = self.summary.traffic + *traffic;
                 self.remove_connection(id);
+                self.sizes.remove_connection(*id);
+                renderer.forget_connection(*id);
+            }
+
+            MapiEvent::MirrorFailed { id, remote, error } => {
+                renderer.message(
+                    Some(*id),
+                    None,
+                    format_args!("MIRROR FAILED: {remote}: {error}"),
+                )?;
             }
 
             MapiEvent::Data {
                 id,
                 direction,
                 data,
+                timestamp,
             } => {
-                let Some((upstream, downstream)) = self.accs.get_mut(id) else {
-                    panic!("got data for conn {id} but don't have accumulators for it")
-                };
-                let acc = match direction {
-                    Direction::Upstream => upstream,
-                    Direction::Downstream => downstream,
+                self.data(*id, *direction, data, *timestamp, renderer)?;
+            }
+
+            MapiEvent::RetransmissionDiffers {
+                id,
+                direction,
+                offset,
+            } => {
+                renderer.message(
+                    Some(*id),
+                    Some(*direction),
+                    format_args!("retransmitted data differs from the original at byte {offset}"),
+                )?;
+            }
+
+            MapiEvent::Tcp {
+                id,
+                direction,
+                event,
+            } => {
+                let sender = direction.sender();
+                let text = match event {
+                    TcpEvent::Retransmission { bytes } => {
+                        format!("TCP: {sender} retransmitted {bytes} bytes")
+                    }
+                    TcpEvent::ZeroWindow => {
+                        format!("TCP: {sender} advertised a zero window")
+                    }
+                    TcpEvent::WindowOpened { stalled } => {
+                        let secs = stalled.as_secs_f64();
+                        format!("TCP: {sender} opened its window after {secs:.3}s")
+                    }
+                    TcpEvent::Reset => format!("TCP: {sender} reset the connection"),
                 };
-                acc.handle_data(data, renderer)?;
+                self.conn_message(*id, Some(*direction), text, renderer)?;
+            }
+
+            MapiEvent::DataMissing {
+                id,
+                direction,
+                bytes,
+            } => {
+                if let Some((upstream, downstream)) = self.accs.get_mut(id) {
+                    match direction {
+                        Direction::Upstream => upstream.resync(renderer)?,
+                        Direction::Downstream => downstream.resync(renderer)?,
+                    }
+                }
+                self.sizes.resync(*id, *direction);
+                renderer.message(
+                    Some(*id),
+                    Some(*direction),
+                    format_args!("{bytes} bytes missing from capture"),
+                )?;
             }
 
             MapiEvent::ShutdownRead { id, direction } => {
                 self.check_incomplete(*id, *direction, renderer)?;
                 let sender = direction.sender();
-                renderer.message(
-                    Some(*id),
+                self.conn_message(
+                    *id,
                     Some(*direction),
                     format_args!("{sender} stopped sending"),
+                    renderer,
                 )?;
             }
 
@@ -107,10 +634,11 @@ impl State {
                 discard: n,
             } => {
                 let receiver = direction.receiver();
-                renderer.message(
-                    Some(*id),
+                self.conn_message(
+                    *id,
                     Some(*direction),
                     format_args!("{receiver} has stopped receiving data, discarding {n} bytes"),
+                    renderer,
                 )?;
             }
         }
@@ -118,17 +646,131 @@ impl State {
         Ok(())
     }
 
-    fn add_connection(&mut self, id: &ConnectionId, unix_client: bool) {
+    fn data(
+        &mut self,
+        id: ConnectionId,
+        direction: Direction,
+        data: &[u8],
+        timestamp: Option<CaptureTime>,
+        renderer: &mut Renderer,
+    ) -> io::Result<()> {
+        let Some((upstream, downstream)) = self.accs.get_mut(&id) else {
+            panic!("got data for conn {id} but don't have accumulators for it")
+        };
+        let acc = match direction {
+            Direction::Upstream => upstream,
+            Direction::Downstream => {
+                downstream.excluded.set(upstream.excluded.get());
+                downstream
+            }
+        };
+        acc.handle_data(data, timestamp, renderer)?;
+        self.sizes.data(id, direction, data);
+        Ok(())
+    }
+
+    /// Show a line about a connection, or hold it back if its client has
+    /// not sent data yet, see `--quiet-connections`.
+    fn conn_message(
+        &mut self,
+        id: ConnectionId,
+        direction: Option<Direction>,
+        message: impl fmt::Display,
+        renderer: &mut Renderer,
+    ) -> io::Result<()> {
+        match self.held.get_mut(&id) {
+            Some(held) => held.push(Held::Line(direction, renderer.now(), message.to_string())),
+            None => renderer.message(Some(id), direction, message)?,
+        }
+        Ok(())
+    }
+
+    /// Show what was held back for a connection, at the time it happened.
+    fn release(&mut self, id: ConnectionId, renderer: &mut Renderer) -> io::Result<()> {
+        for held in self.held.remove(&id).unwrap_or_default() {
+            match held {
+                Held::Line(direction, time, message) => {
+                    renderer.message_at(Some(id), direction, time, message)?
+                }
+                Held::Data(data, time) => {
+                    self.data(id, Direction::Downstream, &data, time, renderer)?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the event is about a connection that is not shown. That is
+    /// decided when it comes in.
+    fn is_hidden(&mut self, event: &MapiEvent) -> bool {
+        let Some(id) = event.id() else {
+            return false;
+        };
+        if let MapiEvent::Incoming { peer, .. } = event {
+            let shown_id = match &self.conns {
+                Some(conns) => conns.iter().any(|r| r.contains(&id.number())),
+                None => true,
+            };
+            let shown_client =
+                self.clients.is_empty() || self.clients.iter().any(|c| c.contains(peer));
+            if !(shown_id && shown_client) {
+                self.hidden.insert(id);
+            }
+        }
+        let hidden = self.hidden.contains(&id);
+        if let MapiEvent::End { .. } | MapiEvent::Aborted { .. } = event {
+            self.hidden.remove(&id);
+        }
+        hidden
+    }
+
+    /// An [Accumulator] with the settings of the options.
+    fn accumulator(
+        &self,
+        id: ConnectionId,
+        direction: Direction,
+        level: Level,
+        unix_client: bool,
+    ) -> Accumulator {
+        let mut acc = Accumulator::new(id, direction, level, self.force_binary, unix_client);
+        acc.quiet = self.quiet;
+        acc.escape = self.escape;
+        acc.table = self.table && direction == Direction::Downstream;
+        acc.hide_empty = self.hide_empty;
+        acc.filter = self.filter.clone();
+        acc
+    }
+
+    /// Show a message, block or chunk of data read back from the output of
+    /// `--format=jsonl`, see `mapiproxy grep`.
+    pub fn show_frame(
+        &self,
+        id: ConnectionId,
+        direction: Direction,
+        kind: &str,
+        data: &[u8],
+        renderer: &mut Renderer,
+    ) -> io::Result<()> {
+        let level = match kind {
+            "message" | "incomplete_message" => Level::Messages,
+            "block" | "incomplete_block" => Level::Blocks,
+            _ => Level::Raw,
+        };
+        let mut acc = self.accumulator(id, direction, level, false);
+        match level {
+            Level::Raw => acc.handle_raw(renderer, data),
+            Level::Blocks | Level::Messages => acc.dump_frame(Some(data), renderer),
+        }
+    }
+
+    fn add_connection(&mut self, id: &ConnectionId, peer: &Addr) {
         let level = self.level;
-        let upstream = Accumulator::new(
-            *id,
-            Direction::Upstream,
-            level,
-            self.force_binary,
-            unix_client,
-        );
-        let downstream =
-            Accumulator::new(*id, Direction::Downstream, level, self.force_binary, false);
+        let mut upstream = self.accumulator(*id, Direction::Upstream, level, peer.is_unix());
+        let mut downstream = self.accumulator(*id, Direction::Downstream, level, false);
+        if self.show_peers {
+            upstream.client = Some(peer.to_string());
+            downstream.client = Some(peer.to_string());
+        }
         let new = (upstream, downstream);
         let prev = self.accs.insert(*id, new);
         if prev.is_some() {
@@ -169,10 +811,26 @@ pub struct Accumulator {
     direction: Direction,
     level: Level,
     force_binary: bool,
+    quiet: bool,
+    escape: bool,
+    table: bool,
+    hide_empty: bool,
+    filter: Filter,
+    /// From the client, whether the last frame was excluded by the filter.
+    /// From the server, whether the request it answers was, see
+    /// [Filter::wants_in_conn].
+    excluded: Cell<bool>,
+    /// Addresses to show in the headers, see `--show-peers`
+    client: Option<String>,
+    server: Option<String>,
     analyzer: Analyzer,
     binary: Binary,
     buf: Vec<u8>,
     error_reported: bool,
+    /// Capture time of the data currently being processed
+    timestamp: Option<CaptureTime>,
+    /// Capture time of the first data in [Accumulator::buf]
+    buf_timestamp: Option<CaptureTime>,
 }
 
 impl Accumulator {
@@ -188,14 +846,54 @@ impl Accumulator {
             direction,
             level,
             force_binary,
+            quiet: false,
+            escape: false,
+            table: false,
+            hide_empty: false,
+            filter: Filter::default(),
+            excluded: Cell::new(false),
+            client: None,
+            server: None,
             analyzer: Analyzer::new(unix_client),
             binary: Binary::new(),
             buf: Vec::with_capacity(8192),
             error_reported: false,
+            timestamp: None,
+            buf_timestamp: None,
+        }
+    }
+
+    /// Start over at a fresh block boundary, discarding any partial frame.
+    /// Show what we have of the current block or message and start looking
+    /// for the next one.
+    fn resync(&mut self, renderer: &mut Renderer) -> io::Result<()> {
+        if !self.buf.is_empty() && self.is_shown(&self.buf) {
+            let kind = if self.level == Level::Messages {
+                "incomplete message before gap"
+            } else {
+                "incomplete block before gap"
+            };
+            if self.quiet {
+                let len = format_args!("{n} bytes", n = self.buf.len());
+                self.summarize(None, &[&kind, &len], "", renderer)?;
+            } else {
+                self.header(None, &[&kind], renderer)?;
+                self.dump_frame_as_binary(&self.buf, renderer)?;
+                renderer.footer(&[])?;
+            }
         }
+        self.analyzer = Analyzer::new(false);
+        self.buf.clear();
+        Ok(())
     }
 
-    fn handle_data(&mut self, data: &[u8], renderer: &mut Renderer) -> io::Result<()> {
+    fn handle_data(
+        &mut self,
+        data: &[u8],
+        timestamp: Option<CaptureTime>,
+        renderer: &mut Renderer,
+    ) -> io::Result<()> {
+        self.timestamp = timestamp;
         match self.level {
             Level::Raw => self.handle_raw(renderer, data),
             Level::Blocks | Level::Messages => self.handle_frame(renderer, data),
@@ -203,11 +901,16 @@ impl Accumulator {
     }
 
     fn handle_raw(&mut self, renderer: &mut Renderer, mut data: &[u8]) -> Result<(), io::Error> {
-        renderer.header(
-            self.id,
-            self.direction,
-            &[&format_args!("{n} bytes", n = data.len())],
-        )?;
+        if !self.is_shown(data) {
+            // Keep the analyzer in step for the chunks that are shown
+            while self.analyzer.split_chunk(&mut data).is_some() {}
+            return Ok(());
+        }
+        let len = format_args!("{n} bytes", n = data.len());
+        if self.quiet {
+            return self.summarize(self.timestamp, &[&len], "", renderer);
+        }
+        self.header(self.timestamp, &[&len], renderer)?;
         let mut n = 0;
         let mut error_at = None;
         while let Some(head) = self.analyzer.split_chunk(&mut data) {
@@ -246,18 +949,22 @@ impl Accumulator {
             };
 
             if self.analyzer.was_error() {
-                if !self.buf.is_empty() {
+                if !self.buf.is_empty() && self.is_shown(&self.buf) {
                     let kind = if self.level == Level::Messages {
                         "incomplete message before error"
                     } else {
                         "incomplete block before error"
                     };
-                    renderer.header(self.id, self.direction, &[&kind])?;
-                    self.dump_frame_as_binary(&self.buf, renderer)?;
-                    renderer.footer(&[])?;
-                    self.buf.clear();
-                    self.level = Level::Raw;
+                    if self.quiet {
+                        let len = format_args!("{n} bytes", n = self.buf.len());
+                        self.summarize(None, &[&kind, &len], "", renderer)?;
+                    } else {
+                        self.header(None, &[&kind], renderer)?;
+                        self.dump_frame_as_binary(&self.buf, renderer)?;
+                        renderer.footer(&[])?;
+                    }
                 }
+                self.buf.clear();
                 renderer.message(Some(self.id), Some(self.direction), "mapi protocol error")?;
                 self.error_reported = true;
                 self.level = Level::Raw;
@@ -274,6 +981,9 @@ impl Accumulator {
             };
 
             if !at_end {
+                if self.buf.is_empty() {
+                    self.buf_timestamp = self.timestamp;
+                }
                 self.buf.extend_from_slice(chunk);
                 continue;
             }
@@ -292,10 +1002,18 @@ impl Accumulator {
     }
 
     fn dump_frame(&mut self, data: Option<&[u8]>, renderer: &mut Renderer) -> io::Result<()> {
+        let timestamp = if data.is_some() {
+            self.timestamp
+        } else {
+            self.buf_timestamp
+        };
         let data = data.unwrap_or(&self.buf);
         let len = data.len();
-        let is_binary =
-            self.force_binary || self.is_scary(data) || std::str::from_utf8(data).is_err();
+        if len == 0 && self.hide_empty || !self.is_shown(data) {
+            return Ok(());
+        }
+        let is_text = !is_scary(data) && std::str::from_utf8(data).is_ok();
+        let is_binary = self.force_binary || !(is_text || self.escape && is_mostly_text(data));
 
         let format = if is_binary { "binary" } else { "text" };
         let kind = if self.level == Level::Messages {
@@ -303,14 +1021,23 @@ impl Accumulator {
         } else {
             "block"
         };
-        renderer.header(
-            self.id,
-            self.direction,
-            &[&format, &kind, &format_args!("{len} bytes")],
-        )?;
+        let len = format_args!("{len} bytes");
+        if self.quiet {
+            // Only the first line of text
+            let text = if is_binary {
+                String::new()
+            } else {
+                escape_text(data)
+            };
+            let preview = text.lines().next().unwrap_or_default();
+            return self.summarize(timestamp, &[&format, &kind, &len], preview, renderer);
+        }
+        self.header(timestamp, &[&format, &kind, &len], renderer)?;
 
         if is_binary {
             self.dump_frame_as_binary(data, renderer)?;
+        } else if let Some(parts) = self.tables(data) {
+            self.dump_frame_as_tables(&parts, renderer)?;
         } else {
             self.dump_frame_as_text(data, renderer)?;
         }
@@ -319,6 +1046,66 @@ impl Accumulator {
         Ok(())
     }
 
+    /// Whether the filter wants the frame, see `--grep`.
+    fn is_shown(&self, data: &[u8]) -> bool {
+        let mut excluded = self.excluded.get();
+        let shown = self
+            .filter
+            .wants_in_conn(&mut excluded, self.direction, data);
+        self.excluded.set(excluded);
+        shown
+    }
+
+    fn header(
+        &self,
+        timestamp: Option<CaptureTime>,
+        items: &[&dyn fmt::Display],
+        renderer: &mut Renderer,
+    ) -> io::Result<()> {
+        let peers = self.peers();
+        let mut items = items.to_vec();
+        items.extend(peers.as_ref().map(|p| p as &dyn fmt::Display));
+        renderer.header(self.id, self.direction, timestamp, &items)
+    }
+
+    /// With `--show-peers`, where the data comes from and goes to.
+    fn peers(&self) -> Option<String> {
+        let client = self.client.as_deref()?;
+        let server = self.server.as_deref().unwrap_or("?");
+        let peers = match self.direction {
+            Direction::Upstream => format!("from {client} to {server}"),
+            Direction::Downstream => format!("from {server} to {client}"),
+        };
+        Some(peers)
+    }
+
+    /// With `--quiet`, show a frame as a single line with the header
+    /// `items` followed by the start of `preview`.
+    fn summarize(
+        &self,
+        timestamp: Option<CaptureTime>,
+        items: &[&dyn fmt::Display],
+        preview: &str,
+        renderer: &mut Renderer,
+    ) -> io::Result<()> {
+        let peers = self.peers();
+        let mut items = items.to_vec();
+        items.extend(peers.as_ref().map(|p| p as &dyn fmt::Display));
+        const PREVIEW_LEN: usize = 60;
+        let glyphs = renderer.glyphs();
+        let mut shown = String::new();
+        for c in preview.chars().take(PREVIEW_LEN) {
+            match c {
+                '\t' => shown.push_str(glyphs.tab),
+                c => shown.push(c),
+            }
+        }
+        if preview.chars().nth(PREVIEW_LEN).is_some() {
+            shown.push_str(glyphs.ellipsis);
+        }
+        renderer.summary(self.id, self.direction, timestamp, &items, &shown)
+    }
+
     fn check_incomplete(&mut self) -> io::Result<()> {
         if let Err(situation) = self.analyzer.check_incomplete() {
             let side = self.direction.sender();
@@ -338,31 +1125,143 @@ impl Accumulator {
         Ok(())
     }
 
-    fn dump_frame_as_text(&self, data: &[u8], renderer: &mut Renderer) -> io::Result<()> {
-        for byte in data {
-            match *byte {
-                b'\n' => {
-                    renderer.put("↵")?;
-                    renderer.nl()?;
+    /// With `--table`, the result sets in the frame and the text around
+    /// them, if it has any.
+    fn tables<'a>(&self, data: &'a [u8]) -> Option<Vec<table::Part<'a>>> {
+        if !self.table {
+            return None;
+        }
+        table::split_tables(std::str::from_utf8(data).ok()?)
+    }
+
+    fn dump_frame_as_tables(
+        &self,
+        parts: &[table::Part],
+        renderer: &mut Renderer,
+    ) -> io::Result<()> {
+        for part in parts {
+            match part {
+                table::Part::Text(text) => self.dump_frame_as_text(text.as_bytes(), renderer)?,
+                table::Part::Table(table) => {
+                    for line in table.lines(renderer.glyphs()) {
+                        renderer.put(line)?;
+                        renderer.nl()?;
+                    }
                 }
-                b'\t' => {
-                    renderer.put("→")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn dump_frame_as_text(&self, data: &[u8], renderer: &mut Renderer) -> io::Result<()> {
+        // Without --escape, text never contains bytes that must be escaped
+        for chunk in data.utf8_chunks() {
+            for byte in chunk.valid().bytes() {
+                match byte {
+                    b'\n' => {
+                        renderer.put(renderer.glyphs().newline)?;
+                        renderer.nl()?;
+                    }
+                    b'\t' => {
+                        renderer.put(renderer.glyphs().tab)?;
+                    }
+                    b if is_scary(&[b]) => put_escaped(b, renderer)?,
+                    b => renderer.put([b])?,
                 }
-                b => renderer.put([b])?,
+            }
+            for &b in chunk.invalid() {
+                put_escaped(b, renderer)?;
             }
         }
         renderer.clear_line()?;
         Ok(())
     }
+}
+
+/// MAPI blocks are at most this large
+pub const MAX_BLOCK_SIZE: usize = 8190;
+
+/// Decode the block header `lo`, `hi` into the length of the block and
+/// whether it is the last one of its message. Returns None if it cannot be
+/// the header of a block: one that is too large, or an empty one that is
+/// not the last.
+pub fn block_header(lo: u8, hi: u8) -> Option<(usize, bool)> {
+    let header = u16::from_le_bytes([lo, hi]) as usize;
+    let (len, last) = (header / 2, header & 1 == 1);
+    if len > MAX_BLOCK_SIZE || (len == 0 && !last) {
+        return None;
+    }
+    Some((len, last))
+}
+
+/// Split the payload of a message into blocks, marking the last one.
+pub fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut message = vec![];
+    let mut chunks = payload.chunks(MAX_BLOCK_SIZE).peekable();
+    if chunks.peek().is_none() {
+        message.extend_from_slice(&[1, 0]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        let header = (chunk.len() * 2 + last as usize) as u16;
+        message.extend_from_slice(&header.to_le_bytes());
+        message.extend_from_slice(chunk);
+    }
+    message
+}
+
+/// With `--escape`, frames in which at most one in this many bytes must be
+/// escaped are shown as text
+const ESCAPE_RATIO: usize = 10;
+
+/// Whether few enough bytes of `data` are control characters or invalid
+/// UTF-8 to show it as text with escapes
+fn is_mostly_text(data: &[u8]) -> bool {
+    let mut escaped = 0;
+    for chunk in data.utf8_chunks() {
+        escaped += chunk.valid().bytes().filter(|&b| is_scary(&[b])).count();
+        escaped += chunk.invalid().len();
+    }
+    escaped * ESCAPE_RATIO <= data.len()
+}
 
-    fn is_scary(&self, data: &[u8]) -> bool {
-        for &b in data {
-            if b < b' ' && b != b'\n' && b != b'\t' {
-                return true;
+/// The text of `data` with control characters and invalid UTF-8 written as
+/// `\xNN`, for previews
+fn escape_text(data: &[u8]) -> String {
+    let mut text = String::with_capacity(data.len());
+    for chunk in data.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c.is_ascii() && is_scary(&[c as u8]) {
+                text.push_str(&format!("\\x{:02x}", c as u8));
+            } else {
+                text.push(c);
             }
         }
-        false
+        for b in chunk.invalid() {
+            text.push_str(&format!("\\x{b:02x}"));
+        }
     }
+    text
+}
+
+fn put_escaped(byte: u8, renderer: &mut Renderer) -> io::Result<()> {
+    // Start the line first, that resets the style
+    renderer.put(b"")?;
+    let old_style = renderer.style(Style::Escape)?;
+    renderer.put(format!("\\x{byte:02x}"))?;
+    renderer.style(old_style)?;
+    Ok(())
+}
+
+/// Check for control characters other than newline and tab, which we don't
+/// show as text
+fn is_scary(data: &[u8]) -> bool {
+    for &b in data {
+        if b < b' ' && b != b'\n' && b != b'\t' {
+            return true;
+        }
+    }
+    false
 }
 
 #[derive(Debug)]
@@ -431,7 +1330,7 @@ impl Binary {
 
         for (byte, style) in &self.row[..self.col] {
             renderer.style(*style)?;
-            renderer.put(Self::readable(&[*byte]))?;
+            renderer.put(Self::readable(&[*byte], renderer.glyphs()))?;
         }
 
         renderer.nl()?;
@@ -456,7 +1355,7 @@ impl Binary {
         ];
         let spaces = "          ";
         let extra = extra_space[i] as usize;
-        let (open, close) = ("⟨", "⟩");
+        let &Glyphs { open, close, .. } = renderer.glyphs();
         let is_head = style == Style::Header;
         // let (open, close) = ("«", "»");
         match (*in_head, is_head) {
@@ -478,16 +1377,73 @@ impl Binary {
         Ok(())
     }
 
-    fn readable(byte: &[u8; 1]) -> &[u8] {
+    fn readable<'a>(byte: &'a [u8; 1], glyphs: &Glyphs) -> &'a [u8] {
         // note that the readable range does not include 0x7f (DEL)
         let s = match byte[0] {
-            b' ' => "·",
+            b' ' => glyphs.space,
             0x21..=0x7e => return byte.as_ref(),
-            b'\n' => "↵",
-            b'\t' => "→",
-            0 => "░",
-            _ => "▒",
+            b'\n' => glyphs.newline,
+            b'\t' => glyphs.tab,
+            0 => glyphs.nul,
+            _ => glyphs.other,
         };
         s.as_bytes()
     }
 }
+
+#[test]
+fn test_escape_text() {
+    assert_eq!(escape_text(b"a\x01b\tc\xffd\n"), "a\\x01b\tc\\xffd\n");
+    assert!(is_mostly_text(b"0123456789\x00"));
+    assert!(!is_mostly_text(b"01234567\x00"));
+}
+
+#[test]
+fn test_quiet_connections() {
+    use crate::output::Output;
+
+    let run = |client_speaks: bool| {
+        let mut state = State::new(Level::Messages, false);
+        state.set_quiet_connections(true);
+        let mut renderer = Renderer::new(false, Output::Memory(vec![]));
+        let id = ConnectionId::new(10);
+        let local = Addr::Tcp("127.0.0.1:50000".parse().unwrap());
+        let peer = Addr::Tcp("127.0.0.1:41394".parse().unwrap());
+        let data = |direction, payload: &[u8]| MapiEvent::Data {
+            id,
+            direction,
+            data: frame(payload).into(),
+            timestamp: None,
+        };
+        let mut events = vec![
+            MapiEvent::Incoming { id, local, peer },
+            data(
+                Direction::Downstream,
+                b"salt:mserver:9:SHA512:LIT:SHA512:\n",
+            ),
+        ];
+        if client_speaks {
+            events.push(data(
+                Direction::Upstream,
+                b"BIG:monetdb:{SHA512}00:sql:demo:\n",
+            ));
+        }
+        events.push(MapiEvent::End {
+            id,
+            traffic: Traffic::default(),
+            timing: Default::default(),
+        });
+        for ev in &events {
+            state.handle(ev, &mut renderer).unwrap();
+        }
+        String::from_utf8(renderer.take_output().unwrap()).unwrap()
+    };
+
+    // A health check only sees the challenge
+    assert_eq!(run(false), "");
+    let output = run(true);
+    let incoming = output.find("INCOMING").unwrap();
+    let challenge = output.find("salt:mserver").unwrap();
+    let login = output.find("BIG:monetdb").unwrap();
+    assert!(incoming < challenge && challenge < login);
+}
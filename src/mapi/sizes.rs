@@ -0,0 +1,242 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+};
+
+use crate::{
+    proxy::event::{ConnectionId, Direction},
+    render::Renderer,
+};
+
+use super::analyzer::Analyzer;
+
+/// Widest bar of the histogram, in characters
+const BAR_WIDTH: u64 = 40;
+
+/// Collects the sizes of the messages and the number of blocks they were
+/// sent in, per direction, to report them in the summary at the end.
+#[derive(Debug, Default)]
+pub struct Sizes {
+    streams: HashMap<(ConnectionId, Direction), Stream>,
+    client: Distribution,
+    server: Distribution,
+}
+
+/// Tracks the block structure of one direction of a connection.
+#[derive(Debug)]
+struct Stream {
+    analyzer: Analyzer,
+    /// Body bytes of the current block so far
+    block: u64,
+    /// Body bytes of the current message so far
+    message: u64,
+}
+
+#[derive(Debug, Default)]
+struct Distribution {
+    /// Number of messages of each size
+    messages: BTreeMap<u64, u64>,
+    blocks: u64,
+}
+
+impl Sizes {
+    pub fn add_connection(&mut self, id: ConnectionId, unix_client: bool) {
+        for (direction, unix) in [
+            (Direction::Upstream, unix_client),
+            (Direction::Downstream, false),
+        ] {
+            let stream = Stream {
+                analyzer: Analyzer::new(unix),
+                block: 0,
+                message: 0,
+            };
+            self.streams.insert((id, direction), stream);
+        }
+    }
+
+    pub fn remove_connection(&mut self, id: ConnectionId) {
+        self.streams.remove(&(id, Direction::Upstream));
+        self.streams.remove(&(id, Direction::Downstream));
+    }
+
+    pub fn data(&mut self, id: ConnectionId, direction: Direction, mut data: &[u8]) {
+        let Some(stream) = self.streams.get_mut(&(id, direction)) else {
+            return;
+        };
+        let dist = match direction {
+            Direction::Upstream => &mut self.client,
+            Direction::Downstream => &mut self.server,
+        };
+        // After a protocol error the analyzer swallows everything
+        while let Some(chunk) = stream.analyzer.split_chunk(&mut data) {
+            if !stream.analyzer.was_body() {
+                continue;
+            }
+            stream.block += chunk.len() as u64;
+            if !stream.analyzer.was_block_boundary() {
+                continue;
+            }
+            dist.blocks += 1;
+            stream.message += stream.block;
+            stream.block = 0;
+            if stream.analyzer.was_message_boundary() {
+                *dist.messages.entry(stream.message).or_default() += 1;
+                stream.message = 0;
+            }
+        }
+    }
+
+    /// Add the messages counted by `other`, for example by another worker
+    /// of `--jobs`.
+    pub fn merge(&mut self, other: Sizes) {
+        for (dist, other) in [
+            (&mut self.client, other.client),
+            (&mut self.server, other.server),
+        ] {
+            for (size, count) in other.messages {
+                *dist.messages.entry(size).or_default() += count;
+            }
+            dist.blocks += other.blocks;
+        }
+    }
+
+    /// Part of the data is missing, skip the message in progress.
+    pub fn resync(&mut self, id: ConnectionId, direction: Direction) {
+        if let Some(stream) = self.streams.get_mut(&(id, direction)) {
+            stream.analyzer = Analyzer::new(false);
+            stream.block = 0;
+            stream.message = 0;
+        }
+    }
+
+    /// Print the percentiles and a histogram of the message sizes of each
+    /// side that sent any messages.
+    pub fn report(&self, renderer: &mut Renderer) -> io::Result<()> {
+        for (side, dist) in [
+            (Direction::CLIENT, &self.client),
+            (Direction::SERVER, &self.server),
+        ] {
+            let n: u64 = dist.messages.values().sum();
+            if n == 0 {
+                continue;
+            }
+            let s = if n == 1 { "" } else { "s" };
+            let blocks = dist.blocks;
+            let bs = if blocks == 1 { "" } else { "s" };
+            let mut line =
+                format!("MESSAGE SIZES: {side} sent {n} message{s} in {blocks} block{bs}");
+            for (label, p) in [("min", 0.0), ("median", 50.0), ("p90", 90.0), ("p99", 99.0)] {
+                line += &format!(", {label} {}", dist.percentile(p));
+            }
+            let max = dist.messages.keys().next_back().unwrap();
+            line += &format!(", max {max} bytes");
+            renderer.message(None, None, line)?;
+
+            let buckets = dist.buckets();
+            let highest = buckets.iter().map(|(_, count)| *count).max().unwrap_or(1);
+            let bar = renderer.glyphs().bar;
+            for (lo, count) in buckets {
+                let width = if count == 0 {
+                    0
+                } else {
+                    (count * BAR_WIDTH / highest).max(1)
+                };
+                let range = format!("{}-{}", human(lo), human(upper_bound(lo)));
+                let bar = bar.repeat(width as usize);
+                let line = format!("MESSAGE SIZES: {side} {range:>11} {count:>8} {bar}");
+                renderer.message(None, None, line.trim_end())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Distribution {
+    /// Nearest-rank percentile of the message sizes, which must not be empty
+    fn percentile(&self, p: f64) -> u64 {
+        let n: u64 = self.messages.values().sum();
+        let rank = ((p / 100.0 * n as f64).ceil() as u64).clamp(1, n);
+        let mut seen = 0;
+        for (&size, &count) in &self.messages {
+            seen += count;
+            if seen >= rank {
+                return size;
+            }
+        }
+        unreachable!("rank is at most the number of messages")
+    }
+
+    /// Message counts per power of four, from the lowest to the highest
+    /// bucket that has any messages. Each bucket is identified by its lower
+    /// bound, see [upper_bound].
+    fn buckets(&self) -> Vec<(u64, u64)> {
+        let mut buckets: BTreeMap<u64, u64> = BTreeMap::new();
+        for (&size, &count) in &self.messages {
+            *buckets.entry(lower_bound(size)).or_default() += count;
+        }
+        let (Some(&first), Some(&last)) = (buckets.keys().next(), buckets.keys().next_back())
+        else {
+            return vec![];
+        };
+        let mut result = vec![];
+        let mut lo = first;
+        while lo <= last {
+            result.push((lo, buckets.get(&lo).copied().unwrap_or(0)));
+            lo = upper_bound(lo);
+        }
+        result
+    }
+}
+
+/// Lower bound of the bucket `size` falls in: 0, 4, 16, 64, ...
+fn lower_bound(size: u64) -> u64 {
+    let mut lo = 0;
+    while upper_bound(lo) <= size {
+        lo = upper_bound(lo);
+    }
+    lo
+}
+
+/// Upper bound of the bucket that starts at `lo`, exclusive
+fn upper_bound(lo: u64) -> u64 {
+    if lo == 0 {
+        4
+    } else {
+        lo * 4
+    }
+}
+
+/// Format a power of two in as few digits as possible
+fn human(n: u64) -> String {
+    match n {
+        n if n >= 1 << 30 && n % (1 << 30) == 0 => format!("{}G", n >> 30),
+        n if n >= 1 << 20 && n % (1 << 20) == 0 => format!("{}M", n >> 20),
+        n if n >= 1 << 10 && n % (1 << 10) == 0 => format!("{}K", n >> 10),
+        n => n.to_string(),
+    }
+}
+
+#[test]
+fn test_distribution() {
+    let mut dist = Distribution::default();
+    for size in [1, 2, 2, 30, 70, 5000] {
+        *dist.messages.entry(size).or_default() += 1;
+    }
+    assert_eq!(dist.percentile(0.0), 1);
+    assert_eq!(dist.percentile(50.0), 2);
+    assert_eq!(dist.percentile(90.0), 5000);
+    assert_eq!(
+        dist.buckets(),
+        vec![
+            (0, 3),
+            (4, 0),
+            (16, 1),
+            (64, 1),
+            (256, 0),
+            (1024, 0),
+            (4096, 1)
+        ]
+    );
+    assert_eq!(human(4096), "4K");
+    assert_eq!(human(1 << 20), "1M");
+}
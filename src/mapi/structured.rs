@@ -0,0 +1,1010 @@
+//! Write the events as structured records instead of rendering them for
+//! humans, or write only the message bodies, see `--format`.
+
+use std::{collections::HashMap, io, mem, sync::Arc, time::Duration};
+
+use base64::Engine;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    proxy::event::{
+        CaptureTime, ConnectionId, Direction, MapiEvent, TcpEvent, TimeZone, Timing, Traffic,
+        Volume,
+    },
+    render::Renderer,
+    Format, Level,
+};
+
+use super::{analyzer::Analyzer, is_scary, Filter, Summary};
+
+/// Version of the records, written in the first record and in [SCHEMA].
+/// Bump it when a field or event is renamed, removed or changes type;
+/// adding them is fine.
+pub const SCHEMA_VERSION: u64 = 1;
+
+/// JSON Schema describing the records, see `--print-schema`
+pub const SCHEMA: &str = include_str!("../schema.json");
+
+/// Turns each event, and each message, block or chunk of data depending on
+/// the [Level], into a JSON object written on a line of its own, or into a
+/// CBOR map with [Format::Cbor] or into lines with [Format::PlainStable],
+/// see [Record::to_plain]. With [Format::Raw], only the data is
+/// written, see [Structured::write_raw].
+#[derive(Debug)]
+pub struct Structured {
+    format: Format,
+    level: Level,
+    force_binary: bool,
+    /// Add the current time to events that don't have a capture time
+    live: bool,
+    framers: HashMap<ConnectionId, (Framer, Framer)>,
+    summary: Summary,
+    /// Which frames are written, see [Structured::set_filter]
+    filter: Filter,
+    /// Per connection, whether the last request was excluded by the filter,
+    /// see [Filter::wants_in_conn]
+    excluded: HashMap<ConnectionId, bool>,
+    /// How the times are written, see [Structured::set_time_format]
+    time_format: Option<(Arc<str>, TimeZone)>,
+}
+
+impl Structured {
+    pub fn new(format: Format, level: Level, force_binary: bool, live: bool) -> Self {
+        Structured {
+            format,
+            level,
+            force_binary,
+            live,
+            framers: HashMap::new(),
+            summary: Summary::default(),
+            filter: Filter::default(),
+            excluded: HashMap::new(),
+            time_format: None,
+        }
+    }
+
+    /// A Structured with the same settings but without any connections
+    pub fn fresh(&self) -> Self {
+        let mut structured = Structured::new(self.format, self.level, self.force_binary, self.live);
+        structured.filter = self.filter.clone();
+        structured.time_format = self.time_format.clone();
+        structured
+    }
+
+    /// Only write the messages, blocks or chunks of data the filter wants.
+    /// The events are always written.
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+    }
+
+    /// Use `level` for the connections that start from now on.
+    pub fn set_level(&mut self, level: Level) {
+        self.level = level;
+    }
+
+    /// Write the times in `format` in `zone` rather than as in RFC 3339,
+    /// see [ZonedTime::format][crate::proxy::event::ZonedTime::format].
+    pub fn set_time_format(&mut self, format: Arc<str>, zone: TimeZone) {
+        self.time_format = Some((format, zone));
+    }
+
+    /// Called before the first event. Writes a record with the
+    /// [SCHEMA_VERSION] so readers know what to expect.
+    pub fn start(&self, renderer: &mut Renderer) -> io::Result<()> {
+        let fields = json!({ "version": SCHEMA_VERSION });
+        let record = self.record("schema", None, None, None, fields);
+        self.write(renderer, &record)
+    }
+
+    /// Called when no more events will arrive. Reports the connections that
+    /// are still open and the totals.
+    pub fn finish(&mut self, renderer: &mut Renderer) -> io::Result<()> {
+        for record in self.finish_records() {
+            self.write(renderer, &record)?;
+        }
+        renderer.flush()
+    }
+
+    /// The records for the connections that are still open and the totals.
+    pub fn finish_records(&self) -> Vec<Record> {
+        let mut open: Vec<ConnectionId> = self.framers.keys().copied().collect();
+        open.sort();
+        let mut records: Vec<Record> = open
+            .iter()
+            .map(|id| self.record("still_open", Some(*id), None, None, json!({})))
+            .collect();
+        let Summary {
+            connections,
+            aborted,
+            traffic,
+            skipped_packets,
+        } = &self.summary;
+        let mut fields = json!({
+            "connections": connections,
+            "aborted": aborted,
+            "still_open": open.len(),
+        });
+        if *skipped_packets > 0 {
+            fields["skipped_packets"] = json!(skipped_packets);
+        }
+        let fields = merge(fields, traffic_fields(traffic));
+        records.push(self.record("summary", None, None, None, fields));
+        records
+    }
+
+    /// Take over the open connections and the totals of `other`, see
+    /// [State::merge][super::State::merge].
+    pub fn merge(&mut self, other: Structured) {
+        self.framers.extend(other.framers);
+        self.excluded.extend(other.excluded);
+        self.summary.merge(&other.summary);
+    }
+
+    /// Count a connection in the summary without writing its records.
+    pub fn count_only(&mut self, event: &MapiEvent) {
+        self.summary.count(event);
+    }
+
+    pub fn handle(&mut self, event: &MapiEvent, renderer: &mut Renderer) -> io::Result<()> {
+        for record in self.records(event) {
+            self.write(renderer, &record)?;
+        }
+        Ok(())
+    }
+
+    /// The records for an event: usually one, none or several for data.
+    pub fn records(&mut self, event: &MapiEvent) -> Vec<Record> {
+        let mut records = vec![];
+        self.collect(event, &mut records);
+        records
+    }
+
+    fn collect(&mut self, event: &MapiEvent, records: &mut Vec<Record>) {
+        let (kind, id, direction, fields) = match event {
+            MapiEvent::BoundPort(addr) => {
+                ("listen", None, None, json!({ "addr": addr.to_string() }))
+            }
+            MapiEvent::Draining { connections } => (
+                "draining",
+                None,
+                None,
+                json!({ "connections": connections }),
+            ),
+            MapiEvent::Status {
+                connections,
+                bytes,
+                messages,
+                messages_per_sec,
+            } => (
+                "status",
+                None,
+                None,
+                json!({
+                    "connections": connections,
+                    "bytes": bytes,
+                    "messages": messages,
+                    "messages_per_sec": messages_per_sec,
+                }),
+            ),
+            MapiEvent::Ignored { connections, bytes } => (
+                "ignored",
+                None,
+                None,
+                json!({ "connections": connections, "bytes": bytes }),
+            ),
+            MapiEvent::SkippedPacket {
+                id,
+                timestamp,
+                error,
+            } => {
+                self.summary.skipped_packets += 1;
+                let fields = json!({ "error": error });
+                let record = self.record("skipped_packet", *id, None, Some(*timestamp), fields);
+                records.push(record);
+                return;
+            }
+            MapiEvent::Incoming { id, local, peer } => {
+                let unix_client = peer.is_unix();
+                let framers = (
+                    Framer::new(self.level, unix_client),
+                    Framer::new(self.level, false),
+                );
+                self.framers.insert(*id, framers);
+                self.summary.connections += 1;
+                let fields = json!({ "local": local.to_string(), "peer": peer.to_string() });
+                ("incoming", Some(*id), None, fields)
+            }
+            MapiEvent::ServerName { id, name } => {
+                ("server_name", Some(*id), None, json!({ "name": name }))
+            }
+            MapiEvent::JoinedMidStream { id } => ("joined_mid_stream", Some(*id), None, json!({})),
+            MapiEvent::TlsDecrypted { id, version } => (
+                "tls_decrypted",
+                Some(*id),
+                None,
+                json!({ "version": version }),
+            ),
+            MapiEvent::TlsNotDecrypted {
+                id,
+                direction,
+                reason,
+            } => (
+                "tls_not_decrypted",
+                Some(*id),
+                *direction,
+                json!({ "reason": reason }),
+            ),
+            MapiEvent::Connecting { id, remote } => (
+                "connecting",
+                Some(*id),
+                None,
+                json!({ "remote": remote.to_string() }),
+            ),
+            MapiEvent::ConnectRetry {
+                id,
+                attempt,
+                retries,
+                delay,
+            } => (
+                "connect_retry",
+                Some(*id),
+                None,
+                json!({
+                    "attempt": attempt,
+                    "retries": retries,
+                    "delay": delay.as_secs_f64(),
+                }),
+            ),
+            MapiEvent::ConnectCancelled { id, remote } => (
+                "connect_cancelled",
+                Some(*id),
+                None,
+                json!({ "remote": remote }),
+            ),
+            MapiEvent::ConnectFailed {
+                id,
+                remote,
+                error,
+                immediately,
+            } => (
+                "connect_failed",
+                Some(*id),
+                None,
+                json!({
+                    "remote": remote,
+                    "error": error.to_string(),
+                    "immediately": immediately,
+                }),
+            ),
+            MapiEvent::Connected { id, peer } => (
+                "connected",
+                Some(*id),
+                None,
+                json!({ "remote": peer.to_string() }),
+            ),
+            MapiEvent::End {
+                id,
+                traffic,
+                timing,
+            } => {
+                self.summary.traffic = self.summary.traffic + *traffic;
+                self.framers.remove(id);
+                self.excluded.remove(id);
+                let fields = merge(traffic_fields(traffic), timing_fields(timing));
+                ("end", Some(*id), None, fields)
+            }
+            MapiEvent::Aborted {
+                id,
+                error,
+                traffic,
+                timing,
+            } => {
+                self.summary.aborted += 1;
+                self.summary.traffic = self.summary.traffic + *traffic;
+                self.framers.remove(id);
+                self.excluded.remove(id);
+                let fields = merge(traffic_fields(traffic), timing_fields(timing));
+                let fields = merge(json!({ "error": error.to_string() }), fields);
+                ("aborted", Some(*id), None, fields)
+            }
+            MapiEvent::MirrorFailed { id, remote, error } => (
+                "mirror_failed",
+                Some(*id),
+                None,
+                json!({ "remote": remote, "error": error.to_string() }),
+            ),
+            MapiEvent::Data {
+                id,
+                direction,
+                data,
+                timestamp,
+            } => {
+                let Some(framer) = self.framer(*id, *direction) else {
+                    return;
+                };
+                let frames = framer.handle_data(data, *timestamp);
+                self.frame_records(*id, *direction, frames, records);
+                return;
+            }
+            MapiEvent::RetransmissionDiffers {
+                id,
+                direction,
+                offset,
+            } => (
+                "retransmission_differs",
+                Some(*id),
+                Some(*direction),
+                json!({ "offset": offset }),
+            ),
+            MapiEvent::Tcp {
+                id,
+                direction,
+                event,
+            } => {
+                let fields = match event {
+                    TcpEvent::Retransmission { bytes } => {
+                        json!({ "tcp": "retransmission", "bytes": bytes })
+                    }
+                    TcpEvent::ZeroWindow => json!({ "tcp": "zero_window" }),
+                    TcpEvent::WindowOpened { stalled } => {
+                        json!({ "tcp": "window_opened", "stalled": stalled.as_secs_f64() })
+                    }
+                    TcpEvent::Reset => json!({ "tcp": "reset" }),
+                };
+                ("tcp", Some(*id), Some(*direction), fields)
+            }
+            MapiEvent::DataMissing {
+                id,
+                direction,
+                bytes,
+            } => {
+                if let Some(framer) = self.framer(*id, *direction) {
+                    let frames = framer.resync().into_iter().collect();
+                    self.frame_records(*id, *direction, frames, records);
+                }
+                (
+                    "data_missing",
+                    Some(*id),
+                    Some(*direction),
+                    json!({ "bytes": bytes }),
+                )
+            }
+            MapiEvent::ShutdownRead { id, direction } => {
+                let mut fields = json!({});
+                if let Some(framer) = self.framer(*id, *direction) {
+                    if let Err(situation) = framer.analyzer.check_incomplete() {
+                        fields = json!({ "incomplete": situation });
+                    }
+                }
+                ("shutdown_read", Some(*id), Some(*direction), fields)
+            }
+            MapiEvent::ShutdownWrite {
+                id,
+                direction,
+                discard,
+            } => (
+                "shutdown_write",
+                Some(*id),
+                Some(*direction),
+                json!({ "discard": discard }),
+            ),
+        };
+        records.push(self.record(kind, id, direction, None, fields));
+    }
+
+    fn framer(&mut self, id: ConnectionId, direction: Direction) -> Option<&mut Framer> {
+        let (upstream, downstream) = self.framers.get_mut(&id)?;
+        match direction {
+            Direction::Upstream => Some(upstream),
+            Direction::Downstream => Some(downstream),
+        }
+    }
+
+    fn frame_records(
+        &mut self,
+        id: ConnectionId,
+        direction: Direction,
+        frames: Vec<Frame>,
+        records: &mut Vec<Record>,
+    ) {
+        for Frame {
+            kind,
+            data,
+            timestamp,
+        } in frames
+        {
+            if let Some(data) = &data {
+                let excluded = self.excluded.entry(id).or_default();
+                if !self.filter.wants_in_conn(excluded, direction, data) {
+                    continue;
+                }
+            }
+            let fields = match &data {
+                Some(data) => self.body_fields(data),
+                None => json!({}),
+            };
+            let mut record = self.record(kind, Some(id), Some(direction), timestamp, fields);
+            record.data = data;
+            records.push(record);
+        }
+    }
+
+    /// The size and contents of a frame, as text if it would be shown as
+    /// text by the renderer. CBOR has byte strings so there binary contents
+    /// are left to [Record::to_cbor].
+    fn body_fields(&self, data: &[u8]) -> Value {
+        match std::str::from_utf8(data) {
+            Ok(text) if !self.force_binary && !is_scary(data) => {
+                json!({ "size": data.len(), "text": text })
+            }
+            _ if self.format == Format::Cbor => json!({ "size": data.len() }),
+            _ => {
+                let encoded = base64::engine::general_purpose::STANDARD.encode(data);
+                json!({ "size": data.len(), "base64": encoded })
+            }
+        }
+    }
+
+    fn record(
+        &self,
+        kind: &'static str,
+        id: Option<ConnectionId>,
+        direction: Option<Direction>,
+        timestamp: Option<CaptureTime>,
+        fields: Value,
+    ) -> Record {
+        let timestamp = timestamp.or_else(|| self.live.then(CaptureTime::now));
+        let time = match (timestamp, &self.time_format) {
+            (Some(timestamp), Some((format, zone))) => {
+                Some(timestamp.in_zone(*zone).format(format))
+            }
+            _ => None,
+        };
+        Record {
+            kind,
+            id,
+            direction,
+            timestamp,
+            time,
+            fields,
+            data: None,
+        }
+    }
+
+    fn write(&self, renderer: &mut Renderer, record: &Record) -> io::Result<()> {
+        match self.format {
+            Format::Raw => match (&record.data, record.kind) {
+                (Some(data), "message" | "block" | "data") => {
+                    write_raw(renderer, record.id, record.direction, data)
+                }
+                _ => Ok(()),
+            },
+            Format::Cbor => renderer.write_raw(&record.to_cbor()),
+            Format::PlainStable => renderer.write_raw(record.to_plain().as_bytes()),
+            _ => {
+                let mut line = record.to_json().to_string();
+                line.push('\n');
+                renderer.write_raw(line.as_bytes())
+            }
+        }
+    }
+}
+
+/// An event, or a message, block or chunk of data, see [Structured::records]
+#[derive(Debug)]
+pub struct Record {
+    pub kind: &'static str,
+    pub id: Option<ConnectionId>,
+    pub direction: Option<Direction>,
+    pub timestamp: Option<CaptureTime>,
+    /// The timestamp as written if not as in RFC 3339, see
+    /// `--timestamp-format`
+    pub time: Option<String>,
+    /// The fields specific to this kind of record
+    pub fields: Value,
+    /// For frames, the data
+    pub data: Option<Vec<u8>>,
+}
+
+impl Record {
+    /// The record as a JSON object, with the fields `event`, `time`, `conn`
+    /// and `direction` first
+    pub fn to_json(&self) -> Value {
+        let mut record = Map::new();
+        record.insert("event".into(), self.kind.into());
+        if let Some(time) = self.time_text() {
+            record.insert("time".into(), time.into());
+        }
+        if let Some(id) = self.id {
+            record.insert("conn".into(), id.number().into());
+        }
+        if let Some(direction) = self.direction {
+            record.insert("direction".into(), direction_name(direction).into());
+        }
+        if let Value::Object(fields) = &self.fields {
+            record.extend(fields.clone());
+        }
+        Value::Object(record)
+    }
+
+    /// The record as a CBOR map with the same fields as
+    /// [to_json][Record::to_json], except that binary data is a byte string
+    /// `bytes` instead of a `base64` string.
+    pub fn to_cbor(&self) -> Vec<u8> {
+        let mut entries: Vec<(ciborium::Value, ciborium::Value)> = vec![];
+        entries.push(("event".into(), self.kind.into()));
+        if let Some(time) = self.time_text() {
+            entries.push(("time".into(), time.into()));
+        }
+        if let Some(id) = self.id {
+            entries.push(("conn".into(), (id.number() as u64).into()));
+        }
+        if let Some(direction) = self.direction {
+            entries.push(("direction".into(), direction_name(direction).into()));
+        }
+        let mut is_text = false;
+        if let Value::Object(fields) = &self.fields {
+            for (key, value) in fields {
+                is_text |= key == "text";
+                entries.push((key.as_str().into(), cbor_value(value)));
+            }
+        }
+        if let (Some(data), false) = (&self.data, is_text) {
+            entries.push(("bytes".into(), ciborium::Value::Bytes(data.clone())));
+        }
+        let mut encoded = vec![];
+        ciborium::into_writer(&ciborium::Value::Map(entries), &mut encoded)
+            .expect("writing to a Vec does not fail");
+        encoded
+    }
+
+    /// The capture time as written, see `--timestamp-format`
+    fn time_text(&self) -> Option<String> {
+        let timestamp = self.timestamp?;
+        Some(self.time.clone().unwrap_or_else(|| iso_time(timestamp)))
+    }
+
+    /// The record as lines for [Format::PlainStable]. Events are a line
+    /// `EVENT kind KEY=VALUE...` with the same fields as
+    /// [to_json][Record::to_json], the values in JSON, except the times, so
+    /// the same traffic gives the same lines. Frames start with
+    /// `BEGIN kind KEY=VALUE...`, followed by a `DATA` line for each line of
+    /// the data, escaped with [escape_plain], and end with `END kind` and
+    /// the connection and direction.
+    pub fn to_plain(&self) -> String {
+        let json = self.to_json();
+        let mut items = String::new();
+        let mut ids = String::new();
+        if let Value::Object(fields) = &json {
+            for (key, value) in fields {
+                match key.as_str() {
+                    "event" | "text" | "base64" => continue,
+                    "time" | "duration" | "first_data" | "last_data" => continue,
+                    "conn" | "direction" => plain_items(&mut ids, key, value),
+                    _ => {}
+                }
+                plain_items(&mut items, key, value);
+            }
+        }
+        let kind = self.kind;
+        let Some(data) = &self.data else {
+            return format!("EVENT {kind}{items}\n");
+        };
+        let mut text = format!("BEGIN {kind}{items}\n");
+        for line in data.split_inclusive(|&b| b == b'\n') {
+            text += "DATA ";
+            text += &escape_plain(line);
+            text.push('\n');
+        }
+        text += &format!("END {kind}{ids}\n");
+        text
+    }
+}
+
+/// The CBOR equivalent of a JSON field value, for [Record::to_cbor]
+fn cbor_value(value: &Value) -> ciborium::Value {
+    match value {
+        Value::Null => ciborium::Value::Null,
+        Value::Bool(b) => ciborium::Value::Bool(*b),
+        Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(n), _) => n.into(),
+            (_, Some(n)) => n.into(),
+            _ => n.as_f64().unwrap_or(f64::NAN).into(),
+        },
+        Value::String(s) => s.as_str().into(),
+        Value::Array(items) => ciborium::Value::Array(items.iter().map(cbor_value).collect()),
+        Value::Object(fields) => ciborium::Value::Map(
+            fields
+                .iter()
+                .map(|(key, value)| (key.as_str().into(), cbor_value(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Add the field `key` to the KEY=VALUE items of [Record::to_plain], with
+/// the value as JSON. The fields of objects become KEY.FIELD items.
+fn plain_items(items: &mut String, key: &str, value: &Value) {
+    match value {
+        Value::Object(fields) => {
+            for (field, value) in fields {
+                plain_items(items, &format!("{key}.{field}"), value);
+            }
+        }
+        value => items.push_str(&format!(" {key}={value}")),
+    }
+}
+
+/// Write `data` so it fits on one line and can be decoded again: a
+/// backslash, newline, tab or carriage return as `\\`, `\n`, `\t` and
+/// `\r`, and the other control characters and invalid UTF-8 as `\xNN`.
+fn escape_plain(data: &[u8]) -> String {
+    let mut text = String::with_capacity(data.len());
+    for chunk in data.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => text.push_str("\\\\"),
+                '\n' => text.push_str("\\n"),
+                '\t' => text.push_str("\\t"),
+                '\r' => text.push_str("\\r"),
+                c if c.is_control() => {
+                    let mut buf = [0; 4];
+                    for b in c.encode_utf8(&mut buf).bytes() {
+                        text.push_str(&format!("\\x{b:02x}"));
+                    }
+                }
+                c => text.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            text.push_str(&format!("\\x{b:02x}"));
+        }
+    }
+    text
+}
+
+/// Write the body of a message, block or chunk of data preceded by a header
+/// of 9 bytes: the direction, 0 from client to server and 1 from server to
+/// client, then the connection id and the size of the body as 32 bit little
+/// endian numbers.
+fn write_raw(
+    renderer: &mut Renderer,
+    id: Option<ConnectionId>,
+    direction: Option<Direction>,
+    data: &[u8],
+) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(9 + data.len());
+    frame.push(match direction {
+        Some(Direction::Downstream) => 1,
+        _ => 0,
+    });
+    let id = id.map_or(0, |id| id.number());
+    frame.extend_from_slice(&(id as u32).to_le_bytes());
+    frame.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    frame.extend_from_slice(data);
+    renderer.write_raw(&frame)
+}
+
+pub fn direction_name(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Upstream => "upstream",
+        Direction::Downstream => "downstream",
+    }
+}
+
+/// Splits the data flowing in one direction into messages or blocks
+#[derive(Debug)]
+struct Framer {
+    level: Level,
+    analyzer: Analyzer,
+    buf: Vec<u8>,
+    /// Capture time of the first data in buf
+    buf_timestamp: Option<CaptureTime>,
+}
+
+/// A message, block or chunk of data, or a protocol error
+#[derive(Debug)]
+struct Frame {
+    kind: &'static str,
+    data: Option<Vec<u8>>,
+    timestamp: Option<CaptureTime>,
+}
+
+impl Framer {
+    fn new(level: Level, unix_client: bool) -> Self {
+        Framer {
+            level,
+            analyzer: Analyzer::new(unix_client),
+            buf: vec![],
+            buf_timestamp: None,
+        }
+    }
+
+    fn handle_data(&mut self, mut data: &[u8], timestamp: Option<CaptureTime>) -> Vec<Frame> {
+        let mut frames = vec![];
+        if self.level == Level::Raw {
+            frames.push(Frame {
+                kind: "data",
+                data: Some(data.to_vec()),
+                timestamp,
+            });
+            return frames;
+        }
+        loop {
+            let whole = data;
+            let Some(chunk) = self.analyzer.split_chunk(&mut data) else {
+                break;
+            };
+            if self.analyzer.was_error() {
+                // Pass on the rest of the data as it comes
+                frames.extend(self.incomplete());
+                frames.push(Frame {
+                    kind: "protocol_error",
+                    data: None,
+                    timestamp,
+                });
+                self.level = Level::Raw;
+                frames.extend(self.handle_data(whole, timestamp));
+                break;
+            }
+            if !self.analyzer.was_body() {
+                continue;
+            }
+            if self.buf.is_empty() {
+                self.buf_timestamp = timestamp;
+            }
+            self.buf.extend_from_slice(chunk);
+            let (at_end, kind) = match self.level {
+                Level::Blocks => (self.analyzer.was_block_boundary(), "block"),
+                _ => (self.analyzer.was_message_boundary(), "message"),
+            };
+            if at_end {
+                frames.push(Frame {
+                    kind,
+                    data: Some(mem::take(&mut self.buf)),
+                    timestamp: self.buf_timestamp,
+                });
+            }
+        }
+        frames
+    }
+
+    /// Start over at a fresh block boundary after a gap, passing on what we
+    /// have of the current frame.
+    fn resync(&mut self) -> Option<Frame> {
+        let frame = self.incomplete();
+        self.analyzer = Analyzer::new(false);
+        frame
+    }
+
+    fn incomplete(&mut self) -> Option<Frame> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let kind = match self.level {
+            Level::Messages => "incomplete_message",
+            _ => "incomplete_block",
+        };
+        Some(Frame {
+            kind,
+            data: Some(mem::take(&mut self.buf)),
+            timestamp: self.buf_timestamp,
+        })
+    }
+}
+
+/// Fields describing the [Traffic] of a connection
+fn traffic_fields(traffic: &Traffic) -> Value {
+    let volume = |volume: &Volume| json!({ "bytes": volume.bytes, "messages": volume.messages });
+    json!({
+        "client": volume(&traffic.upstream),
+        "server": volume(&traffic.downstream),
+    })
+}
+
+/// Fields describing the [Timing] of a connection, in seconds
+fn timing_fields(timing: &Timing) -> Value {
+    let secs = |d: Option<Duration>| d.map(|d| d.as_secs_f64());
+    json!({
+        "duration": timing.duration.as_secs_f64(),
+        "first_data": secs(timing.first_data),
+        "last_data": secs(timing.last_data),
+    })
+}
+
+/// Combine the fields of two objects
+fn merge(mut a: Value, b: Value) -> Value {
+    if let (Value::Object(a), Value::Object(b)) = (&mut a, b) {
+        a.extend(b);
+    }
+    a
+}
+
+/// The connection, direction and data of a record written for a message,
+/// block or chunk of data, when reading back the output of
+/// `--format=jsonl`. None for other records.
+pub fn read_frame(record: &Value) -> Option<(usize, Direction, Vec<u8>)> {
+    let kind = record["event"].as_str()?;
+    if ![
+        "message",
+        "block",
+        "data",
+        "incomplete_message",
+        "incomplete_block",
+    ]
+    .contains(&kind)
+    {
+        return None;
+    }
+    let conn = record["conn"].as_u64()? as usize;
+    let direction = match record["direction"].as_str()? {
+        "upstream" => Direction::Upstream,
+        "downstream" => Direction::Downstream,
+        _ => return None,
+    };
+    let data = if let Some(text) = record["text"].as_str() {
+        text.as_bytes().to_vec()
+    } else {
+        let encoded = record["base64"].as_str().unwrap_or_default();
+        base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .ok()?
+    };
+    Some((conn, direction, data))
+}
+
+/// Format a capture time as in RFC 3339, for example
+/// `2024-03-05T14:02:01.500000Z`
+pub fn iso_time(time: CaptureTime) -> String {
+    format!("{}Z", time.to_string().replacen(' ', "T", 1))
+}
+
+#[test]
+fn test_framer() {
+    let frames = |level, data: &[u8]| {
+        let mut framer = Framer::new(level, false);
+        let mut frames = framer.handle_data(&data[..3], None);
+        frames.extend(framer.handle_data(&data[3..], None));
+        frames
+            .into_iter()
+            .map(|f| (f.kind, f.data.unwrap_or_default()))
+            .collect::<Vec<_>>()
+    };
+    // a message of two blocks followed by an empty message
+    let data = b"\x04\x00ab\x03\x00c\x01\x00";
+    assert_eq!(
+        frames(Level::Messages, data),
+        [("message", b"abc".to_vec()), ("message", vec![])]
+    );
+    assert_eq!(
+        frames(Level::Blocks, data),
+        [
+            ("block", b"ab".to_vec()),
+            ("block", b"c".to_vec()),
+            ("block", vec![])
+        ]
+    );
+    assert_eq!(
+        frames(Level::Raw, data),
+        [("data", data[..3].to_vec()), ("data", data[3..].to_vec())]
+    );
+}
+
+#[test]
+fn test_to_cbor() {
+    let structured = Structured::new(Format::Cbor, Level::Messages, false, false);
+    let fields = structured.body_fields(b"\x00\x01");
+    let mut record = structured.record(
+        "message",
+        Some(ConnectionId::new(10)),
+        Some(Direction::Downstream),
+        None,
+        fields,
+    );
+    record.data = Some(b"\x00\x01".to_vec());
+    let cbor = record.to_cbor();
+    let decoded: ciborium::Value = ciborium::from_reader(&cbor[..]).unwrap();
+    let field = |name: &str| {
+        let map = decoded.as_map().unwrap();
+        let (_, value) = map.iter().find(|(k, _)| k.as_text() == Some(name))?;
+        Some(value.clone())
+    };
+    assert_eq!(field("event"), Some("message".into()));
+    assert_eq!(field("conn"), Some(10.into()));
+    assert_eq!(field("direction"), Some("downstream".into()));
+    assert_eq!(field("size"), Some(2.into()));
+    assert_eq!(field("bytes"), Some(ciborium::Value::Bytes(vec![0, 1])));
+    assert_eq!(field("base64"), None);
+}
+
+#[test]
+fn test_schema() {
+    use crate::proxy::network::Addr;
+
+    let schema: Value = serde_json::from_str(SCHEMA).unwrap();
+    assert_eq!(schema["version"], SCHEMA_VERSION);
+
+    // Every field of these records must be declared, either for all records
+    // or for their kind of event
+    let id = ConnectionId::new(10);
+    let addr = Addr::from("127.0.0.1:50000".parse::<std::net::SocketAddr>().unwrap());
+    let events = [
+        MapiEvent::Incoming {
+            id,
+            local: addr.clone(),
+            peer: addr.clone(),
+        },
+        MapiEvent::Data {
+            id,
+            direction: Direction::Upstream,
+            data: b"\x07\x00sselect".as_slice().into(),
+            timestamp: None,
+        },
+    ];
+    let mut structured = Structured::new(Format::JsonLines, Level::Messages, false, true);
+    let version = json!({ "version": SCHEMA_VERSION });
+    let mut records = vec![structured.record("schema", None, None, None, version)];
+    for event in &events {
+        records.extend(structured.records(event));
+    }
+    records.extend(structured.finish_records());
+    for record in records {
+        let json = record.to_json();
+        let kind = json["event"].as_str().unwrap();
+        let branch = schema["oneOf"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|b| {
+                let event = &b["properties"]["event"];
+                event["const"] == kind
+                    || event["enum"]
+                        .as_array()
+                        .is_some_and(|e| e.contains(&json["event"]))
+            })
+            .unwrap_or_else(|| panic!("event {kind} not in schema"));
+        for field in json.as_object().unwrap().keys() {
+            let declared = |b: &Value| b["properties"].get(field).is_some();
+            let in_refs = branch["allOf"].as_array().is_some_and(|refs| {
+                refs.iter().any(|r| {
+                    let name = r["$ref"].as_str().unwrap().trim_start_matches("#/$defs/");
+                    declared(&schema["$defs"][name])
+                })
+            });
+            assert!(
+                declared(&schema) || declared(branch) || in_refs,
+                "field {field} of {kind} not in schema"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_to_plain() {
+    let structured = Structured::new(Format::PlainStable, Level::Messages, false, false);
+    let data = b"select 'a\\b';\n\x01\xff";
+    let mut record = structured.record(
+        "message",
+        Some(ConnectionId::new(10)),
+        Some(Direction::Upstream),
+        None,
+        structured.body_fields(data),
+    );
+    record.data = Some(data.to_vec());
+    assert_eq!(
+        record.to_plain(),
+        concat!(
+            "BEGIN message conn=10 direction=\"upstream\" size=16\n",
+            "DATA select 'a\\\\b';\\n\n",
+            "DATA \\x01\\xff\n",
+            "END message conn=10 direction=\"upstream\"\n",
+        )
+    );
+    let fields = json!({ "client": { "bytes": 3, "messages": null }, "duration": 1.5 });
+    let timestamp = Some(CaptureTime(Duration::from_secs(1709211909)));
+    let record = structured.record("end", Some(ConnectionId::new(10)), None, timestamp, fields);
+    assert_eq!(
+        record.to_plain(),
+        "EVENT end conn=10 client.bytes=3 client.messages=null\n"
+    );
+}
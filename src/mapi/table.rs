@@ -0,0 +1,198 @@
+//! Show the result sets in server responses as aligned tables, see
+//! `--table`.
+
+use crate::render::Glyphs;
+
+/// Column types whose values are aligned to the right
+const NUMERIC_TYPES: &[&str] = &[
+    "tinyint", "smallint", "int", "bigint", "hugeint", "oid", "decimal", "real", "double", "float",
+];
+
+/// A piece of a response: text to show as is, or a result set
+#[derive(Debug, PartialEq)]
+pub enum Part<'a> {
+    /// One or more lines, including their newlines
+    Text(&'a str),
+    Table(Table<'a>),
+}
+
+/// The column header lines and the rows of a result set
+#[derive(Debug, Default, PartialEq)]
+pub struct Table<'a> {
+    names: Vec<&'a str>,
+    numeric: Vec<bool>,
+    rows: Vec<Vec<&'a str>>,
+}
+
+/// Split a response into the result sets and the text around them. Returns
+/// None if it has no result sets or if their rows don't look like we
+/// expect, in which case it's best shown as text.
+pub fn split_tables(text: &str) -> Option<Vec<Part<'_>>> {
+    let mut parts = vec![];
+    let mut table: Option<Table> = None;
+    let mut text_start = 0;
+    let mut pos = 0;
+    for line in text.split_inclusive('\n') {
+        let start = pos;
+        pos += line.len();
+        let content = line.strip_suffix('\n').unwrap_or(line);
+        let in_table = if let Some(header) = content.strip_prefix("% ") {
+            // Headers after rows start the next result set
+            if table.as_ref().is_some_and(|t| !t.rows.is_empty()) {
+                parts.extend(table.take().map(Part::Table));
+            }
+            table.get_or_insert_with(Table::default).add_header(header);
+            true
+        } else if content.starts_with('[') {
+            // Rows without headers come in later blocks of a large result
+            table
+                .get_or_insert_with(Table::default)
+                .rows
+                .push(split_row(content)?);
+            true
+        } else {
+            false
+        };
+        if in_table {
+            if text_start < start {
+                parts.push(Part::Text(&text[text_start..start]));
+            }
+            text_start = pos;
+        } else {
+            parts.extend(table.take().map(Part::Table));
+        }
+    }
+    parts.extend(table.take().map(Part::Table));
+    if text_start < text.len() {
+        parts.push(Part::Text(&text[text_start..]));
+    }
+
+    let mut found = false;
+    for part in &parts {
+        if let Part::Table(table) = part {
+            let columns = table.columns();
+            if table.rows.iter().any(|row| row.len() != columns) {
+                return None;
+            }
+            found = true;
+        }
+    }
+    found.then_some(parts)
+}
+
+impl<'a> Table<'a> {
+    /// Take the column names and types from lines such as
+    /// `% a,\tb # name`, ignore the other header lines.
+    fn add_header(&mut self, header: &'a str) {
+        let Some((fields, label)) = header.rsplit_once(" # ") else {
+            return;
+        };
+        let fields = fields.split(",\t");
+        match label {
+            "name" => self.names = fields.collect(),
+            "type" => self.numeric = fields.map(|t| NUMERIC_TYPES.contains(&t)).collect(),
+            _ => {}
+        }
+    }
+
+    fn columns(&self) -> usize {
+        match self.rows.first() {
+            Some(row) if self.names.is_empty() => row.len(),
+            _ => self.names.len(),
+        }
+    }
+
+    /// The table as lines of text: the column names and a rule below them
+    /// if we know the names, then the rows.
+    pub fn lines(&self, glyphs: &Glyphs) -> Vec<String> {
+        let mut widths = vec![0; self.columns()];
+        for row in self.rows.iter().chain([&self.names]) {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        let format_row = |row: &[&str]| {
+            let mut line = String::new();
+            for (i, (cell, width)) in row.iter().zip(&widths).enumerate() {
+                if i > 0 {
+                    line.push_str(&glyphs.frame_line);
+                }
+                if self.numeric.get(i) == Some(&true) {
+                    line.push_str(&format!(" {cell:>width$} "));
+                } else {
+                    line.push_str(&format!(" {cell:<width$} "));
+                }
+            }
+            line.trim_end().to_string()
+        };
+
+        let mut lines = vec![];
+        if !self.names.is_empty() {
+            lines.push(format_row(&self.names));
+            let rules: Vec<String> = widths.iter().map(|w| glyphs.rule.repeat(w + 2)).collect();
+            lines.push(rules.join(glyphs.cross));
+        }
+        for row in &self.rows {
+            lines.push(format_row(row));
+        }
+        lines
+    }
+}
+
+/// Split a row such as `[ 1,\t"a,\tb",\tNULL\t]` into its values, keeping
+/// the quotes. Returns None if it isn't a row.
+fn split_row(line: &str) -> Option<Vec<&str>> {
+    let inner = line.strip_prefix("[ ")?.strip_suffix("\t]")?;
+    let mut values = vec![];
+    let mut start = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in inner.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ',' if !in_string && inner[i + 1..].starts_with('\t') => {
+                values.push(&inner[start..i]);
+                start = i + 2;
+            }
+            _ => {}
+        }
+    }
+    if in_string {
+        return None;
+    }
+    values.push(&inner[start..]);
+    Some(values)
+}
+
+#[test]
+fn test_split_tables() {
+    let text = concat!(
+        "&1 0 2 2 2\n",
+        "% sys.t,\tsys.t # table_name\n",
+        "% id,\tname # name\n",
+        "% int,\tvarchar # type\n",
+        "% 2,\t6 # length\n",
+        "[ 1,\t\"a,\\tb\"\t]\n",
+        "[ 10,\tNULL\t]\n",
+    );
+    let parts = split_tables(text).unwrap();
+    assert_eq!(parts.len(), 2);
+    assert_eq!(parts[0], Part::Text("&1 0 2 2 2\n"));
+    let Part::Table(table) = &parts[1] else {
+        panic!("expected a table");
+    };
+    assert_eq!(
+        table.lines(&Glyphs::ASCII),
+        [
+            " id | name",
+            "----+---------",
+            "  1 | \"a,\\tb\"",
+            " 10 | NULL",
+        ]
+    );
+
+    assert_eq!(split_tables("&3 1 -1\n"), None);
+    assert_eq!(split_tables("% a # name\n[ 1,\t2\t]\n"), None);
+}
@@ -0,0 +1,124 @@
+//! Warn when a server announces a newer protocol than the decoders know.
+//! The login challenge of the server, for example
+//! `salt:mserver:9:RIPEMD160,SHA512:LIT:SHA512:sql=6:BINARY=1:OOBINTR=1:`,
+//! holds the version of the protocol and the levels of the features it
+//! supports.
+
+use std::collections::{HashMap, HashSet};
+
+use tracing::warn;
+
+use crate::proxy::event::{ConnectionId, Direction, MapiEvent};
+
+/// The MAPI protocol version the decoders were written for
+const KNOWN_PROTOCOL: u32 = 9;
+
+/// The handshake options the decoders were written for, with the highest
+/// level known
+const KNOWN_OPTIONS: [(&str, u32); 4] =
+    [("sql", 7), ("BINARY", 1), ("OOBINTR", 1), ("CLIENTINFO", 0)];
+
+/// Looks at the first block each server sends.
+#[derive(Debug, Default)]
+pub struct VersionCheck {
+    /// The start of the first block of the connections whose server has not
+    /// sent all of it yet
+    pending: HashMap<ConnectionId, Vec<u8>>,
+    /// Warnings already given, to give each only once
+    warned: HashSet<String>,
+}
+
+impl VersionCheck {
+    pub fn handle(&mut self, event: &MapiEvent) {
+        match event {
+            MapiEvent::Incoming { id, .. } => {
+                self.pending.insert(*id, vec![]);
+            }
+            MapiEvent::Data {
+                id,
+                direction: Direction::Downstream,
+                data,
+                ..
+            } => {
+                let Some(buffer) = self.pending.get_mut(id) else {
+                    return;
+                };
+                buffer.extend_from_slice(data);
+                let [lo, hi, body @ ..] = &buffer[..] else {
+                    return;
+                };
+                let len = u16::from_le_bytes([*lo, *hi]) as usize / 2;
+                if body.len() < len {
+                    return;
+                }
+                for warning in check_challenge(&body[..len]) {
+                    if self.warned.insert(warning.clone()) {
+                        warn!(%id, "{warning}, decoding may be incomplete");
+                    }
+                }
+                self.pending.remove(id);
+            }
+            MapiEvent::DataMissing { id, .. }
+            | MapiEvent::End { id, .. }
+            | MapiEvent::Aborted { id, .. } => {
+                self.pending.remove(id);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// What is newer in the challenge than the decoders know. Nothing if it is
+/// not a challenge at all.
+fn check_challenge(body: &[u8]) -> Vec<String> {
+    let Ok(text) = std::str::from_utf8(body) else {
+        return vec![];
+    };
+    let fields: Vec<&str> = text.trim_end().split(':').collect();
+    let [_salt, server, version, ..] = fields[..] else {
+        return vec![];
+    };
+    if server != "mserver" && server != "merovingian" {
+        return vec![];
+    }
+    let mut warnings = vec![];
+    match version.parse::<u32>() {
+        Ok(version) if version <= KNOWN_PROTOCOL => {}
+        _ => warnings.push(format!(
+            "server speaks MAPI protocol version {version}, mapiproxy knows {KNOWN_PROTOCOL}"
+        )),
+    }
+    // The options follow the salt, server, version, hashes, endianness and
+    // password hash
+    for option in fields.iter().skip(6).filter(|f| !f.is_empty()) {
+        let (name, level) = option.split_once('=').unwrap_or((option, "0"));
+        let known = KNOWN_OPTIONS.iter().find(|(n, _)| *n == name);
+        match (known, level.parse::<u32>()) {
+            (Some((_, max)), Ok(level)) if level <= *max => {}
+            (Some((_, max)), _) => warnings.push(format!(
+                "server offers {name} level {level}, mapiproxy knows up to {max}"
+            )),
+            (None, _) => warnings.push(format!("server offers unknown option {option}")),
+        }
+    }
+    warnings
+}
+
+#[test]
+fn test_check_challenge() {
+    let known =
+        b"abcd:mserver:9:RIPEMD160,SHA512:LIT:SHA512:sql=7:BINARY=1:OOBINTR=1:CLIENTINFO:\n";
+    assert!(check_challenge(known).is_empty());
+    assert!(check_challenge(b"abcd:merovingian:9:RIPEMD160:LIT:SHA512:").is_empty());
+    assert!(check_challenge(b"sSELECT 1;").is_empty());
+
+    let newer = b"abcd:mserver:10:SHA512:LIT:SHA512:sql=8:BINARY=1:SNAPPY=2:";
+    assert_eq!(
+        check_challenge(newer),
+        [
+            "server speaks MAPI protocol version 10, mapiproxy knows 9",
+            "server offers sql level 8, mapiproxy knows up to 7",
+            "server offers unknown option SNAPPY=2",
+        ]
+    );
+}
@@ -0,0 +1,220 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::proxy::event::{CaptureTime, TimeZone, ZonedTime};
+
+/// Destination of the rendered output. Either stdout or a file which can be
+/// reopened, for example after it has been rotated away by logrotate, or
+/// which we rotate ourselves, see [Output::rotate]. Output can also be
+/// collected in memory to be written later.
+#[derive(Debug)]
+pub enum Output {
+    Stdout(io::Stdout),
+    File {
+        path: PathBuf,
+        file: File,
+        /// Size of the file
+        written: u64,
+        /// The path with its placeholders, expanded again when the file is
+        /// rotated or reopened, see [Output::with_template]
+        template: Option<(PathBuf, TimeZone)>,
+    },
+    Memory(Vec<u8>),
+}
+
+impl Output {
+    /// Write to stdout.
+    pub fn stdout() -> Self {
+        Output::Stdout(io::stdout())
+    }
+
+    /// Create or truncate the file and write to it.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = File::create(path)?;
+        let path = path.to_path_buf();
+        Ok(Output::File {
+            path,
+            file,
+            written: 0,
+            template: None,
+        })
+    }
+
+    /// Remember that the path of the file was made from `template` by
+    /// [expand_path]. When the file is rotated or reopened, the template is
+    /// expanded again with the time in `zone` at that moment.
+    pub fn with_template(mut self, template: &Path, zone: TimeZone) -> Self {
+        if let Output::File { template: t, .. } = &mut self {
+            *t = Some((template.to_path_buf(), zone));
+        }
+        self
+    }
+
+    /// The file written to, if any.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Output::File { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Number of bytes in the file, 0 when not writing to a file.
+    pub fn written(&self) -> u64 {
+        match self {
+            Output::File { written, .. } => *written,
+            _ => 0,
+        }
+    }
+
+    /// Return true if output goes to a terminal.
+    pub fn is_terminal(&self) -> bool {
+        match self {
+            Output::Stdout(out) => is_terminal::is_terminal(out),
+            Output::File { file, .. } => is_terminal::is_terminal(file),
+            Output::Memory(_) => false,
+        }
+    }
+
+    /// Number of columns of the terminal, if output goes to one.
+    #[cfg(unix)]
+    pub fn terminal_width(&self) -> Option<usize> {
+        use std::os::fd::AsRawFd;
+        let fd = match self {
+            Output::Stdout(out) => out.as_raw_fd(),
+            Output::File { file, .. } => file.as_raw_fd(),
+            Output::Memory(_) => return None,
+        };
+        // SAFETY: TIOCGWINSZ only writes to the winsize we pass it
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        let ret = unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) };
+        (ret == 0 && size.ws_col > 0).then_some(size.ws_col as usize)
+    }
+
+    #[cfg(not(unix))]
+    pub fn terminal_width(&self) -> Option<usize> {
+        None
+    }
+
+    /// Close the file and open it again. If the file still exists, we append
+    /// to it. If it has been moved away, a new file is created. If the path
+    /// has placeholders, it is expanded again first. Does nothing when
+    /// writing to stdout.
+    pub fn reopen(&mut self) -> io::Result<()> {
+        if let Output::File {
+            path,
+            file,
+            written,
+            template,
+        } = self
+        {
+            if let Some((template, zone)) = template {
+                *path = expand_path(template, &CaptureTime::now().in_zone(*zone));
+            }
+            *file = OpenOptions::new().create(true).append(true).open(path)?;
+            *written = file.metadata()?.len();
+        }
+        Ok(())
+    }
+
+    /// Rename the file to FILE.1 and start a new one. Older files move up
+    /// to FILE.2 and so on, FILE.`keep` is removed. With `keep` 0, the file
+    /// is simply started over. If the path has placeholders and expanding
+    /// them again gives another name, the file is left alone and the new
+    /// one gets that name. Does nothing when not writing to a file.
+    pub fn rotate(&mut self, keep: u32) -> io::Result<()> {
+        let Output::File {
+            path,
+            file,
+            written,
+            template,
+        } = self
+        else {
+            return Ok(());
+        };
+        if let Some((template, zone)) = template {
+            let expanded = expand_path(template, &CaptureTime::now().in_zone(*zone));
+            if expanded != *path {
+                *file = File::create(&expanded)?;
+                *path = expanded;
+                *written = 0;
+                return Ok(());
+            }
+        }
+        let numbered = |n: u32| {
+            let mut name = OsString::from(path.as_os_str());
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        };
+        if keep > 0 {
+            // Renaming replaces the target atomically, older files don't
+            // need to be removed first
+            for n in (1..keep).rev() {
+                match fs::rename(numbered(n), numbered(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&*path, numbered(1))?;
+        }
+        *file = File::create(&*path)?;
+        *written = 0;
+        Ok(())
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Stdout(out) => out.write(buf),
+            Output::File { file, written, .. } => {
+                let n = file.write(buf)?;
+                *written += n as u64;
+                Ok(n)
+            }
+            Output::Memory(data) => data.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(out) => out.flush(),
+            Output::File { file, .. } => file.flush(),
+            Output::Memory(_) => Ok(()),
+        }
+    }
+}
+
+/// Replace the placeholders such as %Y, %m, %d, %H, %M and %S in `path` by
+/// the year, month, day, hour, minute and second of `now`, and %% by %, see
+/// [ZonedTime::format]. Other sequences starting with % are left alone.
+pub fn expand_path(path: &Path, now: &ZonedTime) -> PathBuf {
+    match path.to_str().filter(|p| p.contains('%')) {
+        Some(template) => now.format(template).into(),
+        None => path.to_path_buf(),
+    }
+}
+
+#[test]
+fn test_expand_path() {
+    use crate::proxy::event::{CaptureTime, TimeZone};
+    use std::time::Duration;
+
+    // 2024-02-29 13:05:09 UTC
+    let now = CaptureTime(Duration::from_secs(1709211909)).in_zone(TimeZone::Utc);
+    assert_eq!(
+        expand_path(Path::new("capture-%Y%m%d-%H%M%S.log"), &now),
+        Path::new("capture-20240229-130509.log")
+    );
+    assert_eq!(
+        expand_path(Path::new("100%%-%x-%"), &now),
+        Path::new("100%-%x-%")
+    );
+    assert_eq!(
+        expand_path(Path::new("plain.log"), &now),
+        Path::new("plain.log")
+    );
+}
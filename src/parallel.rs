@@ -0,0 +1,140 @@
+use std::{
+    collections::VecDeque,
+    io,
+    sync::mpsc::{self, Receiver, SyncSender, TryRecvError},
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    mapi,
+    proxy::event::MapiEvent,
+    render::{Clock, Renderer},
+};
+
+/// Number of events a worker can be behind before we wait for it
+const QUEUE_SIZE: usize = 1000;
+
+/// Number of events whose output has not been written yet before we wait
+/// for the oldest one
+const MAX_PENDING: usize = 10_000;
+
+/// Decodes and renders the connections of a capture on several threads, see
+/// `--jobs`. Every connection is handled by one worker, which has its own
+/// [mapi::State] and renders into memory. The rendered output is written in
+/// the order of the events so it's the same as with a single thread.
+pub struct Parallel {
+    workers: Vec<Worker>,
+    /// For each event whose output has not been written yet, the index of
+    /// the worker that handles it
+    pending: VecDeque<usize>,
+    /// Write the output as is, without marking pauses
+    structured: bool,
+}
+
+struct Worker {
+    /// The events, with the clock of the renderer that writes the output
+    events: SyncSender<(MapiEvent, Clock)>,
+    rendered: Receiver<io::Result<Vec<u8>>>,
+    /// Returns the state of the worker once the events have been handled
+    thread: JoinHandle<mapi::State>,
+}
+
+impl Parallel {
+    /// Start `jobs` workers with the same settings as `state` and
+    /// `renderer`.
+    pub fn new(jobs: usize, state: &mapi::State, renderer: &Renderer) -> Self {
+        let workers = (0..jobs)
+            .map(|_| {
+                let (events, event_rx) = mpsc::sync_channel(QUEUE_SIZE);
+                let (rendered_tx, rendered) = mpsc::channel();
+                let mut state = state.fresh();
+                let mut renderer = renderer.in_memory();
+                let thread = thread::spawn(move || {
+                    for (ev, clock) in event_rx {
+                        renderer.set_clock(clock);
+                        let output = state
+                            .handle(&ev, &mut renderer)
+                            .and_then(|_| renderer.take_output());
+                        if rendered_tx.send(output).is_err() {
+                            break;
+                        }
+                    }
+                    state
+                });
+                Worker {
+                    events,
+                    rendered,
+                    thread,
+                }
+            })
+            .collect();
+        Parallel {
+            workers,
+            pending: VecDeque::new(),
+            structured: state.is_structured(),
+        }
+    }
+
+    /// Hand the event to the worker of its connection and write the output
+    /// of earlier events that is ready.
+    pub fn handle(&mut self, ev: MapiEvent, renderer: &mut Renderer) -> io::Result<()> {
+        // Events that don't belong to a connection all go to the first worker
+        let index = ev.id().map_or(0, |id| id.number() % self.workers.len());
+        // Events without a capture time are shown at the time of the latest
+        // event of any connection
+        if let Some(timestamp) = ev.timestamp() {
+            renderer.advance_clock(timestamp);
+        }
+        if self.workers[index]
+            .events
+            .send((ev, renderer.clock()))
+            .is_err()
+        {
+            return Err(worker_gone());
+        }
+        self.pending.push_back(index);
+        self.write_ready(renderer, MAX_PENDING)
+    }
+
+    /// Wait for the workers to render the remaining events and write their
+    /// output. The open connections and totals of the workers are merged
+    /// into `state`, for [mapi::State::finish].
+    pub fn finish(mut self, state: &mut mapi::State, renderer: &mut Renderer) -> io::Result<()> {
+        self.write_ready(renderer, 0)?;
+        for worker in self.workers {
+            drop(worker.events);
+            let worker_state = worker.thread.join().map_err(|_| worker_gone())?;
+            state.merge(worker_state);
+        }
+        renderer.flush()
+    }
+
+    /// Write the output of the oldest events, in order, as long as it's
+    /// available. If more than `max_pending` events are waiting, wait for
+    /// the workers to catch up.
+    fn write_ready(&mut self, renderer: &mut Renderer, max_pending: usize) -> io::Result<()> {
+        while let Some(&index) = self.pending.front() {
+            let rendered = &self.workers[index].rendered;
+            let output = if self.pending.len() > max_pending {
+                rendered.recv().map_err(|_| worker_gone())?
+            } else {
+                match rendered.try_recv() {
+                    Ok(output) => output,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return Err(worker_gone()),
+                }
+            };
+            self.pending.pop_front();
+            if self.structured {
+                renderer.write_raw(&output?)?;
+            } else {
+                renderer.write_rendered(&output?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn worker_gone() -> io::Error {
+    io::Error::other("decoding thread stopped unexpectedly")
+}
@@ -0,0 +1,377 @@
+//! Write a row per message to a Parquet file for analysis with tools such as
+//! DuckDB or Polars, see `--parquet`.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::BufWriter,
+    mem,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result as AResult};
+use parquet::{
+    data_type::{BoolType, ByteArray, ByteArrayType, DataType, Int64Type},
+    errors::Result as PResult,
+    file::{
+        properties::WriterProperties,
+        writer::{SerializedColumnWriter, SerializedFileWriter},
+    },
+    schema::parser::parse_message_type,
+};
+use tracing::error;
+
+use crate::{
+    mapi::{direction_name, Analyzer},
+    proxy::event::{CaptureTime, ConnectionId, Direction, MapiEvent},
+};
+
+const SCHEMA: &str = "
+    message mapiproxy {
+        required int64 conn;
+        required binary direction (UTF8);
+        required int64 seq;
+        optional int64 time (TIMESTAMP(MICROS,true));
+        required int64 size;
+        required boolean error;
+        optional int64 latency_us;
+        optional binary fingerprint (UTF8);
+    }
+";
+
+/// Number of rows collected before they are written as a row group
+const ROW_GROUP_SIZE: usize = 100_000;
+
+/// Writes a row for each message with its connection, direction, number
+/// within the connection and direction, size and the time its last byte was
+/// captured:
+///
+/// - `error` is set for the messages of the server that are error responses,
+/// - `latency_us` is the time between a response and the request it answers,
+///   both measured at their last byte like `--latency` does,
+/// - `fingerprint` is the text of an SQL query with its literals replaced by
+///   `?`, to group similar queries, see [fingerprint].
+///
+/// The file is complete when it is closed, at the end of the run.
+pub struct ParquetLog {
+    path: PathBuf,
+    /// Taken when the file is closed
+    writer: Option<SerializedFileWriter<BufWriter<File>>>,
+    /// Use the current time for data without a capture time
+    live: bool,
+    connections: HashMap<ConnectionId, ConnState>,
+    rows: Rows,
+}
+
+#[derive(Debug)]
+struct ConnState {
+    /// The client's and the server's side
+    streams: [Stream; 2],
+    /// Times of the requests that have not been answered yet
+    unanswered: VecDeque<Option<CaptureTime>>,
+}
+
+/// The message in progress in one direction
+#[derive(Debug)]
+struct Stream {
+    analyzer: Analyzer,
+    /// Messages completed so far
+    seq: i64,
+    /// Body bytes of the current message so far
+    size: i64,
+    /// The first body byte of the current message
+    first: Option<u8>,
+    /// The body of the current message, only kept for the client's queries
+    body: Vec<u8>,
+}
+
+impl Stream {
+    fn new(unix_client: bool) -> Self {
+        Stream {
+            analyzer: Analyzer::new(unix_client),
+            seq: 0,
+            size: 0,
+            first: None,
+            body: vec![],
+        }
+    }
+
+    /// Part of the data is missing, skip the message in progress.
+    fn resync(&mut self) {
+        self.analyzer = Analyzer::new(false);
+        self.size = 0;
+        self.first = None;
+        self.body.clear();
+    }
+}
+
+/// The columns of the rows that have not been written yet
+#[derive(Debug, Default)]
+struct Rows {
+    conn: Vec<i64>,
+    direction: Vec<ByteArray>,
+    seq: Vec<i64>,
+    time: Vec<Option<i64>>,
+    size: Vec<i64>,
+    error: Vec<bool>,
+    latency: Vec<Option<i64>>,
+    fingerprint: Vec<Option<ByteArray>>,
+}
+
+impl ParquetLog {
+    /// Create the file, replacing it if it exists.
+    pub fn create(path: &Path, live: bool) -> AResult<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        let schema = Arc::new(parse_message_type(SCHEMA)?);
+        let properties = Arc::new(WriterProperties::builder().build());
+        let writer = SerializedFileWriter::new(file, schema, properties)?;
+        Ok(ParquetLog {
+            path: path.to_owned(),
+            writer: Some(writer),
+            live,
+            connections: HashMap::new(),
+            rows: Rows::default(),
+        })
+    }
+
+    pub fn handle(&mut self, event: &MapiEvent) -> AResult<()> {
+        match event {
+            MapiEvent::Incoming { id, peer, .. } => {
+                let conn = ConnState {
+                    streams: [Stream::new(peer.is_unix()), Stream::new(false)],
+                    unanswered: VecDeque::new(),
+                };
+                self.connections.insert(*id, conn);
+            }
+            MapiEvent::Data {
+                id,
+                direction,
+                data,
+                timestamp,
+            } => {
+                let timestamp = timestamp.or_else(|| self.live.then(CaptureTime::now));
+                self.data(*id, *direction, data, timestamp);
+            }
+            MapiEvent::DataMissing { id, .. } => {
+                // We can no longer tell which response belongs to which
+                // request
+                if let Some(conn) = self.connections.get_mut(id) {
+                    conn.streams.iter_mut().for_each(Stream::resync);
+                    conn.unanswered.clear();
+                }
+            }
+            MapiEvent::End { id, .. } | MapiEvent::Aborted { id, .. } => {
+                self.connections.remove(id);
+            }
+            _ => {}
+        }
+        if self.rows.conn.len() >= ROW_GROUP_SIZE {
+            self.write_rows()
+                .with_context(|| format!("Could not write to {}", self.path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Follow the messages in `data` and add a row for each one that ends
+    /// in it.
+    fn data(
+        &mut self,
+        id: ConnectionId,
+        direction: Direction,
+        mut data: &[u8],
+        timestamp: Option<CaptureTime>,
+    ) {
+        let Some(conn) = self.connections.get_mut(&id) else {
+            return;
+        };
+        let index = match direction {
+            Direction::Upstream => 0,
+            Direction::Downstream => 1,
+        };
+        // After a protocol error the analyzer swallows everything
+        while let Some(chunk) = conn.streams[index].analyzer.split_chunk(&mut data) {
+            let stream = &mut conn.streams[index];
+            if !stream.analyzer.was_body() {
+                continue;
+            }
+            stream.size += chunk.len() as i64;
+            stream.first = stream.first.or(chunk.first().copied());
+            if direction == Direction::Upstream {
+                stream.body.extend_from_slice(chunk);
+            }
+            if !stream.analyzer.was_message_boundary() {
+                continue;
+            }
+            stream.seq += 1;
+            let (seq, size, first) = (stream.seq, stream.size, stream.first);
+            let body = mem::take(&mut stream.body);
+            stream.size = 0;
+            stream.first = None;
+
+            let mut latency = None;
+            let mut fingerprint = None;
+            match direction {
+                Direction::Upstream => {
+                    conn.unanswered.push_back(timestamp);
+                    if let Some(query) = body.strip_prefix(b"s") {
+                        let query = String::from_utf8_lossy(query);
+                        fingerprint = Some(ByteArray::from(self::fingerprint(&query).as_str()));
+                    }
+                }
+                Direction::Downstream => {
+                    // Responses without a request, such as the login
+                    // challenge, have no latency. Neither do those of
+                    // traffic without times, such as --streams.
+                    if let (Some(Some(sent)), Some(received)) =
+                        (conn.unanswered.pop_front(), timestamp)
+                    {
+                        latency = Some(received.0.saturating_sub(sent.0).as_micros() as i64);
+                    }
+                }
+            }
+
+            let rows = &mut self.rows;
+            rows.conn.push(id.number() as i64);
+            rows.direction.push(direction_name(direction).into());
+            rows.seq.push(seq);
+            rows.time.push(timestamp.map(|t| t.0.as_micros() as i64));
+            rows.size.push(size);
+            rows.error
+                .push(direction == Direction::Downstream && first == Some(b'!'));
+            rows.latency.push(latency);
+            rows.fingerprint.push(fingerprint);
+        }
+    }
+
+    /// Write the rows collected so far as a row group.
+    fn write_rows(&mut self) -> PResult<()> {
+        let Some(writer) = &mut self.writer else {
+            return Ok(());
+        };
+        if self.rows.conn.is_empty() {
+            return Ok(());
+        }
+        let rows = mem::take(&mut self.rows);
+        let mut row_group = writer.next_row_group()?;
+        let mut index = 0;
+        while let Some(mut column) = row_group.next_column()? {
+            match index {
+                0 => write_required::<Int64Type>(&mut column, &rows.conn)?,
+                1 => write_required::<ByteArrayType>(&mut column, &rows.direction)?,
+                2 => write_required::<Int64Type>(&mut column, &rows.seq)?,
+                3 => write_optional::<Int64Type>(&mut column, &rows.time)?,
+                4 => write_required::<Int64Type>(&mut column, &rows.size)?,
+                5 => write_required::<BoolType>(&mut column, &rows.error)?,
+                6 => write_optional::<Int64Type>(&mut column, &rows.latency)?,
+                7 => write_optional::<ByteArrayType>(&mut column, &rows.fingerprint)?,
+                _ => unreachable!("more columns than in the schema"),
+            }
+            column.close()?;
+            index += 1;
+        }
+        row_group.close()?;
+        Ok(())
+    }
+
+    fn close(&mut self) -> PResult<()> {
+        self.write_rows()?;
+        if let Some(writer) = self.writer.take() {
+            writer.close()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ParquetLog {
+    fn drop(&mut self) {
+        // Also after an error, to keep what was decoded before it
+        if let Err(e) = self.close() {
+            error!("Could not write to {}: {e}", self.path.display());
+        }
+    }
+}
+
+fn write_required<T: DataType>(
+    column: &mut SerializedColumnWriter,
+    values: &[T::T],
+) -> PResult<()> {
+    column.typed::<T>().write_batch(values, None, None)?;
+    Ok(())
+}
+
+/// Write a column that may have nulls: the values that are there and a
+/// definition level for every row, 1 if it has a value and 0 if not.
+fn write_optional<T: DataType>(
+    column: &mut SerializedColumnWriter,
+    values: &[Option<T::T>],
+) -> PResult<()> {
+    let present: Vec<T::T> = values.iter().flatten().cloned().collect();
+    let levels: Vec<i16> = values.iter().map(|v| v.is_some() as i16).collect();
+    column
+        .typed::<T>()
+        .write_batch(&present, Some(&levels), None)?;
+    Ok(())
+}
+
+/// Make queries that only differ in their literals the same, by replacing
+/// the strings and numbers with `?` and collapsing whitespace. Comments are
+/// kept.
+pub fn fingerprint(query: &str) -> String {
+    let mut result = String::with_capacity(query.len());
+    let mut chars = query.trim().chars().peekable();
+    // Whether the previous character can be part of an identifier, in which
+    // case digits don't start a number
+    let mut in_word = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                // '' inside a string is an escaped quote, which comes down
+                // to skipping two strings in a row
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '\'' if chars.peek() == Some(&'\'') => {
+                            chars.next();
+                        }
+                        '\'' => break,
+                        _ => {}
+                    }
+                }
+                result.push('?');
+                in_word = false;
+            }
+            '0'..='9' if !in_word => {
+                while chars
+                    .next_if(|c| c.is_ascii_alphanumeric() || *c == '.')
+                    .is_some()
+                {}
+                result.push('?');
+            }
+            c if c.is_whitespace() => {
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                result.push(' ');
+                in_word = false;
+            }
+            c => {
+                result.push(c);
+                in_word = c.is_alphanumeric() || c == '_';
+            }
+        }
+    }
+    result
+}
+
+#[test]
+fn test_fingerprint() {
+    assert_eq!(
+        fingerprint("SELECT *\n  FROM t1 WHERE x = 42 AND y = 'it''s' LIMIT 1.5e3;\n"),
+        "SELECT * FROM t1 WHERE x = ? AND y = ? LIMIT ?;"
+    );
+    assert_eq!(
+        fingerprint("INSERT INTO t VALUES (1,-2, 'a\\'b')"),
+        "INSERT INTO t VALUES (?,-?, ?)"
+    );
+}
@@ -0,0 +1,269 @@
+//! Recognize MAPI connections by what the server sends rather than by their
+//! port number, see [Options::detect_mapi][super::Options::detect_mapi].
+
+use std::{collections::HashMap, io, mem};
+
+use crate::{
+    mapi,
+    proxy::event::{ConnectionId, Direction, MapiEvent},
+};
+
+type Handler<'a> = dyn FnMut(MapiEvent) -> io::Result<()> + 'a;
+
+/// Sits between the TCP tracker and the event handler and drops the events
+/// of connections that do not start with a MAPI login challenge.
+pub struct MapiDetector {
+    enabled: bool,
+    conns: HashMap<ConnectionId, Verdict>,
+    /// Number of connections found not to be MAPI
+    ignored: u64,
+    /// Number of bytes they carried
+    ignored_bytes: u64,
+}
+
+enum Verdict {
+    /// Waiting for the first message of the server. The events of the
+    /// connection are held back until we know.
+    Undecided {
+        held: Vec<MapiEvent>,
+        challenge: Vec<u8>,
+        /// We joined the connection mid-stream, so there is no challenge
+        /// and we wait for a complete message in either direction instead.
+        /// Holds what the client sent, the server's data goes in
+        /// `challenge`.
+        joined: Option<Vec<u8>>,
+    },
+    Mapi,
+    Other,
+}
+
+impl MapiDetector {
+    pub fn new(enabled: bool) -> Self {
+        MapiDetector {
+            enabled,
+            conns: HashMap::new(),
+            ignored: 0,
+            ignored_bytes: 0,
+        }
+    }
+
+    /// The number of connections and bytes dropped because they were not
+    /// MAPI
+    pub fn ignored(&self) -> (u64, u64) {
+        (self.ignored, self.ignored_bytes)
+    }
+
+    pub fn handle(&mut self, ev: MapiEvent, handler: &mut Handler) -> io::Result<()> {
+        if !self.enabled {
+            return handler(ev);
+        }
+        let Some(id) = ev.id() else {
+            return handler(ev);
+        };
+        let last = matches!(ev, MapiEvent::End { .. } | MapiEvent::Aborted { .. });
+        let verdict = self.conns.entry(id).or_insert_with(|| Verdict::Undecided {
+            held: vec![],
+            challenge: vec![],
+            joined: None,
+        });
+
+        if let Verdict::Undecided {
+            held,
+            challenge,
+            joined,
+        } = verdict
+        {
+            let decision = match &ev {
+                MapiEvent::JoinedMidStream { .. } => {
+                    *joined = Some(vec![]);
+                    None
+                }
+                MapiEvent::Data {
+                    direction, data, ..
+                } if joined.is_some() => {
+                    let buffer = match direction {
+                        Direction::Upstream => joined.as_mut().unwrap(),
+                        Direction::Downstream => challenge,
+                    };
+                    buffer.extend_from_slice(data);
+                    looks_like_message(buffer)
+                }
+                MapiEvent::DataMissing { direction, .. } if joined.is_some() => {
+                    match direction {
+                        Direction::Upstream => joined.as_mut().unwrap().clear(),
+                        Direction::Downstream => challenge.clear(),
+                    }
+                    None
+                }
+                MapiEvent::Data {
+                    direction: Direction::Downstream,
+                    data,
+                    ..
+                } => {
+                    challenge.extend_from_slice(data);
+                    looks_like_challenge(challenge)
+                }
+                // The client speaks first, or not at all
+                MapiEvent::Data { .. }
+                | MapiEvent::DataMissing { .. }
+                | MapiEvent::ShutdownRead { .. }
+                | MapiEvent::End { .. }
+                | MapiEvent::Aborted { .. } => Some(false),
+                _ => None,
+            };
+            held.push(ev);
+            match decision {
+                Some(true) => {
+                    for ev in mem::take(held) {
+                        handler(ev)?;
+                    }
+                    *verdict = Verdict::Mapi;
+                }
+                Some(false) => {
+                    self.ignored += 1;
+                    self.ignored_bytes += held.iter().map(data_len).sum::<u64>();
+                    *verdict = Verdict::Other;
+                }
+                None => {}
+            }
+        } else if let Verdict::Mapi = verdict {
+            handler(ev)?;
+        } else {
+            self.ignored_bytes += data_len(&ev);
+        }
+
+        if last {
+            self.conns.remove(&id);
+        }
+        Ok(())
+    }
+}
+
+fn data_len(ev: &MapiEvent) -> u64 {
+    match ev {
+        MapiEvent::Data { data, .. } => data.len() as u64,
+        _ => 0,
+    }
+}
+
+/// Check whether data seen after joining a connection mid-stream is a
+/// sequence of valid MAPI block headers that ends a message. Returns None if
+/// more data is needed to tell.
+fn looks_like_message(mut data: &[u8]) -> Option<bool> {
+    loop {
+        let [lo, hi, body @ ..] = data else {
+            return None;
+        };
+        let Some((len, last)) = mapi::block_header(*lo, *hi) else {
+            return Some(false);
+        };
+        if body.len() < len {
+            return None;
+        }
+        if last {
+            return Some(true);
+        }
+        data = &body[len..];
+    }
+}
+
+/// Check whether the first bytes sent by a server look like a MAPI login
+/// challenge such as `salt:mserver:9:RIPEMD160,SHA512:LIT:SHA512:`, in a
+/// single block. Returns None if more data is needed to tell.
+pub fn looks_like_challenge(data: &[u8]) -> Option<bool> {
+    let [lo, hi, body @ ..] = data else {
+        return None;
+    };
+    let Some((len, true)) = mapi::block_header(*lo, *hi) else {
+        return Some(false);
+    };
+    if len == 0 {
+        return Some(false);
+    }
+    let complete = body.len() >= len;
+    let body = &body[..len.min(body.len())];
+
+    // salt, server type, protocol version and the rest
+    let mut fields = body.split(|&b| b == b':');
+    let (Some(_), Some(server), Some(version), Some(_)) =
+        (fields.next(), fields.next(), fields.next(), fields.next())
+    else {
+        return if complete { Some(false) } else { None };
+    };
+    let server_ok = server == b"mserver" || server == b"merovingian";
+    let version_ok = !version.is_empty() && version.iter().all(u8::is_ascii_digit);
+    Some(server_ok && version_ok)
+}
+
+#[test]
+fn test_looks_like_challenge() {
+    let block = |body: &[u8]| {
+        let mut data = ((body.len() * 2 + 1) as u16).to_le_bytes().to_vec();
+        data.extend_from_slice(body);
+        data
+    };
+    let challenge = block(b"abcd:mserver:9:RIPEMD160,SHA512:LIT:SHA512:\n");
+    assert_eq!(looks_like_challenge(&challenge), Some(true));
+    assert_eq!(looks_like_challenge(&challenge[..10]), None);
+    assert_eq!(looks_like_challenge(&challenge[..1]), None);
+    assert_eq!(
+        looks_like_challenge(&block(b"abcd:merovingian:9:x:")),
+        Some(true)
+    );
+    assert_eq!(looks_like_challenge(b"HTTP/1.1 200 OK\r\n"), Some(false));
+    assert_eq!(looks_like_challenge(&block(b"hello\n")), Some(false));
+    assert_eq!(looks_like_challenge(&block(b"a:sshd:9:x:")), Some(false));
+}
+
+#[test]
+fn test_joined_mid_stream() {
+    let id = ConnectionId::new(7);
+    let data = |direction, data: &[u8]| MapiEvent::Data {
+        id,
+        direction,
+        data: data.into(),
+        timestamp: None,
+    };
+    let end = || MapiEvent::End {
+        id,
+        traffic: Default::default(),
+        timing: Default::default(),
+    };
+    let run = |events: Vec<MapiEvent>| {
+        let mut detector = MapiDetector::new(true);
+        let mut passed = 0;
+        let mut handler = |_| {
+            passed += 1;
+            Ok(())
+        };
+        for ev in events {
+            detector.handle(ev, &mut handler).unwrap();
+        }
+        (passed, detector.ignored())
+    };
+
+    // A query split over two blocks
+    let query = [&[4, 0][..], b"sS", &[7, 0], b"ELE"].concat();
+    let (passed, ignored) = run(vec![
+        MapiEvent::JoinedMidStream { id },
+        data(Direction::Upstream, &query[..5]),
+        data(Direction::Upstream, &query[5..]),
+        end(),
+    ]);
+    assert_eq!((passed, ignored), (4, (0, 0)));
+
+    // Never found a complete message
+    let (passed, ignored) = run(vec![
+        MapiEvent::JoinedMidStream { id },
+        data(Direction::Downstream, &query[..5]),
+        end(),
+    ]);
+    assert_eq!((passed, ignored), (0, (1, 5)));
+
+    // Not MAPI at all
+    let (passed, ignored) = run(vec![
+        MapiEvent::JoinedMidStream { id },
+        data(Direction::Upstream, b"GET / HTTP/1.1\r\n"),
+    ]);
+    assert_eq!((passed, ignored), (0, (1, 16)));
+}
@@ -0,0 +1,74 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+};
+
+/// Identifies the fragments that belong together: source, destination and
+/// the identification field of the fragment header.
+pub type FragmentKey = (IpAddr, IpAddr, u32);
+
+/// Collects the fragments of fragmented IP packets until they are complete.
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    pending: HashMap<FragmentKey, Fragments>,
+}
+
+#[derive(Debug, Default)]
+struct Fragments {
+    /// Fragment data by byte offset
+    pieces: BTreeMap<usize, Vec<u8>>,
+    /// Known once the last fragment has arrived
+    total_len: Option<usize>,
+}
+
+impl Reassembler {
+    /// Add a fragment. If this completes the packet, return its reassembled
+    /// payload.
+    pub fn add(
+        &mut self,
+        key: FragmentKey,
+        offset: usize,
+        more: bool,
+        data: &[u8],
+    ) -> Option<Vec<u8>> {
+        let fragments = self.pending.entry(key).or_default();
+        if !more {
+            fragments.total_len = Some(offset + data.len());
+        }
+        fragments.pieces.insert(offset, data.to_vec());
+
+        let total_len = fragments.total_len?;
+        let mut assembled = Vec::with_capacity(total_len);
+        for (&offset, piece) in &fragments.pieces {
+            if offset > assembled.len() {
+                // there's a gap, wait for more fragments
+                return None;
+            }
+            // fragments may overlap, only append the part we don't have yet
+            let known = assembled.len() - offset;
+            if known < piece.len() {
+                assembled.extend_from_slice(&piece[known..]);
+            }
+        }
+        if assembled.len() < total_len {
+            return None;
+        }
+        assembled.truncate(total_len);
+        self.pending.remove(&key);
+        Some(assembled)
+    }
+}
+
+#[test]
+fn test_reassemble() {
+    let mut r = Reassembler::default();
+    let key = (IpAddr::from([0u8; 16]), IpAddr::from([1u8; 16]), 42);
+    let other = (key.0, key.1, 43);
+
+    assert_eq!(r.add(key, 16, false, b"QRSTUVWX"), None);
+    assert_eq!(r.add(other, 0, true, b"--------"), None);
+    assert_eq!(r.add(key, 0, true, b"ABCDEFGH"), None);
+    let expected = b"ABCDEFGHIJKLMNOPQRSTUVWX".to_vec();
+    assert_eq!(r.add(key, 8, true, b"IJKLMNOP"), Some(expected));
+    assert_eq!(r.pending.len(), 1);
+}
@@ -0,0 +1,73 @@
+//! Parse TLS key log files in the format used by NSS, OpenSSL and others
+//! when SSLKEYLOGFILE is set. Each line holds a label, the client random of
+//! the connection and a secret, all but the label hex encoded.
+
+use std::collections::HashMap;
+
+/// The secrets found in one or more key logs
+#[derive(Debug, Default)]
+pub struct KeyLog {
+    secrets: HashMap<(String, [u8; 32]), Vec<u8>>,
+}
+
+impl KeyLog {
+    /// Add the secrets from the text of a key log. Lines we don't understand
+    /// are skipped.
+    pub fn add(&mut self, text: &str) {
+        for line in text.lines() {
+            let mut words = line.split_ascii_whitespace();
+            let (Some(label), Some(random), Some(secret), None) =
+                (words.next(), words.next(), words.next(), words.next())
+            else {
+                continue;
+            };
+            let Some(random) = unhex(random).and_then(|r| <[u8; 32]>::try_from(r).ok()) else {
+                continue;
+            };
+            let Some(secret) = unhex(secret) else {
+                continue;
+            };
+            self.secrets.insert((label.to_string(), random), secret);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.secrets.is_empty()
+    }
+
+    /// Look up the secret with this label for the connection with this
+    /// client random.
+    pub fn get(&self, label: &str, client_random: &[u8; 32]) -> Option<&[u8]> {
+        self.secrets
+            .get(&(label.to_string(), *client_random))
+            .map(Vec::as_slice)
+    }
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[test]
+fn test_keylog() {
+    let random = [0xAB; 32];
+    let text = format!(
+        "# comment\nCLIENT_RANDOM {r} 0102\nSERVER_TRAFFIC_SECRET_0 {r} ff\nBROKEN {r}\n",
+        r = "ab".repeat(32)
+    );
+    let mut keylog = KeyLog::default();
+    keylog.add(&text);
+    assert_eq!(keylog.get("CLIENT_RANDOM", &random), Some(&[1u8, 2][..]));
+    assert_eq!(
+        keylog.get("SERVER_TRAFFIC_SECRET_0", &random),
+        Some(&[0xFFu8][..])
+    );
+    assert_eq!(keylog.get("BROKEN", &random), None);
+    assert_eq!(keylog.get("CLIENT_RANDOM", &[0; 32]), None);
+}
@@ -1,105 +1,503 @@
+mod detect;
+mod fragments;
+mod keylog;
 mod mybufread;
+mod recorder;
 mod tcp;
+mod tls;
 mod tracker;
 
-use std::io;
+use std::{
+    cmp::Ordering,
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 
-use anyhow::{bail, Result as AResult};
+use anyhow::{anyhow, bail, Context, Result as AResult};
+use etherparse::{LaxNetSlice, LaxSlicedPacket, TransportSlice};
 
 use pcap_file::{
     pcap::PcapReader,
-    pcapng::{Block, PcapNgReader},
-    DataLink,
+    pcapng::{
+        blocks::interface_description::{InterfaceDescriptionBlock, InterfaceDescriptionOption},
+        Block, PcapNgReader,
+    },
+    DataLink, Endianness, TsResolution,
+};
+
+use crate::proxy::{
+    event::{CaptureTime, ConnectionId},
+    network::Addr,
 };
 
+pub use self::detect::looks_like_challenge;
 use self::mybufread::MyBufReader;
+pub use self::recorder::{Recorder, SplitRecorder};
 pub use self::tracker::Tracker;
 
+/// Settings that influence how capture files are processed.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Only follow TCP connections where one of the endpoints uses one of
+    /// these ports. If empty, all TCP connections are followed.
+    pub ports: Vec<u16>,
+    /// Also follow connections whose SYN is not in the capture, starting at
+    /// the first plausible MAPI block header.
+    pub mid_stream: bool,
+    /// Replay the packets at the pace they were captured, sped up by this
+    /// factor.
+    pub pace: Option<f64>,
+    /// Decode the captured part of packets that were cut off at the snapshot
+    /// length instead of failing.
+    pub allow_truncated: bool,
+    /// Only report the events of this connection.
+    pub select: Option<Selection>,
+    /// Unwrap VXLAN, Geneve and GRE tunnels and look at the packets inside.
+    pub decapsulate: bool,
+    /// How many bytes of out-of-order packets to hold on to per stream while
+    /// waiting for a packet that may never arrive. When the limit is
+    /// exceeded, the missing data is reported as a gap. Defaults to 1 MiB.
+    pub reorder_limit: Option<usize>,
+    /// Only follow the connections that start with a MAPI login challenge,
+    /// whatever their port.
+    pub detect_mapi: bool,
+    /// Also report retransmissions, zero windows and resets.
+    pub tcp_events: bool,
+    /// Contents of TLS key log files, used to decrypt the TLS connections
+    /// in the capture.
+    pub keylog: String,
+    /// Skip the packets captured before this moment.
+    pub from: Option<TimeBound>,
+    /// Stop at the first packet captured after this moment.
+    pub to: Option<TimeBound>,
+    /// Number the connections from here instead of [ConnectionId::FIRST].
+    pub first_id: Option<usize>,
+}
+
+/// A moment in the capture, see [Options::from] and [Options::to].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeBound {
+    /// This capture time
+    At(CaptureTime),
+    /// This long after the first packet in the capture
+    After(Duration),
+}
+
+/// A connection to restrict decoding to, see [Options::select].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selection {
+    /// The connection with this id
+    Id(ConnectionId),
+    /// The connections between these two endpoints, in either direction
+    Endpoints(SocketAddr, SocketAddr),
+}
+
+impl Selection {
+    /// Check whether the connection that was announced with this
+    /// [Incoming][crate::proxy::event::MapiEvent::Incoming] event is selected.
+    fn matches(&self, id: ConnectionId, local: &Addr, peer: &Addr) -> bool {
+        match self {
+            Selection::Id(wanted) => id == *wanted,
+            Selection::Endpoints(a, b) => {
+                let (Addr::Tcp(local), Addr::Tcp(peer)) = (local, peer) else {
+                    return false;
+                };
+                (local, peer) == (a, b) || (local, peer) == (b, a)
+            }
+        }
+    }
+}
+
+/// Packets can't be larger than this, larger lengths in the capture file
+/// indicate corruption. With GRO and BIG TCP, the kernel hands captures
+/// packets of up to 512KiB.
+const MAX_PACKET_SIZE: usize = 1 << 20;
+
 /// Parse PCAP records from the reader and hand the packets to the Tracker. This
 /// function works with both the old-style PCAP and with PCAP-NG file formats.
-pub fn parse_pcap_file(mut rd: impl io::Read, tracker: &mut Tracker) -> AResult<()> {
-    // read ahead to inspect the file header
-    let mut signature = [0u8; 4];
-    rd.read_exact(&mut signature)?;
-
-    // create a MyBufReader, which is basically a BufReader except
-    // that we preload it with the bytes we read above
-    let mut buffer = Vec::with_capacity(16384);
-    buffer.extend_from_slice(&signature);
-    let mybufreader = MyBufReader::new(rd, buffer);
-
-    // Pass the file to either the legacy pcap reader or the pcapng reader
-    match signature {
-        [0xD4, 0xC3, 0xB2, 0xA1] | [0xA1, 0xB2, 0xB3, 0xD4] => {
-            parse_legacy_pcap(mybufreader, tracker)
+pub fn parse_pcap_file(rd: impl io::Read, tracker: &mut Tracker) -> AResult<()> {
+    let mut source = PacketSource::open(rd)?;
+    while let Some(packet) = source.next_packet()? {
+        source.take_secrets(tracker);
+        match tracker.check_window(packet.timestamp) {
+            Ordering::Less => continue,
+            Ordering::Equal => process_packet(&packet, tracker)?,
+            Ordering::Greater => break,
         }
-        [0x0A, 0x0D, 0x0D, 0x0A] => parse_pcap_ng(mybufreader, tracker),
-        _ => bail!(
-            "Unknown pcap file signature {:02X} {:02X} {:02X} {:02X}",
-            signature[0],
-            signature[1],
-            signature[2],
-            signature[3]
-        ),
     }
+    source.take_secrets(tracker);
+    tracker.finish()
 }
 
-/// Parse the file as legacy PCAP and pass the packets to [process_packet]
-fn parse_legacy_pcap(rd: MyBufReader, tracker: &mut Tracker) -> AResult<()> {
-    let mut pcap_reader = PcapReader::new(rd)?;
-
-    let header = pcap_reader.header();
+/// Like [parse_pcap_file] but for multiple files, for example files that have
+/// been rotated by the capture tool. The packets of all files are processed in
+/// order of their capture time so connections that span files are
+/// reconstructed correctly.
+pub fn parse_pcap_files<'a>(
+    inputs: Vec<(String, Box<dyn io::Read + 'a>)>,
+    tracker: &mut Tracker,
+) -> AResult<()> {
+    let context = |name: &str| format!("Could not read pcap file {name}");
+    let mut sources = vec![];
+    for (name, rd) in inputs {
+        let mut source = PacketSource::open(rd).with_context(|| context(&name))?;
+        let next = source.next_packet().with_context(|| context(&name))?;
+        source.take_secrets(tracker);
+        sources.push((name, source, next));
+    }
 
-    while let Some(pkt) = pcap_reader.next_packet() {
-        let pkt = pkt?;
-        if pkt.data.len() == header.snaplen as usize {
-            bail!("truncated packet");
+    loop {
+        // Pick the file whose next packet was captured first
+        let earliest = sources
+            .iter_mut()
+            .filter_map(|(name, source, next)| Some((next.as_ref()?.timestamp, name, source, next)))
+            .min_by_key(|(timestamp, ..)| *timestamp);
+        let Some((_, name, source, next)) = earliest else {
+            break;
+        };
+        let packet = next.take().unwrap();
+        *next = source.next_packet().with_context(|| context(name))?;
+        source.take_secrets(tracker);
+        match tracker.check_window(packet.timestamp) {
+            Ordering::Less => continue,
+            Ordering::Equal => process_packet(&packet, tracker)?,
+            Ordering::Greater => break,
         }
-
-        process_packet(header.datalink, &pkt.data, tracker)?;
     }
 
-    Ok(())
+    tracker.finish()
+}
+
+/// A packet read from a capture file
+struct Packet {
+    linktype: DataLink,
+    timestamp: Duration,
+    data: Vec<u8>,
+    /// Length of the packet on the wire, more than `data.len()` if it was
+    /// truncated by the capture tool
+    original_len: usize,
 }
 
-/// Parse the file as PCAP-NG and pass the packets to [process_packet]
-fn parse_pcap_ng(rd: MyBufReader, tracker: &mut Tracker) -> AResult<()> {
-    let mut pcapng_reader = PcapNgReader::new(rd)?;
+/// Reads packets from either an old-style PCAP or a PCAP-NG file.
+enum PacketSource<'a> {
+    Legacy(PcapReader<MyBufReader<'a>>),
+    Ng {
+        reader: PcapNgReader<MyBufReader<'a>>,
+        // With PCAP-NG the linktype and timestamp resolution are not
+        // file-global settings but are set per interface by the Interface
+        // Description blocks of the current section. Packets refer to them
+        // by their index.
+        interfaces: Vec<Interface>,
+        // Simple Packet blocks have no timestamp, we assume they were
+        // captured at the same time as the previous packet.
+        timestamp: Duration,
+        // Byte order of the current section, needed to parse the blocks
+        // pcap-file doesn't know about.
+        endianness: Endianness,
+        // TLS key logs from Decryption Secrets blocks, not yet passed to
+        // the Tracker
+        secrets: Vec<String>,
+    },
+}
+
+impl<'a> PacketSource<'a> {
+    fn open(mut rd: impl io::Read + 'a) -> AResult<Self> {
+        // read ahead to inspect the file header
+        let mut signature = [0u8; 4];
+        rd.read_exact(&mut signature)?;
 
-    // With PCAP-NG the linktype is not a file-global setting but it is set and
-    // can theoretically be changed mid-file using Interface Description blocks.
-    // This mutable holds the latest value we have seen.
-    let mut linktype = None;
+        // Construct a BufReader that will first yield the bytes we already read.
+        // We can't use a regular BufRead for this, we need to construct our own.
+        // This is a bit of a hack as it relies on the implementation detail
+        // that we preload it with the bytes we read above
+        let mut buffer = Vec::with_capacity(16384);
+        buffer.extend_from_slice(&signature);
+        let mybufreader = MyBufReader::new(rd, buffer);
 
-    while let Some(block) = pcapng_reader.next_block() {
-        let data = match block? {
-            Block::InterfaceDescription(iface) => {
-                linktype = Some(iface.linktype);
-                continue;
+        // Pass the file to either the legacy pcap reader or the pcapng reader
+        let source = match signature {
+            // Legacy pcap with microsecond or nanosecond timestamps, little
+            // and big endian
+            [0xD4, 0xC3, 0xB2, 0xA1]
+            | [0xA1, 0xB2, 0xC3, 0xD4]
+            | [0x4D, 0x3C, 0xB2, 0xA1]
+            | [0xA1, 0xB2, 0x3C, 0x4D] => PacketSource::Legacy(PcapReader::new(mybufreader)?),
+            [0x0A, 0x0D, 0x0D, 0x0A] => {
+                let reader = PcapNgReader::new(mybufreader)?;
+                PacketSource::Ng {
+                    endianness: reader.section().endianness,
+                    reader,
+                    interfaces: vec![],
+                    timestamp: Duration::ZERO,
+                    secrets: vec![],
+                }
             }
-            Block::Packet(packet) => packet.data,
-            Block::SimplePacket(packet) => packet.data,
-            Block::EnhancedPacket(packet) => packet.data,
-            _ => continue,
+            _ => bail!(
+                "Unknown pcap file signature {:02X} {:02X} {:02X} {:02X}",
+                signature[0],
+                signature[1],
+                signature[2],
+                signature[3]
+            ),
         };
+        Ok(source)
+    }
 
-        // Broken files might contain packets before the first interface description block.
-        // Ignore them.
-        if let Some(lt) = linktype {
-            process_packet(lt, &data, tracker)?;
+    fn next_packet(&mut self) -> AResult<Option<Packet>> {
+        match self {
+            PacketSource::Legacy(reader) => {
+                let header = reader.header();
+                // pcap-file rejects packets that were longer than the
+                // snapshot length, so we interpret the raw packet ourselves
+                let Some(pkt) = reader.next_raw_packet() else {
+                    return Ok(None);
+                };
+                let pkt = pkt?;
+                if pkt.incl_len > header.snaplen || pkt.incl_len > pkt.orig_len {
+                    bail!("invalid packet length in pcap file");
+                }
+                let nanos = match header.ts_resolution {
+                    TsResolution::MicroSecond => pkt.ts_frac.saturating_mul(1000),
+                    TsResolution::NanoSecond => pkt.ts_frac,
+                };
+                Ok(Some(Packet {
+                    linktype: header.datalink,
+                    timestamp: Duration::new(pkt.ts_sec.into(), nanos),
+                    data: pkt.data.into_owned(),
+                    original_len: pkt.orig_len as usize,
+                }))
+            }
+            PacketSource::Ng {
+                reader,
+                interfaces,
+                timestamp,
+                endianness,
+                secrets,
+            } => {
+                while let Some(block) = reader.next_block() {
+                    // pcap-file does not apply the timestamp resolution of
+                    // the interface, it gives us the raw number of units
+                    let (interface_id, units, data, original_len) = match block? {
+                        Block::SectionHeader(section) => {
+                            interfaces.clear();
+                            *endianness = section.endianness;
+                            continue;
+                        }
+                        Block::InterfaceDescription(iface) => {
+                            interfaces.push(Interface::new(&iface)?);
+                            continue;
+                        }
+                        Block::Packet(packet) => (
+                            packet.interface_id.into(),
+                            Some(packet.timestamp),
+                            packet.data,
+                            packet.original_len,
+                        ),
+                        // Simple Packet blocks always belong to the first interface
+                        Block::SimplePacket(packet) => (0, None, packet.data, packet.original_len),
+                        Block::EnhancedPacket(packet) => (
+                            packet.interface_id,
+                            Some(packet.timestamp.as_nanos() as u64),
+                            packet.data,
+                            packet.original_len,
+                        ),
+                        Block::Unknown(block) if block.type_ == DECRYPTION_SECRETS_BLOCK => {
+                            secrets.extend(tls_key_log(&block.value, *endianness));
+                            continue;
+                        }
+                        _ => continue,
+                    };
+
+                    // Broken files might contain packets that refer to
+                    // interfaces that have not been described. Ignore them.
+                    let Some(iface) = interfaces.get(interface_id as usize) else {
+                        continue;
+                    };
+                    if let Some(units) = units {
+                        *timestamp = iface.timestamp(units);
+                    }
+                    return Ok(Some(Packet {
+                        linktype: iface.linktype,
+                        timestamp: *timestamp,
+                        data: data.into_owned(),
+                        original_len: original_len as usize,
+                    }));
+                }
+                Ok(None)
+            }
         }
     }
+}
 
-    Ok(())
+impl PacketSource<'_> {
+    /// Pass the TLS secrets found in the file so far to the tracker.
+    fn take_secrets(&mut self, tracker: &mut Tracker) {
+        if let PacketSource::Ng { secrets, .. } = self {
+            for text in secrets.drain(..) {
+                tracker.add_key_log(&text);
+            }
+        }
+    }
+}
+
+/// Block type of PCAP-NG Decryption Secrets blocks
+const DECRYPTION_SECRETS_BLOCK: u32 = 0x0000000A;
+
+/// Secrets type of a Decryption Secrets block holding a TLS key log
+const TLS_KEY_LOG: u32 = 0x544c534b;
+
+/// Extract the key log from the body of a Decryption Secrets block. Returns
+/// None if it holds another kind of secrets.
+fn tls_key_log(body: &[u8], endianness: Endianness) -> Option<String> {
+    let u32_at = |pos: usize| {
+        let bytes: [u8; 4] = body.get(pos..pos + 4)?.try_into().ok()?;
+        Some(match endianness {
+            Endianness::Big => u32::from_be_bytes(bytes),
+            Endianness::Little => u32::from_le_bytes(bytes),
+        })
+    };
+    if u32_at(0)? != TLS_KEY_LOG {
+        return None;
+    }
+    let len = u32_at(4)? as usize;
+    let secrets = body.get(8..8 + len)?;
+    Some(String::from_utf8_lossy(secrets).into_owned())
+}
+
+/// The settings of a PCAP-NG interface we need to interpret its packets
+struct Interface {
+    linktype: DataLink,
+    /// Number of timestamp units per second
+    units_per_sec: u64,
+}
+
+impl Interface {
+    fn new(iface: &InterfaceDescriptionBlock) -> AResult<Self> {
+        // Without if_tsresol, timestamps are in microseconds
+        let tsresol = iface
+            .options
+            .iter()
+            .find_map(|opt| match opt {
+                InterfaceDescriptionOption::IfTsResol(r) => Some(*r),
+                _ => None,
+            })
+            .unwrap_or(6);
+        // The high bit selects between negative powers of 2 and of 10
+        let exponent = (tsresol & 0x7F) as u32;
+        let base: u64 = if tsresol & 0x80 == 0 { 10 } else { 2 };
+        let Some(units_per_sec) = base.checked_pow(exponent) else {
+            bail!("unsupported pcap-ng timestamp resolution {tsresol:#04x}");
+        };
+        Ok(Interface {
+            linktype: iface.linktype,
+            units_per_sec,
+        })
+    }
+
+    fn timestamp(&self, units: u64) -> Duration {
+        let secs = units / self.units_per_sec;
+        let fraction = (units % self.units_per_sec) as u128;
+        let nanos = fraction * 1_000_000_000 / self.units_per_sec as u128;
+        Duration::new(secs, nanos as u32)
+    }
 }
 
-/// This function is called from both [parse_legacy_pcap] and [parse_pcap_ng]
-/// for each packet in the file.
-fn process_packet(linktype: DataLink, data: &[u8], tracker: &mut Tracker) -> AResult<()> {
+/// This function is called for each packet in the file.
+fn process_packet(packet: &Packet, tracker: &mut Tracker) -> AResult<()> {
+    let Packet {
+        linktype,
+        timestamp,
+        ref data,
+        original_len,
+    } = *packet;
+    tracker.set_timestamp(timestamp);
+
+    // If the capture tool cut off the packet, pad it to its original length
+    // so the headers still add up. The tracker makes sure the padding is not
+    // delivered as data.
+    let missing = original_len.saturating_sub(data.len());
+    if original_len > MAX_PACKET_SIZE {
+        let error = anyhow!("packet claims to be {original_len} bytes long, it may be corrupt");
+        return tracker.skip_packet(error, tcp_endpoints(linktype, data));
+    }
+    tracker.set_truncated(missing)?;
+    let padded;
+    let data = if missing > 0 {
+        padded = [data.as_slice(), &vec![0; missing]].concat();
+        &padded
+    } else {
+        data
+    };
+
     // We expect to read ethernet frames but it's also possible for pcap files to
-    // capture at the IP level. Right now we only support Ethernet.
-    match linktype {
+    // capture at the IP level. Right now we support Ethernet, the Linux
+    // cooked captures produced when capturing on all interfaces, raw IP and
+    // the BSD loopback encapsulation.
+    let result = match linktype {
         DataLink::ETHERNET => tracker.process_ethernet(data),
+        DataLink::RAW | DataLink::IPV4 | DataLink::IPV6 => tracker.process_raw_ip(data),
+        DataLink::NULL | DataLink::LOOP => tracker.process_loopback(data),
+        DataLink::LINUX_SLL => tracker.process_linux_sll(data),
+        DataLink::LINUX_SLL2 => tracker.process_linux_sll2(data),
         _ => bail!("pcap file contains packet of type {linktype:?}, this is not supported"),
+    };
+
+    // A packet we can't decode only affects its own connection so we skip it
+    // and carry on. I/O errors come from writing the output and are fatal.
+    match result {
+        Err(e) if e.downcast_ref::<io::Error>().is_none() => {
+            tracker.skip_packet(e, tcp_endpoints(linktype, data))
+        }
+        result => result,
     }
 }
+
+/// The source and destination of a packet that could not be decoded, if its
+/// IP and TCP headers can still be made out.
+fn tcp_endpoints(linktype: DataLink, data: &[u8]) -> Option<(SocketAddr, SocketAddr)> {
+    let packet = match linktype {
+        DataLink::ETHERNET => LaxSlicedPacket::from_ethernet(data).ok()?,
+        DataLink::RAW | DataLink::IPV4 | DataLink::IPV6 => LaxSlicedPacket::from_ip(data).ok()?,
+        // skip the fixed size link headers
+        DataLink::NULL | DataLink::LOOP => LaxSlicedPacket::from_ip(data.get(4..)?).ok()?,
+        DataLink::LINUX_SLL => LaxSlicedPacket::from_ip(data.get(16..)?).ok()?,
+        DataLink::LINUX_SLL2 => LaxSlicedPacket::from_ip(data.get(20..)?).ok()?,
+        _ => return None,
+    };
+    let (src, dest): (IpAddr, IpAddr) = match packet.net? {
+        LaxNetSlice::Ipv4(ip) => (
+            ip.header().source_addr().into(),
+            ip.header().destination_addr().into(),
+        ),
+        LaxNetSlice::Ipv6(ip) => (
+            ip.header().source_addr().into(),
+            ip.header().destination_addr().into(),
+        ),
+    };
+    let Some(TransportSlice::Tcp(tcp)) = packet.transport else {
+        return None;
+    };
+    Some((
+        (src, tcp.source_port()).into(),
+        (dest, tcp.destination_port()).into(),
+    ))
+}
+
+#[test]
+fn test_interface_timestamp() {
+    let iface = |tsresol| {
+        let options = vec![InterfaceDescriptionOption::IfTsResol(tsresol)];
+        let block = InterfaceDescriptionBlock {
+            linktype: DataLink::ETHERNET,
+            snaplen: 0,
+            options,
+        };
+        Interface::new(&block).unwrap()
+    };
+    let expected = Duration::new(1_700_000_000, 123_456_000);
+    assert_eq!(iface(6).timestamp(1_700_000_000_123_456), expected);
+    assert_eq!(iface(9).timestamp(1_700_000_000_123_456_000), expected);
+    let expected = Duration::new(1_700_000_000, 500_000_000);
+    assert_eq!(iface(0x81).timestamp(3_400_000_001), expected);
+}
@@ -0,0 +1,301 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use etherparse::{IpHeaders, PacketBuilder, PacketBuilderStep, TcpHeader};
+use pcap_file::{
+    pcapng::{
+        blocks::{
+            enhanced_packet::EnhancedPacketBlock,
+            interface_description::{InterfaceDescriptionBlock, InterfaceDescriptionOption},
+        },
+        PcapNgWriter,
+    },
+    DataLink, PcapError,
+};
+
+use crate::proxy::{
+    event::{ConnectionId, Direction, MapiEvent},
+    network::Addr,
+};
+
+/// Writes the traffic described by a stream of [MapiEvent]s to a PCAP-NG
+/// file. The TCP/IP headers are made up, the payloads and timestamps are real.
+pub struct Recorder {
+    writer: PcapNgWriter<BufWriter<File>>,
+    connections: HashMap<ConnectionId, Connection>,
+    /// When reading a capture file, the capture time of the most recent data.
+    /// Otherwise we use the current time.
+    clock: Option<Duration>,
+}
+
+struct Connection {
+    client: SocketAddr,
+    server: SocketAddr,
+    /// Next sequence number of the client and the server, respectively
+    seqno: [u32; 2],
+    /// Whether the client and the server, respectively, have sent a FIN
+    finished: [bool; 2],
+    /// The handshake is written just before the first packet so it gets the
+    /// right timestamp when reading a capture file
+    handshake_written: bool,
+    /// The client is on a Unix Domain socket, drop the initial '0' (0x30)
+    strip_unix0: bool,
+}
+
+impl Recorder {
+    const CLIENT_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 1];
+    const SERVER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 2];
+
+    /// Fabricated port for the server side of Unix Domain connections
+    const UNIX_SERVER_PORT: u16 = 50000;
+
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut writer = PcapNgWriter::new(file).map_err(pcap_error)?;
+        let interface = InterfaceDescriptionBlock {
+            linktype: DataLink::ETHERNET,
+            snaplen: 0,
+            // pcap-file writes the timestamps in nanoseconds
+            options: vec![InterfaceDescriptionOption::IfTsResol(9)],
+        };
+        writer.write_pcapng_block(interface).map_err(pcap_error)?;
+        Ok(Recorder {
+            writer,
+            connections: Default::default(),
+            clock: None,
+        })
+    }
+
+    pub fn handle(&mut self, event: &MapiEvent) -> io::Result<()> {
+        match event {
+            MapiEvent::Incoming { id, local, peer } => {
+                let conn = Connection::new(*id, local, peer);
+                self.connections.insert(*id, conn);
+            }
+            MapiEvent::Data {
+                id,
+                direction,
+                data,
+                timestamp,
+            } => {
+                if let Some(t) = timestamp {
+                    self.clock = Some(t.0);
+                }
+                let mut data = &data[..];
+                let Some(conn) = self.connections.get_mut(id) else {
+                    return Ok(());
+                };
+                if *direction == Direction::Upstream && conn.strip_unix0 && !data.is_empty() {
+                    conn.strip_unix0 = false;
+                    data = &data[1..];
+                }
+                if !data.is_empty() {
+                    self.send(*id, *direction, data, false)?;
+                }
+            }
+            MapiEvent::ShutdownRead { id, direction } => {
+                self.send(*id, *direction, &[], true)?;
+            }
+            MapiEvent::End { id, .. } | MapiEvent::Aborted { id, .. } => {
+                for direction in [Direction::Upstream, Direction::Downstream] {
+                    self.send(*id, direction, &[], true)?;
+                }
+                self.connections.remove(id);
+                self.writer.get_mut().flush()?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handshake(
+        &mut self,
+        client: SocketAddr,
+        server: SocketAddr,
+        [client_isn, server_isn]: [u32; 2],
+    ) -> io::Result<()> {
+        let syn = Self::builder(client, server, true)
+            .tcp(
+                client.port(),
+                server.port(),
+                client_isn.wrapping_sub(1),
+                0xFFFF,
+            )
+            .syn();
+        self.write_packet(syn, &[])?;
+        let syn_ack = Self::builder(server, client, false)
+            .tcp(
+                server.port(),
+                client.port(),
+                server_isn.wrapping_sub(1),
+                0xFFFF,
+            )
+            .syn()
+            .ack(client_isn);
+        self.write_packet(syn_ack, &[])?;
+        let ack = Self::builder(client, server, true)
+            .tcp(client.port(), server.port(), client_isn, 0xFFFF)
+            .ack(server_isn);
+        self.write_packet(ack, &[])
+    }
+
+    /// Write a packet with the given payload and/or a FIN from the sender of
+    /// the given direction.
+    fn send(
+        &mut self,
+        id: ConnectionId,
+        direction: Direction,
+        data: &[u8],
+        fin: bool,
+    ) -> io::Result<()> {
+        let Some(conn) = self.connections.get_mut(&id) else {
+            return Ok(());
+        };
+        if !conn.handshake_written {
+            conn.handshake_written = true;
+            let (client, server, seqno) = (conn.client, conn.server, conn.seqno);
+            self.handshake(client, server, seqno)?;
+            return self.send(id, direction, data, fin);
+        }
+        let (i, src, dst) = match direction {
+            Direction::Upstream => (0, conn.client, conn.server),
+            Direction::Downstream => (1, conn.server, conn.client),
+        };
+        if conn.finished[i] {
+            return Ok(());
+        }
+        let seqno = conn.seqno[i];
+        let ackno = conn.seqno[1 - i];
+        conn.seqno[i] = seqno.wrapping_add(data.len() as u32 + fin as u32);
+        conn.finished[i] = fin;
+
+        let builder = Self::builder(src, dst, i == 0)
+            .tcp(src.port(), dst.port(), seqno, 0xFFFF)
+            .ack(ackno);
+        let builder = if fin { builder.fin() } else { builder.psh() };
+        self.write_packet(builder, data)
+    }
+
+    fn builder(
+        src: SocketAddr,
+        dst: SocketAddr,
+        from_client: bool,
+    ) -> PacketBuilderStep<IpHeaders> {
+        let (src_mac, dst_mac) = if from_client {
+            (Self::CLIENT_MAC, Self::SERVER_MAC)
+        } else {
+            (Self::SERVER_MAC, Self::CLIENT_MAC)
+        };
+        let ether = PacketBuilder::ethernet2(src_mac, dst_mac);
+        match (src.ip(), dst.ip()) {
+            (IpAddr::V4(s), IpAddr::V4(d)) => ether.ipv4(s.octets(), d.octets(), 64),
+            (s, d) => ether.ipv6(to_v6(s).octets(), to_v6(d).octets(), 64),
+        }
+    }
+
+    fn write_packet(
+        &mut self,
+        builder: PacketBuilderStep<TcpHeader>,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let mut data = Vec::with_capacity(builder.size(payload.len()));
+        builder
+            .write(&mut data, payload)
+            .map_err(io::Error::other)?;
+        let timestamp = self.clock.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+        });
+        let packet = EnhancedPacketBlock {
+            interface_id: 0,
+            timestamp,
+            original_len: data.len() as u32,
+            data: Cow::Owned(data),
+            options: vec![],
+        };
+        self.writer.write_pcapng_block(packet).map_err(pcap_error)?;
+        Ok(())
+    }
+}
+
+impl Connection {
+    fn new(id: ConnectionId, local: &Addr, peer: &Addr) -> Self {
+        // Unix Domain sockets have no ports, make up something that is
+        // unique per connection.
+        let fake_port = 10000 + (id.number() % 50000) as u16;
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let (client, server, strip_unix0) = match (peer, local) {
+            (Addr::Tcp(p), Addr::Tcp(l)) => (*p, *l, false),
+            _ => (
+                SocketAddr::new(localhost, fake_port),
+                SocketAddr::new(localhost, Recorder::UNIX_SERVER_PORT),
+                true,
+            ),
+        };
+        Connection {
+            client,
+            server,
+            seqno: [1000, 5000],
+            finished: [false, false],
+            handshake_written: false,
+            strip_unix0,
+        }
+    }
+}
+
+/// Like [Recorder] but writes each connection to a separate file
+/// connN.pcapng in a directory.
+pub struct SplitRecorder {
+    dir: PathBuf,
+    recorders: HashMap<ConnectionId, Recorder>,
+}
+
+impl SplitRecorder {
+    pub fn create(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(SplitRecorder {
+            dir: dir.to_owned(),
+            recorders: Default::default(),
+        })
+    }
+
+    pub fn handle(&mut self, event: &MapiEvent) -> io::Result<()> {
+        let Some(id) = event.id() else {
+            return Ok(());
+        };
+        if let MapiEvent::Incoming { .. } = event {
+            let path = self.dir.join(format!("conn{}.pcapng", id.number()));
+            self.recorders.insert(id, Recorder::create(&path)?);
+        }
+        let Some(recorder) = self.recorders.get_mut(&id) else {
+            return Ok(());
+        };
+        recorder.handle(event)?;
+        if let MapiEvent::End { .. } | MapiEvent::Aborted { .. } = event {
+            self.recorders.remove(&id);
+        }
+        Ok(())
+    }
+}
+
+fn to_v6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn pcap_error(e: PcapError) -> io::Error {
+    match e {
+        PcapError::IoError(e) => e,
+        other => io::Error::other(other),
+    }
+}
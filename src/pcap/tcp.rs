@@ -1,13 +1,19 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
     io,
     net::{IpAddr, SocketAddr as TcpSocketAddr},
     ops::RangeFrom,
+    time::Duration,
 };
 
 use etherparse::TcpSlice;
 
-use crate::proxy::event::{ConnectionId, Direction, MapiEvent};
+use super::Options;
+use crate::mapi;
+use crate::proxy::{
+    counter::Counter,
+    event::{CaptureTime, ConnectionId, Direction, MapiEvent, TcpEvent, Timing, Traffic},
+};
 
 type Handler<'a> = dyn FnMut(MapiEvent) -> io::Result<()> + 'a;
 
@@ -38,23 +44,58 @@ pub struct TcpTracker {
     /// Container for the [StreamState]s. Once the connection is fully established,
     /// both its [Key] and its flipped ([Key::flip]) key will have an entry.
     streams: HashMap<Key, StreamState>,
+    /// How many bytes of out-of-order packets a stream may hold on to
+    max_pending: usize,
+    /// See [Options::tcp_events]
+    tcp_events: bool,
+    /// Endpoints of the open connections skipped because of
+    /// [Options::ports]. They are forgotten on FIN or RST.
+    ignored: HashSet<(TcpSocketAddr, TcpSocketAddr)>,
+    /// Number of connections skipped because of [Options::ports]
+    ignored_connections: u64,
+    /// Number of payload bytes in the packets of those connections
+    ignored_bytes: u64,
 }
 
 impl TcpTracker {
     /// Create a new, empty, TCP tracker.
-    pub fn new() -> Self {
+    pub fn new(options: &Options) -> Self {
         TcpTracker {
-            conn_ids: 10..,
+            conn_ids: options.first_id.unwrap_or(ConnectionId::FIRST)..,
             streams: Default::default(),
+            max_pending: options
+                .reorder_limit
+                .unwrap_or(StreamState::DEFAULT_MAX_PENDING),
+            tcp_events: options.tcp_events,
+            ignored: HashSet::new(),
+            ignored_connections: 0,
+            ignored_bytes: 0,
         }
     }
 
-    /// Handle a TCP packet.
+    /// The number of connections and bytes skipped because of
+    /// [Options::ports]
+    pub fn ignored(&self) -> (u64, u64) {
+        (self.ignored_connections, self.ignored_bytes)
+    }
+
+    /// The connection with these endpoints, if we're following it.
+    pub fn connection(&self, src: TcpSocketAddr, dest: TcpSocketAddr) -> Option<ConnectionId> {
+        let key = Key { src, dest };
+        self.streams.get(&key).map(|stream| stream.id)
+    }
+
+    /// Handle a TCP packet captured at the given time. The capture tool may
+    /// have cut `truncated` bytes off its end.
+    #[allow(clippy::too_many_arguments)]
     pub fn handle(
         &mut self,
+        options: &Options,
+        timestamp: Duration,
         src_addr: IpAddr,
         dest_addr: IpAddr,
         tcp: &TcpSlice,
+        truncated: usize,
         handler: &mut Handler,
     ) -> io::Result<()> {
         let key = Key {
@@ -62,14 +103,116 @@ impl TcpTracker {
             dest: (dest_addr, tcp.destination_port()).into(),
         };
 
+        let ports = [tcp.source_port(), tcp.destination_port()];
+        if !options.ports.is_empty() && !ports.iter().any(|p| options.ports.contains(p)) {
+            self.ignore(&key, tcp);
+            return Ok(());
+        }
+
         match (tcp.syn(), tcp.ack()) {
-            (true, false) => self.handle_syn(key, tcp, handler),
+            (true, false) => self.handle_syn(timestamp, key, tcp, handler),
             (true, true) => self.handle_syn_ack(key, tcp, handler),
-            _ => self.handle_existing(key, tcp, handler),
+            _ => {
+                if options.mid_stream && !self.streams.contains_key(&key) {
+                    self.adopt(options, timestamp, &key, tcp, handler)?;
+                }
+                self.handle_existing(timestamp, key, tcp, truncated, handler)
+            }
+        }
+    }
+
+    /// Count a packet of a connection skipped because of [Options::ports].
+    /// Only a SYN or data starts a connection, so the ACKs after the FINs
+    /// don't count it again.
+    fn ignore(&mut self, key: &Key, tcp: &TcpSlice) {
+        let endpoints = (key.src.min(key.dest), key.src.max(key.dest));
+        self.ignored_bytes += tcp.payload().len() as u64;
+        let known = self.ignored.contains(&endpoints);
+        if !known && (tcp.syn() || !tcp.payload().is_empty()) {
+            self.ignored_connections += 1;
+            self.ignored.insert(endpoints);
+        }
+        if tcp.fin() || tcp.rst() {
+            self.ignored.remove(&endpoints);
+        }
+    }
+
+    /// Start tracking a connection that was already open when the capture
+    /// started. We have to guess which side is the server: the one that uses
+    /// one of the configured ports or otherwise, the one with the lowest port
+    /// number because clients tend to use high ephemeral ports.
+    fn adopt(
+        &mut self,
+        options: &Options,
+        timestamp: Duration,
+        key: &Key,
+        tcp: &TcpSlice,
+        handler: &mut Handler,
+    ) -> io::Result<()> {
+        // Only data packets, otherwise the final ACKs of connections that
+        // have just ended would show up as new connections.
+        if tcp.payload().is_empty() || !tcp.ack() || tcp.fin() || tcp.rst() {
+            return Ok(());
+        }
+        let flipped = key.flip();
+        if self.streams.contains_key(&flipped) {
+            return Ok(());
+        }
+
+        let src_is_server = if options.ports.is_empty() {
+            key.src.port() < key.dest.port()
+        } else {
+            options.ports.contains(&key.src.port())
+        };
+        let (client, server) = if src_is_server {
+            (&key.dest, &key.src)
+        } else {
+            (&key.src, &key.dest)
+        };
+
+        let id = ConnectionId::new(self.conn_ids.next().unwrap());
+        for ev in [
+            MapiEvent::Incoming {
+                id,
+                local: (*server).into(),
+                peer: (*client).into(),
+            },
+            MapiEvent::JoinedMidStream { id },
+            MapiEvent::Connected {
+                id,
+                peer: (*server).into(),
+            },
+        ] {
+            handler(ev)?;
         }
+
+        // The ACK tells us where the other side is
+        let (up, down) = if src_is_server {
+            (tcp.acknowledgment_number(), tcp.sequence_number())
+        } else {
+            (tcp.sequence_number(), tcp.acknowledgment_number())
+        };
+        let mut upstream = StreamState::new(id, Direction::Upstream, up, timestamp);
+        let mut downstream = StreamState::new(id, Direction::Downstream, down, timestamp);
+        upstream.syncing = true;
+        downstream.syncing = true;
+        let (client_key, server_key) = if src_is_server {
+            (flipped, key.clone())
+        } else {
+            (key.clone(), flipped)
+        };
+        self.streams.insert(client_key, upstream);
+        self.streams.insert(server_key, downstream);
+        Ok(())
     }
 
-    fn handle_syn(&mut self, key: Key, tcp: &TcpSlice, handler: &mut Handler) -> io::Result<()> {
+    fn handle_syn(
+        &mut self,
+        timestamp: Duration,
+        key: Key,
+        tcp: &TcpSlice,
+        handler: &mut Handler,
+    ) -> io::Result<()> {
         let flipped = key.flip();
         if self.streams.contains_key(&key) || self.streams.contains_key(&flipped) {
             return Ok(());
@@ -78,7 +221,7 @@ impl TcpTracker {
         let seqno = tcp.sequence_number();
 
         let id = ConnectionId::new(self.conn_ids.next().unwrap());
-        let upstream = StreamState::new(id, Direction::Upstream, seqno.wrapping_add(1));
+        let upstream = StreamState::new(id, Direction::Upstream, seqno.wrapping_add(1), timestamp);
 
         let ev = MapiEvent::Incoming {
             id,
@@ -105,7 +248,9 @@ impl TcpTracker {
         let seqno = tcp.sequence_number();
 
         let id = upstream.id;
-        let downstream = StreamState::new(id, Direction::Downstream, seqno.wrapping_add(1));
+        let started = upstream.started;
+        let downstream =
+            StreamState::new(id, Direction::Downstream, seqno.wrapping_add(1), started);
 
         let ev = MapiEvent::Connected {
             id,
@@ -119,10 +264,26 @@ impl TcpTracker {
 
     fn handle_existing(
         &mut self,
+        timestamp: Duration,
         key: Key,
         tcp: &TcpSlice,
+        truncated: usize,
         handler: &mut Handler,
     ) -> io::Result<()> {
+        // If the other side acknowledges data we haven't seen, the capture
+        // has missed it.
+        if tcp.ack() {
+            let flipped = key.flip();
+            let ackno = tcp.acknowledgment_number();
+            while let Some(true) = self.streams.get(&flipped).map(|s| s.acked_gap(ackno)) {
+                self.skip_gap(&flipped, timestamp, handler)?;
+            }
+        }
+
+        if self.tcp_events {
+            self.tcp_events(timestamp, &key, tcp, handler)?;
+        }
+
         let Some(stream) = self.streams.get_mut(&key) else {
             return Ok(());
         };
@@ -131,21 +292,144 @@ impl TcpTracker {
         let direction = stream.dir;
 
         let seqno = tcp.sequence_number();
+        // The end of a truncated packet has been padded with zeroes, only the
+        // part before that has actually been captured.
         let payload = tcp.payload();
+        let missing = truncated.min(payload.len());
+        let payload = &payload[..payload.len() - missing];
+        if let Some(offset) = stream.check_retransmission(seqno, payload) {
+            let ev = MapiEvent::RetransmissionDiffers {
+                id,
+                direction,
+                offset,
+            };
+            handler(ev)?;
+        }
         // Packets may arrive in the wrong order.
         // If this is exactly the packet we're waiting for, stream.reorder will
         // return it. If it's a future packet, it will store it.
         // If it's a past packet, it will drop it.
-        let Some(payload) = stream.reorder(seqno, tcp.fin(), payload) else {
+        let Some((payload, missing)) = stream.reorder(seqno, tcp.fin(), payload, missing) else {
+            // Don't wait forever for data the capture has missed. Give up on
+            // it until the packets held back fit in the limit again.
+            while let Some(true) = self.streams.get(&key).map(|s| s.pending > self.max_pending) {
+                self.skip_gap(&key, timestamp, handler)?;
+            }
             return Ok(());
         };
-        Self::emit_data(id, direction, payload, handler)?;
+        if stream.synchronize(payload) {
+            stream.saw_data(timestamp, payload);
+            Self::emit_data(id, direction, timestamp, payload, handler)?;
+        }
+        Self::truncated(stream, missing, handler)?;
+
+        self.deliver(key, timestamp, handler)
+    }
 
-        // If stream.reorder above returned this packet, it means it was exactly
-        // the packet we needed right now. Packets do not always arrive in-order
-        // so it's possible that the next packet is already in our cache.
-        while let Some(payload) = stream.next_ready() {
-            Self::emit_data(id, direction, &payload, handler)?;
+    /// With [Options::tcp_events], report retransmissions, changes to and
+    /// from a zero window and resets.
+    fn tcp_events(
+        &mut self,
+        timestamp: Duration,
+        key: &Key,
+        tcp: &TcpSlice,
+        handler: &mut Handler,
+    ) -> io::Result<()> {
+        let Some(stream) = self.streams.get_mut(key) else {
+            return Ok(());
+        };
+        let mut events = vec![];
+        if tcp.rst() {
+            events.push(TcpEvent::Reset);
+        } else {
+            let bytes = tcp.payload().len();
+            let behind = stream.waiting_for.wrapping_sub(tcp.sequence_number()) as i32;
+            // Keep-alives repeat the last byte, or pretend to
+            let keepalive = behind == 1 && bytes <= 1;
+            if behind > 0 && bytes > 0 && !keepalive {
+                events.push(TcpEvent::Retransmission { bytes });
+            }
+            match (tcp.window_size(), stream.zero_window_since) {
+                (0, None) => {
+                    stream.zero_window_since = Some(timestamp);
+                    events.push(TcpEvent::ZeroWindow);
+                }
+                (1.., Some(since)) => {
+                    stream.zero_window_since = None;
+                    let stalled = timestamp.saturating_sub(since);
+                    events.push(TcpEvent::WindowOpened { stalled });
+                }
+                _ => {}
+            }
+        }
+        for event in events {
+            let ev = MapiEvent::Tcp {
+                id: stream.id,
+                direction: stream.dir,
+                event,
+            };
+            handler(ev)?;
+        }
+        Ok(())
+    }
+
+    /// Called at the end of the capture. Skip over any data the capture has
+    /// missed so the data after it can be delivered.
+    pub fn finish(&mut self, timestamp: Duration, handler: &mut Handler) -> io::Result<()> {
+        let mut keys: Vec<Key> = self.streams.keys().cloned().collect();
+        keys.sort_by_key(|k| {
+            self.streams
+                .get(k)
+                .map(|s| (s.id, s.dir == Direction::Downstream))
+        });
+        for key in keys {
+            while let Some(false) = self.streams.get(&key).map(|s| s.waiting.is_empty()) {
+                self.skip_gap(&key, timestamp, handler)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Report the data missing before the earliest packet that is waiting to
+    /// be delivered, and continue after it.
+    fn skip_gap(
+        &mut self,
+        key: &Key,
+        timestamp: Duration,
+        handler: &mut Handler,
+    ) -> io::Result<()> {
+        let Some(stream) = self.streams.get_mut(key) else {
+            return Ok(());
+        };
+        let Some(missing) = stream.skip_gap() else {
+            return Ok(());
+        };
+        let ev = MapiEvent::DataMissing {
+            id: stream.id,
+            direction: stream.dir,
+            bytes: missing as u64,
+        };
+        handler(ev)?;
+        self.deliver(key.clone(), timestamp, handler)
+    }
+
+    /// Emit the data that has become available now that the packet we were
+    /// waiting for has arrived.
+    fn deliver(&mut self, key: Key, timestamp: Duration, handler: &mut Handler) -> io::Result<()> {
+        let Some(stream) = self.streams.get_mut(&key) else {
+            return Ok(());
+        };
+        let id = stream.id;
+        let direction = stream.dir;
+
+        // Packets do not always arrive in-order so it's possible that the
+        // next packet is already in our cache.
+        while let Some((payload, missing)) = stream.next_ready() {
+            if stream.synchronize(&payload) {
+                stream.saw_data(timestamp, &payload);
+                Self::emit_data(id, direction, timestamp, &payload, handler)?;
+            }
+            Self::truncated(stream, missing, handler)?;
         }
 
         // Stream.finished is set by stream.reorder and stream.next_ready.
@@ -161,18 +445,55 @@ impl TcpTracker {
 
         let flipped = key.flip();
         if let Some(StreamState { finished: true, .. }) = self.streams.get(&flipped) {
-            self.streams.remove(&key);
-            self.streams.remove(&flipped);
-            let ev = MapiEvent::End { id };
+            let this = self.streams.remove(&key).unwrap();
+            let other = self.streams.remove(&flipped).unwrap();
+            let (upstream, downstream) = match direction {
+                Direction::Upstream => (this, other),
+                Direction::Downstream => (other, this),
+            };
+            let traffic = Traffic {
+                upstream: upstream.counter.volume(),
+                downstream: downstream.counter.volume(),
+            };
+            let data_seen = match (upstream.data_seen, downstream.data_seen) {
+                (Some((f1, l1)), Some((f2, l2))) => Some((f1.min(f2), l1.max(l2))),
+                (a, b) => a.or(b),
+            };
+            let timing = Timing::between(upstream.started, data_seen, timestamp);
+            let ev = MapiEvent::End {
+                id,
+                traffic,
+                timing,
+            };
             handler(ev)?;
         }
 
         Ok(())
     }
 
+    /// Report the bytes cut off the end of a truncated packet. The MAPI block
+    /// structure is lost so we have to look for it again.
+    fn truncated(
+        stream: &mut StreamState,
+        missing: usize,
+        handler: &mut Handler,
+    ) -> io::Result<()> {
+        if missing == 0 {
+            return Ok(());
+        }
+        stream.resync();
+        let ev = MapiEvent::DataMissing {
+            id: stream.id,
+            direction: stream.dir,
+            bytes: missing as u64,
+        };
+        handler(ev)
+    }
+
     fn emit_data(
         id: ConnectionId,
         direction: Direction,
+        timestamp: Duration,
         payload: &[u8],
         handler: &mut Handler,
     ) -> io::Result<()> {
@@ -181,6 +502,7 @@ impl TcpTracker {
                 id,
                 direction,
                 data: payload.into(),
+                timestamp: Some(CaptureTime(timestamp)),
             };
             handler(ev)?;
         }
@@ -196,27 +518,119 @@ struct StreamState {
     id: ConnectionId,
     /// Is it upstream traffix (client to server) or downstream (server to client)
     dir: Direction,
+    /// Sequence number of the first byte of the stream
+    first_seqno: u32,
     /// Sequence number of the next byte we hope to receive.
     waiting_for: u32,
+    /// The most recently delivered bytes, to compare retransmissions with
+    delivered: VecDeque<u8>,
     /// Packets with sequence numbers higher than [Self::waiting_for] we have
     /// already received.
-    waiting: HashMap<u32, (Vec<u8>, bool)>,
+    /// Also holds the number of bytes the capture tool cut off the end.
+    waiting: HashMap<u32, (Vec<u8>, usize, bool)>,
+    /// Total size of the packets in [Self::waiting]
+    pending: usize,
     /// If no more packets will arrive
     finished: bool,
+    /// Bytes and messages seen so far
+    counter: Counter,
+    /// Capture time of the SYN packet that started the connection
+    started: Duration,
+    /// Capture times of the first and the last packet carrying data
+    data_seen: Option<(Duration, Duration)>,
+    /// We joined the connection mid-stream and are skipping data until we
+    /// find something that looks like the start of a MAPI block
+    syncing: bool,
+    /// Capture time of the packet in which the sender advertised a zero
+    /// window, if it hasn't opened it again yet
+    zero_window_since: Option<Duration>,
 }
 
 impl StreamState {
     /// Create a new [StreamState]
-    fn new(id: ConnectionId, dir: Direction, seqno: u32) -> Self {
+    fn new(id: ConnectionId, dir: Direction, seqno: u32, started: Duration) -> Self {
         StreamState {
             id,
             dir,
+            first_seqno: seqno,
             waiting_for: seqno,
+            delivered: VecDeque::new(),
             waiting: Default::default(),
+            pending: 0,
             finished: false,
+            counter: Counter::new(false),
+            started,
+            data_seen: None,
+            syncing: false,
+            zero_window_since: None,
         }
     }
 
+    /// How many delivered bytes to keep around to compare retransmissions with
+    const HISTORY_SIZE: usize = 65536;
+
+    /// If this many bytes are waiting for a packet that hasn't arrived, assume
+    /// the capture has missed it. See [Options::reorder_limit].
+    const DEFAULT_MAX_PENDING: usize = 1 << 20;
+
+    /// Return false if this payload should be skipped because we're still
+    /// looking for the start of a MAPI block. We assume blocks start at the
+    /// start of a segment and check whether the segment can be interpreted as
+    /// a sequence of blocks.
+    fn synchronize(&mut self, payload: &[u8]) -> bool {
+        if !self.syncing || payload.is_empty() {
+            return true;
+        }
+        let mut pos = 0;
+        while let Some(&[lo, hi]) = payload.get(pos..pos + 2) {
+            let Some((len, _)) = mapi::block_header(lo, hi) else {
+                return false;
+            };
+            pos += 2 + len;
+        }
+        // A single byte left over cannot be a header
+        if pos == payload.len() - 1 {
+            return false;
+        }
+        self.syncing = false;
+        true
+    }
+
+    /// If this packet is a retransmission of data we have already delivered,
+    /// check that it's identical to the original. If not, return the position
+    /// in the stream of the first byte that differs. This happens when the
+    /// capture was corrupted, for example by checksum offloading or by a
+    /// middlebox mangling the traffic.
+    fn check_retransmission(&self, seqno: u32, payload: &[u8]) -> Option<u64> {
+        let behind = self.waiting_for.wrapping_sub(seqno) as i32;
+        if behind <= 0 {
+            return None;
+        }
+        // payload[i] has been delivered as delivered[start + i]
+        let start = self.delivered.len() as isize - behind as isize;
+        let overlap = payload.len().min(behind as usize);
+        let differs = (0..overlap).find(|&i| {
+            let Ok(pos) = usize::try_from(start + i as isize) else {
+                return false;
+            };
+            self.delivered[pos] != payload[i]
+        })?;
+        let offset = seqno
+            .wrapping_add(differs as u32)
+            .wrapping_sub(self.first_seqno);
+        Some(offset as u64)
+    }
+
+    /// Update the counters when data is about to be emitted
+    fn saw_data(&mut self, timestamp: Duration, payload: &[u8]) {
+        if payload.is_empty() {
+            return;
+        }
+        self.counter.count(payload);
+        let first = self.data_seen.map_or(timestamp, |(first, _)| first);
+        self.data_seen = Some((first, timestamp));
+    }
+
     /// Check for duplicate packets and packets that arrive in the wrong order
     /// based on the sequence number. If this is exactly the sequence number we
     /// were waiting for, return it. If we've already processed this sequence
@@ -225,9 +639,18 @@ impl StreamState {
     ///
     /// When this function returns Some, [Self::next_ready] MUST be called next to
     /// retrieve any stored 'future' packets that can now be processed.
-    fn reorder<'a>(&'a mut self, seqno: u32, fin: bool, payload: &'a [u8]) -> Option<&'a [u8]> {
+    ///
+    /// Truncated packets are passed with the number of bytes that are missing
+    /// after the payload. That number is returned with the payload.
+    fn reorder<'a>(
+        &mut self,
+        seqno: u32,
+        fin: bool,
+        payload: &'a [u8],
+        missing: usize,
+    ) -> Option<(&'a [u8], usize)> {
         if self.waiting_for == seqno {
-            return self.yield_payload(payload, fin);
+            return self.yield_payload(payload, missing, fin);
         }
 
         // Discard packets we've already seen. Be careful with wraparound.
@@ -239,25 +662,154 @@ impl StreamState {
             return None;
         }
 
-        self.waiting.insert(seqno, (payload.to_owned(), fin));
+        self.pending += payload.len();
+        let entry = (payload.to_owned(), missing, fin);
+        if let Some((old, ..)) = self.waiting.insert(seqno, entry) {
+            self.pending -= old.len();
+        }
         None
     }
 
+    /// True if the other side has acknowledged data up to `ackno` and that
+    /// includes data we haven't seen before the packets we're holding on to.
+    fn acked_gap(&self, ackno: u32) -> bool {
+        let acked = ackno.wrapping_sub(self.waiting_for) as i32;
+        acked > 0
+            && self
+                .earliest_waiting()
+                .is_some_and(|seqno| seqno <= acked as u32)
+    }
+
+    /// Distance from [Self::waiting_for] to the earliest packet in
+    /// [Self::waiting].
+    fn earliest_waiting(&self) -> Option<u32> {
+        self.waiting
+            .keys()
+            .map(|seqno| seqno.wrapping_sub(self.waiting_for))
+            .filter(|delta| (*delta as i32) >= 0)
+            .min()
+    }
+
+    /// Give up on the data before the earliest waiting packet and continue
+    /// from there. Returns the number of bytes skipped. The MAPI block
+    /// structure is lost so we have to look for it again.
+    fn skip_gap(&mut self) -> Option<u32> {
+        let Some(missing) = self.earliest_waiting() else {
+            // only stale packets left
+            self.waiting.clear();
+            self.pending = 0;
+            return None;
+        };
+        self.waiting_for = self.waiting_for.wrapping_add(missing);
+        self.resync();
+        Some(missing)
+    }
+
+    /// Forget where the MAPI blocks are after data has gone missing
+    fn resync(&mut self) {
+        self.delivered.clear();
+        self.counter.resync();
+        self.syncing = true;
+    }
+
     /// If the sequence number we're waiting for already exists in the map, return it.
     /// Call this repeatedly when [Self::reorder] has returned Some.
-    fn next_ready(&mut self) -> Option<Vec<u8>> {
-        if let Some((payload, fin)) = self.waiting.remove(&self.waiting_for) {
-            self.yield_payload(payload, fin)
+    fn next_ready(&mut self) -> Option<(Vec<u8>, usize)> {
+        if let Some((payload, missing, fin)) = self.waiting.remove(&self.waiting_for) {
+            self.pending -= payload.len();
+            self.yield_payload(payload, missing, fin)
         } else {
             None
         }
     }
 
     /// Update the bookkeeping before returning the packet.
-    fn yield_payload<T: AsRef<[u8]>>(&mut self, payload: T, fin: bool) -> Option<T> {
+    fn yield_payload<T: AsRef<[u8]>>(
+        &mut self,
+        payload: T,
+        missing: usize,
+        fin: bool,
+    ) -> Option<(T, usize)> {
         self.finished |= fin;
-        let n = payload.as_ref().len() as u32;
-        self.waiting_for = self.waiting_for.wrapping_add(n);
-        Some(payload)
+        let data = payload.as_ref();
+        let len = data.len() + missing;
+        self.waiting_for = self.waiting_for.wrapping_add(len as u32);
+        self.delivered.extend(data);
+        let excess = self.delivered.len().saturating_sub(Self::HISTORY_SIZE);
+        self.delivered.drain(..excess);
+        Some((payload, missing))
+    }
+}
+
+#[test]
+fn test_synchronize() {
+    let id = ConnectionId::new(1);
+    let mut stream = StreamState::new(id, Direction::Upstream, 0, Duration::ZERO);
+    stream.syncing = true;
+    // tail end of a block
+    assert!(!stream.synchronize(b"lo world"));
+    // a lone byte cannot be a header
+    assert!(!stream.synchronize(b"\x0b\x00hello\x0b"));
+    // 'hello' in two blocks
+    assert!(stream.synchronize(b"\x06\x00hel\x05\x00lo"));
+    // once synchronized, everything is passed through
+    assert!(stream.synchronize(b"lo world"));
+}
+
+#[test]
+fn test_check_retransmission() {
+    let id = ConnectionId::new(1);
+    let mut stream = StreamState::new(id, Direction::Upstream, u32::MAX - 2, Duration::ZERO);
+    stream.yield_payload(b"hello", 0, false);
+    stream.yield_payload(b" world", 0, false);
+    let seqno = |offset: u32| (u32::MAX - 2).wrapping_add(offset);
+    assert_eq!(stream.check_retransmission(seqno(5), b" world"), None);
+    assert_eq!(
+        stream.check_retransmission(seqno(2), b"llo wurld!!"),
+        Some(7)
+    );
+    // future data is not checked
+    assert_eq!(stream.check_retransmission(seqno(11), b"xyz"), None);
+}
+
+#[test]
+fn test_ignored_connections() {
+    use etherparse::TcpHeader;
+
+    let options = Options {
+        ports: vec![50000],
+        ..Options::default()
+    };
+    let mut tracker = TcpTracker::new(&options);
+    let client: IpAddr = "10.0.0.1".parse().unwrap();
+    let server: IpAddr = "10.0.0.2".parse().unwrap();
+    let mut send = |from_client: bool, flags: &str, payload: &[u8]| {
+        let (src, dest, ports) = if from_client {
+            (client, server, (40000, 80))
+        } else {
+            (server, client, (80, 40000))
+        };
+        let mut header = TcpHeader::new(ports.0, ports.1, 0, 1000);
+        header.syn = flags.contains('S');
+        header.ack = flags.contains('A');
+        header.fin = flags.contains('F');
+        let packet = [header.to_bytes().as_slice(), payload].concat();
+        let tcp = TcpSlice::from_slice(&packet).unwrap();
+        let mut handler = |_| Ok(());
+        tracker
+            .handle(&options, Duration::ZERO, src, dest, &tcp, 0, &mut handler)
+            .unwrap();
+    };
+
+    // The same endpoints twice, each time ending with FINs and ACKs
+    for _ in 0..2 {
+        send(true, "S", b"");
+        send(false, "SA", b"");
+        send(true, "A", b"GET");
+        send(true, "FA", b"");
+        send(false, "FA", b"");
+        send(true, "A", b"");
     }
+    assert_eq!(tracker.ignored(), (2, 6));
+    assert!(tracker.ignored.is_empty());
 }
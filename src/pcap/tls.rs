@@ -0,0 +1,768 @@
+//! Decrypt the TLS connections found in a capture so the MAPI traffic inside
+//! can be decoded. The secrets come from a [KeyLog]. Only the AEAD cipher
+//! suites of TLS 1.2 and the cipher suites of TLS 1.3 are supported.
+
+use std::{collections::HashMap, io, mem};
+
+use aes_gcm::{
+    aead::{generic_array::GenericArray, Aead, KeyInit, Payload},
+    Aes128Gcm, Aes256Gcm,
+};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Sha384};
+
+use crate::proxy::{
+    counter::Counter,
+    event::{CaptureTime, ConnectionId, Direction, MapiEvent, Traffic},
+    sni::Reader,
+};
+
+use super::keylog::KeyLog;
+
+type Handler<'a> = dyn FnMut(MapiEvent) -> io::Result<()> + 'a;
+
+/// Give up on a handshake if this much data has been exchanged without a
+/// ServerHello
+const MAX_HANDSHAKE_SIZE: usize = 65536;
+
+/// Records are at most 2^14 bytes plus the encryption overhead
+const MAX_RECORD_SIZE: usize = (1 << 14) + 256;
+
+/// The random of a ServerHello that is actually a HelloRetryRequest
+const HELLO_RETRY_REQUEST: [u8; 32] = [
+    0xCF, 0x21, 0xAD, 0x74, 0xE5, 0x9A, 0x61, 0x11, 0xBE, 0x1D, 0x8C, 0x02, 0x1E, 0x65, 0xB8, 0x91,
+    0xC2, 0xA2, 0x11, 0x16, 0x7A, 0xBB, 0x8C, 0x5E, 0x07, 0x9E, 0x09, 0xE2, 0xC8, 0xA8, 0x33, 0x9C,
+];
+
+// Record content types
+const CHANGE_CIPHER_SPEC: u8 = 20;
+const HANDSHAKE: u8 = 22;
+const APPLICATION_DATA: u8 = 23;
+
+// Handshake message types
+const CLIENT_HELLO: u8 = 1;
+const SERVER_HELLO: u8 = 2;
+const FINISHED: u8 = 20;
+const KEY_UPDATE: u8 = 24;
+
+/// Sits between the TCP tracker and the event handler and replaces the
+/// encrypted data of TLS connections with the decrypted data.
+#[derive(Default)]
+pub struct TlsDecoder {
+    keylog: KeyLog,
+    conns: HashMap<ConnectionId, Conn>,
+}
+
+enum Conn {
+    /// Waiting for the first data to see whether it's TLS
+    Detecting,
+    /// Not TLS, or TLS we can't decrypt. Data is passed on unchanged.
+    Passthrough,
+    Tls(Box<Session>),
+}
+
+impl TlsDecoder {
+    /// Add the secrets from the text of a key log.
+    pub fn add_key_log(&mut self, text: &str) {
+        self.keylog.add(text);
+    }
+
+    pub fn handle(&mut self, ev: MapiEvent, handler: &mut Handler) -> io::Result<()> {
+        if self.keylog.is_empty() {
+            return handler(ev);
+        }
+        match ev {
+            MapiEvent::Incoming { id, .. } => {
+                self.conns.insert(id, Conn::Detecting);
+            }
+            MapiEvent::JoinedMidStream { id } => {
+                // we can't decrypt without the handshake
+                self.conns.insert(id, Conn::Passthrough);
+            }
+            MapiEvent::Data {
+                id,
+                direction,
+                ref data,
+                timestamp,
+            } => {
+                let Some(conn) = self.conns.get_mut(&id) else {
+                    return handler(ev);
+                };
+                if let Conn::Detecting = conn {
+                    // The client speaks first in TLS, the server in MAPI
+                    let client_hello =
+                        direction == Direction::Upstream && data.starts_with(&[HANDSHAKE, 0x03]);
+                    *conn = if client_hello {
+                        Conn::Tls(Box::new(Session::new(id)))
+                    } else {
+                        Conn::Passthrough
+                    };
+                }
+                if let Conn::Tls(session) = conn {
+                    if !session.data(direction, data, timestamp, &self.keylog, handler)? {
+                        *conn = Conn::Passthrough;
+                    }
+                    return Ok(());
+                }
+            }
+            MapiEvent::DataMissing { id, direction, .. } => {
+                if let Some(conn) = self.conns.get_mut(&id) {
+                    let Conn::Tls(session) = conn else {
+                        return handler(ev);
+                    };
+                    if !session.lost(direction, handler)? {
+                        *conn = Conn::Passthrough;
+                    }
+                }
+            }
+            MapiEvent::End {
+                id,
+                traffic,
+                timing,
+            } => {
+                self.closing(id, handler)?;
+                let traffic = self.traffic(id, traffic);
+                let ev = MapiEvent::End {
+                    id,
+                    traffic,
+                    timing,
+                };
+                return handler(ev);
+            }
+            MapiEvent::Aborted {
+                id,
+                error,
+                traffic,
+                timing,
+            } => {
+                self.closing(id, handler)?;
+                let traffic = self.traffic(id, traffic);
+                let ev = MapiEvent::Aborted {
+                    id,
+                    error,
+                    traffic,
+                    timing,
+                };
+                return handler(ev);
+            }
+            MapiEvent::ShutdownRead { id, .. } => self.closing(id, handler)?,
+            _ => {}
+        }
+        handler(ev)
+    }
+
+    /// The connection is being closed. If that happens before the handshake
+    /// is complete, pass on the data we held back.
+    fn closing(&mut self, id: ConnectionId, handler: &mut Handler) -> io::Result<()> {
+        let Some(conn) = self.conns.get_mut(&id) else {
+            return Ok(());
+        };
+        if let Conn::Tls(session) = conn {
+            if session.suite.is_none() {
+                session.give_up("TLS handshake did not complete".to_string(), handler)?;
+                *conn = Conn::Passthrough;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget the connection. If it was decrypted, the traffic is what was
+    /// sent inside TLS rather than the TLS records.
+    fn traffic(&mut self, id: ConnectionId, traffic: Traffic) -> Traffic {
+        match self.conns.remove(&id) {
+            Some(Conn::Tls(session)) => Traffic {
+                upstream: session.halves[0].counter.volume(),
+                downstream: session.halves[1].counter.volume(),
+            },
+            _ => traffic,
+        }
+    }
+}
+
+/// A TLS connection we are trying to decrypt
+struct Session {
+    id: ConnectionId,
+    /// Until we know whether we can decrypt, the data we have received so
+    /// far. It is passed on unchanged if we can't.
+    held: Vec<(Direction, Vec<u8>, Option<CaptureTime>)>,
+    held_size: usize,
+    client_random: Option<[u8; 32]>,
+    /// Known once the ServerHello has been processed
+    suite: Option<Suite>,
+    /// Upstream and downstream
+    halves: [Half; 2],
+}
+
+/// One direction of a TLS connection
+struct Half {
+    /// Incomplete record
+    records: Vec<u8>,
+    /// Incomplete handshake message
+    handshake: Vec<u8>,
+    /// Decrypts the records
+    decrypter: Option<Decrypter>,
+    /// Takes over after a ChangeCipherSpec (TLS 1.2) or Finished (TLS 1.3)
+    next: Option<Decrypter>,
+    /// Counts the decrypted data
+    counter: Counter,
+    /// Decryption has failed, drop the rest of the data
+    broken: bool,
+}
+
+impl Half {
+    fn new() -> Self {
+        Half {
+            records: vec![],
+            handshake: vec![],
+            decrypter: None,
+            next: None,
+            counter: Counter::new(false),
+            broken: false,
+        }
+    }
+}
+
+impl Session {
+    fn new(id: ConnectionId) -> Self {
+        Session {
+            id,
+            held: vec![],
+            held_size: 0,
+            client_random: None,
+            suite: None,
+            halves: [Half::new(), Half::new()],
+        }
+    }
+
+    /// Process data received in the given direction. Returns false if we
+    /// have given up and the data of this connection should be passed on
+    /// unchanged from now on.
+    fn data(
+        &mut self,
+        direction: Direction,
+        data: &[u8],
+        timestamp: Option<CaptureTime>,
+        keylog: &KeyLog,
+        handler: &mut Handler,
+    ) -> io::Result<bool> {
+        let decrypting = self.suite.is_some();
+        if !decrypting {
+            self.held.push((direction, data.to_vec(), timestamp));
+            self.held_size += data.len();
+        }
+
+        let d = direction as usize;
+        if self.halves[d].broken {
+            return Ok(true);
+        }
+        let mut plain = vec![];
+        let result = self.records(d, data, keylog, &mut plain);
+
+        if !decrypting {
+            if let Err(reason) = result {
+                return self.give_up(reason, handler);
+            }
+            let Some(suite) = self.suite else {
+                if self.held_size > MAX_HANDSHAKE_SIZE {
+                    return self.give_up("no ServerHello found".to_string(), handler);
+                }
+                return Ok(true);
+            };
+            self.held.clear();
+            let ev = MapiEvent::TlsDecrypted {
+                id: self.id,
+                version: if suite.tls13 { "1.3" } else { "1.2" },
+            };
+            handler(ev)?;
+        }
+
+        for data in plain {
+            self.halves[d].counter.count(&data);
+            let ev = MapiEvent::Data {
+                id: self.id,
+                direction,
+                data: data.into(),
+                timestamp,
+            };
+            handler(ev)?;
+        }
+        if let Err(reason) = result {
+            self.halves[d].broken = true;
+            let ev = MapiEvent::TlsNotDecrypted {
+                id: self.id,
+                direction: Some(direction),
+                reason,
+            };
+            handler(ev)?;
+        }
+        Ok(true)
+    }
+
+    /// Part of the data in this direction is missing from the capture. We
+    /// can't find the records after the gap.
+    fn lost(&mut self, direction: Direction, handler: &mut Handler) -> io::Result<bool> {
+        let reason = "data is missing from the capture".to_string();
+        if self.suite.is_none() {
+            return self.give_up(reason, handler);
+        }
+        let half = &mut self.halves[direction as usize];
+        if !half.broken {
+            half.broken = true;
+            let ev = MapiEvent::TlsNotDecrypted {
+                id: self.id,
+                direction: Some(direction),
+                reason,
+            };
+            handler(ev)?;
+        }
+        Ok(true)
+    }
+
+    /// Report why we can't decrypt and pass on the data we held back.
+    fn give_up(&mut self, reason: String, handler: &mut Handler) -> io::Result<bool> {
+        let ev = MapiEvent::TlsNotDecrypted {
+            id: self.id,
+            direction: None,
+            reason,
+        };
+        handler(ev)?;
+        for (direction, data, timestamp) in mem::take(&mut self.held) {
+            let ev = MapiEvent::Data {
+                id: self.id,
+                direction,
+                data: data.into(),
+                timestamp,
+            };
+            handler(ev)?;
+        }
+        Ok(false)
+    }
+
+    /// Split the data into records and process the complete ones. The
+    /// decrypted application data is appended to `plain`.
+    fn records(
+        &mut self,
+        d: usize,
+        data: &[u8],
+        keylog: &KeyLog,
+        plain: &mut Vec<Vec<u8>>,
+    ) -> Result<(), String> {
+        let mut buf = mem::take(&mut self.halves[d].records);
+        buf.extend_from_slice(data);
+        let mut pos = 0;
+        let mut result = Ok(());
+        while let Some(header) = buf.get(pos..pos + 5) {
+            let header: [u8; 5] = header.try_into().unwrap();
+            let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+            if header[1] != 0x03 || len > MAX_RECORD_SIZE {
+                result = Err("invalid TLS record header".to_string());
+                break;
+            }
+            let Some(body) = buf.get(pos + 5..pos + 5 + len) else {
+                break;
+            };
+            result = self.record(d, header, body, keylog, plain);
+            if result.is_err() {
+                break;
+            }
+            pos += 5 + len;
+        }
+        buf.drain(..pos);
+        self.halves[d].records = buf;
+        result
+    }
+
+    fn record(
+        &mut self,
+        d: usize,
+        header: [u8; 5],
+        body: &[u8],
+        keylog: &KeyLog,
+        plain: &mut Vec<Vec<u8>>,
+    ) -> Result<(), String> {
+        let tls13 = self.suite.is_some_and(|s| s.tls13);
+        let half = &mut self.halves[d];
+        if header[0] == CHANGE_CIPHER_SPEC {
+            // In TLS 1.3 these are only sent for compatibility
+            if !tls13 {
+                if let Some(next) = half.next.take() {
+                    half.decrypter = Some(next);
+                }
+            }
+            return Ok(());
+        }
+        let (content_type, data) = match &mut half.decrypter {
+            Some(decrypter) => decrypter.decrypt(header, body)?,
+            None => (header[0], body.to_vec()),
+        };
+        match content_type {
+            APPLICATION_DATA if half.decrypter.is_some() => plain.push(data),
+            HANDSHAKE => {
+                half.handshake.extend_from_slice(&data);
+                while let Some(message) = take_handshake_message(&mut self.halves[d].handshake) {
+                    self.handshake_message(d, &message, keylog)?;
+                }
+            }
+            // alerts and such
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handshake_message(
+        &mut self,
+        d: usize,
+        message: &[u8],
+        keylog: &KeyLog,
+    ) -> Result<(), String> {
+        let tls13 = self.suite.is_some_and(|s| s.tls13);
+        let half = &mut self.halves[d];
+        match message[0] {
+            CLIENT_HELLO if d == Direction::Upstream as usize => {
+                // handshake header, legacy_version, random
+                let Some(random) = message.get(6..38) else {
+                    return Err("invalid ClientHello".to_string());
+                };
+                self.client_random = Some(random.try_into().unwrap());
+            }
+            SERVER_HELLO if d == Direction::Downstream as usize && self.suite.is_none() => {
+                self.server_hello(message, keylog)?;
+            }
+            FINISHED if tls13 => {
+                if let Some(next) = half.next.take() {
+                    half.decrypter = Some(next);
+                }
+            }
+            KEY_UPDATE if tls13 => {
+                if let Some(decrypter) = &mut half.decrypter {
+                    let secret =
+                        decrypter
+                            .suite
+                            .expand_label(&decrypter.secret, "traffic upd", None)?;
+                    *decrypter = Decrypter::tls13(decrypter.suite, secret)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn server_hello(&mut self, message: &[u8], keylog: &KeyLog) -> Result<(), String> {
+        let invalid = || "invalid ServerHello".to_string();
+        let mut r = Reader(&message[4..]);
+        r.take(2).ok_or_else(invalid)?;
+        let server_random: [u8; 32] = r.take(32).ok_or_else(invalid)?.try_into().unwrap();
+        if server_random == HELLO_RETRY_REQUEST {
+            // another ClientHello and ServerHello will follow
+            return Ok(());
+        }
+        let n = r.u8().ok_or_else(invalid)?;
+        r.take(n).ok_or_else(invalid)?;
+        let cipher_suite = r.u16().ok_or_else(invalid)? as u16;
+        r.u8().ok_or_else(invalid)?;
+
+        // TLS 1.3 announces itself in the supported_versions extension
+        let mut tls13 = false;
+        if let Some(n) = r.u16() {
+            let mut extensions = Reader(r.take(n).ok_or_else(invalid)?);
+            while !extensions.0.is_empty() {
+                let ext_type = extensions.u16().ok_or_else(invalid)?;
+                let n = extensions.u16().ok_or_else(invalid)?;
+                let ext = extensions.take(n).ok_or_else(invalid)?;
+                if ext_type == 43 {
+                    tls13 = ext == [0x03, 0x04];
+                }
+            }
+        }
+
+        let Some(suite) = Suite::lookup(cipher_suite).filter(|s| s.tls13 == tls13) else {
+            return Err(format!("unsupported cipher suite {cipher_suite:#06x}"));
+        };
+        let Some(client_random) = self.client_random else {
+            return Err("no ClientHello found".to_string());
+        };
+        let secret = |label: &str| {
+            keylog
+                .get(label, &client_random)
+                .ok_or_else(|| format!("no {label} for this connection in the key log"))
+        };
+
+        let [client, server] = &mut self.halves;
+        if tls13 {
+            client.decrypter = Some(Decrypter::tls13(
+                suite,
+                secret("CLIENT_HANDSHAKE_TRAFFIC_SECRET")?.to_vec(),
+            )?);
+            client.next = Some(Decrypter::tls13(
+                suite,
+                secret("CLIENT_TRAFFIC_SECRET_0")?.to_vec(),
+            )?);
+            server.decrypter = Some(Decrypter::tls13(
+                suite,
+                secret("SERVER_HANDSHAKE_TRAFFIC_SECRET")?.to_vec(),
+            )?);
+            server.next = Some(Decrypter::tls13(
+                suite,
+                secret("SERVER_TRAFFIC_SECRET_0")?.to_vec(),
+            )?);
+        } else {
+            let master_secret = secret("CLIENT_RANDOM")?;
+            let seed = [&server_random[..], &client_random[..]].concat();
+            let (key_len, iv_len) = (suite.key_len(), suite.tls12_iv_len());
+            let key_block = suite.prf(
+                master_secret,
+                b"key expansion",
+                &seed,
+                2 * key_len + 2 * iv_len,
+            );
+            let (client_key, rest) = key_block.split_at(key_len);
+            let (server_key, rest) = rest.split_at(key_len);
+            let (client_iv, server_iv) = rest.split_at(iv_len);
+            client.next = Some(Decrypter::tls12(suite, client_key, client_iv)?);
+            server.next = Some(Decrypter::tls12(suite, server_key, server_iv)?);
+        }
+        self.suite = Some(suite);
+        Ok(())
+    }
+}
+
+/// If the buffer holds a complete handshake message, remove and return it.
+fn take_handshake_message(buf: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let len = Reader(buf.get(1..4)?).u24()?;
+    if buf.len() < 4 + len {
+        return None;
+    }
+    Some(buf.drain(..4 + len).collect())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CipherKind {
+    Aes128Gcm,
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Suite {
+    cipher: CipherKind,
+    sha384: bool,
+    tls13: bool,
+}
+
+impl Suite {
+    fn lookup(id: u16) -> Option<Suite> {
+        use CipherKind::*;
+        let (cipher, sha384, tls13) = match id {
+            0x1301 => (Aes128Gcm, false, true),
+            0x1302 => (Aes256Gcm, true, true),
+            0x1303 => (ChaCha20Poly1305, false, true),
+            // (EC)DHE and RSA key exchange with AES-GCM
+            0x009C | 0x009E | 0xC02B | 0xC02F => (Aes128Gcm, false, false),
+            0x009D | 0x009F | 0xC02C | 0xC030 => (Aes256Gcm, true, false),
+            0xCCA8..=0xCCAA => (ChaCha20Poly1305, false, false),
+            _ => return None,
+        };
+        Some(Suite {
+            cipher,
+            sha384,
+            tls13,
+        })
+    }
+
+    fn key_len(&self) -> usize {
+        match self.cipher {
+            CipherKind::Aes128Gcm => 16,
+            CipherKind::Aes256Gcm | CipherKind::ChaCha20Poly1305 => 32,
+        }
+    }
+
+    /// In TLS 1.2, AES-GCM only derives the first 4 bytes of the nonce from
+    /// the key block, the rest is sent with each record.
+    fn tls12_iv_len(&self) -> usize {
+        match self.cipher {
+            CipherKind::Aes128Gcm | CipherKind::Aes256Gcm => 4,
+            CipherKind::ChaCha20Poly1305 => 12,
+        }
+    }
+
+    fn hmac(&self, key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+        if self.sha384 {
+            let mut mac = <Hmac<Sha384> as Mac>::new_from_slice(key).expect("any key length");
+            parts.iter().for_each(|p| mac.update(p));
+            mac.finalize().into_bytes().to_vec()
+        } else {
+            let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("any key length");
+            parts.iter().for_each(|p| mac.update(p));
+            mac.finalize().into_bytes().to_vec()
+        }
+    }
+
+    /// The TLS 1.2 pseudo-random function
+    fn prf(&self, secret: &[u8], label: &[u8], seed: &[u8], len: usize) -> Vec<u8> {
+        let mut a = self.hmac(secret, &[label, seed]);
+        let mut out = vec![];
+        while out.len() < len {
+            out.extend(self.hmac(secret, &[&a, label, seed]));
+            a = self.hmac(secret, &[&a]);
+        }
+        out.truncate(len);
+        out
+    }
+
+    /// HKDF-Expand-Label from TLS 1.3 with an empty context. Without a
+    /// length, the hash length is used.
+    fn expand_label(
+        &self,
+        secret: &[u8],
+        label: &str,
+        len: Option<usize>,
+    ) -> Result<Vec<u8>, String> {
+        let hash_len = if self.sha384 { 48 } else { 32 };
+        let len = len.unwrap_or(hash_len);
+        let label = format!("tls13 {label}");
+        let mut info = (len as u16).to_be_bytes().to_vec();
+        info.push(label.len() as u8);
+        info.extend_from_slice(label.as_bytes());
+        info.push(0);
+
+        let mut out = vec![0; len];
+        let expanded = if self.sha384 {
+            Hkdf::<Sha384>::from_prk(secret).map(|h| h.expand(&info, &mut out))
+        } else {
+            Hkdf::<Sha256>::from_prk(secret).map(|h| h.expand(&info, &mut out))
+        };
+        match expanded {
+            Ok(Ok(())) => Ok(out),
+            _ => Err("invalid secret in key log".to_string()),
+        }
+    }
+}
+
+enum Cipher {
+    Aes128Gcm(Box<Aes128Gcm>),
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(Box<ChaCha20Poly1305>),
+}
+
+impl Cipher {
+    fn new(kind: CipherKind, key: &[u8]) -> Result<Self, String> {
+        let cipher = match kind {
+            CipherKind::Aes128Gcm => {
+                Aes128Gcm::new_from_slice(key).map(|c| Cipher::Aes128Gcm(Box::new(c)))
+            }
+            CipherKind::Aes256Gcm => {
+                Aes256Gcm::new_from_slice(key).map(|c| Cipher::Aes256Gcm(Box::new(c)))
+            }
+            CipherKind::ChaCha20Poly1305 => {
+                ChaCha20Poly1305::new_from_slice(key).map(|c| Cipher::ChaCha20Poly1305(Box::new(c)))
+            }
+        };
+        cipher.map_err(|_| "invalid key length".to_string())
+    }
+
+    fn decrypt(&self, nonce: &[u8; 12], msg: &[u8], aad: &[u8]) -> Result<Vec<u8>, String> {
+        let nonce = GenericArray::from_slice(nonce);
+        let payload = Payload { msg, aad };
+        let plain = match self {
+            Cipher::Aes128Gcm(c) => c.decrypt(nonce, payload),
+            Cipher::Aes256Gcm(c) => c.decrypt(nonce, payload),
+            Cipher::ChaCha20Poly1305(c) => c.decrypt(nonce, payload),
+        };
+        plain.map_err(|_| "decryption failed, wrong key or corrupted record".to_string())
+    }
+}
+
+/// Decrypts the records sent in one direction with one set of keys
+struct Decrypter {
+    suite: Suite,
+    cipher: Cipher,
+    iv: Vec<u8>,
+    /// Sequence number of the next record
+    seq: u64,
+    /// With TLS 1.3, the traffic secret the keys were derived from
+    secret: Vec<u8>,
+}
+
+impl Decrypter {
+    fn tls13(suite: Suite, secret: Vec<u8>) -> Result<Self, String> {
+        let key = suite.expand_label(&secret, "key", Some(suite.key_len()))?;
+        let iv = suite.expand_label(&secret, "iv", Some(12))?;
+        Ok(Decrypter {
+            suite,
+            cipher: Cipher::new(suite.cipher, &key)?,
+            iv,
+            seq: 0,
+            secret,
+        })
+    }
+
+    fn tls12(suite: Suite, key: &[u8], iv: &[u8]) -> Result<Self, String> {
+        Ok(Decrypter {
+            suite,
+            cipher: Cipher::new(suite.cipher, key)?,
+            iv: iv.to_vec(),
+            seq: 0,
+            secret: vec![],
+        })
+    }
+
+    /// The IV with the sequence number xor'ed into its last bytes
+    fn sequence_nonce(&self) -> [u8; 12] {
+        let mut nonce: [u8; 12] = self.iv[..].try_into().unwrap();
+        for (n, s) in nonce[4..].iter_mut().zip(self.seq.to_be_bytes()) {
+            *n ^= s;
+        }
+        nonce
+    }
+
+    /// Decrypt a record, returning its real content type and its contents.
+    fn decrypt(&mut self, header: [u8; 5], body: &[u8]) -> Result<(u8, Vec<u8>), String> {
+        let result = if self.suite.tls13 {
+            let mut plain = self.cipher.decrypt(&self.sequence_nonce(), body, &header)?;
+            // the content type follows the contents, optionally followed by padding
+            let Some(end) = plain.iter().rposition(|&b| b != 0) else {
+                return Err("record without content type".to_string());
+            };
+            let content_type = plain[end];
+            plain.truncate(end);
+            (content_type, plain)
+        } else {
+            let (nonce, ciphertext) = if self.iv.len() == 4 {
+                let Some((explicit, ciphertext)) = body.split_at_checked(8) else {
+                    return Err("record too short".to_string());
+                };
+                let nonce: [u8; 12] = [&self.iv[..], explicit].concat().try_into().unwrap();
+                (nonce, ciphertext)
+            } else {
+                (self.sequence_nonce(), body)
+            };
+            let Some(len) = ciphertext.len().checked_sub(16) else {
+                return Err("record too short".to_string());
+            };
+            let mut aad = self.seq.to_be_bytes().to_vec();
+            aad.extend_from_slice(&header[..3]);
+            aad.extend_from_slice(&(len as u16).to_be_bytes());
+            (header[0], self.cipher.decrypt(&nonce, ciphertext, &aad)?)
+        };
+        self.seq += 1;
+        Ok(result)
+    }
+}
+
+#[test]
+fn test_tls13_traffic_keys() {
+    // RFC 8448, Simple 1-RTT Handshake, server handshake traffic keys
+    let suite = Suite::lookup(0x1301).unwrap();
+    let secret = "b67b7d690cc16c4e75e54213cb2d37b4e9c912bcded9105d42befd59d391ad38";
+    let secret: Vec<u8> = (0..64)
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&secret[i..i + 2], 16).unwrap())
+        .collect();
+    let key = suite.expand_label(&secret, "key", Some(16)).unwrap();
+    let iv = suite.expand_label(&secret, "iv", Some(12)).unwrap();
+    let hex = |b: &[u8]| b.iter().map(|b| format!("{b:02x}")).collect::<String>();
+    assert_eq!(hex(&key), "3fce516009c21727d0f2e4e86ee403bc");
+    assert_eq!(hex(&iv), "5d313eb2671276ee13000b30");
+}
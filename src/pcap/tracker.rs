@@ -1,53 +1,369 @@
-use std::{io, net::IpAddr};
+use std::{
+    cmp::Ordering,
+    collections::HashSet,
+    io,
+    net::{IpAddr, SocketAddr},
+    thread,
+    time::{Duration, Instant},
+};
 
-use anyhow::{bail, Result as AResult};
-use etherparse::{InternetSlice, Ipv4Slice, Ipv6Slice, SlicedPacket, TcpSlice, TransportSlice};
+use anyhow::{bail, Error as AError, Result as AResult};
+use etherparse::{
+    ip_number, EtherType, InternetSlice, Ipv4Slice, Ipv6ExtensionSlice, Ipv6ExtensionsSlice,
+    Ipv6Slice, SlicedPacket, TcpSlice, TransportSlice,
+};
 
-use crate::proxy::event::MapiEvent;
+use crate::proxy::event::{CaptureTime, MapiEvent};
 
-use super::tcp::TcpTracker;
+use super::{
+    detect::MapiDetector, fragments::Reassembler, tcp::TcpTracker, tls::TlsDecoder, Options,
+    TimeBound,
+};
+
+/// UDP ports used by VXLAN: the IANA assigned one and the Linux default
+const VXLAN_PORTS: [u16; 2] = [4789, 8472];
+
+/// UDP port used by Geneve
+const GENEVE_PORT: u16 = 6081;
+
+/// Protocol type of tunnels that carry whole Ethernet frames
+/// (Transparent Ethernet Bridging)
+const TRANSPARENT_ETHERNET: u16 = 0x6558;
 
 /// Struct Tracker holds the state necessary to process packets and emit MapiEvents.
 pub struct Tracker<'a> {
+    options: Options,
     handler: Box<dyn FnMut(MapiEvent) -> io::Result<()> + 'a>,
     tcp_tracker: TcpTracker,
+    /// Decrypts the TLS connections the TcpTracker finds
+    tls: TlsDecoder,
+    /// With [Options::detect_mapi], drops the connections that aren't MAPI
+    detector: MapiDetector,
+    /// Capture time of the packet being processed
+    timestamp: Duration,
+    /// Capture time of the first packet, whether it was processed or not
+    first_timestamp: Option<Duration>,
+    /// Number of bytes the capture tool cut off the packet being processed
+    truncated: usize,
+    /// Fragments of IPv6 packets that are not complete yet
+    ipv6_fragments: Reassembler,
+    /// With [Options::pace], the capture time of the first packet and the
+    /// moment we processed it
+    pace_origin: Option<(Duration, Instant)>,
 }
 
 impl<'a> Tracker<'a> {
     /// Create a new Tracker which calls the given closure for each MapiEvent it needs to emit.
-    pub fn new(event_handler: impl FnMut(MapiEvent) -> io::Result<()> + 'a) -> Self {
-        let handler = Box::new(event_handler);
+    pub fn new(
+        options: Options,
+        mut event_handler: impl FnMut(MapiEvent) -> io::Result<()> + 'a,
+    ) -> Self {
+        // With Options::select, drop the events of the other connections.
+        // Events that don't belong to a connection are always passed on.
+        let select = options.select.clone();
+        let mut selected = HashSet::new();
+        let handler = Box::new(move |ev: MapiEvent| {
+            let Some(select) = &select else {
+                return event_handler(ev);
+            };
+            if let MapiEvent::Incoming { id, local, peer } = &ev {
+                if select.matches(*id, local, peer) {
+                    selected.insert(*id);
+                }
+            }
+            match ev.id() {
+                Some(id) if !selected.contains(&id) => Ok(()),
+                _ => event_handler(ev),
+            }
+        });
+        let mut tls = TlsDecoder::default();
+        tls.add_key_log(&options.keylog);
         Tracker {
+            tcp_tracker: TcpTracker::new(&options),
+            tls,
+            detector: MapiDetector::new(options.detect_mapi),
+            options,
             handler,
-            tcp_tracker: TcpTracker::new(),
+            timestamp: Duration::ZERO,
+            first_timestamp: None,
+            truncated: 0,
+            ipv6_fragments: Reassembler::default(),
+            pace_origin: None,
+        }
+    }
+
+    /// Set the capture time of the packets that follow. Packets without a
+    /// timestamp of their own are assumed to be captured at the same time as
+    /// the previous one.
+    ///
+    /// With [Options::pace], sleep until it's time to process the packet.
+    pub fn set_timestamp(&mut self, timestamp: Duration) {
+        self.timestamp = timestamp;
+        if let Some(speed) = self.options.pace {
+            let (first, started) = *self.pace_origin.get_or_insert((timestamp, Instant::now()));
+            let delay = timestamp.saturating_sub(first).div_f64(speed);
+            let wait = (started + delay).saturating_duration_since(Instant::now());
+            if !wait.is_zero() {
+                thread::sleep(wait);
+            }
+        }
+    }
+
+    /// Check whether a packet captured at this time falls within
+    /// [Options::from] and [Options::to]. Returns Less if it was captured
+    /// before the window and Greater if after.
+    pub fn check_window(&mut self, timestamp: Duration) -> Ordering {
+        let first = *self.first_timestamp.get_or_insert(timestamp);
+        let moment = |bound| match bound {
+            TimeBound::At(time) => time.0,
+            TimeBound::After(offset) => first.saturating_add(offset),
+        };
+        if self
+            .options
+            .from
+            .is_some_and(|from| timestamp < moment(from))
+        {
+            Ordering::Less
+        } else if self.options.to.is_some_and(|to| timestamp > moment(to)) {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
         }
     }
 
+    /// Set the number of bytes that are missing from the end of the packet
+    /// that follows because the capture tool truncated it. This is an error
+    /// unless [Options::allow_truncated] is set.
+    pub fn set_truncated(&mut self, missing: usize) -> AResult<()> {
+        if missing > 0 && !self.options.allow_truncated {
+            bail!("truncated packet, {missing} bytes were not captured (see --allow-truncated)");
+        }
+        self.truncated = missing;
+        Ok(())
+    }
+
+    /// Report that the current packet could not be decoded and has been
+    /// skipped. If the `endpoints` of its TCP connection are known, the
+    /// report goes with the other events of that connection.
+    pub fn skip_packet(
+        &mut self,
+        error: AError,
+        endpoints: Option<(SocketAddr, SocketAddr)>,
+    ) -> AResult<()> {
+        let id = endpoints.and_then(|(src, dest)| self.tcp_tracker.connection(src, dest));
+        let ev = MapiEvent::SkippedPacket {
+            id,
+            timestamp: CaptureTime(self.timestamp),
+            error: format!("{error:#}"),
+        };
+        if id.is_none() {
+            (self.handler)(ev)?;
+            return Ok(());
+        }
+        let Tracker {
+            handler,
+            tls,
+            detector,
+            ..
+        } = self;
+        tls.handle(ev, &mut |ev| detector.handle(ev, handler))?;
+        Ok(())
+    }
+
+    /// Add TLS secrets found in the capture file itself, in the key log
+    /// format.
+    pub fn add_key_log(&mut self, text: &str) {
+        self.tls.add_key_log(text);
+    }
+
+    /// Called after the last packet. Delivers data that was held back waiting
+    /// for packets the capture has missed.
+    pub fn finish(&mut self) -> AResult<()> {
+        let Tracker {
+            handler,
+            tls,
+            detector,
+            ..
+        } = self;
+        let mut handler = |ev| tls.handle(ev, &mut |ev| detector.handle(ev, handler));
+        self.tcp_tracker.finish(self.timestamp, &mut handler)?;
+
+        let (port_connections, port_bytes) = self.tcp_tracker.ignored();
+        let (other_connections, other_bytes) = self.detector.ignored();
+        let connections = port_connections + other_connections;
+        if connections > 0 {
+            let bytes = port_bytes + other_bytes;
+            (self.handler)(MapiEvent::Ignored { connections, bytes })?;
+        }
+        Ok(())
+    }
+
     /// Process the given packet as an Ethernet frame.
     pub fn process_ethernet(&mut self, data: &[u8]) -> AResult<()> {
-        let ether_slice = SlicedPacket::from_ethernet(data)?;
-        let transport_slice = ether_slice.transport.as_ref();
-        match &ether_slice.net {
+        // destination MAC, source MAC, ether type
+        let Some((header, payload)) = data.split_at_checked(14) else {
+            bail!("truncated Ethernet header");
+        };
+        let ether_type = u16::from_be_bytes([header[12], header[13]]);
+        self.process_ether_type(EtherType(ether_type), payload)
+    }
+
+    /// Process the given packet as a Linux cooked capture (LINKTYPE_LINUX_SLL),
+    /// as produced by `tcpdump -i any`.
+    pub fn process_linux_sll(&mut self, data: &[u8]) -> AResult<()> {
+        // packet type, ARPHRD type, address length, 8 bytes of address, protocol
+        let Some((header, payload)) = data.split_at_checked(16) else {
+            bail!("truncated Linux cooked capture header");
+        };
+        let protocol = u16::from_be_bytes([header[14], header[15]]);
+        self.process_ether_type(EtherType(protocol), payload)
+    }
+
+    /// Process the given packet as a Linux cooked capture v2
+    /// (LINKTYPE_LINUX_SLL2), as produced by newer versions of `tcpdump -i any`.
+    pub fn process_linux_sll2(&mut self, data: &[u8]) -> AResult<()> {
+        // protocol, reserved, interface index, ARPHRD type, packet type,
+        // address length, 8 bytes of address
+        let Some((header, payload)) = data.split_at_checked(20) else {
+            bail!("truncated Linux cooked capture v2 header");
+        };
+        let protocol = u16::from_be_bytes([header[0], header[1]]);
+        self.process_ether_type(EtherType(protocol), payload)
+    }
+
+    /// Process a payload of the given ether type. Any 802.1Q VLAN tags are
+    /// skipped, including stacked ones (QinQ).
+    fn process_ether_type(&mut self, mut ether_type: EtherType, mut payload: &[u8]) -> AResult<()> {
+        use etherparse::ether_type::{
+            PROVIDER_BRIDGING, VLAN_DOUBLE_TAGGED_FRAME, VLAN_TAGGED_FRAME,
+        };
+        while let VLAN_TAGGED_FRAME | PROVIDER_BRIDGING | VLAN_DOUBLE_TAGGED_FRAME = ether_type {
+            // priority and VLAN id, ether type of what follows
+            let Some((tag, rest)) = payload.split_at_checked(4) else {
+                bail!("truncated VLAN tag");
+            };
+            ether_type = EtherType(u16::from_be_bytes([tag[2], tag[3]]));
+            payload = rest;
+        }
+        let sliced = SlicedPacket::from_ether_type(ether_type, payload)?;
+        self.handle_sliced(&sliced)
+    }
+
+    /// Process the given packet as a bare IPv4 or IPv6 packet.
+    pub fn process_raw_ip(&mut self, data: &[u8]) -> AResult<()> {
+        let sliced = SlicedPacket::from_ip(data)?;
+        self.handle_sliced(&sliced)
+    }
+
+    /// Process the given packet as captured on a BSD loopback interface
+    /// (LINKTYPE_NULL or LINKTYPE_LOOP). These start with a 4 byte address
+    /// family. It is in host byte order for NULL and in network byte order
+    /// for LOOP, and the value for IPv6 differs between platforms. We ignore it
+    /// and look at the IP version instead.
+    pub fn process_loopback(&mut self, data: &[u8]) -> AResult<()> {
+        let Some((_family, payload)) = data.split_at_checked(4) else {
+            bail!("truncated loopback header");
+        };
+        self.process_raw_ip(payload)
+    }
+
+    /// Hand the IP layer of a parsed packet to [Self::handle_ipv4] or
+    /// [Self::handle_ipv6].
+    fn handle_sliced(&mut self, sliced: &SlicedPacket) -> AResult<()> {
+        if self.options.decapsulate && self.process_tunnel(sliced)? {
+            return Ok(());
+        }
+        let transport_slice = sliced.transport.as_ref();
+        match &sliced.net {
             Some(InternetSlice::Ipv4(inet4)) => self.handle_ipv4(inet4, transport_slice),
             Some(InternetSlice::Ipv6(inet6)) => self.handle_ipv6(inet6, transport_slice),
             None => Ok(()),
         }
     }
 
-    /// Examine IPv6 packet. If it's a TCP packet and not fragmented, hand it to [Self::handle_tcp]
+    /// If the packet belongs to a VXLAN, Geneve or GRE tunnel, process the
+    /// packet it carries and return true.
+    fn process_tunnel(&mut self, sliced: &SlicedPacket) -> AResult<bool> {
+        let ip_payload = match &sliced.net {
+            Some(InternetSlice::Ipv4(inet4)) => inet4.payload(),
+            Some(InternetSlice::Ipv6(inet6)) => inet6.payload(),
+            None => return Ok(false),
+        };
+        if ip_payload.fragmented {
+            return Ok(false);
+        }
+
+        if let Some(TransportSlice::Udp(udp)) = &sliced.transport {
+            let payload = udp.payload();
+            let port = udp.destination_port();
+            if VXLAN_PORTS.contains(&port) {
+                // flags, reserved, network identifier, reserved
+                let Some((_, frame)) = payload.split_at_checked(8) else {
+                    bail!("truncated VXLAN header");
+                };
+                self.process_ethernet(frame)?;
+                return Ok(true);
+            } else if port == GENEVE_PORT {
+                // version and options length, flags, protocol type, network
+                // identifier, reserved, options
+                let Some(header) = payload.get(..8) else {
+                    bail!("truncated Geneve header");
+                };
+                let header_len = 8 + (header[0] & 0x3F) as usize * 4;
+                let protocol = u16::from_be_bytes([header[2], header[3]]);
+                let Some(inner) = payload.get(header_len..) else {
+                    bail!("truncated Geneve options");
+                };
+                self.process_tunneled(protocol, inner)?;
+                return Ok(true);
+            }
+            return Ok(false);
+        }
+
+        if ip_payload.ip_number != ip_number::GRE {
+            return Ok(false);
+        }
+        // flags and version, protocol type, then depending on the flags a
+        // checksum, a key and a sequence number of 4 bytes each
+        let payload = ip_payload.payload;
+        let Some(header) = payload.get(..4) else {
+            bail!("truncated GRE header");
+        };
+        let optional = [0x80, 0x20, 0x10]
+            .into_iter()
+            .filter(|bit| header[0] & bit != 0)
+            .count();
+        let protocol = u16::from_be_bytes([header[2], header[3]]);
+        let Some(inner) = payload.get(4 + 4 * optional..) else {
+            bail!("truncated GRE header");
+        };
+        self.process_tunneled(protocol, inner)?;
+        Ok(true)
+    }
+
+    /// Process a packet carried by a tunnel, identified by its ether type.
+    fn process_tunneled(&mut self, protocol: u16, inner: &[u8]) -> AResult<()> {
+        if protocol == TRANSPARENT_ETHERNET {
+            self.process_ethernet(inner)
+        } else {
+            self.process_ether_type(EtherType(protocol), inner)
+        }
+    }
+
+    /// Examine IPv6 packet. If it's a TCP packet, hand it to [Self::handle_tcp].
+    /// Fragmented packets are handed over once all fragments have arrived.
     pub fn handle_ipv6(
         &mut self,
         ipv6: &Ipv6Slice,
         transport: Option<&TransportSlice>,
     ) -> AResult<()> {
         if ipv6.is_payload_fragmented() {
-            bail!("pcap file contains fragmented ipv6 packet, not supported");
+            return self.handle_ipv6_fragment(ipv6);
         }
 
-        let tcp = match transport {
-            None => bail!("transport not found, expected this only with fragmented packets"),
-            Some(TransportSlice::Tcp(tcp)) => tcp,
-            _ => return Ok(()),
+        // Protocols etherparse doesn't know, such as GRE, have no transport
+        let Some(TransportSlice::Tcp(tcp)) = transport else {
+            return Ok(());
         };
 
         let header = &ipv6.header();
@@ -56,6 +372,53 @@ impl<'a> Tracker<'a> {
         self.handle_tcp(src, dest, tcp)
     }
 
+    /// Collect the fragments of a fragmented IPv6 packet. When the last one
+    /// arrives, parse the reassembled packet.
+    fn handle_ipv6_fragment(&mut self, ipv6: &Ipv6Slice) -> AResult<()> {
+        // A truncated fragment cannot be reassembled. Drop it, the TCP
+        // tracker will report the data as missing.
+        if self.truncated > 0 {
+            return Ok(());
+        }
+
+        let exts = ipv6.extensions();
+        let fragment_header = exts.clone().into_iter().find_map(|ext| match ext {
+            Ipv6ExtensionSlice::Fragment(frag) => Some(frag),
+            _ => None,
+        });
+        let Some(frag) = fragment_header else {
+            bail!("fragmented ipv6 packet without fragment header");
+        };
+
+        // The fragmentable part starts right after the fragment header. It
+        // consists of the extension headers that follow it and the payload.
+        let frag_end = frag.slice().as_ptr() as usize + frag.slice().len();
+        let start = frag_end - exts.slice().as_ptr() as usize;
+        let mut data = exts.slice()[start..].to_vec();
+        data.extend_from_slice(ipv6.payload().payload);
+
+        // Fragment offset in units of 8 bytes, 2 reserved bits, more fragments flag
+        let b = frag.slice();
+        let offset = (u16::from_be_bytes([b[2], b[3]]) >> 3) as usize * 8;
+        let more = b[3] & 1 != 0;
+        let identification = u32::from_be_bytes([b[4], b[5], b[6], b[7]]);
+
+        let header = &ipv6.header();
+        let src = IpAddr::from(header.source_addr());
+        let dest = IpAddr::from(header.destination_addr());
+        let key = (src, dest, identification);
+        let Some(packet) = self.ipv6_fragments.add(key, offset, more, &data) else {
+            return Ok(());
+        };
+
+        let (_, next, rest) = Ipv6ExtensionsSlice::from_slice(frag.next_header(), &packet)?;
+        if next != ip_number::TCP {
+            return Ok(());
+        }
+        let tcp = TcpSlice::from_slice(rest)?;
+        self.handle_tcp(src, dest, &tcp)
+    }
+
     /// Examine IPv4 packet. If it's a TCP packet and not fragmented, hand it to [Self::handle_tcp]
     pub fn handle_ipv4(
         &mut self,
@@ -66,10 +429,9 @@ impl<'a> Tracker<'a> {
             bail!("pcap file contains fragmented ipv4 packet, not supported");
         }
 
-        let tcp = match transport {
-            None => bail!("transport not found, expected this only with fragmented packets"),
-            Some(TransportSlice::Tcp(tcp)) => tcp,
-            _ => return Ok(()),
+        // Protocols etherparse doesn't know, such as GRE, have no transport
+        let Some(TransportSlice::Tcp(tcp)) = transport else {
+            return Ok(());
         };
 
         let header = &ipv4.header();
@@ -82,7 +444,24 @@ impl<'a> Tracker<'a> {
     pub fn handle_tcp(&mut self, src: IpAddr, dest: IpAddr, tcp: &TcpSlice) -> AResult<()> {
         // It's nice for handle_ipv4 and handle_ipv6 to simply call handle_tcp, but it turns
         // out that the actual handling is done by the [TcpTracker] subobject.
-        self.tcp_tracker.handle(src, dest, tcp, &mut self.handler)?;
+        // Its events pass through the TlsDecoder and the MapiDetector before
+        // they are emitted.
+        let Tracker {
+            handler,
+            tls,
+            detector,
+            ..
+        } = self;
+        let mut handler = |ev| tls.handle(ev, &mut |ev| detector.handle(ev, handler));
+        self.tcp_tracker.handle(
+            &self.options,
+            self.timestamp,
+            src,
+            dest,
+            tcp,
+            self.truncated,
+            &mut handler,
+        )?;
         Ok(())
     }
 }
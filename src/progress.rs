@@ -0,0 +1,73 @@
+use std::{
+    cell::RefCell,
+    io::{self, Read, Write},
+    rc::Rc,
+};
+
+/// Reports on stderr how much of the capture files has been read, see
+/// `--progress`. Clones share the count so the readers of several files can
+/// contribute to the same percentage.
+#[derive(Debug, Clone)]
+pub struct Progress(Rc<RefCell<State>>);
+
+#[derive(Debug)]
+struct State {
+    /// Total size of the files, 0 if not known
+    total: u64,
+    done: u64,
+    /// The percentage last printed
+    shown: Option<u64>,
+}
+
+impl Progress {
+    pub fn new(total: u64) -> Self {
+        let state = State {
+            total,
+            done: 0,
+            shown: None,
+        };
+        Progress(Rc::new(RefCell::new(state)))
+    }
+
+    /// Count the bytes read from `rd`.
+    pub fn wrap<'a>(&self, rd: impl Read + 'a) -> Box<dyn Read + 'a> {
+        Box::new(ProgressReader {
+            inner: rd,
+            progress: self.clone(),
+        })
+    }
+
+    fn advance(&self, n: usize) {
+        let mut state = self.0.borrow_mut();
+        if state.total == 0 {
+            return;
+        }
+        state.done += n as u64;
+        let percent = (state.done * 100 / state.total).min(100);
+        if state.shown != Some(percent) {
+            state.shown = Some(percent);
+            // Nothing useful we can do if stderr is gone
+            let _ = write!(io::stderr(), "\rReading capture: {percent:3}%");
+        }
+    }
+
+    /// End the progress line.
+    pub fn finish(&self) {
+        if self.0.borrow().shown.is_some() {
+            let _ = writeln!(io::stderr());
+        }
+    }
+}
+
+struct ProgressReader<R> {
+    inner: R,
+    progress: Progress,
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.advance(n);
+        Ok(n)
+    }
+}
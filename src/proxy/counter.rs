@@ -0,0 +1,114 @@
+//! Keep track of the MAPI block structure of a byte stream so we can count
+//! messages without fully parsing them.
+
+use super::event::Volume;
+
+/// Counts the bytes and MAPI messages that have been forwarded in one direction.
+#[derive(Debug, Clone)]
+pub struct Counter {
+    pub bytes: u64,
+    pub messages: u64,
+    /// Some of the bytes went past without us looking at them so the message
+    /// count is not reliable
+    spliced: bool,
+    /// Skip the '0' (0x30) we send to Unix Domain sockets, it's not part of
+    /// the block structure
+    skip_byte: bool,
+    /// We have seen part of a message but not its end
+    in_message: bool,
+    /// First byte of a block header whose second byte hasn't arrived yet
+    half_header: Option<u8>,
+    /// Bytes left in the current block
+    remaining: usize,
+    /// The current block is the last block of its message
+    last: bool,
+}
+
+impl Counter {
+    pub fn new(skip_byte: bool) -> Self {
+        Counter {
+            bytes: 0,
+            messages: 0,
+            spliced: false,
+            skip_byte,
+            in_message: false,
+            half_header: None,
+            remaining: 0,
+            last: false,
+        }
+    }
+
+    pub fn volume(&self) -> Volume {
+        Volume {
+            bytes: self.bytes,
+            messages: if self.spliced {
+                None
+            } else {
+                Some(self.messages)
+            },
+        }
+    }
+
+    /// Count bytes that were forwarded without passing through our hands.
+    pub fn count_spliced(&mut self, n: usize) {
+        self.bytes += n as u64;
+        self.spliced = true;
+    }
+
+    /// Forget about the message in progress, the next byte will be the start
+    /// of a new message. Used when a capture has missed part of the data.
+    pub fn resync(&mut self) {
+        self.skip_byte = false;
+        self.in_message = false;
+        self.half_header = None;
+        self.remaining = 0;
+        self.last = false;
+    }
+
+    /// True if the next byte will be the first byte of a message
+    pub fn at_message_start(&self) -> bool {
+        !self.skip_byte && !self.in_message
+    }
+
+    pub fn count(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = self.advance(data);
+            data = &data[n..];
+        }
+    }
+
+    /// Count `data` up to and including the end of the first message that
+    /// ends in it. Returns the number of bytes counted.
+    pub fn advance(&mut self, data: &[u8]) -> usize {
+        let mut pos = 0;
+        if self.skip_byte && !data.is_empty() {
+            pos = 1;
+            self.skip_byte = false;
+        }
+        while pos < data.len() {
+            self.in_message = true;
+            if self.remaining > 0 {
+                let n = self.remaining.min(data.len() - pos);
+                pos += n;
+                self.remaining -= n;
+            } else if let Some(lo) = self.half_header.take() {
+                let header = u16::from_le_bytes([lo, data[pos]]);
+                pos += 1;
+                self.remaining = header as usize / 2;
+                self.last = header & 1 == 1;
+            } else {
+                self.half_header = Some(data[pos]);
+                pos += 1;
+                continue;
+            }
+            if self.remaining == 0 && self.last {
+                self.messages += 1;
+                self.in_message = false;
+                self.last = false;
+                break;
+            }
+        }
+        self.bytes += pos as u64;
+        pos
+    }
+}
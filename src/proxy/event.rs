@@ -1,4 +1,8 @@
-use std::{fmt, io};
+use std::{
+    fmt, io, ops,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use smallvec::SmallVec;
 
@@ -16,13 +20,21 @@ impl fmt::Display for ConnectionId {
 }
 
 impl ConnectionId {
+    /// The number of the first connection, unless `--first-id` says
+    /// otherwise
+    pub const FIRST: usize = 10;
+
     pub fn new(n: usize) -> Self {
         ConnectionId(n)
     }
+
+    pub fn number(&self) -> usize {
+        self.0
+    }
 }
 
 /// Enum to indicate client->server versus server->client
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum Direction {
     /// Traffic flowing from client to server
     Upstream,
@@ -62,12 +74,487 @@ impl Direction {
     }
 }
 
+/// Number of bytes and MAPI messages forwarded in one direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Volume {
+    pub bytes: u64,
+    /// None if the data was forwarded without looking at it, see
+    /// [Options::pass_through][super::Options::pass_through].
+    pub messages: Option<u64>,
+}
+
+impl Default for Volume {
+    fn default() -> Self {
+        Volume {
+            bytes: 0,
+            messages: Some(0),
+        }
+    }
+}
+
+impl ops::Add for Volume {
+    type Output = Volume;
+
+    fn add(self, rhs: Volume) -> Volume {
+        Volume {
+            bytes: self.bytes + rhs.bytes,
+            messages: self.messages.zip(rhs.messages).map(|(a, b)| a + b),
+        }
+    }
+}
+
+impl fmt::Display for Volume {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = if self.bytes == 1 { "" } else { "s" };
+        write!(f, "{n} byte{s}", n = self.bytes)?;
+        if let Some(n) = self.messages {
+            let s = if n == 1 { "" } else { "s" };
+            write!(f, " in {n} message{s}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The [Volume] forwarded in both directions of a connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Traffic {
+    pub upstream: Volume,
+    pub downstream: Volume,
+}
+
+impl Traffic {
+    pub fn total(&self) -> Volume {
+        self.upstream + self.downstream
+    }
+}
+
+impl ops::Add for Traffic {
+    type Output = Traffic;
+
+    fn add(self, rhs: Traffic) -> Traffic {
+        Traffic {
+            upstream: self.upstream + rhs.upstream,
+            downstream: self.downstream + rhs.downstream,
+        }
+    }
+}
+
+/// How long a connection lasted and when data was flowing, measured from
+/// the moment it was accepted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timing {
+    pub duration: Duration,
+    pub first_data: Option<Duration>,
+    pub last_data: Option<Duration>,
+}
+
+impl Timing {
+    /// Compute the [Timing] of a connection that started at `start`, has
+    /// seen data between `first_data` and `last_data` and ended at `end`.
+    /// Works for [Instant][std::time::Instant]s and for timestamps read from
+    /// capture files alike. Timestamps in a capture file can go backwards,
+    /// moments before `start` count as zero.
+    pub fn between<T>(start: T, data: Option<(T, T)>, end: T) -> Self
+    where
+        T: ops::Sub<T, Output = Duration> + PartialOrd + Copy,
+    {
+        let since = |t: T| if t > start { t - start } else { Duration::ZERO };
+        Timing {
+            duration: since(end),
+            first_data: data.map(|(first, _)| since(first)),
+            last_data: data.map(|(_, last)| since(last)),
+        }
+    }
+}
+
+impl fmt::Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = |d: Duration| d.as_secs_f64();
+        write!(f, "after {:.3}s", secs(self.duration))?;
+        if let (Some(first), Some(last)) = (self.first_data, self.last_data) {
+            write!(f, ", data from {:.3}s to {:.3}s", secs(first), secs(last))?;
+        }
+        Ok(())
+    }
+}
+
+/// Moment at which a packet was captured, as time since the Unix epoch.
+/// Displayed in UTC with microsecond precision, see [CaptureTime::in_zone]
+/// for other time zones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CaptureTime(pub Duration);
+
+impl CaptureTime {
+    /// Display the time in the given time zone. Unless that's UTC, the
+    /// offset from UTC is appended.
+    pub fn in_zone(self, zone: TimeZone) -> ZonedTime {
+        ZonedTime {
+            time: self,
+            zone,
+            format: None,
+        }
+    }
+
+    /// The current time, for events that happen live.
+    pub fn now() -> CaptureTime {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        CaptureTime(since_epoch)
+    }
+
+    /// Parse a time the way it is displayed, for example
+    /// `2024-03-05 14:02:01.5 +01:00`. The date and the time may also be
+    /// separated by a 'T', the seconds are optional and the offset may be
+    /// given as 'Z' for UTC. Without an offset, the time is taken to be in
+    /// `zone`.
+    pub fn parse(text: &str, zone: TimeZone) -> Option<CaptureTime> {
+        let (date, rest) = text.trim().split_once([' ', 'T'])?;
+        let end = rest.find([' ', '+', '-', 'Z']).unwrap_or(rest.len());
+        let (time, suffix) = rest.split_at(end);
+        let offset = match suffix.trim_start() {
+            "" => None,
+            "Z" => Some(0),
+            offset => Some(parse_offset(offset)?),
+        };
+
+        let mut date = date.split('-').map(|s| s.parse::<i64>().ok());
+        let (Some(Some(year)), Some(Some(month)), Some(Some(day)), None) =
+            (date.next(), date.next(), date.next(), date.next())
+        else {
+            return None;
+        };
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return None;
+        }
+        let mut time = time.split(':');
+        let (Some(hour), Some(min), sec, None) =
+            (time.next(), time.next(), time.next(), time.next())
+        else {
+            return None;
+        };
+        let (hour, min) = (hour.parse::<i64>().ok()?, min.parse::<i64>().ok()?);
+        let sec = sec.unwrap_or("0");
+        let (sec, fraction) = sec.split_once('.').unwrap_or((sec, ""));
+        let sec = sec.parse::<i64>().ok()?;
+        if hour > 23 || min > 59 || sec > 60 || fraction.len() > 9 {
+            return None;
+        }
+        let nanos = if fraction.is_empty() {
+            0
+        } else {
+            fraction.parse::<u32>().ok()? * 10u32.pow(9 - fraction.len() as u32)
+        };
+
+        // Days since the epoch of a date in the proleptic Gregorian
+        // calendar, see Howard Hinnant's 'days_from_civil'.
+        let y = if month <= 2 { year - 1 } else { year };
+        let era = y.div_euclid(400);
+        let yoe = y - era * 400;
+        let mp = (month + 9) % 12;
+        let doy = (153 * mp + 2) / 5 + day - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era.checked_mul(146097)?.checked_add(doe - 719468)?;
+
+        let secs = days
+            .checked_mul(86400)?
+            .checked_add(hour * 3600 + min * 60 + sec)?;
+        let offset = match (offset, zone) {
+            (Some(offset), _) => offset,
+            (None, TimeZone::Local) => {
+                // We need the offset at the moment we're looking for, which
+                // is close to the local time read as UTC
+                let guess = u64::try_from(secs).ok()?;
+                let guess = guess.saturating_add_signed(-i64::from(local_offset(guess)));
+                local_offset(guess)
+            }
+            (None, zone) => zone.offset_at(0),
+        };
+        let secs = u64::try_from(secs.checked_sub(i64::from(offset))?).ok()?;
+        Some(CaptureTime(Duration::new(secs, nanos)))
+    }
+}
+
+impl fmt::Display for CaptureTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.in_zone(TimeZone::Utc).fmt(f)
+    }
+}
+
+/// How to display [CaptureTime]s, see `--tz`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimeZone {
+    #[default]
+    Utc,
+    /// The time zone of this machine, including daylight saving time
+    Local,
+    /// A fixed offset from UTC in seconds
+    Offset(i32),
+}
+
+impl TimeZone {
+    /// Parse 'utc', 'local' or an offset from UTC such as +02:00
+    pub fn parse(text: &str) -> Option<TimeZone> {
+        let zone = match text {
+            "utc" | "UTC" => TimeZone::Utc,
+            "local" => TimeZone::Local,
+            _ => TimeZone::Offset(parse_offset(text)?),
+        };
+        Some(zone)
+    }
+
+    /// Offset from UTC in seconds at the given moment
+    fn offset_at(self, utc_secs: u64) -> i32 {
+        match self {
+            TimeZone::Utc => 0,
+            TimeZone::Local => local_offset(utc_secs),
+            TimeZone::Offset(offset) => offset,
+        }
+    }
+}
+
+/// Parse an offset from UTC such as +02:00 or -05:30, in seconds
+fn parse_offset(text: &str) -> Option<i32> {
+    let (sign, hhmm) = text.split_at_checked(1)?;
+    let sign = match sign {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+    let (hh, mm) = hhmm.split_once(':')?;
+    if hh.len() != 2 || mm.len() != 2 {
+        return None;
+    }
+    let (hh, mm) = (hh.parse::<i32>().ok()?, mm.parse::<i32>().ok()?);
+    (hh <= 23 && mm <= 59).then_some(sign * (hh * 3600 + mm * 60))
+}
+
+/// A [CaptureTime] displayed in a [TimeZone], optionally in a format of
+/// its own, see [ZonedTime::with_format].
+#[derive(Debug, Clone)]
+pub struct ZonedTime {
+    time: CaptureTime,
+    zone: TimeZone,
+    format: Option<Arc<str>>,
+}
+
+/// The placeholders understood by [ZonedTime::format], without the %
+const FORMAT_PLACEHOLDERS: [&str; 15] = [
+    "Y", "m", "d", "H", "M", "S", "f", "3f", "6f", "z", ":z", "F", "T", "s", "%",
+];
+
+impl ZonedTime {
+    /// Display the time in `format` instead of the default, see
+    /// [ZonedTime::format].
+    pub fn with_format(self, format: Option<Arc<str>>) -> ZonedTime {
+        ZonedTime { format, ..self }
+    }
+
+    /// Replace the placeholders in `format` like strftime(3) does:
+    /// %Y, %m, %d, %H, %M and %S by the year, month, day, hour, minute and
+    /// second, %f or %6f by the microseconds and %3f by the milliseconds,
+    /// %z and %:z by the offset from UTC as +0200 or +02:00, %F by
+    /// %Y-%m-%d, %T by %H:%M:%S, %s by the seconds since the epoch and %%
+    /// by %. Other sequences starting with % are left alone.
+    pub fn format(&self, format: &str) -> String {
+        let Fields {
+            year,
+            month,
+            day,
+            hour,
+            min,
+            sec,
+            micros,
+            offset,
+        } = self.fields();
+        let sign = if offset < 0 { '-' } else { '+' };
+        let minutes = offset.unsigned_abs() / 60;
+        let (offset_hours, offset_minutes) = (minutes / 60, minutes % 60);
+
+        let mut result = String::with_capacity(format.len() + 16);
+        let mut rest = format;
+        while let Some(pos) = rest.find('%') {
+            result.push_str(&rest[..pos]);
+            rest = &rest[pos + 1..];
+            let Some(placeholder) = placeholder_at(rest) else {
+                result.push('%');
+                continue;
+            };
+            rest = &rest[placeholder.len()..];
+            let field = match placeholder {
+                "Y" => format!("{year:04}"),
+                "m" => format!("{month:02}"),
+                "d" => format!("{day:02}"),
+                "H" => format!("{hour:02}"),
+                "M" => format!("{min:02}"),
+                "S" => format!("{sec:02}"),
+                "f" | "6f" => format!("{micros:06}"),
+                "3f" => format!("{:03}", micros / 1000),
+                "z" => format!("{sign}{offset_hours:02}{offset_minutes:02}"),
+                ":z" => format!("{sign}{offset_hours:02}:{offset_minutes:02}"),
+                "F" => format!("{year:04}-{month:02}-{day:02}"),
+                "T" => format!("{hour:02}:{min:02}:{sec:02}"),
+                "s" => self.time.0.as_secs().to_string(),
+                _ => "%".to_string(),
+            };
+            result.push_str(&field);
+        }
+        result.push_str(rest);
+        result
+    }
+
+    /// The date and time of day in the time zone
+    fn fields(&self) -> Fields {
+        let utc_secs = self.time.0.as_secs();
+        let offset = self.zone.offset_at(utc_secs);
+        let secs = utc_secs.saturating_add_signed(offset.into());
+        let micros = self.time.0.subsec_micros();
+        let (days, secs_of_day) = (secs / 86400, secs % 86400);
+        let (hour, min, sec) = (secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60);
+
+        // Convert days since the epoch to a date in the proleptic Gregorian
+        // calendar, see Howard Hinnant's 'civil_from_days'.
+        let z = days + 719468;
+        let era = z / 146097;
+        let doe = z % 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + (month <= 2) as u64;
+
+        Fields {
+            year,
+            month,
+            day,
+            hour,
+            min,
+            sec,
+            micros,
+            offset,
+        }
+    }
+}
+
+/// The parts of a [ZonedTime]
+struct Fields {
+    year: u64,
+    month: u64,
+    day: u64,
+    hour: u64,
+    min: u64,
+    sec: u64,
+    micros: u32,
+    /// Offset from UTC in seconds
+    offset: i32,
+}
+
+/// The placeholder at the start of `text`, which follows a %
+fn placeholder_at(text: &str) -> Option<&'static str> {
+    FORMAT_PLACEHOLDERS
+        .into_iter()
+        .filter(|p| text.starts_with(p))
+        // %3f rather than a placeholder %3
+        .max_by_key(|p| p.len())
+}
+
+/// The first sequence starting with % in `format` that
+/// [ZonedTime::format] does not understand, if any.
+pub fn unknown_placeholder(format: &str) -> Option<String> {
+    let mut rest = format;
+    while let Some(pos) = rest.find('%') {
+        rest = &rest[pos + 1..];
+        let Some(placeholder) = placeholder_at(rest) else {
+            let next: String = rest.chars().take(1).collect();
+            return Some(format!("%{next}"));
+        };
+        rest = &rest[placeholder.len()..];
+    }
+    None
+}
+
+impl fmt::Display for ZonedTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(format) = &self.format {
+            return f.write_str(&self.format(format));
+        }
+        let Fields {
+            year,
+            month,
+            day,
+            hour,
+            min,
+            sec,
+            micros,
+            offset,
+        } = self.fields();
+        write!(
+            f,
+            "{year:04}-{month:02}-{day:02} {hour:02}:{min:02}:{sec:02}.{micros:06}"
+        )?;
+        if self.zone != TimeZone::Utc {
+            let sign = if offset < 0 { '-' } else { '+' };
+            let minutes = offset.unsigned_abs() / 60;
+            write!(f, " {sign}{:02}:{:02}", minutes / 60, minutes % 60)?;
+        }
+        Ok(())
+    }
+}
+
+/// Offset from UTC in seconds of the local time zone at the given moment
+#[cfg(unix)]
+fn local_offset(utc_secs: u64) -> i32 {
+    let Ok(t) = libc::time_t::try_from(utc_secs) else {
+        return 0;
+    };
+    // SAFETY: localtime_r only writes to the tm we pass it
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let ret = unsafe { libc::localtime_r(&t, &mut tm) };
+    if ret.is_null() {
+        return 0;
+    }
+    tm.tm_gmtoff as i32
+}
+
+#[cfg(not(unix))]
+fn local_offset(_utc_secs: u64) -> i32 {
+    0
+}
+
 /// Type to represent the events that need to be reported on
 #[derive(Debug)]
 pub enum MapiEvent {
     /// Proxy has succesfully bound listen port
     BoundPort(Addr),
 
+    /// Proxy has stopped accepting new connections and is waiting for the
+    /// given number of connections to end.
+    Draining { connections: usize },
+
+    /// Periodic overview of the proxy's activity. The byte and message
+    /// counts are totals since the proxy started.
+    Status {
+        connections: usize,
+        bytes: u64,
+        messages: u64,
+        messages_per_sec: f64,
+    },
+
+    /// At the end of a capture file, the number of TCP connections that were
+    /// skipped because of the port filter or MAPI detection, and the number
+    /// of bytes they carried.
+    Ignored { connections: u64, bytes: u64 },
+
+    /// A packet in a capture file could not be decoded and has been skipped.
+    /// The connection it belonged to, if any, sees its data as missing. If
+    /// its headers could still be made out, `id` says which one that was.
+    SkippedPacket {
+        id: Option<ConnectionId>,
+        timestamp: CaptureTime,
+        error: String,
+    },
+
     /// A new client connection has been detected. Introduces a newly allocated
     /// [ConnectionId].
     Incoming {
@@ -76,22 +563,56 @@ pub enum MapiEvent {
         peer: Addr,
     },
 
+    /// The client has started a TLS handshake for this server name
+    ServerName { id: ConnectionId, name: String },
+
+    /// The connection was already open when the capture started. Data before
+    /// the first plausible MAPI block header has been skipped.
+    JoinedMidStream { id: ConnectionId },
+
+    /// The connection in the capture file uses TLS and will be decrypted
+    /// using the key log. The Data events carry the decrypted data.
+    TlsDecrypted {
+        id: ConnectionId,
+        version: &'static str,
+    },
+
+    /// The connection in the capture file uses TLS but cannot be decrypted,
+    /// either from the start or from some point on in one direction.
+    TlsNotDecrypted {
+        id: ConnectionId,
+        direction: Option<Direction>,
+        reason: String,
+    },
+
     /// Proxy is connecting to the server
     Connecting {
         id: ConnectionId,
         remote: Addr,
     },
 
-    /// Server has accepted the new connection
-    Connected {
+    /// All connection attempts have failed, the proxy will try again
+    /// after the given delay.
+    ConnectRetry {
         id: ConnectionId,
-        peer: Addr,
+        attempt: u32,
+        retries: u32,
+        delay: Duration,
     },
 
+    /// Another connection attempt succeeded first, this one has been
+    /// abandoned.
+    ConnectCancelled { id: ConnectionId, remote: String },
+
+    /// Server has accepted the new connection
+    Connected { id: ConnectionId, peer: Addr },
+
     /// The connection has ended peacefully, no more events on this
     /// [ConnectionId] will be reported.
     End {
         id: ConnectionId,
+        traffic: Traffic,
+        timing: Timing,
     },
 
     /// Something went wrong in Mapiproxy (not in the client or the server), no
@@ -99,6 +620,16 @@ pub enum MapiEvent {
     Aborted {
         id: ConnectionId,
         error: Error,
+        traffic: Traffic,
+        timing: Timing,
+    },
+
+    /// The connection to the mirror server has failed. The main connection
+    /// is not affected.
+    MirrorFailed {
+        id: ConnectionId,
+        remote: String,
+        error: io::Error,
     },
 
     /// Data has been observed flowing from client to server
@@ -108,6 +639,33 @@ pub enum MapiEvent {
         id: ConnectionId,
         direction: Direction,
         data: SmallVec<[u8; 8]>,
+        /// When the data was captured, only known when reading pcap files
+        timestamp: Option<CaptureTime>,
+    },
+
+    /// A packet in a capture file repeats data that has already been seen but
+    /// its contents are different. The offset is the position in the stream
+    /// of the first byte that differs.
+    RetransmissionDiffers {
+        id: ConnectionId,
+        direction: Direction,
+        offset: u64,
+    },
+
+    /// Noteworthy TCP behavior seen in a capture file. The direction is that
+    /// of the packet that shows it.
+    Tcp {
+        id: ConnectionId,
+        direction: Direction,
+        event: TcpEvent,
+    },
+
+    /// A capture file is missing some of the data of a connection. Decoding
+    /// resumes at the first MAPI block header after the gap.
+    DataMissing {
+        id: ConnectionId,
+        direction: Direction,
+        bytes: u64,
     },
 
     /// Client or server has shut down the write-half of its socket. No more data will
@@ -139,6 +697,61 @@ pub enum MapiEvent {
     },
 }
 
+/// Things that happen at the TCP level that help explain why traffic is
+/// slow, reported with `--tcp-events`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpEvent {
+    /// A packet repeats this many bytes that have already been sent
+    Retransmission { bytes: usize },
+    /// The sender can't receive any more data for now
+    ZeroWindow,
+    /// The sender can receive data again after advertising a zero window
+    /// for this long
+    WindowOpened { stalled: Duration },
+    /// The sender has reset the connection
+    Reset,
+}
+
+impl MapiEvent {
+    /// The connection this event is about, if any.
+    pub fn id(&self) -> Option<ConnectionId> {
+        use MapiEvent::*;
+        match self {
+            BoundPort(_) | Draining { .. } | Status { .. } | Ignored { .. } => None,
+            SkippedPacket { id, .. } => *id,
+            Incoming { id, .. }
+            | ServerName { id, .. }
+            | JoinedMidStream { id }
+            | TlsDecrypted { id, .. }
+            | TlsNotDecrypted { id, .. }
+            | Connecting { id, .. }
+            | ConnectRetry { id, .. }
+            | ConnectCancelled { id, .. }
+            | Connected { id, .. }
+            | End { id, .. }
+            | Aborted { id, .. }
+            | MirrorFailed { id, .. }
+            | Data { id, .. }
+            | RetransmissionDiffers { id, .. }
+            | Tcp { id, .. }
+            | DataMissing { id, .. }
+            | ShutdownRead { id, .. }
+            | ShutdownWrite { id, .. }
+            | ConnectFailed { id, .. } => Some(*id),
+        }
+    }
+
+    /// The capture time of this event, if it was read from a capture file
+    /// and says when it happened.
+    pub fn timestamp(&self) -> Option<CaptureTime> {
+        match self {
+            MapiEvent::Data { timestamp, .. } => *timestamp,
+            MapiEvent::SkippedPacket { timestamp, .. } => Some(*timestamp),
+            _ => None,
+        }
+    }
+}
+
 /// Struct [EventSink] knows what to do with new [MapiEvent]s and
 /// provides helper functions to generate such events.
 ///
@@ -169,6 +782,21 @@ impl EventSink {
     pub fn emit_bound(&mut self, port: Addr) {
         self.emit_event(MapiEvent::BoundPort(port))
     }
+
+    /// Emit a [MapiEvent::Draining] event.
+    pub fn emit_draining(&mut self, connections: usize) {
+        self.emit_event(MapiEvent::Draining { connections })
+    }
+
+    /// Emit a [MapiEvent::Status] event.
+    pub fn emit_status(&mut self, connections: usize, total: Volume, messages_per_sec: f64) {
+        self.emit_event(MapiEvent::Status {
+            connections,
+            bytes: total.bytes,
+            messages: total.messages.unwrap_or_default(),
+            messages_per_sec,
+        })
+    }
 }
 
 /// Helper struct to emit [MapiEvent]s about a specific connection.
@@ -193,6 +821,14 @@ impl<'a> ConnectionSink<'a> {
         });
     }
 
+    /// Emit a [MapiEvent::ServerName] event.
+    pub fn emit_server_name(&mut self, name: String) {
+        self.0.emit_event(MapiEvent::ServerName {
+            id: self.id(),
+            name,
+        });
+    }
+
     /// Emit a [MapiEvent::Connecting] event.
     pub fn emit_connecting(&mut self, remote: Addr) {
         self.0.emit_event(MapiEvent::Connecting {
@@ -211,6 +847,24 @@ impl<'a> ConnectionSink<'a> {
         });
     }
 
+    /// Emit a [MapiEvent::ConnectRetry] event.
+    pub fn emit_connect_retry(&mut self, attempt: u32, retries: u32, delay: Duration) {
+        self.0.emit_event(MapiEvent::ConnectRetry {
+            id: self.id(),
+            attempt,
+            retries,
+            delay,
+        });
+    }
+
+    /// Emit a [MapiEvent::ConnectCancelled] event.
+    pub fn emit_connect_cancelled(&mut self, remote: String) {
+        self.0.emit_event(MapiEvent::ConnectCancelled {
+            id: self.id(),
+            remote,
+        });
+    }
+
     /// Emit a [MapiEvent::Connected] event.
     pub fn emit_connected(&mut self, remote: Addr) {
         self.0.emit_event(MapiEvent::Connected {
@@ -220,15 +874,30 @@ impl<'a> ConnectionSink<'a> {
     }
 
     /// Emit a [MapiEvent::End] event.
-    pub fn emit_end(&mut self) {
-        self.0.emit_event(MapiEvent::End { id: self.id() });
+    pub fn emit_end(&mut self, traffic: Traffic, timing: Timing) {
+        self.0.emit_event(MapiEvent::End {
+            id: self.id(),
+            traffic,
+            timing,
+        });
     }
 
     /// Emit a [MapiEvent::Aborted] event.
-    pub fn emit_aborted(&mut self, error: Error) {
+    pub fn emit_aborted(&mut self, error: Error, traffic: Traffic, timing: Timing) {
         self.0.emit_event(MapiEvent::Aborted {
             id: self.id(),
             error,
+            traffic,
+            timing,
+        });
+    }
+
+    /// Emit a [MapiEvent::MirrorFailed] event.
+    pub fn emit_mirror_failed(&mut self, remote: String, error: io::Error) {
+        self.0.emit_event(MapiEvent::MirrorFailed {
+            id: self.id(),
+            remote,
+            error,
         });
     }
 
@@ -238,6 +907,7 @@ impl<'a> ConnectionSink<'a> {
             id: self.id(),
             direction,
             data: SmallVec::from_slice(data),
+            timestamp: None,
         })
     }
 
@@ -258,3 +928,70 @@ impl<'a> ConnectionSink<'a> {
         });
     }
 }
+
+#[test]
+fn test_timing_backwards() {
+    let secs = Duration::from_secs;
+    let timing = Timing::between(secs(10), Some((secs(12), secs(9))), secs(8));
+    assert_eq!(timing.duration, Duration::ZERO);
+    assert_eq!(timing.first_data, Some(secs(2)));
+    assert_eq!(timing.last_data, Some(Duration::ZERO));
+}
+
+#[test]
+fn test_capture_time() {
+    let t = |secs, micros: u32| CaptureTime(Duration::new(secs, micros * 1000)).to_string();
+    assert_eq!(t(0, 0), "1970-01-01 00:00:00.000000");
+    assert_eq!(t(951_868_799, 42), "2000-02-29 23:59:59.000042");
+    assert_eq!(t(1_709_647_321, 500_000), "2024-03-05 14:02:01.500000");
+
+    let time = CaptureTime(Duration::new(1_709_647_321, 0));
+    let zoned = |offset| time.in_zone(TimeZone::Offset(offset)).to_string();
+    assert_eq!(zoned(0), "2024-03-05 14:02:01.000000 +00:00");
+    assert_eq!(zoned(5 * 3600 + 1800), "2024-03-05 19:32:01.000000 +05:30");
+    assert_eq!(zoned(-15 * 3600), "2024-03-04 23:02:01.000000 -15:00");
+
+    let time = CaptureTime(Duration::new(1_709_647_321, 500_000_000));
+    let zoned = time.in_zone(TimeZone::Offset(2 * 3600));
+    assert_eq!(
+        zoned.format("%Y-%m-%dT%H:%M:%S.%3f%:z"),
+        "2024-03-05T16:02:01.500+02:00"
+    );
+    assert_eq!(
+        zoned
+            .with_format(Some("%F %T.%f %z, %s, 100%% %q".into()))
+            .to_string(),
+        "2024-03-05 16:02:01.500000 +0200, 1709647321, 100% %q"
+    );
+    assert_eq!(unknown_placeholder("%F %T.%3f %%"), None);
+    assert_eq!(unknown_placeholder("%Y %q"), Some("%q".to_string()));
+    assert_eq!(unknown_placeholder("100%"), Some("%".to_string()));
+}
+
+#[test]
+fn test_parse_capture_time() {
+    let utc = |text| CaptureTime::parse(text, TimeZone::Utc).map(|t| t.to_string());
+    let expected = Some("2024-03-05 14:02:01.500000".to_string());
+    assert_eq!(utc("2024-03-05 14:02:01.500000"), expected);
+    assert_eq!(utc("2024-03-05T14:02:01.5Z"), expected);
+    assert_eq!(utc("2024-03-05 15:02:01.5 +01:00"), expected);
+    assert_eq!(utc("2024-03-05T09:02:01.5-05:00"), expected);
+    assert_eq!(
+        utc("2000-02-29 23:59"),
+        Some("2000-02-29 23:59:00.000000".to_string())
+    );
+    assert_eq!(
+        utc("1970-01-01 00:00:00"),
+        Some("1970-01-01 00:00:00.000000".to_string())
+    );
+
+    let t = CaptureTime::parse("2024-03-05 19:32:01", TimeZone::Offset(5 * 3600 + 1800));
+    assert_eq!(t, Some(CaptureTime(Duration::new(1_709_647_321, 0))));
+
+    assert_eq!(utc("2024-03-05"), None);
+    assert_eq!(utc("2024-13-05 14:02"), None);
+    assert_eq!(utc("2024-03-05 14:02:01 +1"), None);
+    assert_eq!(utc("1969-12-31 23:59:59"), None);
+    assert_eq!(utc("99999999999999-01-01 00:00"), None);
+    assert_eq!(utc("9223372036854775807-12-31 23:59"), None);
+}
@@ -1,29 +1,49 @@
 use std::{
+    collections::VecDeque,
     io::{self, ErrorKind, Read, Write},
     ops::ControlFlow::{self, Break, Continue},
+    time::{Duration, Instant},
     vec,
 };
 
-use mio::{
-    event::{Event, Source},
-    Interest, Registry, Token,
-};
+use mio::{event::Source, Interest, Registry, Token};
+use tracing::{debug, trace};
 
+#[cfg(target_os = "linux")]
+use super::splice::Pipe;
 use super::{
-    event::{ConnectionId, ConnectionSink, Direction},
-    network::{Addr, MioStream, MonetAddr},
-    would_block, Error, Result,
+    counter::Counter,
+    event::{ConnectionId, ConnectionSink, Direction, Timing, Traffic},
+    network::{Addr, MioStream, MonetAddr, Resolver},
+    sni::{self, Sniffed},
+    would_block, Error, KillAfter, Options, Result,
 };
 
-pub struct Forwarder(Option<Forwarding>, ConnectionId);
+pub struct Forwarder {
+    state: Option<Forwarding>,
+    id: ConnectionId,
+    mirror: Option<Mirror>,
+    /// What has been forwarded so far
+    traffic: Traffic,
+    /// When the client connection was accepted
+    started: Instant,
+    /// When data was forwarded for the first and for the last time
+    data_seen: Option<(Instant, Instant)>,
+}
 
+// Running is the large one but it's also the common case so boxing it
+// would not help
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 enum Forwarding {
+    Sniffing(Sniffing),
     Connecting(Connecting),
+    Retrying(Retrying),
     Running(Running),
 }
 
 impl Forwarder {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         registry: &Registry,
         event_sink: &mut ConnectionSink,
@@ -32,72 +52,313 @@ impl Forwarder {
         client_token: Token,
         forward_addr: &MonetAddr,
         server_token: Token,
+        mirror_token: Token,
+        options: &Options,
+        resolver: &mut Resolver,
     ) -> Result<Self> {
-        let connecting = Connecting::new(
-            event_sink,
-            forward_addr,
-            peer,
-            client_token,
-            conn,
-            server_token,
-            registry,
-        )?;
-        let forwarding = Forwarding::Connecting(connecting);
-        let forwarder = Forwarder(Some(forwarding), event_sink.id());
+        let client_is_unix = conn.is_unix();
+        let client = Registered::new(peer.to_string(), client_token, conn);
+        let forwarding = if options.sni_routes.is_empty() || client_is_unix {
+            Connecting::start(
+                event_sink,
+                forward_addr,
+                client,
+                server_token,
+                registry,
+                options,
+                resolver,
+            )?
+        } else {
+            let wait = options.sni_wait.unwrap_or(Sniffing::TIMEOUT);
+            Sniffing::start(client, forward_addr, server_token, registry, wait)?
+        };
+
+        let mirror = if let Some(mirror_addr) = &options.mirror {
+            match Mirror::start(
+                mirror_addr,
+                mirror_token,
+                registry,
+                client_is_unix,
+                resolver,
+            ) {
+                Ok(m) => Some(m),
+                Err(e) => {
+                    event_sink.emit_mirror_failed(mirror_addr.to_string(), e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let forwarder = Forwarder {
+            state: Some(forwarding),
+            id: event_sink.id(),
+            mirror,
+            traffic: Traffic::default(),
+            started: Instant::now(),
+            data_seen: None,
+        };
         Ok(forwarder)
     }
 
     pub fn id(&self) -> ConnectionId {
-        self.1
+        self.id
+    }
+
+    pub fn traffic(&self) -> Traffic {
+        self.traffic
+    }
+
+    /// The [Timing] of the connection so far
+    pub fn timing(&self) -> Timing {
+        Timing::between(self.started, self.data_seen, Instant::now())
+    }
+
+    /// If the forwarder needs to be processed at a certain time even if no
+    /// IO events occur, return that time.
+    pub fn deadline(&self) -> Option<Instant> {
+        match (&self.state, &self.mirror) {
+            (Some(Forwarding::Sniffing(s)), _) => Some(s.deadline),
+            (Some(Forwarding::Connecting(c)), _) => c.deadline(),
+            (Some(Forwarding::Retrying(r)), _) => Some(r.deadline),
+            (Some(Forwarding::Running(r)), _) => [r.kill_deadline, r.downstream.hold_until]
+                .into_iter()
+                .flatten()
+                .min(),
+            (None, Some(m)) => m.closing,
+            _ => None,
+        }
     }
 
     pub fn deregister(&mut self, registry: &Registry) {
-        match &mut self.0 {
+        if let Some(m) = &mut self.mirror {
+            m.deregister(registry);
+        }
+        match &mut self.state {
+            Some(Forwarding::Sniffing(s)) => s.deregister(registry),
             Some(Forwarding::Connecting(c)) => c.deregister(registry),
+            Some(Forwarding::Retrying(r)) => r.deregister(registry),
             Some(Forwarding::Running(r)) => r.deregister(registry),
             None => {}
         }
     }
 
+    /// Make as much progress as possible. Called when an IO event occurs and
+    /// when the [Forwarder::deadline] has passed.
+    ///
+    /// Emits [MapiEvent::End][super::event::MapiEvent::End] when the connection
+    /// ends. Returns [Break] when the forwarder can be dropped, which may be
+    /// later if we're still sending data to the mirror.
     pub fn handle_event(
         &mut self,
         sink: &mut ConnectionSink,
         registry: &Registry,
-        _ev: &Event,
+        options: &Options,
+        resolver: &mut Resolver,
     ) -> Result<ControlFlow<()>> {
-        let old_state = self.0.take().unwrap();
-        let handled: ControlFlow<(), Forwarding> = match old_state {
-            Forwarding::Connecting(c) => c.process(sink, registry)?,
-            Forwarding::Running(r) => r.process(sink, registry)?,
+        let mirror = self.mirror.as_mut();
+        let was_active = self.state.is_some();
+        // The state is None if the connection has ended but the mirror
+        // still has data to send.
+        let bytes_before = self.traffic.total().bytes;
+        let handled = match self.state.take() {
+            Some(Forwarding::Sniffing(s)) => s.process(sink, registry, options, resolver),
+            Some(Forwarding::Connecting(c)) => {
+                c.process(sink, registry, options, mirror, &mut self.traffic)
+            }
+            Some(Forwarding::Retrying(r)) => r.process(sink, registry, options),
+            Some(Forwarding::Running(r)) => r.process(sink, registry, mirror, &mut self.traffic),
+            None => Ok(Break(())),
         };
-        match handled {
-            Continue(forwarding) => {
-                self.0 = Some(forwarding);
-                Ok(Continue(()))
+        if self.traffic.total().bytes != bytes_before {
+            let now = Instant::now();
+            let first = self.data_seen.map_or(now, |(first, _)| first);
+            self.data_seen = Some((first, now));
+        }
+        match handled? {
+            Continue(forwarding) => self.state = Some(forwarding),
+            Break(()) if was_active => {
+                // Report the end of the connection now, even if the mirror
+                // still needs some time.
+                sink.emit_end(self.traffic, self.timing());
+                if let Some(m) = &mut self.mirror {
+                    m.close();
+                }
             }
-            Break(()) => Ok(Break(())),
+            Break(()) => {}
+        }
+
+        self.process_mirror(sink, registry);
+
+        if self.state.is_none() && self.mirror.is_none() {
+            Ok(Break(()))
+        } else {
+            Ok(Continue(()))
         }
     }
+
+    /// Send pending data to the mirror and discard its responses. If anything
+    /// goes wrong we report it and drop the mirror, the main connection is not
+    /// affected.
+    fn process_mirror(&mut self, sink: &mut ConnectionSink, registry: &Registry) {
+        let Some(mirror) = &mut self.mirror else {
+            return;
+        };
+        mirror.pump();
+        let result = match mirror.error.take() {
+            Some(e) => Err(e),
+            None => mirror.conn.update_registration(registry),
+        };
+        if let Err(e) = result {
+            sink.emit_mirror_failed(mirror.conn.name.clone(), e);
+        } else if !mirror.done() {
+            return;
+        }
+        mirror.deregister(registry);
+        self.mirror = None;
+    }
+}
+
+/// Waiting for the TLS ClientHello to find out where to forward to.
+#[derive(Debug)]
+struct Sniffing {
+    client: Registered<MioStream>,
+    forward_addr: MonetAddr,
+    server_token: Token,
+    deadline: Instant,
+}
+
+impl Sniffing {
+    /// Clients that speak plain MAPI wait for the server to speak first.
+    /// This is how long we wait for a ClientHello before giving up, unless
+    /// [Options::sni_wait] says otherwise. It delays every plain MAPI login.
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Largest possible TLS record including its header. We only look at the
+    /// first record.
+    const MAX_PEEK: usize = 5 + 16 * 1024;
+
+    fn start(
+        mut client: Registered<MioStream>,
+        forward_addr: &MonetAddr,
+        server_token: Token,
+        registry: &Registry,
+        wait: Duration,
+    ) -> Result<Forwarding> {
+        client.need(Some(Interest::READABLE));
+        client
+            .update_registration(registry)
+            .map_err(|err| Error::Forward {
+                doing: "registering",
+                side: "client",
+                err,
+            })?;
+        let sniffing = Sniffing {
+            client,
+            forward_addr: forward_addr.clone(),
+            server_token,
+            deadline: Instant::now() + wait,
+        };
+        Ok(Forwarding::Sniffing(sniffing))
+    }
+
+    fn deregister(&mut self, registry: &Registry) {
+        let _ = self.client.deregister(registry);
+    }
+
+    fn process(
+        mut self,
+        sink: &mut ConnectionSink,
+        registry: &Registry,
+        options: &Options,
+        resolver: &mut Resolver,
+    ) -> Result<ControlFlow<(), Forwarding>> {
+        // Peek rather than read so the ClientHello is forwarded unchanged later
+        let mut buf = vec![0u8; Self::MAX_PEEK];
+        let peeked = self
+            .client
+            .attempt(Interest::READABLE, |conn| conn.peek(&mut buf));
+        let sniffed = match peeked {
+            // The client has closed the connection, let Running deal with it
+            Ok(0) => Sniffed::NoServerName,
+            Ok(n) => match sni::sniff(&buf[..n]) {
+                Sniffed::Incomplete if n == buf.len() => Sniffed::NoServerName,
+                sniffed => sniffed,
+            },
+            Err(e) if would_block(&e) => Sniffed::Incomplete,
+            Err(err) => {
+                return Err(Error::Forward {
+                    doing: "reading",
+                    side: "client",
+                    err,
+                })
+            }
+        };
+
+        let server_addr = match sniffed {
+            Sniffed::Incomplete if Instant::now() < self.deadline => {
+                return Ok(Continue(Forwarding::Sniffing(self)));
+            }
+            Sniffed::ServerName(name) => {
+                let route = options
+                    .sni_routes
+                    .iter()
+                    .find(|(server_name, _)| server_name.eq_ignore_ascii_case(&name));
+                sink.emit_server_name(name);
+                match route {
+                    Some((_, addr)) => addr.clone(),
+                    None => self.forward_addr,
+                }
+            }
+            _ => self.forward_addr,
+        };
+
+        let forwarding = Connecting::start(
+            sink,
+            &server_addr,
+            self.client,
+            self.server_token,
+            registry,
+            options,
+            resolver,
+        )?;
+        Ok(Continue(forwarding))
+    }
 }
 
 #[derive(Debug)]
 struct Connecting {
     client: Registered<MioStream>,
-    server: Registered<MioStream>,
+    /// Connection attempts in progress, with the time to give up on each.
+    /// Without happy eyeballs there is at most one.
+    servers: Vec<(Registered<MioStream>, Option<Instant>)>,
+    server_token: Token,
     addrs: vec::IntoIter<Addr>,
+    /// All addresses, in case we have to start over
+    all_addrs: Vec<Addr>,
+    /// How many times we have already started over
+    attempt: u32,
+    /// With happy eyeballs, when to start the next attempt even though
+    /// the earlier ones are still in progress
+    stagger: Option<Instant>,
 }
 
 impl Connecting {
-    fn new(
+    /// Happy eyeballs: delay between starting two connection attempts,
+    /// as recommended in RFC 8305.
+    const STAGGER: Duration = Duration::from_millis(250);
+
+    fn start(
         event_sink: &mut ConnectionSink,
         server_addr: &MonetAddr,
-        client_addr: Addr,
-        client_token: Token,
-        client: MioStream,
+        client: Registered<MioStream>,
         server_token: Token,
         registry: &Registry,
-    ) -> Result<Connecting> {
-        let addrs = match server_addr.resolve() {
+        options: &Options,
+        resolver: &mut Resolver,
+    ) -> Result<Forwarding> {
+        let mut addrs = match resolver.resolve(server_addr) {
             Ok(addrs) => addrs,
             Err(e) => {
                 event_sink.emit_connect_failed(server_addr.to_string(), true, e);
@@ -112,20 +373,46 @@ impl Connecting {
             return Err(Error::Connect);
         }
 
-        let client = Registered::new(client_addr.to_string(), client_token, client);
+        if options.happy_eyeballs {
+            addrs = interleave_families(addrs);
+        }
 
-        let mut addrs = addrs.into_iter();
-        let Some(server) = Self::connect_addrs(event_sink, server_token, registry, &mut addrs)
-        else {
-            return Err(Error::Connect);
-        };
+        match Self::retry(
+            event_sink,
+            client,
+            server_token,
+            addrs,
+            0,
+            registry,
+            options,
+        )? {
+            Continue(forwarding) => Ok(forwarding),
+            Break(()) => unreachable!("we don't break before we're connected"),
+        }
+    }
 
+    /// Try the addresses in `all_addrs` from the beginning. If all of them
+    /// fail immediately, schedule another attempt if the retry policy in
+    /// `options` allows it.
+    fn retry(
+        event_sink: &mut ConnectionSink,
+        client: Registered<MioStream>,
+        server_token: Token,
+        all_addrs: Vec<Addr>,
+        attempt: u32,
+        registry: &Registry,
+        options: &Options,
+    ) -> Result<ControlFlow<(), Forwarding>> {
         let connecting = Connecting {
             client,
-            server,
-            addrs,
+            servers: vec![],
+            server_token,
+            addrs: all_addrs.clone().into_iter(),
+            all_addrs,
+            attempt,
+            stagger: None,
         };
-        Ok(connecting)
+        connecting.continue_with_next(event_sink, registry, options)
     }
 
     /// Try to connect to each of the addrs in turn, returning when one succeeds.
@@ -139,6 +426,7 @@ impl Connecting {
         addrs: impl Iterator<Item = Addr>,
     ) -> Option<Registered<MioStream>> {
         for addr in addrs {
+            debug!(id = %event_sink.id(), %addr, "connect attempt");
             event_sink.emit_connecting(addr.clone());
             let err = match addr.connect() {
                 Ok(stream) => {
@@ -151,6 +439,7 @@ impl Connecting {
                 }
                 Err(e) => e,
             };
+            debug!(id = %event_sink.id(), %addr, %err, "connect failed immediately");
             event_sink.emit_connect_failed(addr.to_string(), true, err);
         }
         None
@@ -158,59 +447,199 @@ impl Connecting {
 
     fn deregister(&mut self, registry: &Registry) {
         let _ = self.client.deregister(registry);
-        let _ = self.server.deregister(registry);
+        for (server, _) in &mut self.servers {
+            let _ = server.deregister(registry);
+        }
     }
 
-    fn process(
-        self,
+    /// The earliest time at which something needs to happen even if there
+    /// are no IO events.
+    fn deadline(&self) -> Option<Instant> {
+        let timeouts = self.servers.iter().filter_map(|(_, t)| *t);
+        timeouts.chain(self.stagger).min()
+    }
+
+    /// Start connecting to the next address. If there are no addresses left
+    /// and no attempts in progress, schedule a retry or give up.
+    fn continue_with_next(
+        mut self,
         sink: &mut ConnectionSink,
         registry: &Registry,
+        options: &Options,
     ) -> Result<ControlFlow<(), Forwarding>> {
+        let token = self.server_token;
+        if let Some(server) = Self::connect_addrs(sink, token, registry, &mut self.addrs) {
+            let timeout = options.connect_timeout.map(|t| Instant::now() + t);
+            self.servers.push((server, timeout));
+            if options.happy_eyeballs && self.addrs.len() > 0 {
+                self.stagger = Some(Instant::now() + Self::STAGGER);
+            }
+            return Ok(Continue(Forwarding::Connecting(self)));
+        }
+
+        if !self.servers.is_empty() {
+            return Ok(Continue(Forwarding::Connecting(self)));
+        }
+
         let Connecting {
             client,
-            mut server,
-            mut addrs,
+            all_addrs,
+            attempt,
+            ..
         } = self;
+        let retrying = Retrying::schedule(sink, client, token, all_addrs, attempt, options)?;
+        Ok(Continue(Forwarding::Retrying(retrying)))
+    }
 
-        let established = server.attempt(Interest::WRITABLE, |conn| conn.established());
-
-        // If it succeeded or if we're still waiting, handle that here.
-        // Otherwise, we'll have to report the error and try another address
-        let error = match established {
-            Ok(Some(peer)) => {
-                sink.emit_connected(peer);
-                let running = Running::from(client, server)?;
-                // kickstart it by running its process method too
-                return running.process(sink, registry);
+    fn process(
+        mut self,
+        sink: &mut ConnectionSink,
+        registry: &Registry,
+        options: &Options,
+        mirror: Option<&mut Mirror>,
+        traffic: &mut Traffic,
+    ) -> Result<ControlFlow<(), Forwarding>> {
+        let now = Instant::now();
+        let mut failed = false;
+        let mut i = 0;
+        while i < self.servers.len() {
+            let (server, timeout) = &mut self.servers[i];
+            let mut established = server.attempt(Interest::WRITABLE, |conn| conn.established());
+            if matches!(established, Ok(None)) && timeout.is_some_and(|t| now >= t) {
+                let msg = "connection attempt timed out";
+                established = Err(io::Error::new(ErrorKind::TimedOut, msg));
             }
-            Ok(None) => {
-                let connecting = Connecting {
-                    client,
-                    server,
-                    addrs,
-                };
-                let forwarding = Forwarding::Connecting(connecting);
-                return Ok(Continue(forwarding));
+
+            // If it succeeded or if we're still waiting, handle that here.
+            // Otherwise, we'll have to report the error and try another address
+            match established {
+                Ok(Some(peer)) => {
+                    debug!(id = %sink.id(), server = server.name, "connect succeeded");
+                    let (server, _) = self.servers.swap_remove(i);
+                    for (loser, _) in self.servers.drain(..) {
+                        sink.emit_connect_cancelled(loser.name.clone());
+                    }
+                    sink.emit_connected(peer);
+                    let running = Running::from(self.client, server, options)?;
+                    // kickstart it by running its process method too
+                    return running.process(sink, registry, mirror, traffic);
+                }
+                Ok(None) => {
+                    i += 1;
+                }
+                Err(e) => {
+                    let (server, _) = self.servers.remove(i);
+                    debug!(id = %sink.id(), server = server.name, %e, "connect failed");
+                    sink.emit_connect_failed(server.name.clone(), false, e);
+                    failed = true;
+                }
             }
-            Err(e) => e,
+        }
+
+        let stagger_expired = self.stagger.is_some_and(|t| now >= t);
+        if stagger_expired {
+            self.stagger = None;
+        }
+        if failed || stagger_expired {
+            self.continue_with_next(sink, registry, options)
+        } else {
+            Ok(Continue(Forwarding::Connecting(self)))
+        }
+    }
+}
+
+/// Reorder the addresses so IPv6 and IPv4 alternate, starting with the
+/// family of the first address, as described in RFC 8305.
+fn interleave_families(addrs: Vec<Addr>) -> Vec<Addr> {
+    let is_v6 = |a: &Addr| matches!(a, Addr::Tcp(sa) if sa.is_ipv6());
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let first_is_v6 = is_v6(first);
+    let (same, other): (Vec<Addr>, Vec<Addr>) =
+        addrs.into_iter().partition(|a| is_v6(a) == first_is_v6);
+    itertools::interleave(same, other).collect()
+}
+
+/// All addresses have failed, we're waiting until it's time to try again.
+#[derive(Debug)]
+struct Retrying {
+    client: Registered<MioStream>,
+    server_token: Token,
+    all_addrs: Vec<Addr>,
+    /// The number of the upcoming attempt, starting at 1
+    attempt: u32,
+    deadline: Instant,
+}
+
+impl Retrying {
+    /// Longest delay between two attempts, the exponential backoff stops here
+    const MAX_DELAY: Duration = Duration::from_secs(60);
+
+    /// Schedule the next attempt or return [Error::Connect] if we have
+    /// exhausted the retries allowed by `options`.
+    fn schedule(
+        sink: &mut ConnectionSink,
+        client: Registered<MioStream>,
+        server_token: Token,
+        all_addrs: Vec<Addr>,
+        previous_attempt: u32,
+        options: &Options,
+    ) -> Result<Retrying> {
+        let Some((retries, initial_delay)) = options.retry else {
+            return Err(Error::Connect);
         };
+        let attempt = previous_attempt + 1;
+        if attempt > retries {
+            return Err(Error::Connect);
+        }
+
+        let factor = 1u32.checked_shl(previous_attempt).unwrap_or(u32::MAX);
+        let delay = initial_delay
+            .checked_mul(factor)
+            .unwrap_or(Self::MAX_DELAY)
+            .min(Self::MAX_DELAY);
+        sink.emit_connect_retry(attempt, retries, delay);
 
-        sink.emit_connect_failed(server.name.clone(), false, error);
+        let retrying = Retrying {
+            client,
+            server_token,
+            all_addrs,
+            attempt,
+            deadline: Instant::now() + delay,
+        };
+        Ok(retrying)
+    }
 
-        let token = server.token;
-        drop(server);
+    fn deregister(&mut self, registry: &Registry) {
+        let _ = self.client.deregister(registry);
+    }
 
-        if let Some(server) = Self::connect_addrs(sink, token, registry, &mut addrs) {
-            let connecting = Connecting {
-                client,
-                server,
-                addrs,
-            };
-            let forwarding = Forwarding::Connecting(connecting);
-            Ok(Continue(forwarding))
-        } else {
-            Err(Error::Connect)
+    fn process(
+        self,
+        sink: &mut ConnectionSink,
+        registry: &Registry,
+        options: &Options,
+    ) -> Result<ControlFlow<(), Forwarding>> {
+        if Instant::now() < self.deadline {
+            return Ok(Continue(Forwarding::Retrying(self)));
         }
+        let Retrying {
+            client,
+            server_token,
+            all_addrs,
+            attempt,
+            ..
+        } = self;
+        Connecting::retry(
+            sink,
+            client,
+            server_token,
+            all_addrs,
+            attempt,
+            registry,
+            options,
+        )
     }
 }
 
@@ -220,21 +649,43 @@ struct Running {
     server: Registered<MioStream>,
     upstream: Copying,
     downstream: Copying,
+    kill_after: KillAfter,
+    /// When to kill the connection because of [KillAfter::duration]
+    kill_deadline: Option<Instant>,
 }
 
 impl Running {
-    fn from(client: Registered<MioStream>, server: Registered<MioStream>) -> Result<Running> {
+    fn from(
+        client: Registered<MioStream>,
+        server: Registered<MioStream>,
+        options: &Options,
+    ) -> Result<Running> {
         let client_is_unix = client.source.is_unix();
         let server_is_unix = server.source.is_unix();
-        let upstream = Copying::new(client_is_unix, server_is_unix);
-        let downstream = Copying::new(false, false);
-
-        for (side, sock) in [("client", &client), ("server", &server)] {
-            sock.source.set_nodelay(true).map_err(|e| Error::Forward {
-                doing: "setting nodelay",
-                side,
-                err: e,
-            })?;
+        let quiet = options.pass_through;
+        // The mirror needs to see the upstream data so we can't splice it.
+        // Neither can we count messages without looking at the data.
+        let delay = options.first_byte_delay;
+        let count_messages =
+            options.kill_after.messages.is_some() || options.status_interval.is_some();
+        let splice = quiet && !count_messages;
+        let splice_upstream = splice && options.mirror.is_none();
+        let splice_downstream = splice && delay.is_none();
+        let upstream = Copying::new(client_is_unix, server_is_unix, quiet, splice_upstream, None);
+        let downstream = Copying::new(false, false, quiet, splice_downstream, delay);
+
+        let sides = [
+            ("client", &client, !options.nagle_client),
+            ("server", &server, !options.nagle_server),
+        ];
+        for (side, sock, nodelay) in sides {
+            sock.source
+                .set_nodelay(nodelay)
+                .map_err(|e| Error::Forward {
+                    doing: "setting nodelay",
+                    side,
+                    err: e,
+                })?;
         }
 
         let running = Running {
@@ -242,6 +693,8 @@ impl Running {
             server,
             upstream,
             downstream,
+            kill_after: options.kill_after,
+            kill_deadline: options.kill_after.duration.map(|d| Instant::now() + d),
         };
         Ok(running)
     }
@@ -251,17 +704,36 @@ impl Running {
         let _ = self.server.deregister(registry);
     }
 
-    fn process(
-        mut self,
-        sink: &mut ConnectionSink,
-        registry: &Registry,
-    ) -> Result<ControlFlow<(), Forwarding>> {
+    /// If one of the [KillAfter] thresholds has been crossed, say which.
+    fn kill_reason(
+        kill_after: &KillAfter,
+        kill_deadline: Option<Instant>,
+        upstream: &Copying,
+        downstream: &Copying,
+    ) -> Option<String> {
+        let bytes = upstream.counter.bytes + downstream.counter.bytes;
+        let messages = upstream.counter.messages + downstream.counter.messages;
+        if kill_after.bytes.is_some_and(|n| bytes >= n) {
+            Some(format!("{bytes} bytes"))
+        } else if kill_after.messages.is_some_and(|n| messages >= n) {
+            Some(format!("{messages} messages"))
+        } else if kill_deadline.is_some_and(|d| Instant::now() >= d) {
+            Some(format!("{:?}", kill_after.duration.unwrap()))
+        } else {
+            None
+        }
+    }
+
+    /// Copy data in both directions until no more progress can be made.
+    fn pump(&mut self, sink: &mut ConnectionSink, mut mirror: Option<&mut Mirror>) -> Result<()> {
         let Running {
             client,
             server,
             upstream,
             downstream,
-        } = &mut self;
+            kill_after,
+            kill_deadline,
+        } = self;
 
         let mut progress = true;
         while progress {
@@ -269,8 +741,50 @@ impl Running {
             client.clear();
             server.clear();
 
-            progress |= downstream.handle_one(Direction::Downstream, sink, server, client)?;
-            progress |= upstream.handle_one(Direction::Upstream, sink, client, server)?;
+            progress |= downstream.handle_one(Direction::Downstream, sink, server, client, None)?;
+            progress |= upstream.handle_one(
+                Direction::Upstream,
+                sink,
+                client,
+                server,
+                mirror.as_deref_mut(),
+            )?;
+
+            if let Some(reason) =
+                Self::kill_reason(kill_after, *kill_deadline, upstream, downstream)
+            {
+                return Err(Error::Killed(reason));
+            }
+        }
+        Ok(())
+    }
+
+    fn process(
+        mut self,
+        sink: &mut ConnectionSink,
+        registry: &Registry,
+        mut mirror: Option<&mut Mirror>,
+        traffic: &mut Traffic,
+    ) -> Result<ControlFlow<(), Forwarding>> {
+        let pumped = self.pump(sink, mirror.as_deref_mut());
+        *traffic = Traffic {
+            upstream: self.upstream.counter.volume(),
+            downstream: self.downstream.counter.volume(),
+        };
+        pumped?;
+
+        let Running {
+            client,
+            server,
+            upstream,
+            downstream,
+            ..
+        } = &mut self;
+
+        if let Some(m) = mirror {
+            if !upstream.can_read {
+                m.close();
+            }
         }
 
         client
@@ -304,12 +818,29 @@ pub struct Copying {
     unsent_data: usize,
     free_space: usize,
     fix_unix_read: bool,
+    /// Do not emit the data that passes through
+    quiet: bool,
+    /// If set, forward using splice(2) once the buffer is empty
+    #[cfg(target_os = "linux")]
+    pipe: Option<Pipe>,
+    /// Keeps track of what has been written
+    counter: Counter,
+    /// Hold back the first byte of every message for this long
+    first_byte_delay: Option<Duration>,
+    /// If set, we're holding back the first byte of a message until then
+    hold_until: Option<Instant>,
 }
 
 impl Copying {
     const BUFSIZE: usize = 8192;
 
-    fn new(fix_unix_read: bool, fix_unix_write: bool) -> Self {
+    fn new(
+        fix_unix_read: bool,
+        fix_unix_write: bool,
+        quiet: bool,
+        splice: bool,
+        first_byte_delay: Option<Duration>,
+    ) -> Self {
         let mut free_space = 0;
         let mut buffer = Box::new([0; Self::BUFSIZE]);
 
@@ -325,6 +856,13 @@ impl Copying {
             unsent_data: 0,
             free_space,
             fix_unix_read,
+            quiet,
+            counter: Counter::new(fix_unix_write),
+            first_byte_delay,
+            hold_until: None,
+            // If we cannot create a pipe we simply fall back to copying
+            #[cfg(target_os = "linux")]
+            pipe: if splice { Pipe::new().ok() } else { None },
         }
     }
 
@@ -334,11 +872,17 @@ impl Copying {
         sink: &mut ConnectionSink,
         rd: &mut Registered<MioStream>,
         wr: &mut Registered<MioStream>,
+        tee: Option<&mut Mirror>,
     ) -> Result<bool> {
         assert!(self.unsent_data <= self.free_space);
         assert!(self.free_space <= Self::BUFSIZE);
         assert!(self.unsent_data == self.free_space || self.can_write);
 
+        #[cfg(target_os = "linux")]
+        if self.pipe.is_some() && !self.fix_unix_read && self.unsent_data == self.free_space {
+            return self.handle_one_spliced(direction, sink, rd, wr);
+        }
+
         let mut progress = false;
 
         if self.fix_unix_read && self.free_space > 0 {
@@ -354,12 +898,17 @@ impl Copying {
             }
         }
 
-        let to_write = &self.buffer[self.unsent_data..self.free_space];
+        let mut end = self.free_space;
+        if self.first_byte_delay.is_some() && self.unsent_data < end {
+            end = self.unsent_data + self.hold_back();
+        }
+        let to_write = &self.buffer[self.unsent_data..end];
         if !to_write.is_empty() {
             assert!(self.can_write);
             match wr.attempt(Interest::WRITABLE, |w| w.write(to_write)) {
                 Ok(n @ 1..) => {
                     progress = true;
+                    self.counter.count(&to_write[..n]);
                     self.unsent_data += n;
                 }
                 Ok(0) => {
@@ -404,7 +953,12 @@ impl Copying {
             match rd.attempt(Interest::READABLE, |r| r.read(dest)) {
                 Ok(n @ 1..) => {
                     let data = &dest[..n];
-                    sink.emit_data(direction, data);
+                    if !self.quiet {
+                        sink.emit_data(direction, data);
+                    }
+                    if let Some(mirror) = tee {
+                        mirror.feed(data);
+                    }
                     progress = true;
                     self.free_space += n;
                 }
@@ -431,11 +985,282 @@ impl Copying {
         Ok(progress)
     }
 
+    /// Like [Copying::handle_one] but moves the data through a pipe using
+    /// splice(2) rather than through our buffer. Only used when the buffer
+    /// is empty.
+    #[cfg(target_os = "linux")]
+    fn handle_one_spliced(
+        &mut self,
+        direction: Direction,
+        sink: &mut ConnectionSink,
+        rd: &mut Registered<MioStream>,
+        wr: &mut Registered<MioStream>,
+    ) -> Result<bool> {
+        let pipe = self.pipe.as_mut().unwrap();
+        assert!(pipe.pending() == 0 || self.can_write);
+
+        let mut progress = false;
+
+        if pipe.pending() > 0 {
+            match wr.attempt(Interest::WRITABLE, |w| pipe.drain_into(w)) {
+                Ok(n @ 1..) => {
+                    progress = true;
+                    // Only used when we don't need to count messages
+                    self.counter.count_spliced(n);
+                }
+                Ok(0) => {
+                    // eof
+                    progress = true;
+                    let n = pipe.discard();
+                    sink.emit_shutdown_write(direction, n);
+                    self.can_write = false;
+                    let _ = wr.source.shutdown(std::net::Shutdown::Write);
+                }
+                Err(e) if would_block(&e) => {
+                    // don't touch progress
+                }
+                Err(err) => {
+                    return Err(Error::Forward {
+                        doing: "writing",
+                        side: direction.receiver(),
+                        err,
+                    })
+                }
+            }
+        }
+
+        if pipe.pending() == 0 {
+            if self.can_write && !self.can_read {
+                // No data in the pipe and no option to get more
+                self.can_write = false;
+                let _ = wr.source.shutdown(std::net::Shutdown::Write);
+            }
+            if self.can_read && !self.can_write {
+                sink.emit_shutdown_read(direction);
+                self.can_read = false;
+                let _ = rd.source.shutdown(std::net::Shutdown::Read);
+            }
+        }
+
+        if self.can_read && self.can_write && pipe.free() > 0 {
+            match rd.attempt(Interest::READABLE, |r| pipe.fill_from(r)) {
+                Ok(1..) => {
+                    progress = true;
+                }
+                Ok(0) => {
+                    // eof
+                    progress = true;
+                    sink.emit_shutdown_read(direction);
+                    self.can_read = false;
+                    let _ = rd.source.shutdown(std::net::Shutdown::Read);
+                }
+                Err(e) if would_block(&e) => {
+                    // don't touch progress
+                }
+                Err(err) => {
+                    return Err(Error::Forward {
+                        doing: "reading",
+                        side: direction.sender(),
+                        err,
+                    })
+                }
+            }
+        }
+
+        Ok(progress)
+    }
+
+    /// Implement [Copying::first_byte_delay]. Returns how many of the unsent
+    /// bytes may be written now.
+    fn hold_back(&mut self) -> usize {
+        if self.counter.at_message_start() {
+            let now = Instant::now();
+            let delay = self.first_byte_delay.unwrap_or_default();
+            let hold_until = *self.hold_until.get_or_insert(now + delay);
+            if now < hold_until {
+                return 0;
+            }
+            self.hold_until = None;
+        }
+        // Do not let the start of the next message slip through
+        let data = &self.buffer[self.unsent_data..self.free_space];
+        self.counter.clone().advance(data)
+    }
+
     fn finished(&self) -> bool {
         !self.can_read && !self.can_write
     }
 }
 
+/// Second server which receives a copy of the upstream traffic. Its responses
+/// are read and discarded.
+#[derive(Debug)]
+pub struct Mirror {
+    conn: Registered<MioStream>,
+    connected: bool,
+    /// Upstream data that has not been sent to the mirror yet
+    pending: VecDeque<u8>,
+    /// The client is on a Unix Domain socket, strip the initial '0' (0x30)
+    strip_unix0: bool,
+    /// The client has stopped sending, shut down when pending is empty.
+    /// After that, wait until this deadline for the mirror to close its side.
+    closing: Option<Instant>,
+    /// We have shut down our side
+    shut_down: bool,
+    /// The mirror has not closed its side yet
+    can_read: bool,
+    /// Errors are reported by [Forwarder::process_mirror], not by whoever
+    /// happened to call [Mirror::pump].
+    error: Option<io::Error>,
+}
+
+impl Mirror {
+    /// Do not let a slow mirror consume unlimited amounts of memory
+    const MAX_PENDING: usize = 16 * 1024 * 1024;
+
+    /// How long to keep the mirror around after the client has left
+    const LINGER: Duration = Duration::from_secs(10);
+
+    fn start(
+        mirror_addr: &MonetAddr,
+        token: Token,
+        registry: &Registry,
+        client_is_unix: bool,
+        resolver: &mut Resolver,
+    ) -> io::Result<Mirror> {
+        let msg = "name does not resolve to any addresses";
+        let mut error = io::Error::new(ErrorKind::NotFound, msg);
+        for addr in resolver.resolve(mirror_addr)? {
+            debug!(%addr, "mirror connect attempt");
+            let stream = match addr.connect() {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!(%addr, %e, "mirror connect failed");
+                    error = e;
+                    continue;
+                }
+            };
+            let mut pending = VecDeque::new();
+            if stream.is_unix() {
+                pending.push_back(b'0');
+            }
+            let mut conn = Registered::new(addr.to_string(), token, stream);
+            conn.need(Some(Interest::WRITABLE));
+            conn.update_registration(registry)?;
+            let mirror = Mirror {
+                conn,
+                connected: false,
+                pending,
+                strip_unix0: client_is_unix,
+                closing: None,
+                shut_down: false,
+                can_read: true,
+                error: None,
+            };
+            return Ok(mirror);
+        }
+        Err(error)
+    }
+
+    /// Queue upstream data to be sent to the mirror.
+    fn feed(&mut self, mut data: &[u8]) {
+        if self.strip_unix0 && !data.is_empty() {
+            data = &data[1..];
+            self.strip_unix0 = false;
+        }
+        self.pending.extend(data);
+        if self.pending.len() > Self::MAX_PENDING && self.error.is_none() {
+            self.error = Some(io::Error::other("mirror cannot keep up"));
+        }
+    }
+
+    /// Shut down the connection to the mirror after the pending data has
+    /// been sent.
+    fn close(&mut self) {
+        if self.closing.is_none() {
+            self.closing = Some(Instant::now() + Self::LINGER);
+        }
+    }
+
+    /// True if there is nothing more to do, or if we have waited long enough.
+    fn done(&self) -> bool {
+        match self.closing {
+            None => false,
+            Some(deadline) => !self.can_read || Instant::now() >= deadline,
+        }
+    }
+
+    /// Send pending data and discard incoming data until we would block.
+    /// Returns true if any progress was made.
+    fn pump(&mut self) -> bool {
+        if self.error.is_some() {
+            return false;
+        }
+        self.conn.clear();
+        match self.try_pump() {
+            Ok(progress) => progress,
+            Err(e) => {
+                self.error = Some(e);
+                false
+            }
+        }
+    }
+
+    fn try_pump(&mut self) -> io::Result<bool> {
+        let mut progress = false;
+
+        if !self.connected {
+            if self.conn.source.established()?.is_some() {
+                self.connected = true;
+            } else {
+                self.conn.need(Some(Interest::WRITABLE));
+                return Ok(false);
+            }
+        }
+
+        while !self.pending.is_empty() {
+            let (data, _) = self.pending.as_slices();
+            match self.conn.attempt(Interest::WRITABLE, |w| w.write(data)) {
+                Ok(0) => {
+                    let msg = "mirror has stopped receiving data";
+                    return Err(io::Error::new(ErrorKind::WriteZero, msg));
+                }
+                Ok(n) => {
+                    progress = true;
+                    self.pending.drain(..n);
+                }
+                Err(e) if would_block(&e) => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        if self.closing.is_some() && self.pending.is_empty() && !self.shut_down {
+            let _ = self.conn.source.shutdown(std::net::Shutdown::Write);
+            self.shut_down = true;
+            progress = true;
+        }
+
+        let mut discard = [0u8; 8192];
+        while self.can_read {
+            match self
+                .conn
+                .attempt(Interest::READABLE, |r| r.read(&mut discard))
+            {
+                Ok(0) => self.can_read = false,
+                Ok(_) => progress = true,
+                Err(e) if would_block(&e) => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(progress)
+    }
+
+    fn deregister(&mut self, registry: &Registry) {
+        let _ = self.conn.deregister(registry);
+    }
+}
+
 #[derive(Debug)]
 pub struct Registered<S: Source> {
     name: String,
@@ -486,6 +1311,15 @@ impl<S: Source> Registered<S> {
     }
 
     fn update_registration(&mut self, registry: &Registry) -> io::Result<()> {
+        if self.registered != self.needed {
+            trace!(
+                name = self.name,
+                token = self.token.0,
+                old = ?self.registered,
+                new = ?self.needed,
+                "mio registration"
+            );
+        }
         match (self.registered, self.needed) {
             (None, None) => {}
             (Some(_), None) => registry.deregister(&mut self.source)?,
@@ -1,23 +1,32 @@
+pub mod counter;
 pub mod event;
 mod forward;
 pub mod network;
+pub mod sni;
+#[cfg(target_os = "linux")]
+mod splice;
 
 use std::{
     io::{self, ErrorKind},
     ops::{ControlFlow, RangeFrom},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use forward::Forwarder;
 use network::Addr;
 
-use mio::{event::Event, Events, Interest, Poll, Token};
+use mio::{Events, Interest, Poll, Token};
 use slab::Slab;
 use thiserror::Error as ThisError;
+use tracing::{debug, error};
 
 use self::{
-    event::{ConnectionId, EventSink, MapiEvent},
-    network::{MioListener, MioStream, MonetAddr},
+    event::{ConnectionId, EventSink, MapiEvent, Timing, Traffic, Volume},
+    network::{MioListener, MioStream, MonetAddr, ResolvePolicy, Resolver},
 };
 
 /// Errors that can occur in the [Proxy].
@@ -48,12 +57,86 @@ pub enum Error {
         err: io::Error,
     },
 
+    #[error("killed after {0}")]
+    Killed(String),
+
     #[error("{0}")]
     Other(String),
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// Settings that influence how the [Proxy] listens and forwards its connections.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Do not emit [MapiEvent::Data] events, only report the connection
+    /// lifecycle. On Linux this allows us to forward using splice(2).
+    pub pass_through: bool,
+    /// If all addresses of the server fail, retry this many times, waiting the
+    /// given delay before the first retry and doubling it on every next one.
+    pub retry: Option<(u32, Duration)>,
+    /// Give up on a server address if connecting takes longer than this.
+    pub connect_timeout: Option<Duration>,
+    /// Start connecting to the next server address if the previous one
+    /// hasn't succeeded within 250ms, alternating IPv6 and IPv4 (RFC 8305).
+    pub happy_eyeballs: bool,
+    /// Whether to resolve the server address for every connection or to
+    /// cache the result.
+    pub resolve: ResolvePolicy,
+    /// Forward each connection to the address the client originally connected
+    /// to before it was redirected to us by iptables REDIRECT or TPROXY.
+    /// Only supported on Linux.
+    pub transparent: bool,
+    /// Also send the upstream traffic to this server, discarding its responses.
+    pub mirror: Option<MonetAddr>,
+    /// Forward TLS connections whose ClientHello carries the given server
+    /// name (SNI) to the corresponding address instead of the default one.
+    pub sni_routes: Vec<(String, MonetAddr)>,
+    /// How long to wait for the ClientHello before forwarding to the default
+    /// address, instead of one second.
+    pub sni_wait: Option<Duration>,
+    /// Set SO_REUSEPORT on the TCP listen sockets so multiple processes can
+    /// share the same port.
+    pub reuse_port: bool,
+    /// Set IPV6_V6ONLY on IPv6 listen sockets. If not set, the platform
+    /// default applies, which differs between platforms.
+    pub v6only: Option<bool>,
+    /// Terminate connections when they cross one of these thresholds.
+    pub kill_after: KillAfter,
+    /// Delay the first byte of every message from the server by this much.
+    pub first_byte_delay: Option<Duration>,
+    /// Do not set TCP_NODELAY on the client socket, leaving Nagle's
+    /// algorithm enabled.
+    pub nagle_client: bool,
+    /// Do not set TCP_NODELAY on the server socket.
+    pub nagle_server: bool,
+    /// Emit a [MapiEvent::Status] event this often.
+    pub status_interval: Option<Duration>,
+    /// Stop accepting new connections this long after starting, as if the
+    /// shutdown trigger was used.
+    pub duration: Option<Duration>,
+    /// Stop accepting new connections once this many have ended.
+    pub max_total_connections: Option<u64>,
+    /// Number the connections from here instead of [ConnectionId::FIRST].
+    pub first_id: Option<usize>,
+}
+
+/// Thresholds after which a connection is terminated. Useful for testing
+/// how clients deal with lost connections.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KillAfter {
+    /// Bytes forwarded, in both directions together
+    pub bytes: Option<u64>,
+    /// MAPI messages forwarded, in both directions together
+    pub messages: Option<u64>,
+    /// Time since the connection to the server was established
+    pub duration: Option<Duration>,
+}
+
+/// How long the open connections may continue after the proxy has stopped by
+/// itself, see [Proxy::get_stop_trigger], before they are aborted.
+pub const STOP_GRACE: Duration = Duration::from_secs(10);
+
 /// The Proxy listens on a number of sockets, forwards the connections
 /// to another server and reports on the traffic as a series of
 /// [MapiEvent]s.
@@ -64,23 +147,47 @@ pub struct Proxy {
     /// Configured address to forward to. May map to multiple concrete addresses,
     /// the proxy will try each in turn.
     forward_addr: MonetAddr,
+    /// Settings for the forwarded connections.
+    options: Options,
     /// The mio Poll object used to multiplex all IO on a single thread.
     poll: Poll,
     /// The waker can be used to trigger the proxy externally, we use it
     /// to stop the proxy on Control-C.
     waker: Arc<mio::Waker>,
+    /// Set when a shutdown has been triggered. We no longer accept new
+    /// connections but keep forwarding the existing ones until they end.
+    draining: bool,
+    /// Set by the trigger of [Proxy::get_stop_trigger].
+    stop_requested: Arc<AtomicBool>,
+    /// When the proxy stopped by itself, the moment the connections that are
+    /// still open are aborted.
+    abort_deadline: Option<Instant>,
+    /// If the listen address had port 0, the port picked by the OS.
+    ephemeral_port: Option<u16>,
     /// mio Tokens below this number are belong to listeners, the rest belong
     /// to forwarded connections.
     token_base: usize,
     /// Holds ownership of the listeners. `Token(t)` maps to `listeners[t]`.
     listeners: Vec<(Addr, MioListener)>,
     /// Holds ownership of the forwarders. `Token(t+self.token_base)` maps to
-    /// `forwarders[t/2]`.
+    /// `forwarders[t/3]`.
     forwarders: Slab<Forwarder>,
     /// Iterator that yields fresh connection id's.
     ids: RangeFrom<usize>,
     /// This is where events are reported.
     event_sink: EventSink,
+    /// Resolves the server addresses, possibly caching the result.
+    resolver: Resolver,
+    /// What has been forwarded by the forwarders that have been dropped.
+    ended_volume: Volume,
+    /// When the last [MapiEvent::Status] was emitted and the message count
+    /// at that time.
+    last_status: (Instant, u64),
+    /// When the proxy was created, for [Options::duration].
+    started: Instant,
+    /// Number of connections that have ended, for
+    /// [Options::max_total_connections].
+    ended: u64,
 }
 
 impl Proxy {
@@ -92,22 +199,35 @@ impl Proxy {
     pub fn new(
         listen_addr: MonetAddr,
         forward_addr: MonetAddr,
+        options: Options,
         event_handler: impl FnMut(MapiEvent) + 'static + Send,
     ) -> Result<Proxy> {
         let poll = Poll::new().map_err(Error::CreatePoll)?;
         let waker = mio::Waker::new(poll.registry(), Self::TRIGGER_SHUTDOWN_TOKEN)
             .map_err(Error::CreatePoll)?;
         let waker = Arc::new(waker);
+        let resolver = Resolver::new(options.resolve);
+        let first_id = options.first_id.unwrap_or(ConnectionId::FIRST);
         let mut proxy = Proxy {
             listen_addr,
             forward_addr,
+            options,
             poll,
             waker,
+            draining: false,
+            stop_requested: Arc::new(AtomicBool::new(false)),
+            abort_deadline: None,
+            ephemeral_port: None,
             token_base: usize::MAX,
             listeners: Default::default(),
             forwarders: Default::default(),
-            ids: 10..,
+            ids: first_id..,
             event_sink: EventSink::new(event_handler),
+            resolver,
+            ended_volume: Volume::default(),
+            last_status: (Instant::now(), 0),
+            started: Instant::now(),
+            ended: 0,
         };
 
         proxy.add_listeners()?;
@@ -115,16 +235,34 @@ impl Proxy {
     }
 
     fn add_listeners(&mut self) -> Result<()> {
-        let addrs = self
-            .listen_addr
-            .resolve()
-            .map_err(|e| Error::StartListening(self.listen_addr.to_string(), e))?;
+        let listen_addr = self.listen_addr.clone();
+        let err = |e| Error::StartListening(listen_addr.to_string(), e);
+        let tcp_addrs = self.listen_addr.resolve_tcp().map_err(err)?;
+        let mut unix_addrs = self.listen_addr.resolve_unix().map_err(err)?;
 
-        if addrs.is_empty() {
+        if tcp_addrs.is_empty() && unix_addrs.is_empty() {
             let err = io::Error::new(ErrorKind::NotFound, "listen address not found");
-            return Err(Error::StartListening(self.listen_addr.to_string(), err));
+            return Err(Error::StartListening(listen_addr.to_string(), err));
         }
-        for addr in addrs {
+
+        // Port 0 means the OS picks a free port. The first TCP listener
+        // determines the port, the others use the same one.
+        for mut addr in tcp_addrs {
+            if let (Addr::Tcp(a), Some(port)) = (&mut addr, self.ephemeral_port) {
+                if a.port() == 0 {
+                    a.set_port(port);
+                }
+            }
+            let requested_port = addr.port();
+            let bound = self.add_tcp_listener(addr)?;
+            if requested_port == Some(0) {
+                self.ephemeral_port = bound.port();
+            }
+        }
+        if let (MonetAddr::PortOnly(0), Some(port)) = (&listen_addr, self.ephemeral_port) {
+            unix_addrs = MonetAddr::PortOnly(port).resolve_unix().map_err(err)?;
+        }
+        for addr in unix_addrs {
             self.add_tcp_listener(addr)?;
         }
 
@@ -133,12 +271,22 @@ impl Proxy {
         Ok(())
     }
 
-    fn add_tcp_listener(&mut self, addr: Addr) -> Result<()> {
+    /// If the listen address had port 0, return the port the OS picked.
+    pub fn ephemeral_port(&self) -> Option<u16> {
+        self.ephemeral_port
+    }
+
+    /// Start listening on the given address. Returns the address actually
+    /// bound, which differs from `addr` if it has port 0.
+    fn add_tcp_listener(&mut self, addr: Addr) -> Result<Addr> {
         let n = self.listeners.len();
         let token = Token(n);
 
         let mut listener = addr
-            .listen()
+            .listen(&self.options)
+            .map_err(|e| Error::StartListening(addr.to_string(), e))?;
+        let addr = listener
+            .local_addr()
             .map_err(|e| Error::StartListening(addr.to_string(), e))?;
 
         self.poll
@@ -147,40 +295,202 @@ impl Proxy {
             .map_err(|e| Error::StartListening(addr.to_string(), e))?;
 
         self.event_sink.emit_bound(addr.clone());
-        self.listeners.push((addr, listener));
+        self.listeners.push((addr.clone(), listener));
 
-        Ok(())
+        Ok(addr)
     }
 
     /// Run the Proxy's main loop. This will block until the result of a call to [Proxy::get_shutdown_trigger]
-    /// is used to trigger a shutdown.
+    /// is used to trigger a shutdown and all remaining connections have ended.
     pub fn run(&mut self) -> Result<()> {
         let mut events = Events::with_capacity(20);
         loop {
-            match self.poll.poll(&mut events, None) {
+            let timeout = [
+                self.next_deadline(),
+                self.status_deadline(),
+                self.stop_deadline(),
+                self.abort_deadline,
+            ]
+            .into_iter()
+            .flatten()
+            .min()
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            match self.poll.poll(&mut events, timeout) {
                 Ok(_) => {}
                 Err(e) if e.kind() == ErrorKind::Interrupted => continue,
                 Err(e) => return Err(Error::Poll(e)),
             }
             for ev in events.iter() {
                 let token = ev.token();
-                if token == Self::TRIGGER_SHUTDOWN_TOKEN {
-                    return Ok(());
+                if token == Self::TRIGGER_SHUTDOWN_TOKEN
+                    && self.stop_requested.swap(false, Ordering::Relaxed)
+                {
+                    self.stop();
+                } else if token == Self::TRIGGER_SHUTDOWN_TOKEN && self.draining {
+                    self.abort_all();
+                } else if token == Self::TRIGGER_SHUTDOWN_TOKEN {
+                    self.start_draining();
                 } else if token.0 < self.token_base {
                     self.handle_listener_event(token.0)?;
                 } else {
-                    self.handle_forward_event(ev, (token.0 - self.token_base) / 2);
+                    self.handle_forward_event((token.0 - self.token_base) / 3);
                 }
             }
+            self.handle_deadlines();
+            self.handle_status();
+            self.handle_stop_conditions();
+            if self.draining && self.forwarders.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Return the earliest [Forwarder::deadline], if any.
+    fn next_deadline(&self) -> Option<Instant> {
+        self.forwarders
+            .iter()
+            .filter_map(|(_, f)| f.deadline())
+            .min()
+    }
+
+    /// Process the forwarders whose deadline has passed.
+    fn handle_deadlines(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<usize> = self
+            .forwarders
+            .iter()
+            .filter(|(_, f)| f.deadline().is_some_and(|d| d <= now))
+            .map(|(n, _)| n)
+            .collect();
+        for n in expired {
+            self.handle_forward_event(n);
+        }
+    }
+
+    fn status_deadline(&self) -> Option<Instant> {
+        let interval = self.options.status_interval?;
+        Some(self.last_status.0 + interval)
+    }
+
+    /// Emit a [MapiEvent::Status] if it's time to do so.
+    fn handle_status(&mut self) {
+        let Some(deadline) = self.status_deadline() else {
+            return;
+        };
+        let now = Instant::now();
+        if now < deadline {
+            return;
+        }
+        let total = self
+            .forwarders
+            .iter()
+            .map(|(_, f)| f.traffic().total())
+            .fold(self.ended_volume, |a, b| a + b);
+        // Messages are always counted when the status is enabled
+        let messages = total.messages.unwrap_or_default();
+        let (then, messages_then) = self.last_status;
+        let elapsed = now.duration_since(then).as_secs_f64();
+        let rate = (messages - messages_then) as f64 / elapsed;
+        self.event_sink
+            .emit_status(self.forwarders.len(), total, rate);
+        self.last_status = (now, messages);
+    }
+
+    /// When to start draining because of [Options::duration], if we haven't
+    /// already.
+    fn stop_deadline(&self) -> Option<Instant> {
+        if self.draining {
+            return None;
+        }
+        Some(self.started + self.options.duration?)
+    }
+
+    /// Stop if [Options::duration] has passed or
+    /// [Options::max_total_connections] have ended, and abort the remaining
+    /// connections once the [STOP_GRACE] is over.
+    fn handle_stop_conditions(&mut self) {
+        let now = Instant::now();
+        if self.abort_deadline.is_some_and(|d| d <= now) {
+            self.abort_deadline = None;
+            self.abort_all();
+            return;
+        }
+        let expired = self.stop_deadline().is_some_and(|d| d <= now);
+        let enough = self
+            .options
+            .max_total_connections
+            .is_some_and(|max| self.ended >= max);
+        if expired || enough {
+            self.stop();
+        }
+    }
+
+    /// Start draining, and give the open connections [STOP_GRACE] to end.
+    fn stop(&mut self) {
+        if self.draining {
+            return;
         }
+        self.start_draining();
+        self.abort_deadline = Some(Instant::now() + STOP_GRACE);
     }
 
-    /// Obtain a shutdown trigger that when called, will end the main loop of [Proxy::run].
+    /// Stop accepting new connections. The main loop keeps running until the
+    /// existing connections have ended.
+    fn start_draining(&mut self) {
+        if self.draining {
+            return;
+        }
+        self.draining = true;
+        debug!(connections = self.forwarders.len(), "draining");
+
+        // Dropping the listeners also cleans up the Unix Domain sockets
+        let registry = self.poll.registry();
+        for (_, mut listener) in self.listeners.drain(..) {
+            let _ = registry.deregister(&mut listener);
+        }
+
+        let remaining = self.forwarders.len();
+        if remaining > 0 {
+            self.event_sink.emit_draining(remaining);
+        }
+    }
+
+    /// Abort the remaining connections. Called if the shutdown trigger is
+    /// used again while we're draining.
+    fn abort_all(&mut self) {
+        let registry = self.poll.registry();
+        for mut forwarder in self.forwarders.drain() {
+            forwarder.deregister(registry);
+            let error = Error::Other("proxy is shutting down".to_string());
+            self.event_sink
+                .connection_sink(forwarder.id())
+                .emit_aborted(error, forwarder.traffic(), forwarder.timing());
+            self.ended_volume = self.ended_volume + forwarder.traffic().total();
+        }
+    }
+
+    /// Obtain a shutdown trigger that when called, will make the main loop of
+    /// [Proxy::run] stop accepting new connections. The main loop ends when all
+    /// existing connections have ended. Calling it again aborts them.
     pub fn get_shutdown_trigger(&mut self) -> Box<dyn Fn() + Send + Sync + 'static> {
         let waker = Arc::clone(&self.waker);
         Box::new(move || {
             if let Err(e) = waker.wake() {
-                eprintln!("Failed to shut down the proxy: {e}");
+                error!("Failed to shut down the proxy: {e}");
+            }
+        })
+    }
+
+    /// Obtain a trigger that makes the proxy stop by itself, as when
+    /// [Options::duration] has passed: it stops accepting new connections and
+    /// aborts the existing ones if they haven't ended after [STOP_GRACE].
+    pub fn get_stop_trigger(&mut self) -> Box<dyn Fn() + Send + Sync + 'static> {
+        let waker = Arc::clone(&self.waker);
+        let stop_requested = Arc::clone(&self.stop_requested);
+        Box::new(move || {
+            stop_requested.store(true, Ordering::Relaxed);
+            if let Err(e) = waker.wake() {
+                error!("Failed to stop the proxy: {e}");
             }
         })
     }
@@ -189,7 +499,10 @@ impl Proxy {
         // When mio notifies us of readiness may only re-enter mio when we
         // have observed an EWOULDBLOCK. Hence the loop.
         loop {
-            let (local, listener) = &self.listeners[n];
+            // The listeners are dropped when we start draining
+            let Some((local, listener)) = self.listeners.get(n) else {
+                return Ok(());
+            };
             let (conn, peer) = match listener.accept() {
                 Ok(x) => x,
                 Err(e) if would_block(&e) => return Ok(()),
@@ -199,39 +512,82 @@ impl Proxy {
             };
 
             let id = ConnectionId::new(self.ids.next().unwrap());
+            debug!(%id, %local, %peer, "accepted");
             self.event_sink
                 .connection_sink(id)
                 .emit_incoming(local.clone(), peer.clone());
-            self.start_forwarder(id, peer, conn);
+            let local = local.clone();
+            self.start_forwarder(id, &local, peer, conn);
         }
     }
 
-    fn start_forwarder(&mut self, id: ConnectionId, peer: Addr, conn: MioStream) {
+    fn start_forwarder(&mut self, id: ConnectionId, local: &Addr, peer: Addr, conn: MioStream) {
+        let forward_addr = self.forward_addr_for(local, &conn);
         let mut sink = self.event_sink.connection_sink(id);
+        let forward_addr = match forward_addr {
+            Ok(addr) => addr,
+            Err(e) => {
+                sink.emit_aborted(e, Traffic::default(), Timing::default());
+                self.ended += 1;
+                return;
+            }
+        };
         let entry = self.forwarders.vacant_entry();
         let n = entry.key();
-        let client_token = self.token_base + 2 * n;
-        let server_token = self.token_base + 2 * n + 1;
+        let client_token = self.token_base + 3 * n;
+        let server_token = self.token_base + 3 * n + 1;
+        let mirror_token = self.token_base + 3 * n + 2;
         let new = Forwarder::new(
             self.poll.registry(),
             &mut sink,
             conn,
             peer,
             Token(client_token),
-            &self.forward_addr,
+            &forward_addr,
             Token(server_token),
+            Token(mirror_token),
+            &self.options,
+            &mut self.resolver,
         );
         match new {
             Ok(forwarder) => {
                 entry.insert(forwarder);
             }
             Err(e) => {
-                sink.emit_aborted(e);
+                sink.emit_aborted(e, Traffic::default(), Timing::default());
+                self.ended += 1;
+            }
+        }
+    }
+
+    /// Normally we forward to the configured address but in transparent
+    /// mode we forward to wherever the client was trying to go before it
+    /// was redirected to us.
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+    fn forward_addr_for(&self, local: &Addr, conn: &MioStream) -> Result<MonetAddr> {
+        #[cfg(target_os = "linux")]
+        if self.options.transparent {
+            let dst = conn.original_dst().map_err(|err| Error::Forward {
+                doing: "determining the original destination of",
+                side: "client",
+                err,
+            })?;
+            // If the client connected to us directly, it has not been
+            // redirected and we fall back to the configured address.
+            let (Addr::Tcp(dst), Addr::Tcp(local)) = (&dst, local) else {
+                return Ok(self.forward_addr.clone());
+            };
+            let direct = dst.port() == local.port()
+                && (local.ip().is_unspecified() || local.ip() == dst.ip());
+            if !direct {
+                let (ip, port) = (dst.ip(), dst.port());
+                return Ok(MonetAddr::Ip { ip, port });
             }
         }
+        Ok(self.forward_addr.clone())
     }
 
-    fn handle_forward_event(&mut self, ev: &Event, n: usize) {
+    fn handle_forward_event(&mut self, n: usize) {
         let registry = self.poll.registry();
         let Some(forwarder) = self.forwarders.get_mut(n) else {
             return;
@@ -244,23 +600,25 @@ impl Proxy {
         // we don't have a loop right here because `Forwarder::handle_event`
         // does the looping. It returns a `ControlFlow` to indicate whether
         // this connection needs to stay around or whether it can be removed.
-        match forwarder.handle_event(&mut sink, registry, ev) {
+        match forwarder.handle_event(&mut sink, registry, &self.options, &mut self.resolver) {
             Ok(ControlFlow::Continue(_)) => {
                 // return instead of removing it
                 return;
             }
             Err(e) => {
-                sink.emit_aborted(e);
+                sink.emit_aborted(e, forwarder.traffic(), forwarder.timing());
                 // fall through to removal
             }
             Ok(ControlFlow::Break(_)) => {
-                sink.emit_end();
+                // the forwarder has already emitted the end event
                 // fall through to removal
             }
         }
 
         // Removal
         forwarder.deregister(registry);
+        self.ended_volume = self.ended_volume + forwarder.traffic().total();
+        self.ended += 1;
         self.forwarders.remove(n);
     }
 }
@@ -1,19 +1,31 @@
 use std::{
+    collections::HashMap,
     ffi::{OsStr, OsString},
     fmt::Display,
     io::{self, ErrorKind},
     net::{self, IpAddr, SocketAddr as TcpSocketAddr, ToSocketAddrs},
     path::PathBuf,
+    time::{Duration, Instant},
 };
 
 // These are only used by Unix Domain socket code
 #[cfg(unix)]
 use std::{fs, path::Path};
 
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, RawFd};
+
 use lazy_regex::{regex_captures, regex_is_match};
 #[cfg(unix)]
 use mio::net::{SocketAddr as UnixSocketAddr, UnixListener, UnixStream};
 use mio::net::{TcpListener, TcpStream};
+#[cfg(target_os = "linux")]
+use socket2::SockRef;
+use socket2::{Domain, Protocol, Socket, Type};
+#[cfg(target_os = "linux")]
+use std::os::fd::BorrowedFd;
+
+use super::Options;
 
 #[cfg(not(unix))]
 fn unix_not_supported() -> io::Error {
@@ -138,6 +150,55 @@ impl TryFrom<OsString> for MonetAddr {
     }
 }
 
+/// When to resolve server addresses again.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum ResolvePolicy {
+    /// For every new connection
+    #[default]
+    PerConnection,
+    /// Only the first time, after that always use the same addresses
+    Once,
+    /// When the previous result is older than this
+    Ttl(Duration),
+}
+
+/// Resolves [MonetAddr]s and caches the result according to a
+/// [ResolvePolicy].
+#[derive(Debug, Default)]
+pub struct Resolver {
+    policy: ResolvePolicy,
+    cache: HashMap<MonetAddr, (Instant, Vec<Addr>)>,
+}
+
+impl Resolver {
+    pub fn new(policy: ResolvePolicy) -> Self {
+        Resolver {
+            policy,
+            cache: Default::default(),
+        }
+    }
+
+    pub fn resolve(&mut self, addr: &MonetAddr) -> io::Result<Vec<Addr>> {
+        let now = Instant::now();
+        let fresh = |resolved_at: &Instant| match self.policy {
+            ResolvePolicy::PerConnection => false,
+            ResolvePolicy::Once => true,
+            ResolvePolicy::Ttl(ttl) => now.duration_since(*resolved_at) < ttl,
+        };
+        if let Some((resolved_at, addrs)) = self.cache.get(addr) {
+            if fresh(resolved_at) {
+                return Ok(addrs.clone());
+            }
+        }
+
+        let addrs = addr.resolve()?;
+        if !matches!(self.policy, ResolvePolicy::PerConnection) {
+            self.cache.insert(addr.clone(), (now, addrs.clone()));
+        }
+        Ok(addrs)
+    }
+}
+
 impl MonetAddr {
     pub fn resolve(&self) -> io::Result<Vec<Addr>> {
         let mut addrs = self.resolve_unix()?;
@@ -187,13 +248,20 @@ impl Addr {
         matches!(self, Self::Tcp(_))
     }
 
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            Addr::Tcp(a) => Some(a.port()),
+            Addr::Unix(_) => None,
+        }
+    }
+
     pub fn is_unix(&self) -> bool {
         !self.is_tcp()
     }
 
-    pub fn listen(&self) -> io::Result<MioListener> {
+    pub fn listen(&self, options: &Options) -> io::Result<MioListener> {
         let listener = match self {
-            Addr::Tcp(a) => MioListener::Tcp(TcpListener::bind(*a)?),
+            Addr::Tcp(a) => MioListener::Tcp(Self::listen_tcp(*a, options)?),
             #[cfg(unix)]
             Addr::Unix(a) => {
                 let listener = match UnixListener::bind(a) {
@@ -212,6 +280,36 @@ impl Addr {
         Ok(listener)
     }
 
+    /// Like [TcpListener::bind] but with control over the socket options.
+    fn listen_tcp(addr: TcpSocketAddr, options: &Options) -> io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        // mio does this too
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        if options.reuse_port {
+            #[cfg(unix)]
+            socket.set_reuse_port(true)?;
+            #[cfg(not(unix))]
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "SO_REUSEPORT is not supported on this platform",
+            ));
+        }
+        // TPROXY needs this. It requires CAP_NET_ADMIN but REDIRECT works
+        // without it, so ignore errors.
+        #[cfg(target_os = "linux")]
+        if options.transparent {
+            let _ = socket.set_ip_transparent(true);
+        }
+        if let (true, Some(v6only)) = (addr.is_ipv6(), options.v6only) {
+            socket.set_only_v6(v6only)?;
+        }
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        socket.set_nonblocking(true)?;
+        Ok(TcpListener::from_std(socket.into()))
+    }
+
     pub fn connect(&self) -> io::Result<MioStream> {
         let conn = match self {
             Addr::Tcp(a) => MioStream::Tcp(TcpStream::connect(*a)?),
@@ -247,6 +345,52 @@ impl From<UnixSocketAddr> for Addr {
     }
 }
 
+/// A range of IP addresses such as 10.1.2.0/24 or fd00::/8, or a single
+/// address, see `--client`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subnet {
+    ip: IpAddr,
+    prefix: u32,
+}
+
+impl Subnet {
+    pub fn parse(text: &str) -> Option<Subnet> {
+        let (ip, prefix) = match text.split_once('/') {
+            Some((ip, prefix)) => (ip.parse().ok()?, Some(prefix.parse().ok()?)),
+            None => (text.parse().ok()?, None),
+        };
+        let bits = match ip {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = prefix.unwrap_or(bits);
+        (prefix <= bits).then_some(Subnet { ip, prefix })
+    }
+
+    /// Whether the address is in the subnet. IPv4 addresses written as IPv6,
+    /// as seen by listeners that accept both, count as IPv4. Unix Domain
+    /// socket addresses never are.
+    pub fn contains(&self, addr: &Addr) -> bool {
+        let Addr::Tcp(addr) = addr else {
+            return false;
+        };
+        match (self.ip, addr.ip().to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                same_prefix(net.to_bits().into(), ip.to_bits().into(), self.prefix + 96)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                same_prefix(net.to_bits(), ip.to_bits(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the first `prefix` bits of `a` and `b` are equal
+fn same_prefix(a: u128, b: u128, prefix: u32) -> bool {
+    (a ^ b).checked_shr(128 - prefix).unwrap_or(0) == 0
+}
+
 impl mio::event::Source for MioListener {
     fn register(
         &mut self,
@@ -294,6 +438,15 @@ impl MioListener {
         !self.is_tcp()
     }
 
+    pub fn local_addr(&self) -> io::Result<Addr> {
+        let addr = match self {
+            MioListener::Tcp(lis) => lis.local_addr()?.into(),
+            #[cfg(unix)]
+            MioListener::Unix(lis) => lis.local_addr()?.into(),
+        };
+        Ok(addr)
+    }
+
     pub fn accept(&self) -> io::Result<(MioStream, Addr)> {
         match self {
             MioListener::Tcp(lis) => {
@@ -418,6 +571,39 @@ impl MioStream {
         Ok(addr)
     }
 
+    /// Return the address the client was connecting to before it was
+    /// redirected to us. With iptables REDIRECT the kernel remembers it in
+    /// SO_ORIGINAL_DST, with TPROXY it is simply our local address.
+    #[cfg(target_os = "linux")]
+    pub fn original_dst(&self) -> io::Result<Addr> {
+        let MioStream::Tcp(s) = self else {
+            return Err(io::Error::from(ErrorKind::Unsupported));
+        };
+        let local = s.local_addr()?;
+        // mio 0.8 does not implement AsFd
+        let fd = unsafe { BorrowedFd::borrow_raw(s.as_raw_fd()) };
+        let sock = SockRef::from(&fd);
+        let original = if local.is_ipv6() {
+            sock.original_dst_ipv6()
+        } else {
+            sock.original_dst()
+        };
+        match original.map(|a| a.as_socket()) {
+            Ok(Some(addr)) => Ok(Addr::Tcp(addr)),
+            // not redirected, or redirected using TPROXY
+            Ok(None) | Err(_) => Ok(Addr::Tcp(local)),
+        }
+    }
+
+    /// Look at incoming data without consuming it. Only supported on TCP.
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            MioStream::Tcp(s) => s.peek(buf),
+            #[cfg(unix)]
+            MioStream::Unix(_) => Err(io::Error::from(ErrorKind::Unsupported)),
+        }
+    }
+
     pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
         match self {
             MioStream::Tcp(s) => s.set_nodelay(nodelay),
@@ -427,6 +613,16 @@ impl MioStream {
     }
 }
 
+#[cfg(unix)]
+impl AsRawFd for MioStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            MioStream::Tcp(s) => s.as_raw_fd(),
+            MioStream::Unix(s) => s.as_raw_fd(),
+        }
+    }
+}
+
 impl io::Write for MioStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         match self {
@@ -470,3 +666,23 @@ impl io::Read for MioStream {
         }
     }
 }
+
+#[test]
+fn test_subnet() {
+    let addr = |text: &str| Addr::Tcp(text.parse().unwrap());
+    let net = Subnet::parse("10.1.2.0/24").unwrap();
+    assert!(net.contains(&addr("10.1.2.77:40000")));
+    assert!(net.contains(&addr("[::ffff:10.1.2.3]:40000")));
+    assert!(!net.contains(&addr("10.1.3.1:40000")));
+    assert!(!net.contains(&Addr::Unix("/tmp/.s.monetdb.50000".into())));
+
+    let host = Subnet::parse("::1").unwrap();
+    assert!(host.contains(&addr("[::1]:40000")));
+    assert!(!host.contains(&addr("127.0.0.1:40000")));
+    assert!(Subnet::parse("0.0.0.0/0")
+        .unwrap()
+        .contains(&addr("1.2.3.4:5")));
+
+    assert_eq!(Subnet::parse("10.1.2.0/33"), None);
+    assert_eq!(Subnet::parse("localhost"), None);
+}
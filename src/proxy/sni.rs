@@ -0,0 +1,145 @@
+//! Extract the server name (SNI) from the TLS ClientHello sent by a client.
+//!
+//! We do not terminate TLS, we only look at the first handshake record to
+//! decide where to forward the connection. The record itself is forwarded
+//! unchanged.
+
+/// Outcome of looking at the first bytes sent by the client.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Sniffed {
+    /// Not enough data yet to decide
+    Incomplete,
+    /// The client presented this server name
+    ServerName(String),
+    /// Not TLS, or TLS without a server name
+    NoServerName,
+}
+
+/// Look for the server_name extension in the TLS ClientHello at the start of
+/// `data`. Only the first TLS record is examined.
+pub fn sniff(data: &[u8]) -> Sniffed {
+    // TLS record header: content type, protocol version, length
+    let Some(header) = data.get(..5) else {
+        // Bail out early if it's not a handshake record
+        if data.first().is_some_and(|&b| b != 0x16) {
+            return Sniffed::NoServerName;
+        }
+        return Sniffed::Incomplete;
+    };
+    if header[0] != 0x16 || header[1] != 0x03 {
+        return Sniffed::NoServerName;
+    }
+    let record_len = u16::from_be_bytes([header[3], header[4]]) as usize;
+    let Some(record) = data[5..].get(..record_len) else {
+        return Sniffed::Incomplete;
+    };
+
+    match client_hello_server_name(record) {
+        Some(name) => Sniffed::ServerName(name),
+        None => Sniffed::NoServerName,
+    }
+}
+
+fn client_hello_server_name(record: &[u8]) -> Option<String> {
+    let mut r = Reader(record);
+
+    // Handshake header, type 1 is ClientHello
+    if r.u8()? != 1 {
+        return None;
+    }
+    let len = r.u24()?;
+    let mut hello = Reader(r.take(len)?);
+
+    // legacy_version, random, legacy_session_id, cipher_suites,
+    // legacy_compression_methods
+    hello.take(2 + 32)?;
+    let n = hello.u8()?;
+    hello.take(n)?;
+    let n = hello.u16()?;
+    hello.take(n)?;
+    let n = hello.u8()?;
+    hello.take(n)?;
+
+    let n = hello.u16()?;
+    let mut extensions = Reader(hello.take(n)?);
+    while !extensions.0.is_empty() {
+        let ext_type = extensions.u16()?;
+        let n = extensions.u16()?;
+        let mut ext = Reader(extensions.take(n)?);
+        if ext_type != 0 {
+            continue;
+        }
+        // server_name extension: a list of (name_type, name), type 0 is host_name
+        let n = ext.u16()?;
+        let mut list = Reader(ext.take(n)?);
+        while !list.0.is_empty() {
+            let name_type = list.u8()?;
+            let n = list.u16()?;
+            let name = list.take(n)?;
+            if name_type == 0 {
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+        return None;
+    }
+    None
+}
+
+/// Reads the big endian fields of TLS messages
+pub struct Reader<'a>(pub &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if n > self.0.len() {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    pub fn u8(&mut self) -> Option<usize> {
+        Some(self.take(1)?[0] as usize)
+    }
+
+    pub fn u16(&mut self) -> Option<usize> {
+        let b = self.take(2)?;
+        Some(u16::from_be_bytes([b[0], b[1]]) as usize)
+    }
+
+    pub fn u24(&mut self) -> Option<usize> {
+        let b = self.take(3)?;
+        Some(u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+}
+
+#[test]
+fn test_sniff() {
+    let name = b"db1.example.com";
+    let mut sni = vec![0, 0];
+    sni.extend(((name.len() + 5) as u16).to_be_bytes());
+    sni.extend(((name.len() + 3) as u16).to_be_bytes());
+    sni.push(0);
+    sni.extend((name.len() as u16).to_be_bytes());
+    sni.extend(name);
+
+    let mut hello = vec![3, 3];
+    hello.extend([0xAA; 32]); // random
+    hello.extend([0]); // session id
+    hello.extend([0, 2, 0x13, 0x01]); // cipher suites
+    hello.extend([1, 0]); // compression methods
+    hello.extend((sni.len() as u16).to_be_bytes());
+    hello.extend(sni);
+
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend(((hello.len() + 4) as u16).to_be_bytes());
+    record.push(1);
+    record.extend(&(hello.len() as u32).to_be_bytes()[1..]);
+    record.extend(hello);
+
+    let expected = Sniffed::ServerName("db1.example.com".to_string());
+    assert_eq!(sniff(&record), expected);
+    assert_eq!(sniff(&record[..20]), Sniffed::Incomplete);
+    assert_eq!(sniff(b""), Sniffed::Incomplete);
+    assert_eq!(sniff(b"0"), Sniffed::NoServerName);
+}
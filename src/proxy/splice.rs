@@ -0,0 +1,91 @@
+//! Zero-copy forwarding using splice(2). This is only available on Linux.
+//!
+//! Data is moved from the source socket into a pipe and from the pipe into the
+//! destination socket without ever being copied to user space.
+
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+};
+
+/// A nonblocking pipe that keeps track of how many bytes are in it.
+#[derive(Debug)]
+pub struct Pipe {
+    read_end: OwnedFd,
+    write_end: OwnedFd,
+    capacity: usize,
+    pending: usize,
+}
+
+impl Pipe {
+    pub fn new() -> io::Result<Self> {
+        let mut fds: [RawFd; 2] = [-1, -1];
+        // SAFETY: pipe2 only writes the two descriptors into fds
+        let ret = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: pipe2 succeeded, so both are open descriptors that nothing
+        // else owns
+        let read_end = unsafe { OwnedFd::from_raw_fd(fds[0]) };
+        let write_end = unsafe { OwnedFd::from_raw_fd(fds[1]) };
+
+        // Never try to put more into the pipe than fits, otherwise we would
+        // mistake a full pipe for a socket that is not readable.
+        // SAFETY: F_GETPIPE_SZ takes no argument and only reads the pipe
+        let ret = unsafe { libc::fcntl(write_end.as_raw_fd(), libc::F_GETPIPE_SZ) };
+        if ret <= 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let capacity = ret as usize;
+
+        Ok(Pipe {
+            read_end,
+            write_end,
+            capacity,
+            pending: 0,
+        })
+    }
+
+    /// Number of bytes in the pipe that have not been sent onward yet
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// Number of bytes that can still be moved into the pipe
+    pub fn free(&self) -> usize {
+        self.capacity - self.pending
+    }
+
+    /// Move as many bytes as possible from the socket into the pipe.
+    pub fn fill_from(&mut self, source: &impl AsRawFd) -> io::Result<usize> {
+        let n = splice(source.as_raw_fd(), self.write_end.as_raw_fd(), self.free())?;
+        self.pending += n;
+        Ok(n)
+    }
+
+    /// Move as many bytes as possible from the pipe into the socket.
+    pub fn drain_into(&mut self, dest: &impl AsRawFd) -> io::Result<usize> {
+        let n = splice(self.read_end.as_raw_fd(), dest.as_raw_fd(), self.pending)?;
+        self.pending -= n;
+        Ok(n)
+    }
+
+    /// Forget about the bytes in the pipe, they will never be sent.
+    pub fn discard(&mut self) -> usize {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    // SAFETY: the offsets are null, so splice only reads and writes the two
+    // descriptors, which the caller keeps open
+    let ret = unsafe { libc::splice(from, ptr::null_mut(), to, ptr::null_mut(), len, flags) };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
@@ -1,89 +1,543 @@
 use core::fmt;
 use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
     fmt::Display,
     io::{self, BufWriter, Write},
     mem,
+    path::Path,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
-use crate::proxy::event::{ConnectionId, Direction};
+use crate::{
+    output::Output,
+    proxy::event::{CaptureTime, ConnectionId, Direction, TimeZone, ZonedTime},
+};
 
 pub struct Renderer {
     colored: bool,
+    theme: Theme,
+    glyphs: Glyphs,
     last_time: Option<Instant>,
-    out: BufWriter<Box<dyn io::Write + 'static + Send>>,
+    out: BufWriter<Output>,
     current_style: Style,
     at_start: Option<Style>, // if Some(s), we're at line start, style to be reset to s
+    time_zone: TimeZone,
+    /// Format of the capture times, see `--timestamp-format`
+    time_format: Option<Arc<str>>,
+    /// Rotate the output file when it grows beyond this many bytes, keeping
+    /// this many old files
+    rotate: Option<(u64, u32)>,
+    /// Set by `--timestamps`. If not set, capture times are shown at the end
+    /// of the data headers
+    timestamps: Option<Timestamps>,
+    /// Show the current time rather than capture times
+    live: bool,
+    clock: Clock,
+    /// For [Timestamps::Delta], the time shown for the previous event of
+    /// each connection
+    previous: HashMap<Option<ConnectionId>, CaptureTime>,
+    /// Show at most this many lines of the body of a frame, see `--max-lines`
+    max_lines: Option<u64>,
+    /// Lines of the body of the current frame shown so far
+    lines: u64,
+    /// Lines of the body of the current frame left out
+    hidden: u64,
+    /// Width of the columns when showing the client on the left and the
+    /// server on the right, see `--side-by-side`
+    side_by_side: Option<usize>,
+    /// Wrap the lines of the body that are wider than this, see `--wrap`
+    wrap: Option<usize>,
+    /// Spaces before every line of the current frame
+    margin: usize,
+    /// Characters on the current line of the body, including the frame
+    column: usize,
+    /// Mark pauses at least this long, see `--idle-gap`
+    idle_gap: Option<Duration>,
+    /// Give each connection its own frame color, see `--color-connections`
+    color_connections: bool,
+    /// The frame color of the connection being rendered, if not the one of
+    /// the theme
+    frame_code: Option<&'static str>,
+}
+
+/// What to show in the time column, see `--timestamps`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timestamps {
+    None,
+    /// Date and time of day
+    Absolute,
+    /// Time since the first event
+    Relative,
+    /// Time since the previous event of the same connection
+    Delta,
+}
+
+/// Latest and earliest capture time seen. Events without a capture time of
+/// their own are shown at the latest.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Clock {
+    now: Option<CaptureTime>,
+    start: Option<CaptureTime>,
 }
 
 impl Renderer {
-    pub fn new(colored: bool, out: impl io::Write + 'static + Send) -> Self {
-        let boxed: Box<dyn io::Write + 'static + Send> = Box::new(out);
-        let buffered = BufWriter::with_capacity(4 * 8192, boxed);
+    pub fn new(colored: bool, out: Output) -> Self {
+        let buffered = BufWriter::with_capacity(4 * 8192, out);
         Renderer {
             colored,
+            theme: Theme::light(),
+            glyphs: Glyphs::UNICODE,
             out: buffered,
             current_style: Style::Normal,
             at_start: Some(Style::Normal),
             last_time: None,
+            time_zone: TimeZone::Utc,
+            time_format: None,
+            rotate: None,
+            timestamps: None,
+            live: false,
+            clock: Clock::default(),
+            previous: HashMap::new(),
+            max_lines: None,
+            lines: 0,
+            hidden: 0,
+            side_by_side: None,
+            wrap: None,
+            margin: 0,
+            column: 0,
+            idle_gap: Some(Self::IDLE_GAP),
+            color_connections: false,
+            frame_code: None,
         }
     }
 
-    const THRESHOLD: Duration = Duration::from_millis(500);
+    /// Start every frame header and message with a time column. If the
+    /// events happen `live`, that's the current time, otherwise the capture
+    /// time.
+    pub fn set_timestamps(&mut self, timestamps: Timestamps, live: bool) {
+        self.timestamps = Some(timestamps);
+        self.live = live;
+    }
+
+    /// Called for every event that has a capture time.
+    pub fn advance_clock(&mut self, time: CaptureTime) {
+        self.clock.start.get_or_insert(time);
+        self.clock.now = self.clock.now.max(Some(time));
+    }
 
-    fn before(&mut self) -> io::Result<()> {
-        if let Some(then) = self.last_time {
-            let duration = then.elapsed();
-            if duration >= Self::THRESHOLD {
-                writeln!(self.out)?;
+    /// The time to show for what happens now: the current time if the
+    /// events happen live, otherwise the latest capture time.
+    pub fn now(&self) -> Option<CaptureTime> {
+        if self.live {
+            Some(CaptureTime::now())
+        } else {
+            self.clock.now
+        }
+    }
+
+    pub fn clock(&self) -> Clock {
+        self.clock
+    }
+
+    /// Take over the clock of another renderer, see [Renderer::in_memory].
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    /// Called when a connection has ended.
+    pub fn forget_connection(&mut self, id: ConnectionId) {
+        self.previous.remove(&Some(id));
+    }
+
+    /// Leave out the lines of the body of a frame after the first
+    /// `max_lines`, mentioning how many there were in the footer.
+    pub fn set_max_lines(&mut self, max_lines: u64) {
+        self.max_lines = Some(max_lines);
+    }
+
+    /// Show what the server sends to the right of what the client sends,
+    /// each in half of `width` columns. Longer lines are wrapped.
+    pub fn set_side_by_side(&mut self, width: usize) {
+        let column = width.saturating_sub(Self::GAP) / 2;
+        self.side_by_side = Some(column);
+        self.wrap = Some(self.wrap.map_or(column, |w| w.min(column)));
+    }
+
+    /// Wrap the lines of the body that don't fit in `width` columns, marking
+    /// where they continue.
+    pub fn set_wrap(&mut self, width: usize) {
+        self.wrap = Some(self.wrap.map_or(width, |w| w.min(width)));
+    }
+
+    /// Mark pauses in the output of at least `gap` with a line that says how
+    /// long they were, or not at all if None.
+    pub fn set_idle_gap(&mut self, gap: Option<Duration>) {
+        self.idle_gap = gap;
+    }
+
+    /// Draw the frames and messages of each connection in a color of its
+    /// own, so interleaved connections are easier to tell apart.
+    pub fn set_color_connections(&mut self, color_connections: bool) {
+        self.color_connections = color_connections;
+    }
+
+    /// Rotate the output file when it grows beyond `size` bytes, keeping
+    /// `keep` old files, see [Output::rotate]. This only happens between
+    /// frames so a frame is never split across files.
+    pub fn set_rotate(&mut self, size: u64, keep: u32) {
+        self.rotate = Some((size, keep));
+    }
+
+    /// Create a renderer with the same settings that renders into memory,
+    /// see [Renderer::take_output].
+    pub fn in_memory(&self) -> Renderer {
+        self.with_output(self.colored, Output::Memory(vec![]))
+    }
+
+    /// Create a renderer with the same settings that writes to `out`, for
+    /// example the file of a single connection.
+    pub fn with_output(&self, colored: bool, out: Output) -> Renderer {
+        let mut renderer = Renderer::new(colored, out);
+        renderer.theme = self.theme.clone();
+        renderer.glyphs = self.glyphs.clone();
+        renderer.time_zone = self.time_zone;
+        renderer.time_format = self.time_format.clone();
+        renderer.timestamps = self.timestamps;
+        renderer.live = self.live;
+        renderer.max_lines = self.max_lines;
+        renderer.side_by_side = self.side_by_side;
+        renderer.wrap = self.wrap;
+        renderer.color_connections = self.color_connections;
+        renderer.idle_gap = self.idle_gap;
+        renderer
+    }
+
+    /// Take what an [in_memory][Renderer::in_memory] renderer has rendered so
+    /// far.
+    pub fn take_output(&mut self) -> io::Result<Vec<u8>> {
+        self.out.flush()?;
+        // The renderer that writes the output decides about pauses
+        self.last_time = None;
+        match self.out.get_mut() {
+            Output::Memory(data) => Ok(mem::take(data)),
+            _ => Ok(vec![]),
+        }
+    }
+
+    /// Write output taken from an in-memory renderer.
+    pub fn write_rendered(&mut self, rendered: &[u8]) -> io::Result<()> {
+        if rendered.is_empty() {
+            return Ok(());
+        }
+        self.before()?;
+        self.out.write_all(rendered)?;
+        self.after();
+        self.check_rotate()
+    }
+
+    /// Write output that has been formatted elsewhere, for example JSON,
+    /// without any styling or pauses.
+    pub fn write_raw(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+        self.out.flush()?;
+        self.check_rotate()
+    }
+
+    /// Set the colors used when the output is colored.
+    pub fn set_theme(&mut self, theme: Theme) {
+        self.theme = theme;
+    }
+
+    /// Only use ASCII characters for the frames and the symbols, see
+    /// `--ascii`.
+    pub fn set_ascii(&mut self, ascii: bool) {
+        self.glyphs = if ascii {
+            Glyphs::ASCII
+        } else {
+            Glyphs::UNICODE
+        };
+    }
+
+    /// Use other symbols than those of [Renderer::set_ascii], see
+    /// [Glyphs::with_frame_chars].
+    pub fn set_glyphs(&mut self, glyphs: Glyphs) {
+        self.glyphs = glyphs;
+    }
+
+    /// The symbols to use for frames and special characters.
+    pub fn glyphs(&self) -> &Glyphs {
+        &self.glyphs
+    }
+
+    /// Set the time zone capture times are displayed in.
+    pub fn set_time_zone(&mut self, time_zone: TimeZone) {
+        self.time_zone = time_zone;
+    }
+
+    /// Display capture times in `format` rather than the default, see
+    /// [ZonedTime::format].
+    pub fn set_time_format(&mut self, format: Arc<str>) {
+        self.time_format = Some(format);
+    }
+
+    /// Prepare a capture time for display.
+    pub fn capture_time(&self, time: CaptureTime) -> ZonedTime {
+        time.in_zone(self.time_zone)
+            .with_format(self.time_format.clone())
+    }
+
+    /// Flush pending output and reopen the output file, see [Output::reopen].
+    pub fn reopen(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        self.out.get_mut().reopen()
+    }
+
+    /// Flush pending output and continue writing to `out`.
+    pub fn set_output(&mut self, out: Output) -> io::Result<()> {
+        self.flush()?;
+        *self.out.get_mut() = out;
+        Ok(())
+    }
+
+    /// The file the output goes to, if any.
+    pub fn output_path(&self) -> Option<&Path> {
+        self.out.get_ref().path()
+    }
+
+    /// Finish the current line, if any, and flush the output.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.clear_line()?;
+        self.out.flush()
+    }
+
+    /// Default for [Renderer::set_idle_gap]
+    const IDLE_GAP: Duration = Duration::from_millis(500);
+
+    /// Spaces between the columns, see [Renderer::set_side_by_side]
+    const GAP: usize = 2;
+
+    /// Use the frame color of connection `id`, or the one of the theme if
+    /// there's no connection.
+    fn frame_color(&mut self, id: Option<ConnectionId>) -> io::Result<()> {
+        if !self.color_connections {
+            return Ok(());
+        }
+        let code = id.map(|id| self.theme.connection_code(id));
+        if code != self.frame_code {
+            self.frame_code = code;
+            if self.current_style == Style::Frame && self.colored && !self.hiding() {
+                self.write_style(Style::Frame)?;
             }
         }
         Ok(())
     }
 
+    /// Spaces before the lines about `direction`, to put what the server
+    /// sends in the right column
+    fn margin_for(&self, direction: Option<Direction>) -> usize {
+        match (self.side_by_side, direction) {
+            (Some(width), Some(Direction::Downstream)) => width + Self::GAP,
+            _ => 0,
+        }
+    }
+
+    fn before(&mut self) -> io::Result<()> {
+        let (Some(then), Some(gap)) = (self.last_time, self.idle_gap) else {
+            return Ok(());
+        };
+        let duration = then.elapsed();
+        if duration >= gap {
+            let rule = self.glyphs.rule;
+            let old_style = self.style(Style::Frame)?;
+            let secs = duration.as_secs_f64();
+            writeln!(self.out, "{rule} {secs:.1}s idle {rule}")?;
+            self.style(old_style)?;
+        }
+        Ok(())
+    }
+
     fn after(&mut self) {
         self.last_time = Some(Instant::now());
     }
 
+    /// The time column for an event of connection `id` which happened at
+    /// `timestamp` if known, including a leading space. Empty if there's no
+    /// time column or no time to show.
+    fn time_column(&mut self, id: Option<ConnectionId>, timestamp: Option<CaptureTime>) -> String {
+        let Some(timestamps) = self.timestamps.filter(|&t| t != Timestamps::None) else {
+            return String::new();
+        };
+        let Some(time) = timestamp.or_else(|| self.now()) else {
+            return String::new();
+        };
+        self.advance_clock(time);
+        let since = |earlier: Option<CaptureTime>| {
+            let elapsed = time.0.saturating_sub(earlier.unwrap_or(time).0);
+            let elapsed = format!("+{}.{:06}", elapsed.as_secs(), elapsed.subsec_micros());
+            format!(" {elapsed:>10}")
+        };
+        match timestamps {
+            Timestamps::None => unreachable!(),
+            Timestamps::Absolute => format!(" {}", self.capture_time(time)),
+            Timestamps::Relative => since(self.clock.start),
+            Timestamps::Delta => since(self.previous.insert(id, time)),
+        }
+    }
+
+    /// Whether the output is being left out because the frame already has
+    /// `--max-lines` lines.
+    fn hiding(&self) -> bool {
+        self.max_lines.is_some_and(|max| self.lines >= max)
+    }
+
+    /// Called after each frame or message.
+    fn check_rotate(&mut self) -> io::Result<()> {
+        let Some((size, keep)) = self.rotate else {
+            return Ok(());
+        };
+        // Only what has been flushed counts but that's close enough
+        if self.out.get_ref().written() >= size {
+            self.out.flush()?;
+            self.out.get_mut().rotate(keep)?;
+        }
+        Ok(())
+    }
+
     pub fn message(
         &mut self,
         id: Option<ConnectionId>,
         direction: Option<Direction>,
         message: impl Display,
+    ) -> io::Result<()> {
+        self.message_at(id, direction, None, message)
+    }
+
+    /// Like [Renderer::message], for something that happened at `timestamp`
+    /// rather than now, see [Renderer::now].
+    pub fn message_at(
+        &mut self,
+        id: Option<ConnectionId>,
+        direction: Option<Direction>,
+        timestamp: Option<CaptureTime>,
+        message: impl Display,
     ) -> io::Result<()> {
         self.before()?;
+        let time = self.time_column(id, timestamp);
+        self.frame_color(id)?;
         self.style(Style::Frame)?;
-        writeln!(self.out, "‣{} {message}", IdStream::from((id, direction)))?;
+        let margin = self.margin_for(direction);
+        writeln!(
+            self.out,
+            "{:margin$}{}{time}{} {message}",
+            "",
+            self.glyphs.message,
+            IdStream::from((id, direction))
+        )?;
         self.style(Style::Normal)?;
         self.out.flush()?;
         self.after();
-        Ok(())
+        self.check_rotate()
     }
 
     pub fn header(
         &mut self,
         id: ConnectionId,
         direction: Direction,
+        timestamp: Option<CaptureTime>,
         items: &[&dyn fmt::Display],
     ) -> io::Result<()> {
         self.before()?;
+        let time = self.time_column(Some(id), timestamp);
+        self.frame_color(Some(id))?;
         let old_style = self.style(Style::Frame)?;
-        write!(self.out, "┌{}", IdStream::from((id, direction)))?;
+        let start = &self.glyphs.frame_start;
+        self.margin = self.margin_for(Some(direction));
+        let margin = self.margin;
+        write!(
+            self.out,
+            "{:margin$}{start}{time}{}",
+            "",
+            IdStream::from((id, direction))
+        )?;
         let mut sep = " ";
         for item in items {
             write!(self.out, "{sep}{item}")?;
             sep = ", ";
         }
+        if let (None, Some(timestamp)) = (self.timestamps, timestamp) {
+            write!(self.out, "{sep}{}", self.capture_time(timestamp))?;
+        }
         writeln!(self.out)?;
         self.at_start = Some(old_style);
         assert_eq!(self.current_style, Style::Frame);
+        self.lines = 0;
+        self.hidden = 0;
         Ok(())
     }
 
+    /// Show a frame as a single line, with the same `items` as
+    /// [Renderer::header] followed by a `preview` of the contents, if any.
+    pub fn summary(
+        &mut self,
+        id: ConnectionId,
+        direction: Direction,
+        timestamp: Option<CaptureTime>,
+        items: &[&dyn fmt::Display],
+        preview: &str,
+    ) -> io::Result<()> {
+        self.before()?;
+        let time = self.time_column(Some(id), timestamp);
+        self.frame_color(Some(id))?;
+        self.style(Style::Frame)?;
+        let start = &self.glyphs.message;
+        let margin = self.margin_for(Some(direction));
+        write!(
+            self.out,
+            "{:margin$}{start}{time}{}",
+            "",
+            IdStream::from((id, direction))
+        )?;
+        let mut sep = " ";
+        for item in items {
+            write!(self.out, "{sep}{item}")?;
+            sep = ", ";
+        }
+        if let (None, Some(timestamp)) = (self.timestamps, timestamp) {
+            write!(self.out, "{sep}{}", self.capture_time(timestamp))?;
+        }
+        if !preview.is_empty() {
+            write!(self.out, ": {preview}")?;
+        }
+        writeln!(self.out)?;
+        self.style(Style::Normal)?;
+        self.out.flush()?;
+        self.after();
+        self.check_rotate()
+    }
+
     pub fn footer(&mut self, items: &[&dyn fmt::Display]) -> io::Result<()> {
         self.clear_line()?;
         assert_eq!(self.current_style, Style::Frame);
-        write!(self.out, "└")?;
+        let margin = mem::take(&mut self.margin);
+        if self.hidden > 0 {
+            let Glyphs {
+                frame_line,
+                ellipsis,
+                ..
+            } = &self.glyphs;
+            let n = self.hidden;
+            let s = if n == 1 { "" } else { "s" };
+            writeln!(
+                self.out,
+                "{:margin$}{frame_line}{ellipsis} ({n} more line{s})",
+                ""
+            )?;
+        }
+        self.lines = 0;
+        self.hidden = 0;
+        write!(self.out, "{:margin$}{}", "", self.glyphs.frame_end)?;
         let mut sep = " ";
         for item in items {
             write!(self.out, "{sep}{item}")?;
@@ -93,18 +547,66 @@ impl Renderer {
         self.style(Style::Normal)?;
         self.out.flush()?;
         self.after();
-        Ok(())
+        self.check_rotate()
     }
 
     pub fn put(&mut self, data: impl AsRef<[u8]>) -> io::Result<()> {
-        if let Some(style) = self.at_start {
-            assert_eq!(self.current_style, Style::Frame);
-            self.out.write_all("│".as_bytes())?;
-            self.style(style)?;
-            self.at_start = None;
+        if self.hiding() {
+            if let Some(style) = self.at_start.take() {
+                self.style(style)?;
+            }
+            return Ok(());
+        }
+        let mut data = data.as_ref();
+        let mut wrapped = false;
+        loop {
+            let line_start = self.at_start.is_some();
+            if let Some(style) = self.at_start {
+                assert_eq!(self.current_style, Style::Frame);
+                let margin = self.margin;
+                write!(self.out, "{:margin$}{}", "", self.glyphs.frame_line)?;
+                self.column = self.glyphs.frame_line.chars().count();
+                if wrapped {
+                    self.out.write_all(self.glyphs.wrap.as_bytes())?;
+                    self.column += 1;
+                }
+                self.style(style)?;
+                self.at_start = None;
+            }
+            let Some(width) = self.wrap else {
+                self.out.write_all(data)?;
+                return Ok(());
+            };
+            // Wrap where the line reaches the width of the column, which
+            // is counted in characters rather than bytes
+            let room = width.saturating_sub(self.column);
+            let mut fits = data.len();
+            let mut chars = 0;
+            for (i, b) in data.iter().enumerate() {
+                if b & 0xC0 != 0x80 {
+                    if chars == room {
+                        fits = i;
+                        break;
+                    }
+                    chars += 1;
+                }
+            }
+            // Always make progress, even if the column is very narrow
+            if fits == 0 && line_start {
+                fits = data.len();
+            }
+            self.out.write_all(&data[..fits])?;
+            self.column += chars;
+            data = &data[fits..];
+            if data.is_empty() {
+                return Ok(());
+            }
+            self.nl()?;
+            wrapped = true;
+            if self.hiding() {
+                return Ok(());
+            }
         }
-        self.out.write_all(data.as_ref())?;
-        Ok(())
     }
 
     pub fn clear_line(&mut self) -> io::Result<()> {
@@ -116,7 +618,12 @@ impl Renderer {
 
     pub fn nl(&mut self) -> io::Result<()> {
         let old_style = self.style(Style::Frame)?;
-        writeln!(self.out)?;
+        if self.hiding() {
+            self.hidden += 1;
+        } else {
+            writeln!(self.out)?;
+            self.lines += 1;
+        }
         self.at_start = Some(old_style);
         Ok(())
     }
@@ -125,7 +632,9 @@ impl Renderer {
         if style == self.current_style {
             return Ok(style);
         }
-        if self.colored {
+        // While hiding, the terminal stays in the frame style of the last
+        // line shown
+        if self.colored && !self.hiding() {
             self.write_style(style)?;
         }
         mem::swap(&mut self.current_style, &mut style);
@@ -133,23 +642,214 @@ impl Renderer {
     }
 
     fn write_style(&mut self, style: Style) -> io::Result<()> {
-        // Black=30 Red=31 Green=32 Yellow=33 Blue=34 Magenta=35 Cyan=36 White=37
-
-        let escape_sequence = match style {
-            Style::Normal => "",
-            Style::Header => "\u{1b}[1m",          // bold
-            Style::Frame => "\u{1b}[36m",          // cyan
-            Style::Error => "\u{1b}[1m\u{1b}[31m", // bold red
-            Style::Whitespace => "\u{1b}[31m",     // red
-            Style::Digit => "\u{1b}[32m",          // green
-            Style::Letter => "\u{1b}[34m",         // blue
-        };
         self.out.write_all(b"\x1b[m")?; // NORMAL
-        self.out.write_all(escape_sequence.as_bytes())?;
+        let code = match (style, self.frame_code) {
+            (Style::Frame, Some(code)) => code,
+            _ => self.theme.code(style),
+        };
+        if !code.is_empty() {
+            write!(self.out, "\x1b[{code}m")?;
+        }
         Ok(())
     }
 }
 
+/// The ANSI SGR codes used for each [Style], see `--theme`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Theme {
+    codes: BTreeMap<Style, String>,
+    /// Frame colors to tell connections apart, see `--color-connections`
+    connections: &'static [&'static str],
+}
+
+impl Theme {
+    const NAMES: [(&'static str, Style); 8] = [
+        ("normal", Style::Normal),
+        ("error", Style::Error),
+        ("frame", Style::Frame),
+        ("header", Style::Header),
+        ("whitespace", Style::Whitespace),
+        ("digit", Style::Digit),
+        ("letter", Style::Letter),
+        ("escape", Style::Escape),
+    ];
+
+    fn new(codes: [(Style, &str); 7], connections: &'static [&'static str]) -> Self {
+        let codes = codes
+            .into_iter()
+            .map(|(style, code)| (style, code.to_string()))
+            .collect();
+        Theme { codes, connections }
+    }
+
+    /// The default, for terminals with a light background
+    pub fn light() -> Self {
+        // Black=30 Red=31 Green=32 Yellow=33 Blue=34 Magenta=35 Cyan=36 White=37
+        Theme::new(
+            [
+                (Style::Header, "1"),      // bold
+                (Style::Frame, "36"),      // cyan
+                (Style::Error, "1;31"),    // bold red
+                (Style::Whitespace, "31"), // red
+                (Style::Digit, "32"),      // green
+                (Style::Letter, "34"),     // blue
+                (Style::Escape, "35"),     // magenta
+            ],
+            &["36", "32", "35", "34", "33", "31"],
+        )
+    }
+
+    /// Brighter colors for terminals with a dark background
+    pub fn dark() -> Self {
+        Theme::new(
+            [
+                (Style::Header, "1"),      // bold
+                (Style::Frame, "96"),      // bright cyan
+                (Style::Error, "1;91"),    // bold bright red
+                (Style::Whitespace, "91"), // bright red
+                (Style::Digit, "92"),      // bright green
+                (Style::Letter, "93"),     // bright yellow
+                (Style::Escape, "95"),     // bright magenta
+            ],
+            &["96", "92", "95", "94", "93", "91"],
+        )
+    }
+
+    /// Parse the parameter of `--theme`: a comma separated list of `light`,
+    /// `dark` and STYLE=CODE items, for example `dark,frame=35,digit=1;32`.
+    /// Later items override earlier ones.
+    pub fn parse(text: &str) -> Option<Theme> {
+        let mut theme = Theme::light();
+        for item in text.split(',') {
+            match item.trim().split_once('=') {
+                None if item.trim() == "light" => theme = Theme::light(),
+                None if item.trim() == "dark" => theme = Theme::dark(),
+                None => return None,
+                Some((name, code)) => {
+                    let (_, style) = Self::NAMES.iter().find(|(n, _)| *n == name)?;
+                    if !code.chars().all(|c| c.is_ascii_digit() || c == ';') {
+                        return None;
+                    }
+                    theme.codes.insert(*style, code.to_string());
+                }
+            }
+        }
+        Some(theme)
+    }
+
+    fn code(&self, style: Style) -> &str {
+        self.codes.get(&style).map_or("", String::as_str)
+    }
+
+    /// The frame color of connection `id`, always the same for an id
+    fn connection_code(&self, id: ConnectionId) -> &'static str {
+        self.connections[id.number() % self.connections.len()]
+    }
+}
+
+/// The non-ASCII characters used in the output and their replacements for
+/// `--ascii`. The ones shown in the binary dump must be a single column wide.
+/// The frame characters can also be chosen, see [Glyphs::with_frame_chars].
+#[derive(Debug, Clone)]
+pub struct Glyphs {
+    pub frame_start: Cow<'static, str>,
+    pub frame_line: Cow<'static, str>,
+    pub frame_end: Cow<'static, str>,
+    /// Starts single line messages such as connection events
+    pub message: Cow<'static, str>,
+    pub newline: &'static str,
+    pub tab: &'static str,
+    pub space: &'static str,
+    pub nul: &'static str,
+    /// Other bytes that are not printable
+    pub other: &'static str,
+    /// Around message headers in the binary dump
+    pub open: &'static str,
+    pub close: &'static str,
+    /// Where text has been cut short
+    pub ellipsis: &'static str,
+    /// Bars of histograms
+    pub bar: &'static str,
+    /// Below the column names of a table and where it crosses the column
+    /// separators, see `--table`
+    pub rule: &'static str,
+    pub cross: &'static str,
+    /// Starts the rest of a line that has been wrapped, see `--wrap`
+    pub wrap: &'static str,
+}
+
+impl Glyphs {
+    pub const UNICODE: Glyphs = Glyphs {
+        frame_start: Cow::Borrowed("┌"),
+        frame_line: Cow::Borrowed("│"),
+        frame_end: Cow::Borrowed("└"),
+        message: Cow::Borrowed("‣"),
+        newline: "↵",
+        tab: "→",
+        space: "·",
+        nul: "░",
+        other: "▒",
+        open: "⟨",
+        close: "⟩",
+        ellipsis: "…",
+        bar: "█",
+        rule: "─",
+        cross: "┼",
+        wrap: "↪",
+    };
+
+    pub const ASCII: Glyphs = Glyphs {
+        frame_start: Cow::Borrowed("+"),
+        frame_line: Cow::Borrowed("|"),
+        frame_end: Cow::Borrowed("+"),
+        message: Cow::Borrowed("*"),
+        newline: "$",
+        tab: ">",
+        space: " ",
+        nul: ".",
+        other: ":",
+        open: "<",
+        close: ">",
+        ellipsis: "...",
+        bar: "#",
+        rule: "-",
+        cross: "+",
+        wrap: "~",
+    };
+
+    /// Parse the parameter of `--frame-chars`: a comma separated list of
+    /// `unicode` and `ascii`, which set all frame characters, and NAME=CHARS
+    /// items where NAME is start, line, end or message, for example
+    /// `ascii,message=>>`. Later items override earlier ones. As commas
+    /// separate the items, CHARS cannot contain one.
+    pub fn with_frame_chars(&self, text: &str) -> Option<Glyphs> {
+        let mut glyphs = self.clone();
+        for item in text.split(',') {
+            let preset = match item.split_once('=') {
+                None if item == "unicode" => Glyphs::UNICODE,
+                None if item == "ascii" => Glyphs::ASCII,
+                None => return None,
+                Some((name, chars)) => {
+                    let chars = Cow::Owned(chars.to_string());
+                    match name {
+                        "start" => glyphs.frame_start = chars,
+                        "line" => glyphs.frame_line = chars,
+                        "end" => glyphs.frame_end = chars,
+                        "message" => glyphs.message = chars,
+                        _ => return None,
+                    }
+                    continue;
+                }
+            };
+            glyphs.frame_start = preset.frame_start;
+            glyphs.frame_line = preset.frame_line;
+            glyphs.frame_end = preset.frame_end;
+            glyphs.message = preset.message;
+        }
+        Some(glyphs)
+    }
+}
+
 pub struct IdStream(Option<ConnectionId>, Option<Direction>);
 
 impl fmt::Display for IdStream {
@@ -187,4 +887,114 @@ pub enum Style {
     Whitespace,
     Digit,
     Letter,
+    /// Bytes shown as `\xNN` in text, see `--escape`
+    Escape,
+}
+
+#[test]
+fn test_time_column() {
+    let render = |timestamps| {
+        let mut renderer = Renderer::new(false, Output::Memory(vec![]));
+        renderer.set_timestamps(timestamps, false);
+        let t = |micros| Some(CaptureTime(Duration::from_micros(micros)));
+        let (a, b) = (ConnectionId::new(10), ConnectionId::new(11));
+        renderer
+            .header(a, Direction::Upstream, t(1_000_000), &[])
+            .unwrap();
+        renderer.footer(&[]).unwrap();
+        renderer
+            .header(b, Direction::Upstream, t(1_500_000), &[])
+            .unwrap();
+        renderer.footer(&[]).unwrap();
+        renderer.message(Some(a), None, "ENDED").unwrap();
+        let output = renderer.take_output().unwrap();
+        String::from_utf8(output).unwrap()
+    };
+    assert_eq!(
+        render(Timestamps::Relative),
+        "┌  +0.000000 #10 UPSTREAM\n└\n┌  +0.500000 #11 UPSTREAM\n└\n‣  +0.500000 #10 ENDED\n"
+    );
+    assert_eq!(
+        render(Timestamps::Delta),
+        "┌  +0.000000 #10 UPSTREAM\n└\n┌  +0.000000 #11 UPSTREAM\n└\n‣  +0.500000 #10 ENDED\n"
+    );
+}
+
+#[test]
+fn test_parse_theme() {
+    assert_eq!(Theme::parse("light"), Some(Theme::light()));
+    assert_eq!(Theme::parse("dark"), Some(Theme::dark()));
+    let theme = Theme::parse("dark, frame=35,error=1;4;31").unwrap();
+    assert_eq!(theme.code(Style::Frame), "35");
+    assert_eq!(theme.code(Style::Error), "1;4;31");
+    assert_eq!(theme.code(Style::Digit), "92");
+    assert_eq!(Theme::parse("frame=").unwrap().code(Style::Frame), "");
+    assert_eq!(Theme::parse("purple"), None);
+    assert_eq!(Theme::parse("frame=red"), None);
+    assert_eq!(Theme::parse("border=36"), None);
+}
+
+#[test]
+fn test_max_lines() {
+    let mut renderer = Renderer::new(false, Output::Memory(vec![]));
+    renderer.set_max_lines(2);
+    let id = ConnectionId::new(10);
+    for n in [2, 5] {
+        renderer.header(id, Direction::Upstream, None, &[]).unwrap();
+        for i in 0..n {
+            renderer.put(format!("line {i}")).unwrap();
+            renderer.nl().unwrap();
+        }
+        renderer.footer(&[]).unwrap();
+    }
+    let output = String::from_utf8(renderer.take_output().unwrap()).unwrap();
+    assert_eq!(
+        output,
+        "┌ #10 UPSTREAM\n│line 0\n│line 1\n└\n┌ #10 UPSTREAM\n│line 0\n│line 1\n│… (3 more lines)\n└\n"
+    );
+}
+
+#[test]
+fn test_color_connections() {
+    let mut renderer = Renderer::new(true, Output::Memory(vec![]));
+    renderer.set_color_connections(true);
+    for n in [1, 2] {
+        let id = ConnectionId::new(n);
+        renderer.header(id, Direction::Upstream, None, &[]).unwrap();
+        renderer.footer(&[]).unwrap();
+    }
+    let output = String::from_utf8(renderer.take_output().unwrap()).unwrap();
+    assert_eq!(
+        output,
+        "\x1b[m\x1b[32m┌ #1 UPSTREAM\n└\n\x1b[m\x1b[m\x1b[35m┌ #2 UPSTREAM\n└\n\x1b[m"
+    );
+}
+
+#[test]
+fn test_wrap() {
+    let mut renderer = Renderer::new(false, Output::Memory(vec![]));
+    renderer.set_wrap(6);
+    let id = ConnectionId::new(10);
+    renderer.header(id, Direction::Upstream, None, &[]).unwrap();
+    renderer.put("abcdéfghijk").unwrap();
+    renderer.nl().unwrap();
+    renderer.footer(&[]).unwrap();
+    let output = String::from_utf8(renderer.take_output().unwrap()).unwrap();
+    assert_eq!(output, "┌ #10 UPSTREAM\n│abcdé\n│↪fghi\n│↪jk\n└\n");
+}
+
+#[test]
+fn test_frame_chars() {
+    let glyphs = Glyphs::UNICODE
+        .with_frame_chars("ascii,message=>>")
+        .unwrap();
+    assert_eq!(glyphs.frame_start, "+");
+    assert_eq!(glyphs.frame_line, "|");
+    assert_eq!(glyphs.message, ">>");
+    assert_eq!(glyphs.newline, "↵");
+    let glyphs = Glyphs::ASCII.with_frame_chars("unicode,line=").unwrap();
+    assert_eq!(glyphs.frame_start, "┌");
+    assert_eq!(glyphs.frame_line, "");
+    assert!(Glyphs::UNICODE.with_frame_chars("fancy").is_none());
+    assert!(Glyphs::UNICODE.with_frame_chars("corner=+").is_none());
 }
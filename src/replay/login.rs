@@ -0,0 +1,100 @@
+//! The MAPI login salts the password hash with a random challenge so a
+//! recorded login response is rejected when it is replayed. If we know the
+//! password we can compute a new response for the challenge we received.
+
+use sha2::{Digest, Sha224, Sha256, Sha384, Sha512};
+
+use crate::mapi::frame;
+
+/// Hash algorithms we can compute, in order of preference
+const ALGORITHMS: [&str; 4] = ["SHA512", "SHA384", "SHA256", "SHA224"];
+
+/// The parts of a login challenge we need to compute a response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Challenge {
+    salt: String,
+    hashes: Vec<String>,
+    password_hash: String,
+}
+
+impl Challenge {
+    /// Recognize a login challenge such as
+    /// `salt:mserver:9:RIPEMD160,SHA512,SHA384:LIT:SHA512:`.
+    pub fn parse(message: &[u8]) -> Option<Challenge> {
+        let payload = unframe(message)?;
+        let text = std::str::from_utf8(&payload).ok()?;
+        let fields: Vec<&str> = text.trim_end().split(':').collect();
+        let [salt, server, "9", hashes, _endian, password_hash, ..] = fields[..] else {
+            return None;
+        };
+        if server != "mserver" && server != "merovingian" {
+            return None;
+        }
+        Some(Challenge {
+            salt: salt.to_string(),
+            hashes: hashes.split(',').map(str::to_string).collect(),
+            password_hash: password_hash.to_string(),
+        })
+    }
+}
+
+/// If `message` is a login response such as
+/// `LIT:monetdb:{SHA512}0123abcd:sql:demo:`, return a copy in which the
+/// password hash has been replaced by one computed for `challenge`.
+pub fn rewrite_response(message: &[u8], challenge: &Challenge, password: &str) -> Option<Vec<u8>> {
+    let payload = unframe(message)?;
+    let text = std::str::from_utf8(&payload).ok()?;
+    let mut fields: Vec<&str> = text.split(':').collect();
+    if fields.len() < 5 || !matches!(fields[0], "LIT" | "BIG") || !fields[2].starts_with('{') {
+        return None;
+    }
+
+    let algo = ALGORITHMS
+        .into_iter()
+        .find(|a| challenge.hashes.iter().any(|h| h == a))?;
+    let hashed_password = hash(&challenge.password_hash, password.as_bytes())?;
+    let salted = format!("{hashed_password}{salt}", salt = challenge.salt);
+    let response = format!("{{{algo}}}{}", hash(algo, salted.as_bytes())?);
+    fields[2] = &response;
+
+    Some(frame(fields.join(":").as_bytes()))
+}
+
+fn hash(algo: &str, data: &[u8]) -> Option<String> {
+    let digest = match algo {
+        "SHA512" => Sha512::digest(data).to_vec(),
+        "SHA384" => Sha384::digest(data).to_vec(),
+        "SHA256" => Sha256::digest(data).to_vec(),
+        "SHA224" => Sha224::digest(data).to_vec(),
+        _ => return None,
+    };
+    Some(digest.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Concatenate the contents of the blocks of a message. Returns None if the
+/// message is not a well-formed sequence of blocks.
+fn unframe(mut message: &[u8]) -> Option<Vec<u8>> {
+    let mut payload = vec![];
+    while let [lo, hi, rest @ ..] = message {
+        let len = u16::from_le_bytes([*lo, *hi]) as usize / 2;
+        let block = rest.get(..len)?;
+        payload.extend_from_slice(block);
+        message = &rest[len..];
+    }
+    message.is_empty().then_some(payload)
+}
+
+#[test]
+fn test_rewrite_response() {
+    let challenge = Challenge::parse(&frame(b"abcd:mserver:9:RIPEMD160,SHA256:LIT:SHA512:\n"));
+    let challenge = challenge.unwrap();
+    let response = frame(b"LIT:monetdb:{SHA512}0123:sql:demo:FILETRANS:");
+    let rewritten = rewrite_response(&response, &challenge, "monetdb").unwrap();
+    let expected = "LIT:monetdb:{SHA256}a8f9e012669d14e001516017b711b4001d8db9ff9cd1cf46c8d7deb7f9e25b34:sql:demo:FILETRANS:";
+    assert_eq!(unframe(&rewritten).unwrap(), expected.as_bytes());
+
+    assert_eq!(
+        rewrite_response(&frame(b"sSELECT 42;\n"), &challenge, "x"),
+        None
+    );
+}
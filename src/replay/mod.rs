@@ -0,0 +1,414 @@
+//! Replay the client side of the connections in a capture file against a
+//! live server, so the server's responses can be compared with the original
+//! ones.
+
+mod login;
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Read, Write},
+    net::Shutdown,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Error as AError, Result as AResult};
+use socket2::{Domain, SockAddr, Socket, Type};
+
+use crate::{
+    pcap::{self, Tracker},
+    proxy::{
+        counter::Counter,
+        event::{ConnectionId, Direction, MapiEvent, Timing, Traffic},
+        network::{Addr, MonetAddr},
+        Error,
+    },
+};
+
+use self::login::Challenge;
+
+/// How long to wait for the server before giving up on a connection
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Login challenges are small, don't keep larger server messages around
+const MAX_CHALLENGE_SIZE: usize = 1024;
+
+type Handler<'a> = dyn FnMut(MapiEvent) -> AResult<()> + 'a;
+
+/// What the client sent on one captured connection
+#[derive(Debug)]
+struct Script {
+    id: ConnectionId,
+    local: Addr,
+    peer: Addr,
+    /// The messages sent by the client, each with the number of messages the
+    /// server had sent when the client started sending it
+    steps: Vec<(u64, Vec<u8>)>,
+    /// Total number of messages sent by the server
+    responses: u64,
+
+    upstream: Counter,
+    downstream: Counter,
+    partial: Option<(u64, Vec<u8>)>,
+}
+
+impl Script {
+    fn add_upstream(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let before = self.upstream.messages;
+            let (_, message) = self
+                .partial
+                .get_or_insert_with(|| (self.downstream.messages, vec![]));
+            let n = self.upstream.advance(data);
+            message.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.upstream.messages > before {
+                self.steps.extend(self.partial.take());
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        // send whatever was captured of an incomplete last message too
+        self.steps.extend(self.partial.take());
+        self.responses = self.downstream.messages;
+    }
+}
+
+/// Extracts the client side of the connections in a capture file and
+/// replays them against a server, one connection at a time.
+pub struct Replayer {
+    scripts: Vec<Script>,
+}
+
+impl Replayer {
+    pub fn from_pcap(reader: impl Read, options: pcap::Options) -> AResult<Self> {
+        let mut scripts: HashMap<ConnectionId, Script> = HashMap::new();
+        let handler = |ev: MapiEvent| {
+            match ev {
+                MapiEvent::Incoming { id, local, peer } => {
+                    let script = Script {
+                        id,
+                        local,
+                        peer,
+                        steps: vec![],
+                        responses: 0,
+                        upstream: Counter::new(false),
+                        downstream: Counter::new(false),
+                        partial: None,
+                    };
+                    scripts.insert(id, script);
+                }
+                MapiEvent::Data {
+                    id,
+                    direction,
+                    data,
+                    ..
+                } => {
+                    if let Some(script) = scripts.get_mut(&id) {
+                        match direction {
+                            Direction::Upstream => script.add_upstream(&data),
+                            Direction::Downstream => script.downstream.count(&data),
+                        }
+                    }
+                }
+                _ => {}
+            }
+            Ok(())
+        };
+        let mut tracker = Tracker::new(options, handler);
+        pcap::parse_pcap_file(reader, &mut tracker)?;
+        drop(tracker);
+
+        let mut scripts: Vec<Script> = scripts.into_values().collect();
+        scripts.sort_by_key(|s| s.id);
+        for script in &mut scripts {
+            script.finish();
+        }
+        Ok(Replayer { scripts })
+    }
+
+    /// Replay the connections against the server at `forward_addr`. If a
+    /// password is given, login responses are recomputed for the new
+    /// challenges.
+    pub fn run(
+        self,
+        forward_addr: &MonetAddr,
+        password: Option<&str>,
+        mut handler: impl FnMut(MapiEvent) -> AResult<()>,
+    ) -> AResult<()> {
+        for script in self.scripts {
+            replay_connection(script, forward_addr, password, &mut handler)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a replayed connection stopped early
+enum Stop {
+    /// Could not report the events, give up altogether
+    Output(AError),
+    /// Something went wrong with the connection to the server
+    Failed(Error),
+}
+
+impl From<AError> for Stop {
+    fn from(e: AError) -> Self {
+        Stop::Output(e)
+    }
+}
+
+fn replay_connection(
+    script: Script,
+    forward_addr: &MonetAddr,
+    password: Option<&str>,
+    handler: &mut Handler,
+) -> AResult<()> {
+    let id = script.id;
+    let started = Instant::now();
+    handler(MapiEvent::Incoming {
+        id,
+        local: script.local.clone(),
+        peer: script.peer.clone(),
+    })?;
+
+    let socket = match connect(id, forward_addr, handler) {
+        Ok(connected) => connected,
+        Err(Stop::Output(e)) => return Err(e),
+        Err(Stop::Failed(error)) => {
+            return handler(MapiEvent::Aborted {
+                id,
+                error,
+                traffic: Traffic::default(),
+                timing: Timing::between(started, None, Instant::now()),
+            });
+        }
+    };
+
+    let mut session = Session {
+        id,
+        socket,
+        handler,
+        password,
+        // open_socket has already sent the '0' of a Unix domain socket
+        upstream: Counter::new(false),
+        downstream: Counter::new(false),
+        response: vec![],
+        challenge: None,
+        server_closed: false,
+        data_seen: None,
+    };
+    let result = session.play(&script);
+    let traffic = Traffic {
+        upstream: session.upstream.volume(),
+        downstream: session.downstream.volume(),
+    };
+    let timing = Timing::between(started, session.data_seen, Instant::now());
+    let ev = match result {
+        Ok(()) => MapiEvent::End {
+            id,
+            traffic,
+            timing,
+        },
+        Err(Stop::Output(e)) => return Err(e),
+        Err(Stop::Failed(error)) => MapiEvent::Aborted {
+            id,
+            error,
+            traffic,
+            timing,
+        },
+    };
+    (session.handler)(ev)
+}
+
+/// Connect to the first address of the server that works. Also returns
+/// whether it's a Unix Domain socket.
+fn connect(
+    id: ConnectionId,
+    forward_addr: &MonetAddr,
+    handler: &mut Handler,
+) -> Result<Socket, Stop> {
+    let addrs = forward_addr.resolve().map_err(|e| {
+        Stop::Failed(Error::Other(format!(
+            "could not resolve {forward_addr}: {e}"
+        )))
+    })?;
+    for addr in addrs {
+        handler(MapiEvent::Connecting {
+            id,
+            remote: addr.clone(),
+        })?;
+        match open_socket(&addr) {
+            Ok(socket) => {
+                handler(MapiEvent::Connected {
+                    id,
+                    peer: addr.clone(),
+                })?;
+                return Ok(socket);
+            }
+            Err(error) => handler(MapiEvent::ConnectFailed {
+                id,
+                remote: addr.to_string(),
+                error,
+                immediately: true,
+            })?,
+        }
+    }
+    Err(Stop::Failed(Error::Connect))
+}
+
+fn open_socket(addr: &Addr) -> io::Result<Socket> {
+    let socket = match addr {
+        Addr::Tcp(a) => {
+            let socket = Socket::new(Domain::for_address(*a), Type::STREAM, None)?;
+            socket.connect(&SockAddr::from(*a))?;
+            socket.set_nodelay(true)?;
+            socket
+        }
+        Addr::Unix(path) => {
+            let mut socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+            socket.connect(&SockAddr::unix(path)?)?;
+            // Unix Domain sockets expect the client to send a '0' first
+            socket.write_all(b"0")?;
+            socket
+        }
+    };
+    socket.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+    Ok(socket)
+}
+
+/// A single replayed connection
+struct Session<'a> {
+    id: ConnectionId,
+    socket: Socket,
+    handler: &'a mut Handler<'a>,
+    password: Option<&'a str>,
+    upstream: Counter,
+    downstream: Counter,
+    /// The message the server is currently sending, as long as it might be
+    /// a login challenge
+    response: Vec<u8>,
+    /// The most recent login challenge sent by the server
+    challenge: Option<Challenge>,
+    server_closed: bool,
+    data_seen: Option<(Instant, Instant)>,
+}
+
+impl Session<'_> {
+    fn play(&mut self, script: &Script) -> Result<(), Stop> {
+        for (after, message) in &script.steps {
+            self.receive_until(*after)?;
+            if self.server_closed {
+                break;
+            }
+            self.send(message)?;
+        }
+        self.receive_until(script.responses)?;
+
+        if !self.server_closed {
+            self.socket
+                .shutdown(Shutdown::Write)
+                .map_err(|err| forward_error("shutting down", "server", err))?;
+            self.emit(MapiEvent::ShutdownRead {
+                id: self.id,
+                direction: Direction::Upstream,
+            })?;
+            self.receive_until(u64::MAX)?;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, message: &[u8]) -> Result<(), Stop> {
+        let rewritten = match (&self.challenge, self.password) {
+            (Some(challenge), Some(password)) => {
+                login::rewrite_response(message, challenge, password)
+            }
+            _ => None,
+        };
+        if rewritten.is_some() {
+            self.challenge = None;
+        }
+        let message = rewritten.as_deref().unwrap_or(message);
+
+        self.socket
+            .write_all(message)
+            .map_err(|err| forward_error("writing to", "server", err))?;
+        self.upstream.count(message);
+        self.saw_data();
+        self.emit_data(Direction::Upstream, message)
+    }
+
+    /// Read from the server until it has sent `n` messages in total or has
+    /// closed the connection.
+    fn receive_until(&mut self, n: u64) -> Result<(), Stop> {
+        let mut buf = [0u8; 8192];
+        while !self.server_closed && self.downstream.messages < n {
+            let nread = match self.socket.read(&mut buf) {
+                Ok(nread) => nread,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    let secs = RESPONSE_TIMEOUT.as_secs();
+                    let msg = format!("server did not respond within {secs}s");
+                    return Err(Stop::Failed(Error::Other(msg)));
+                }
+                Err(err) => return Err(forward_error("reading from", "server", err)),
+            };
+            if nread == 0 {
+                self.server_closed = true;
+                self.emit(MapiEvent::ShutdownRead {
+                    id: self.id,
+                    direction: Direction::Downstream,
+                })?;
+                break;
+            }
+            let data = &buf[..nread];
+            self.saw_data();
+            self.emit_data(Direction::Downstream, data)?;
+            self.track_challenge(data);
+        }
+        Ok(())
+    }
+
+    /// Count the data from the server and remember the last login challenge.
+    fn track_challenge(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let before = self.downstream.messages;
+            let n = self.downstream.advance(data);
+            if self.response.len() <= MAX_CHALLENGE_SIZE {
+                self.response.extend_from_slice(&data[..n]);
+            }
+            data = &data[n..];
+            if self.downstream.messages > before {
+                if self.response.len() <= MAX_CHALLENGE_SIZE {
+                    if let Some(challenge) = Challenge::parse(&self.response) {
+                        self.challenge = Some(challenge);
+                    }
+                }
+                self.response.clear();
+            }
+        }
+    }
+
+    fn saw_data(&mut self) {
+        let now = Instant::now();
+        let (first, _) = self.data_seen.get_or_insert((now, now));
+        self.data_seen = Some((*first, now));
+    }
+
+    fn emit_data(&mut self, direction: Direction, data: &[u8]) -> Result<(), Stop> {
+        self.emit(MapiEvent::Data {
+            id: self.id,
+            direction,
+            data: data.into(),
+            timestamp: None,
+        })
+    }
+
+    fn emit(&mut self, ev: MapiEvent) -> Result<(), Stop> {
+        (self.handler)(ev)?;
+        Ok(())
+    }
+}
+
+fn forward_error(doing: &'static str, side: &'static str, err: io::Error) -> Stop {
+    Stop::Failed(Error::Forward { doing, side, err })
+}
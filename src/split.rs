@@ -0,0 +1,130 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result as AResult};
+
+use crate::{
+    mapi,
+    output::{self, Output},
+    proxy::{
+        event::{CaptureTime, ConnectionId, MapiEvent, TimeZone},
+        network::Addr,
+    },
+    render::Renderer,
+};
+
+/// Writes the output of every connection to a file of its own, see
+/// `--split-output`. Each connection has its own [mapi::State] and
+/// [Renderer] with the same settings as the main ones.
+pub struct SplitOutput {
+    template: PathBuf,
+    zone: TimeZone,
+    colored: bool,
+    state: mapi::State,
+    renderer: Renderer,
+    connections: HashMap<ConnectionId, (mapi::State, Renderer)>,
+}
+
+impl SplitOutput {
+    pub fn new(
+        template: &Path,
+        zone: TimeZone,
+        colored: bool,
+        state: &mapi::State,
+        renderer: &Renderer,
+    ) -> Self {
+        SplitOutput {
+            template: template.to_owned(),
+            zone,
+            colored,
+            state: state.fresh(),
+            renderer: renderer.in_memory(),
+            connections: HashMap::new(),
+        }
+    }
+
+    pub fn handle(&mut self, ev: &MapiEvent) -> AResult<()> {
+        if let MapiEvent::Incoming { id, peer, .. } = ev {
+            let path = self.path(*id, peer);
+            let out = Output::create(&path)
+                .with_context(|| format!("Could not create output file {}", path.display()))?;
+            let mut renderer = self.renderer.with_output(self.colored, out);
+            let mut state = self.state.fresh();
+            state.start(&mut renderer)?;
+            self.connections.insert(*id, (state, renderer));
+        }
+        let Some(id) = ev.id() else {
+            return Ok(());
+        };
+        let Some((state, renderer)) = self.connections.get_mut(&id) else {
+            return Ok(());
+        };
+        state.handle(ev, renderer)?;
+        if let MapiEvent::End { .. } | MapiEvent::Aborted { .. } = ev {
+            if let Some((mut state, mut renderer)) = self.connections.remove(&id) {
+                state.finish(&mut renderer)?;
+                renderer.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write what is left of the connections that are still open, at the
+    /// end of the run.
+    pub fn finish(&mut self) -> AResult<()> {
+        for (_, (mut state, mut renderer)) in self.connections.drain() {
+            state.finish(&mut renderer)?;
+            renderer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Expand %id and %peer in the template, then the date and time
+    /// placeholders as in `--output`.
+    fn path(&self, id: ConnectionId, peer: &Addr) -> PathBuf {
+        let peer = match peer {
+            Addr::Tcp(addr) => format!("{}-{}", addr.ip(), addr.port()),
+            Addr::Unix(_) => "unix".to_string(),
+        };
+        let template = self.template.to_string_lossy();
+        let mut expanded = String::new();
+        let mut rest = template.as_ref();
+        while let Some(pos) = rest.find('%') {
+            expanded.push_str(&rest[..pos]);
+            rest = &rest[pos..];
+            let (replacement, len) = if rest.starts_with("%id") {
+                (id.number().to_string(), 3)
+            } else if rest.starts_with("%peer") {
+                (peer.clone(), 5)
+            } else if rest.starts_with("%%") {
+                // Left for expand_path
+                ("%%".to_string(), 2)
+            } else {
+                ("%".to_string(), 1)
+            };
+            expanded.push_str(&replacement);
+            rest = &rest[len..];
+        }
+        expanded.push_str(rest);
+        let now = CaptureTime::now().in_zone(self.zone);
+        output::expand_path(Path::new(&expanded), &now)
+    }
+}
+
+#[test]
+fn test_split_path() {
+    let state = mapi::State::new(crate::Level::Messages, false);
+    let renderer = Renderer::new(false, Output::Memory(vec![]));
+    let template = Path::new("conn-%id-%peer-%%id.txt");
+    let split = SplitOutput::new(template, TimeZone::Utc, false, &state, &renderer);
+    let id = ConnectionId::new(12);
+    let peer = Addr::Tcp("10.1.2.3:41394".parse().unwrap());
+    assert_eq!(
+        split.path(id, &peer),
+        Path::new("conn-12-10.1.2.3-41394-%id.txt")
+    );
+    let peer = Addr::Unix(PathBuf::new());
+    assert_eq!(split.path(id, &peer), Path::new("conn-12-unix-%id.txt"));
+}
@@ -0,0 +1,180 @@
+//! Store the connections, events and messages in an SQLite database as they
+//! are observed, see `--sqlite`.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result as AResult};
+use rusqlite::{params, Connection};
+use serde_json::Value;
+use tracing::error;
+
+use crate::{
+    mapi::{direction_name, iso_time, Record, Structured},
+    proxy::event::MapiEvent,
+    Format, Level,
+};
+
+const SCHEMA: &str = "
+    CREATE TABLE connections (
+        conn INTEGER PRIMARY KEY,
+        started TEXT,
+        ended TEXT,
+        local TEXT,
+        peer TEXT,
+        outcome TEXT,               -- 'end', 'aborted' or NULL if still open
+        error TEXT,
+        client_bytes INTEGER,
+        client_messages INTEGER,
+        server_bytes INTEGER,
+        server_messages INTEGER
+    );
+    CREATE TABLE events (
+        seq INTEGER PRIMARY KEY,
+        time TEXT,
+        conn INTEGER,
+        direction TEXT,
+        event TEXT NOT NULL,
+        fields TEXT NOT NULL        -- JSON, as written by --format=jsonl
+    );
+    CREATE TABLE messages (
+        seq INTEGER PRIMARY KEY,
+        time TEXT,
+        conn INTEGER NOT NULL,
+        direction TEXT NOT NULL,
+        kind TEXT NOT NULL,         -- 'message' or 'incomplete_message'
+        size INTEGER NOT NULL,
+        text TEXT,                  -- NULL if the body is binary
+        body BLOB NOT NULL
+    );
+";
+
+/// Writes the records of the `--format=jsonl` encoding into tables. Events
+/// and messages are numbered in the order they happened. A capture file is
+/// written in a single transaction, live traffic is committed as it comes.
+pub struct SqliteLog {
+    path: PathBuf,
+    db: Connection,
+    structured: Structured,
+    live: bool,
+    seq: u64,
+}
+
+impl SqliteLog {
+    /// Create the database, replacing the file if it exists.
+    pub fn create(path: &Path, live: bool) -> AResult<Self> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let db = Connection::open(path)?;
+        db.pragma_update(None, "journal_mode", "WAL")?;
+        db.pragma_update(None, "synchronous", "NORMAL")?;
+        db.execute_batch(SCHEMA)?;
+        if !live {
+            db.execute_batch("BEGIN")?;
+        }
+        Ok(SqliteLog {
+            path: path.to_owned(),
+            db,
+            structured: Structured::new(Format::JsonLines, Level::Messages, false, live),
+            live,
+            seq: 0,
+        })
+    }
+
+    pub fn handle(&mut self, event: &MapiEvent) -> AResult<()> {
+        for record in self.structured.records(event) {
+            self.insert(&record)
+                .with_context(|| format!("Could not write to {}", self.path.display()))?;
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, record: &Record) -> rusqlite::Result<()> {
+        self.seq += 1;
+        let time = record.timestamp.map(iso_time);
+        let conn = record.id.map(|id| id.number() as i64);
+        let direction = record.direction.map(direction_name);
+        let fields = &record.fields;
+
+        if let Some(data) = &record.data {
+            let mut stmt = self.db.prepare_cached(
+                "INSERT INTO messages(seq, time, conn, direction, kind, size, text, body)
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            )?;
+            let text = fields.get("text").and_then(Value::as_str);
+            stmt.execute(params![
+                self.seq,
+                time,
+                conn,
+                direction,
+                record.kind,
+                data.len(),
+                text,
+                data
+            ])?;
+            return Ok(());
+        }
+
+        match record.kind {
+            "incoming" => {
+                let mut stmt = self.db.prepare_cached(
+                    "INSERT INTO connections(conn, started, local, peer) VALUES (?, ?, ?, ?)",
+                )?;
+                stmt.execute(params![
+                    conn,
+                    time,
+                    fields["local"].as_str(),
+                    fields["peer"].as_str()
+                ])?;
+            }
+            "end" | "aborted" => {
+                let mut stmt = self.db.prepare_cached(
+                    "UPDATE connections
+                     SET ended = ?, outcome = ?, error = ?,
+                         client_bytes = ?, client_messages = ?,
+                         server_bytes = ?, server_messages = ?
+                     WHERE conn = ?",
+                )?;
+                stmt.execute(params![
+                    time,
+                    record.kind,
+                    fields.get("error").and_then(Value::as_str),
+                    fields["client"]["bytes"].as_u64(),
+                    fields["client"]["messages"].as_u64(),
+                    fields["server"]["bytes"].as_u64(),
+                    fields["server"]["messages"].as_u64(),
+                    conn
+                ])?;
+            }
+            _ => {}
+        }
+        let mut stmt = self.db.prepare_cached(
+            "INSERT INTO events(seq, time, conn, direction, event, fields)
+             VALUES (?, ?, ?, ?, ?, ?)",
+        )?;
+        stmt.execute(params![
+            self.seq,
+            time,
+            conn,
+            direction,
+            record.kind,
+            fields.to_string()
+        ])?;
+        Ok(())
+    }
+}
+
+impl Drop for SqliteLog {
+    fn drop(&mut self) {
+        if !self.live {
+            // Also after an error, to keep what was decoded before it
+            if let Err(e) = self.db.execute_batch("COMMIT") {
+                error!("Could not write to {}: {e}", self.path.display());
+            }
+        }
+    }
+}
@@ -0,0 +1,399 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    time::Duration,
+};
+
+use crate::{
+    latency::{millis, percentile, Latency},
+    proxy::{
+        counter::Counter,
+        event::{CaptureTime, ConnectionId, Direction, MapiEvent, Timing, Traffic, Volume},
+        network::Addr,
+    },
+    render::Renderer,
+};
+use tracing::warn;
+
+/// Collects a summary of each connection instead of dumping the traffic,
+/// see `--pcap-stats`.
+#[derive(Debug, Default)]
+pub struct Stats {
+    connections: BTreeMap<ConnectionId, ConnStats>,
+    /// Capture times of the first and the last data seen
+    span: Option<(CaptureTime, CaptureTime)>,
+    /// Connections and bytes skipped by the port filter or MAPI detection
+    ignored: Option<(u64, u64)>,
+}
+
+#[derive(Debug)]
+struct ConnStats {
+    local: Addr,
+    peer: Addr,
+    upstream: Counter,
+    downstream: ErrorCounter,
+    /// Set when the connection has ended
+    timing: Option<Timing>,
+}
+
+/// Counts the messages from the server that are error responses, that is,
+/// whose first byte is a '!'.
+#[derive(Debug)]
+struct ErrorCounter {
+    counter: Counter,
+    /// The block header and first byte of the current message
+    start: Vec<u8>,
+    errors: u64,
+}
+
+impl ErrorCounter {
+    fn new() -> Self {
+        ErrorCounter {
+            counter: Counter::new(false),
+            start: Vec::with_capacity(3),
+            errors: 0,
+        }
+    }
+
+    fn count(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.counter.at_message_start() {
+                self.start.clear();
+            }
+            let n = self.counter.advance(data);
+            if self.start.len() < 3 {
+                let wanted = (3 - self.start.len()).min(n);
+                self.start.extend_from_slice(&data[..wanted]);
+                if self.start.len() == 3 && self.start[2] == b'!' {
+                    self.errors += 1;
+                }
+            }
+            data = &data[n..];
+        }
+    }
+}
+
+impl Stats {
+    pub fn handle(&mut self, event: &MapiEvent) {
+        match event {
+            MapiEvent::Incoming { id, local, peer } => {
+                let conn = ConnStats {
+                    local: local.clone(),
+                    peer: peer.clone(),
+                    upstream: Counter::new(false),
+                    downstream: ErrorCounter::new(),
+                    timing: None,
+                };
+                self.connections.insert(*id, conn);
+            }
+            MapiEvent::Data {
+                id,
+                direction,
+                data,
+                timestamp,
+            } => {
+                if let Some(ts) = *timestamp {
+                    let (first, _) = self.span.get_or_insert((ts, ts));
+                    self.span = Some((*first, ts));
+                }
+                if let Some(conn) = self.connections.get_mut(id) {
+                    match direction {
+                        Direction::Upstream => conn.upstream.count(data),
+                        Direction::Downstream => conn.downstream.count(data),
+                    }
+                }
+            }
+            MapiEvent::DataMissing { id, direction, .. } => {
+                if let Some(conn) = self.connections.get_mut(id) {
+                    match direction {
+                        Direction::Upstream => conn.upstream.resync(),
+                        Direction::Downstream => conn.downstream.counter.resync(),
+                    }
+                }
+            }
+            MapiEvent::Ignored { connections, bytes } => {
+                self.ignored = Some((*connections, *bytes));
+            }
+            MapiEvent::End { id, timing, .. } | MapiEvent::Aborted { id, timing, .. } => {
+                if let Some(conn) = self.connections.get_mut(id) {
+                    conn.timing = Some(*timing);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Print a line for each connection followed by the totals.
+    pub fn report(&self, renderer: &mut Renderer) -> io::Result<()> {
+        let mut traffic = Traffic::default();
+        let mut errors = 0;
+        for (id, conn) in &self.connections {
+            let client = conn.upstream.volume();
+            let server = conn.downstream.counter.volume();
+            traffic = traffic
+                + Traffic {
+                    upstream: client,
+                    downstream: server,
+                };
+            errors += conn.downstream.errors;
+            let timing = match &conn.timing {
+                Some(timing) => format!("ended {timing}"),
+                None => "still open".to_string(),
+            };
+            let ConnStats { local, peer, .. } = conn;
+            let n = conn.downstream.errors;
+            let s = if n == 1 { "" } else { "s" };
+            renderer.message(
+                Some(*id),
+                None,
+                format_args!("{peer} to {local}, {timing}; client sent {client}, server sent {server}, {n} error{s}"),
+            )?;
+        }
+
+        let connections = self.connections.len();
+        let s = if connections == 1 { "" } else { "s" };
+        let span = match self.span {
+            Some((first, last)) => {
+                let (first, last) = (renderer.capture_time(first), renderer.capture_time(last));
+                format!(" from {first} to {last}")
+            }
+            None => String::new(),
+        };
+        let Traffic {
+            upstream: client,
+            downstream: server,
+        } = traffic;
+        let e = if errors == 1 { "" } else { "s" };
+        renderer.message(
+            None,
+            None,
+            format_args!("TOTAL: {connections} connection{s}{span}; client sent {client}, server sent {server}, {errors} error{e}"),
+        )?;
+        if let Some((connections, bytes)) = self.ignored {
+            let s = if connections == 1 { "" } else { "s" };
+            renderer.message(
+                None,
+                None,
+                format_args!("IGNORED: {connections} connection{s} with {bytes} bytes"),
+            )?;
+        }
+        renderer.flush()
+    }
+}
+
+/// Number of clients listed by [Totals::report]
+const TOP_TALKERS: usize = 5;
+
+/// Collects the totals of a whole run, printed when the proxy stops or the
+/// capture ends, see `--stats`. Unlike [Stats] this keeps no state for
+/// connections that have ended.
+#[derive(Debug, Default)]
+pub struct Totals {
+    open: HashMap<ConnectionId, ConnTotals>,
+    connections: u64,
+    aborted: u64,
+    connect_failures: u64,
+    client: Volume,
+    server: Volume,
+    /// Messages from the server that are error responses
+    errors: u64,
+    latency: Latency,
+    /// Connections and bytes in both directions per client host
+    talkers: HashMap<String, (u64, u64)>,
+}
+
+#[derive(Debug)]
+struct ConnTotals {
+    host: String,
+    upstream: Counter,
+    downstream: ErrorCounter,
+}
+
+impl Totals {
+    /// See [Latency::new] for `live`.
+    pub fn new(live: bool) -> Self {
+        Totals {
+            latency: Latency::new(live),
+            ..Totals::default()
+        }
+    }
+
+    pub fn handle(&mut self, event: &MapiEvent) {
+        // Only collects, never renders
+        let _ = self.latency.handle(event, None);
+        match event {
+            MapiEvent::Incoming { id, peer, .. } => {
+                let host = match peer {
+                    Addr::Tcp(addr) => addr.ip().to_string(),
+                    Addr::Unix(_) => peer.to_string(),
+                };
+                self.talkers.entry(host.clone()).or_default().0 += 1;
+                let conn = ConnTotals {
+                    host,
+                    upstream: Counter::new(peer.is_unix()),
+                    downstream: ErrorCounter::new(),
+                };
+                self.open.insert(*id, conn);
+                self.connections += 1;
+            }
+            MapiEvent::ConnectFailed { .. } => self.connect_failures += 1,
+            MapiEvent::Data {
+                id,
+                direction,
+                data,
+                ..
+            } => {
+                if let Some(conn) = self.open.get_mut(id) {
+                    match direction {
+                        Direction::Upstream => conn.upstream.count(data),
+                        Direction::Downstream => conn.downstream.count(data),
+                    }
+                    if let Some((_, bytes)) = self.talkers.get_mut(&conn.host) {
+                        *bytes += data.len() as u64;
+                    }
+                }
+            }
+            MapiEvent::DataMissing { id, direction, .. } => {
+                if let Some(conn) = self.open.get_mut(id) {
+                    match direction {
+                        Direction::Upstream => conn.upstream.resync(),
+                        Direction::Downstream => conn.downstream.counter.resync(),
+                    }
+                }
+            }
+            MapiEvent::End { id, .. } | MapiEvent::Aborted { id, .. } => {
+                if let MapiEvent::Aborted { .. } = event {
+                    self.aborted += 1;
+                }
+                if let Some(conn) = self.open.remove(id) {
+                    self.add(&conn);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn add(&mut self, conn: &ConnTotals) {
+        self.client = self.client + conn.upstream.volume();
+        self.server = self.server + conn.downstream.counter.volume();
+        self.errors += conn.downstream.errors;
+    }
+
+    /// Print the totals, counting the connections that are still open as
+    /// far as they got.
+    pub fn report(&mut self, renderer: &mut Renderer) -> io::Result<()> {
+        for conn in std::mem::take(&mut self.open).into_values() {
+            self.add(&conn);
+        }
+
+        let Totals {
+            connections,
+            aborted,
+            connect_failures,
+            client,
+            server,
+            errors,
+            ..
+        } = *self;
+        let s = if connections == 1 { "" } else { "s" };
+        let f = if connect_failures == 1 { "" } else { "s" };
+        let e = if errors == 1 { "" } else { "s" };
+        renderer.message(
+            None,
+            None,
+            format_args!("STATS: {connections} connection{s}, {aborted} aborted, {connect_failures} failed connect{f}, {errors} error response{e}"),
+        )?;
+        renderer.message(
+            None,
+            None,
+            format_args!("STATS: client sent {client}, server sent {server}"),
+        )?;
+
+        let samples = self.latency.samples();
+        if !samples.is_empty() {
+            let n = samples.len();
+            let s = if n == 1 { "" } else { "s" };
+            let average = samples.iter().sum::<Duration>() / n as u32;
+            let p95 = percentile(samples, 95.0);
+            renderer.message(
+                None,
+                None,
+                format_args!(
+                    "STATS: {n} request{s}, average latency {}, p95 {}",
+                    millis(average),
+                    millis(p95)
+                ),
+            )?;
+        }
+
+        let mut talkers: Vec<_> = self.talkers.iter().collect();
+        talkers.sort_by(|(a, (_, x)), (b, (_, y))| y.cmp(x).then(a.cmp(b)));
+        for (host, (connections, bytes)) in talkers.into_iter().take(TOP_TALKERS) {
+            let s = if *connections == 1 { "" } else { "s" };
+            renderer.message(
+                None,
+                None,
+                format_args!(
+                    "STATS: top client {host}, {connections} connection{s}, {bytes} bytes"
+                ),
+            )?;
+        }
+        renderer.flush()
+    }
+}
+
+/// Stops the capture once a number of bytes of payload has been seen in
+/// both directions together, see `--limit-bytes`.
+pub struct ByteLimit {
+    limit: u64,
+    seen: u64,
+    reached: bool,
+    /// Whether to stop the proxy too, see `--limit-exit`
+    exit: bool,
+    shutdown_trigger: Option<Box<dyn Fn() + Send + Sync>>,
+}
+
+impl ByteLimit {
+    pub fn new(limit: u64, exit: bool) -> Self {
+        ByteLimit {
+            limit,
+            seen: 0,
+            reached: false,
+            exit,
+            shutdown_trigger: None,
+        }
+    }
+
+    /// With `--limit-exit`, the trigger is called when the limit is reached.
+    pub fn set_shutdown_trigger(&mut self, trigger: Box<dyn Fn() + Send + Sync>) {
+        if self.exit {
+            self.shutdown_trigger = Some(trigger);
+        }
+    }
+
+    /// Whether the event should still be captured. The first chunk of data
+    /// that does not fit stops the capture for good, so no more than the
+    /// limit is ever written.
+    pub fn admits(&mut self, event: &MapiEvent) -> bool {
+        if self.reached {
+            return false;
+        }
+        let MapiEvent::Data { data, .. } = event else {
+            return true;
+        };
+        let seen = self.seen + data.len() as u64;
+        if seen <= self.limit {
+            self.seen = seen;
+            return true;
+        }
+        self.reached = true;
+        warn!(
+            "stopped capturing after {} bytes of payload, see --limit-bytes",
+            self.seen
+        );
+        if let Some(trigger) = &self.shutdown_trigger {
+            trigger();
+        }
+        false
+    }
+}
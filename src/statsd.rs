@@ -0,0 +1,180 @@
+//! Send counters to a StatsD server, for example Telegraf or the Datadog
+//! agent, see `--statsd`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io, mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{Arc, Mutex, Weak},
+    thread,
+    time::Duration,
+};
+
+use crate::proxy::{
+    counter::Counter,
+    event::{ConnectionId, Direction, MapiEvent},
+};
+
+/// How often the metrics are sent
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Stay below the typical MTU, StatsD servers don't reassemble
+const MAX_PACKET: usize = 1400;
+
+/// Counts bytes, messages and connections and sends them every [INTERVAL]
+/// as StatsD metrics named `mapiproxy.*`:
+///
+/// - counters `connections`, `connections.ended` and `connections.aborted`,
+/// - counter `connect_failures`, the attempts to connect to a server address
+///   that failed, whichever address it was,
+/// - counters `bytes.client`, `bytes.server`, `messages.client` and
+///   `messages.server`, for the data sent by the client and by the server,
+/// - gauge `connections.open`.
+pub struct Statsd {
+    shared: Arc<Mutex<Pending>>,
+    counters: HashMap<ConnectionId, [Counter; 2]>,
+}
+
+struct Pending {
+    socket: UdpSocket,
+    counts: BTreeMap<&'static str, u64>,
+    open: usize,
+    /// Whether `open` changed since the last time it was sent
+    open_changed: bool,
+}
+
+impl Statsd {
+    /// Send metrics to `addr`, given as HOST:PORT. If it resolves to several
+    /// addresses, the first one is used.
+    pub fn new(addr: &str) -> io::Result<Self> {
+        let Some(target) = addr.to_socket_addrs()?.next() else {
+            return Err(io::ErrorKind::NotFound.into());
+        };
+        let local: SocketAddr = match target {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(target)?;
+        let pending = Pending {
+            socket,
+            counts: BTreeMap::new(),
+            open: 0,
+            open_changed: false,
+        };
+        let shared = Arc::new(Mutex::new(pending));
+        let weak = Arc::downgrade(&shared);
+        thread::spawn(move || send_periodically(weak));
+        Ok(Statsd {
+            shared,
+            counters: HashMap::new(),
+        })
+    }
+
+    pub fn handle(&mut self, event: &MapiEvent) {
+        let mut pending = self.shared.lock().unwrap();
+        match event {
+            MapiEvent::Incoming { id, peer, .. } => {
+                let counters = [Counter::new(peer.is_unix()), Counter::new(false)];
+                self.counters.insert(*id, counters);
+                pending.add("connections", 1);
+                pending.set_open(self.counters.len());
+            }
+            MapiEvent::ConnectFailed { .. } => pending.add("connect_failures", 1),
+            MapiEvent::End { id, .. } | MapiEvent::Aborted { id, .. } => {
+                if let MapiEvent::End { .. } = event {
+                    pending.add("connections.ended", 1);
+                } else {
+                    pending.add("connections.aborted", 1);
+                }
+                self.counters.remove(id);
+                pending.set_open(self.counters.len());
+            }
+            MapiEvent::Data {
+                id,
+                direction,
+                data,
+                ..
+            } => {
+                let (bytes, messages, index) = match direction {
+                    Direction::Upstream => ("bytes.client", "messages.client", 0),
+                    Direction::Downstream => ("bytes.server", "messages.server", 1),
+                };
+                pending.add(bytes, data.len() as u64);
+                if let Some(counters) = self.counters.get_mut(id) {
+                    let counter = &mut counters[index];
+                    let before = counter.volume().messages.unwrap_or(0);
+                    counter.count(data);
+                    let after = counter.volume().messages.unwrap_or(0);
+                    pending.add(messages, after - before);
+                }
+            }
+            MapiEvent::DataMissing { id, direction, .. } => {
+                if let Some(counters) = self.counters.get_mut(id) {
+                    let index = match direction {
+                        Direction::Upstream => 0,
+                        Direction::Downstream => 1,
+                    };
+                    counters[index].resync();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Drop for Statsd {
+    fn drop(&mut self) {
+        // Don't lose the counts of the last interval
+        self.shared.lock().unwrap().send();
+    }
+}
+
+impl Pending {
+    fn add(&mut self, name: &'static str, n: u64) {
+        if n > 0 {
+            *self.counts.entry(name).or_default() += n;
+        }
+    }
+
+    fn set_open(&mut self, open: usize) {
+        self.open = open;
+        self.open_changed = true;
+    }
+
+    /// Send what has been counted since the last time. Errors are ignored,
+    /// like UDP would lose the packets anyway if the server isn't there.
+    fn send(&mut self) {
+        let mut lines: Vec<String> = mem::take(&mut self.counts)
+            .into_iter()
+            .map(|(name, n)| format!("mapiproxy.{name}:{n}|c"))
+            .collect();
+        if mem::take(&mut self.open_changed) {
+            lines.push(format!("mapiproxy.connections.open:{}|g", self.open));
+        }
+        let mut packet = String::new();
+        for line in lines {
+            if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET {
+                let _ = self.socket.send(packet.as_bytes());
+                packet.clear();
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            packet.push_str(&line);
+        }
+        if !packet.is_empty() {
+            let _ = self.socket.send(packet.as_bytes());
+        }
+    }
+}
+
+fn send_periodically(pending: Weak<Mutex<Pending>>) {
+    loop {
+        thread::sleep(INTERVAL);
+        let Some(pending) = pending.upgrade() else {
+            return;
+        };
+        pending.lock().unwrap().send();
+    }
+}
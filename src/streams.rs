@@ -0,0 +1,178 @@
+//! Decode the bytes sent by a client and by a server that were saved in two
+//! separate files, see `--streams`. Such files can be made with `--tee`,
+//! strace or socat, also for Unix Domain socket traffic that tcpdump cannot
+//! capture.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+};
+
+use anyhow::Result as AResult;
+
+use crate::{
+    pcap::looks_like_challenge,
+    proxy::{
+        counter::Counter,
+        event::{ConnectionId, Direction, MapiEvent, Timing, Traffic},
+        network::Addr,
+    },
+};
+
+/// Emit the events of connection `id` in which the client sent
+/// `client_data` and the server sent `server_data`.
+///
+/// The files don't tell us in which order the messages were sent. The
+/// server speaks first and answers each message of the client with one
+/// message, except that after a redirect it may send a new login challenge
+/// without waiting for the client.
+pub fn decode_streams(
+    id: ConnectionId,
+    client: (&Path, &[u8]),
+    server: (&Path, &[u8]),
+    mut handler: impl FnMut(MapiEvent) -> AResult<()>,
+) -> AResult<()> {
+    let (client_path, client_data) = client;
+    let (server_path, server_data) = server;
+
+    // Clients send a '0' before anything else on Unix Domain sockets
+    let unix = client_data.first() == Some(&b'0');
+    let (local, peer) = if unix {
+        (
+            Addr::Unix(server_path.into()),
+            Addr::Unix(client_path.into()),
+        )
+    } else {
+        // We don't know the addresses
+        let unknown = Addr::from(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+        (unknown.clone(), unknown)
+    };
+
+    handler(MapiEvent::Incoming {
+        id,
+        local: local.clone(),
+        peer,
+    })?;
+    handler(MapiEvent::Connected { id, peer: local })?;
+
+    let mut upstream = Counter::new(unix);
+    let mut downstream = Counter::new(false);
+    let mut client_messages = Messages::new(client_data, &mut upstream).peekable();
+    let mut server_messages = Messages::new(server_data, &mut downstream).peekable();
+    let mut server_turn = true;
+    loop {
+        let direction = match (client_messages.peek(), server_messages.peek()) {
+            (None, None) => break,
+            (Some(_), None) => Direction::Upstream,
+            (None, Some(_)) => Direction::Downstream,
+            (Some(_), Some(message)) => {
+                if server_turn || looks_like_challenge(message) == Some(true) {
+                    Direction::Downstream
+                } else {
+                    Direction::Upstream
+                }
+            }
+        };
+        let messages = match direction {
+            Direction::Upstream => &mut client_messages,
+            Direction::Downstream => &mut server_messages,
+        };
+        let data = messages.next().unwrap();
+        server_turn = direction == Direction::Upstream;
+        handler(MapiEvent::Data {
+            id,
+            direction,
+            data: data.into(),
+            timestamp: None,
+        })?;
+    }
+
+    for direction in [Direction::Upstream, Direction::Downstream] {
+        handler(MapiEvent::ShutdownRead { id, direction })?;
+    }
+    let traffic = Traffic {
+        upstream: upstream.volume(),
+        downstream: downstream.volume(),
+    };
+    handler(MapiEvent::End {
+        id,
+        traffic,
+        timing: Timing::default(),
+    })
+}
+
+/// Splits a byte stream into MAPI messages. The last one may be incomplete.
+struct Messages<'a> {
+    data: &'a [u8],
+    counter: &'a mut Counter,
+}
+
+impl<'a> Messages<'a> {
+    fn new(data: &'a [u8], counter: &'a mut Counter) -> Self {
+        Messages { data, counter }
+    }
+}
+
+impl<'a> Iterator for Messages<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let n = self.counter.advance(self.data);
+        let (message, rest) = self.data.split_at(n);
+        self.data = rest;
+        Some(message)
+    }
+}
+
+#[test]
+fn test_decode_streams() {
+    let block = |body: &[u8]| {
+        let mut data = ((body.len() * 2 + 1) as u16).to_le_bytes().to_vec();
+        data.extend_from_slice(body);
+        data
+    };
+    let challenge = block(b"abcd:mserver:9:RIPEMD160,SHA512:LIT:SHA512:\n");
+    let login = block(b"LIT:monetdb:{SHA512}xx:sql:demo:\n");
+    let server = [
+        challenge.clone(),
+        block(b"^mapi:merovingian://proxy?database=demo\n"),
+        challenge,
+        block(b""),
+        block(b"&1 0 1 1 1\n"),
+    ]
+    .concat();
+    let client = [b"0".to_vec(), login.clone(), login, block(b"sSELECT 2;\n")].concat();
+
+    let mut events = vec![];
+    let handler = |ev: MapiEvent| {
+        events.push(ev);
+        Ok(())
+    };
+    let path = Path::new("x");
+    decode_streams(
+        ConnectionId::new(10),
+        (path, &client),
+        (path, &server),
+        handler,
+    )
+    .unwrap();
+
+    use Direction::*;
+    let directions: Vec<Direction> = events
+        .iter()
+        .filter_map(|ev| match ev {
+            MapiEvent::Data { direction, .. } => Some(*direction),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        directions,
+        [
+            Downstream, Upstream, Downstream, Downstream, Upstream, Downstream, Upstream,
+            Downstream
+        ]
+    );
+}
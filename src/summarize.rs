@@ -0,0 +1,293 @@
+//! Give an overview of the workload in a capture, see `mapiproxy summarize`.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs::File,
+    io,
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{Context, Result as AResult};
+
+use crate::{
+    latency::millis,
+    mapi::Structured,
+    parquet::fingerprint,
+    pcap::{self, Tracker},
+    proxy::{
+        event::{CaptureTime, ConnectionId, Direction, MapiEvent},
+        network::Addr,
+    },
+    render::Renderer,
+    Format, Level,
+};
+
+/// Queries are cut off after this many characters in the report
+const MAX_QUERY_LEN: usize = 80;
+
+/// What the queries are ranked by, highest first
+type RankKey = fn(&QueryStats) -> u128;
+
+/// The orders in which the queries are listed
+const RANKINGS: [(&str, RankKey); 3] = [
+    ("BY COUNT", |s| s.count.into()),
+    ("BY LATENCY", |s| s.latency.as_nanos()),
+    ("BY BYTES", |s| s.bytes.into()),
+];
+
+/// Number of queries in each ranking unless `--top` says otherwise
+pub const DEFAULT_TOP: usize = 10;
+
+/// The queries in a capture grouped by their [fingerprint], and what each
+/// connection did. Responses are paired with requests like `--latency`
+/// does.
+#[derive(Debug, Default)]
+pub struct Summary {
+    queries: HashMap<String, QueryStats>,
+    connections: BTreeMap<ConnectionId, ConnSummary>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct QueryStats {
+    count: u64,
+    /// Sum of the round trip times of the queries that were answered
+    latency: Duration,
+    /// Sizes of the queries and their responses
+    bytes: u64,
+}
+
+#[derive(Debug)]
+struct ConnSummary {
+    peer: Addr,
+    queries: u64,
+    latency: Duration,
+    client_bytes: u64,
+    server_bytes: u64,
+    errors: u64,
+    /// The requests that have not been answered yet, with their capture time
+    /// and, for queries, their fingerprint
+    unanswered: VecDeque<(Option<CaptureTime>, Option<String>)>,
+}
+
+impl Summary {
+    /// Read the traffic in a capture.
+    pub fn read(path: &Path, options: &pcap::Options) -> AResult<Summary> {
+        let file = File::open(path)
+            .with_context(|| format!("Could not open pcap file {}", path.display()))?;
+        let mut summary = Summary::default();
+        let mut structured = Structured::new(Format::JsonLines, Level::Messages, false, false);
+        let handler = |ev: MapiEvent| {
+            summary.handle_event(&ev);
+            for record in structured.records(&ev) {
+                if let (Some(data), "message", Some(id), Some(direction)) =
+                    (&record.data, record.kind, record.id, record.direction)
+                {
+                    summary.add_message(id, direction, record.timestamp, data);
+                }
+            }
+            Ok(())
+        };
+        let mut tracker = Tracker::new(options.clone(), handler);
+        pcap::parse_pcap_file(file, &mut tracker)?;
+        drop(tracker);
+        Ok(summary)
+    }
+
+    fn handle_event(&mut self, event: &MapiEvent) {
+        match event {
+            MapiEvent::Incoming { id, peer, .. } => {
+                let conn = ConnSummary {
+                    peer: peer.clone(),
+                    queries: 0,
+                    latency: Duration::ZERO,
+                    client_bytes: 0,
+                    server_bytes: 0,
+                    errors: 0,
+                    unanswered: VecDeque::new(),
+                };
+                self.connections.insert(*id, conn);
+            }
+            MapiEvent::DataMissing { id, .. } => {
+                // We can no longer tell which response belongs to which
+                // request
+                if let Some(conn) = self.connections.get_mut(id) {
+                    conn.unanswered.clear();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn add_message(
+        &mut self,
+        id: ConnectionId,
+        direction: Direction,
+        timestamp: Option<CaptureTime>,
+        data: &[u8],
+    ) {
+        let Some(conn) = self.connections.get_mut(&id) else {
+            return;
+        };
+        let size = data.len() as u64;
+        match direction {
+            Direction::Upstream => {
+                conn.client_bytes += size;
+                let mut query = None;
+                if let Some(text) = data.strip_prefix(b"s") {
+                    let key = fingerprint(&String::from_utf8_lossy(text));
+                    let stats = self.queries.entry(key.clone()).or_default();
+                    stats.count += 1;
+                    stats.bytes += size;
+                    conn.queries += 1;
+                    query = Some(key);
+                }
+                conn.unanswered.push_back((timestamp, query));
+            }
+            Direction::Downstream => {
+                conn.server_bytes += size;
+                if data.starts_with(b"!") {
+                    conn.errors += 1;
+                }
+                // Responses without a request, such as the login challenge,
+                // are not counted
+                let Some((sent, query)) = conn.unanswered.pop_front() else {
+                    return;
+                };
+                let latency = match (sent, timestamp) {
+                    (Some(sent), Some(received)) => received.0.saturating_sub(sent.0),
+                    _ => Duration::ZERO,
+                };
+                let Some(stats) = query.and_then(|q| self.queries.get_mut(&q)) else {
+                    return;
+                };
+                stats.latency += latency;
+                stats.bytes += size;
+                conn.latency += latency;
+            }
+        }
+    }
+
+    /// The `top` queries with the highest `key`, most first, with ties in
+    /// alphabetical order.
+    fn top<K: Ord>(&self, top: usize, key: impl Fn(&QueryStats) -> K) -> Vec<(&str, QueryStats)> {
+        let mut queries: Vec<(&str, QueryStats)> =
+            self.queries.iter().map(|(q, s)| (q.as_str(), *s)).collect();
+        queries.sort_by(|(qa, a), (qb, b)| key(b).cmp(&key(a)).then(qa.cmp(qb)));
+        queries.truncate(top);
+        queries
+    }
+
+    /// Print the `top` queries by count, by total latency and by bytes,
+    /// followed by a line for each connection.
+    pub fn report(&self, top: usize, renderer: &mut Renderer) -> io::Result<()> {
+        for (label, key) in RANKINGS {
+            for (query, stats) in self.top(top, key) {
+                let QueryStats {
+                    count,
+                    latency,
+                    bytes,
+                } = stats;
+                let latency = millis(latency);
+                let query = shorten(query);
+                renderer.message(
+                    None,
+                    None,
+                    format_args!("{label}: {count}x, {latency}, {bytes} bytes: {query}"),
+                )?;
+            }
+        }
+
+        for (id, conn) in &self.connections {
+            let ConnSummary {
+                peer,
+                queries,
+                client_bytes,
+                server_bytes,
+                errors,
+                ..
+            } = conn;
+            let q = if *queries == 1 { "query" } else { "queries" };
+            let e = if *errors == 1 { "" } else { "s" };
+            let latency = millis(conn.latency);
+            renderer.message(
+                Some(*id),
+                None,
+                format_args!("{peer}, {queries} {q}, latency {latency}; client sent {client_bytes} bytes, server sent {server_bytes} bytes, {errors} error{e}"),
+            )?;
+        }
+
+        let queries: u64 = self.queries.values().map(|s| s.count).sum();
+        let distinct = self.queries.len();
+        let connections = self.connections.len();
+        let c = if connections == 1 { "" } else { "s" };
+        let q = if queries == 1 { "query" } else { "queries" };
+        renderer.message(
+            None,
+            None,
+            format_args!("TOTAL: {connections} connection{c}, {queries} {q}, {distinct} distinct"),
+        )?;
+        renderer.flush()
+    }
+}
+
+fn shorten(text: &str) -> String {
+    match text.char_indices().nth(MAX_QUERY_LEN) {
+        Some((pos, _)) => format!("{}...", &text[..pos]),
+        None => text.to_string(),
+    }
+}
+
+/// Summarize the capture in `path`, listing the `top` queries in each
+/// ranking.
+pub fn run_summarize(
+    path: &Path,
+    top: usize,
+    options: &pcap::Options,
+    renderer: &mut Renderer,
+) -> AResult<()> {
+    let summary = Summary::read(path, options)?;
+    summary.report(top, renderer)?;
+    Ok(())
+}
+
+#[test]
+fn test_summary() {
+    let mut summary = Summary::default();
+    let id = ConnectionId::new(10);
+    let local = Addr::Tcp("[::1]:50000".parse().unwrap());
+    let peer = Addr::Tcp("[::1]:41394".parse().unwrap());
+    summary.handle_event(&MapiEvent::Incoming { id, local, peer });
+    let at = |ms| Some(CaptureTime(Duration::from_millis(ms)));
+
+    summary.add_message(id, Direction::Downstream, at(0), b"challenge:mserver:9:");
+    summary.add_message(
+        id,
+        Direction::Upstream,
+        at(1),
+        b"BIG:monetdb:{SHA512}12ab:sql:demo:",
+    );
+    summary.add_message(id, Direction::Downstream, at(2), b"");
+    for (n, (sent, received)) in [(3, 5), (10, 11), (20, 30)].into_iter().enumerate() {
+        let query = format!("sSELECT * FROM t WHERE i = {n};\n");
+        summary.add_message(id, Direction::Upstream, at(sent), query.as_bytes());
+        summary.add_message(id, Direction::Downstream, at(received), b"&1 0 1 1 1\n");
+    }
+    summary.add_message(id, Direction::Upstream, at(40), b"sDROP TABLE t;\n");
+    summary.add_message(id, Direction::Downstream, at(60), b"!42000!no such table\n");
+
+    let by_count = summary.top(10, |s| s.count);
+    assert_eq!(by_count.len(), 2);
+    let (query, stats) = by_count[0];
+    assert_eq!(query, "SELECT * FROM t WHERE i = ?;");
+    assert_eq!(stats.count, 3);
+    assert_eq!(stats.latency, Duration::from_millis(13));
+    assert_eq!(stats.bytes, 3 * (30 + 11));
+    let by_latency = summary.top(1, |s| s.latency);
+    assert_eq!(by_latency[0].0, "DROP TABLE t;");
+
+    let conn = &summary.connections[&id];
+    assert_eq!(conn.queries, 4);
+    assert_eq!(conn.latency, Duration::from_millis(33));
+    assert_eq!(conn.errors, 1);
+}
@@ -0,0 +1,87 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result as AResult};
+
+use crate::proxy::event::{ConnectionId, Direction, MapiEvent};
+
+/// Writes the bytes flowing in each direction of each connection to
+/// separate files connN.up.bin and connN.down.bin in a directory.
+pub struct Tee {
+    dir: PathBuf,
+    connections: HashMap<ConnectionId, [BufWriter<File>; 2]>,
+}
+
+impl Tee {
+    pub fn new(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Tee {
+            dir: dir.to_owned(),
+            connections: Default::default(),
+        })
+    }
+
+    pub fn handle(&mut self, event: &MapiEvent) -> AResult<()> {
+        match event {
+            MapiEvent::Incoming { id, .. } => {
+                let up = self.create(*id, Direction::Upstream)?;
+                let down = self.create(*id, Direction::Downstream)?;
+                self.connections.insert(*id, [up, down]);
+            }
+            MapiEvent::Data {
+                id,
+                direction,
+                data,
+                ..
+            } => {
+                if let Some([up, down]) = self.connections.get_mut(id) {
+                    let file = match direction {
+                        Direction::Upstream => up,
+                        Direction::Downstream => down,
+                    };
+                    file.write_all(data)
+                        .with_context(|| self.error_message(*id, *direction))?;
+                }
+            }
+            MapiEvent::End { id, .. } | MapiEvent::Aborted { id, .. } => {
+                if let Some(files) = self.connections.remove(id) {
+                    for (file, direction) in files.into_iter().zip(DIRECTIONS) {
+                        file.into_inner()
+                            .map_err(|e| e.into_error())
+                            .with_context(|| self.error_message(*id, direction))?;
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn create(&self, id: ConnectionId, direction: Direction) -> AResult<BufWriter<File>> {
+        let path = self.path(id, direction);
+        let file = File::create(&path)
+            .with_context(|| format!("Could not create tee file {}", path.display()))?;
+        Ok(BufWriter::new(file))
+    }
+
+    fn path(&self, id: ConnectionId, direction: Direction) -> PathBuf {
+        let suffix = match direction {
+            Direction::Upstream => "up",
+            Direction::Downstream => "down",
+        };
+        self.dir.join(format!("conn{}.{suffix}.bin", id.number()))
+    }
+
+    fn error_message(&self, id: ConnectionId, direction: Direction) -> String {
+        format!(
+            "Could not write tee file {}",
+            self.path(id, direction).display()
+        )
+    }
+}
+
+const DIRECTIONS: [Direction; 2] = [Direction::Upstream, Direction::Downstream];
@@ -0,0 +1,208 @@
+//! Serve a small web page that shows the events as they happen, see `--web`.
+//! The page receives the events over a WebSocket in the same JSON as
+//! `--format=jsonl`.
+
+use std::{
+    io::{self, BufRead, BufReader, Cursor, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use tungstenite::{
+    handshake::server::{ErrorResponse, Request, Response},
+    http::{HeaderMap, StatusCode},
+    Message,
+};
+
+use crate::{mapi::Structured, proxy::event::MapiEvent, Format, Level};
+
+const PAGE: &str = include_str!("web.html");
+
+/// Number of events a viewer can be behind before it is disconnected
+const QUEUE_SIZE: usize = 10_000;
+
+/// Longest request line we accept
+const MAX_REQUEST_LINE: u64 = 8192;
+
+/// Sends the events to the browsers that are watching.
+pub struct Web {
+    structured: Structured,
+    viewers: Arc<Mutex<Vec<SyncSender<String>>>>,
+}
+
+impl Web {
+    /// Listen on `addr` and serve the page and the events in the background.
+    pub fn start(addr: SocketAddr, level: Level, force_binary: bool) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let viewers = Arc::new(Mutex::new(vec![]));
+        let viewers2 = Arc::clone(&viewers);
+        thread::spawn(move || {
+            for conn in listener.incoming().flatten() {
+                let viewers = Arc::clone(&viewers2);
+                thread::spawn(move || {
+                    // The browser will show that it lost the connection
+                    let _ = serve(conn, &viewers);
+                });
+            }
+        });
+        Ok(Web {
+            structured: Structured::new(Format::JsonLines, level, force_binary, true),
+            viewers,
+        })
+    }
+
+    pub fn handle(&mut self, event: &MapiEvent) {
+        let mut viewers = self.viewers.lock().unwrap();
+        if viewers.is_empty() {
+            // Still keep track of the connections, for when one shows up
+            self.structured.records(event);
+            return;
+        }
+        for record in self.structured.records(event) {
+            let json = record.to_json().to_string();
+            // Drop the viewers that have gone away or can't keep up
+            viewers.retain(|viewer| match viewer.try_send(json.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) => false,
+            });
+        }
+    }
+}
+
+/// Serve the page, or the events if the browser asks for /events.
+fn serve(conn: TcpStream, viewers: &Mutex<Vec<SyncSender<String>>>) -> io::Result<()> {
+    let mut reader = BufReader::new(conn);
+    let mut line = vec![];
+    reader
+        .by_ref()
+        .take(MAX_REQUEST_LINE)
+        .read_until(b'\n', &mut line)?;
+    if !line.starts_with(b"GET /events ") {
+        return serve_page(reader);
+    }
+
+    // The WebSocket handshake needs the whole request, including what we
+    // have read already
+    line.extend_from_slice(reader.buffer());
+    let conn = Replayed {
+        start: Cursor::new(line),
+        conn: reader.into_inner(),
+    };
+    let mut socket = tungstenite::accept_hdr(conn, check_origin).map_err(io::Error::other)?;
+    let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+    viewers.lock().unwrap().push(tx);
+    for json in rx {
+        socket.send(Message::Text(json)).map_err(io::Error::other)?;
+    }
+    // We couldn't keep up
+    let _ = socket.close(None);
+    Ok(())
+}
+
+/// Refuse the WebSocket handshake if the page is not ours, see
+/// [same_origin].
+// The error type is the one tungstenite wants
+#[allow(clippy::result_large_err)]
+fn check_origin(request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+    if same_origin(request.headers()) {
+        return Ok(response);
+    }
+    let mut error = ErrorResponse::new(Some("cross-origin request refused".into()));
+    *error.status_mut() = StatusCode::FORBIDDEN;
+    Err(error)
+}
+
+/// Whether the page that opens the WebSocket was served by us. Otherwise any
+/// page open in the browser could watch the traffic. Clients that are not
+/// browsers send no Origin.
+fn same_origin(headers: &HeaderMap) -> bool {
+    let Some(origin) = headers.get("origin") else {
+        return true;
+    };
+    let Some(host) = headers.get("host") else {
+        return false;
+    };
+    let (Ok(origin), Ok(host)) = (origin.to_str(), host.to_str()) else {
+        return false;
+    };
+    let authority = origin
+        .strip_prefix("http://")
+        .or_else(|| origin.strip_prefix("https://"));
+    authority.is_some_and(|a| a.eq_ignore_ascii_case(host))
+}
+
+/// A connection of which the start has already been read
+struct Replayed {
+    start: Cursor<Vec<u8>>,
+    conn: TcpStream,
+}
+
+impl Read for Replayed {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.start.read(buf)? {
+            0 => self.conn.read(buf),
+            n => Ok(n),
+        }
+    }
+}
+
+impl Write for Replayed {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.conn.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.conn.flush()
+    }
+}
+
+fn serve_page(mut reader: BufReader<TcpStream>) -> io::Result<()> {
+    // We serve the same page whatever is asked, so only read the headers to
+    // be polite
+    let mut header = vec![];
+    loop {
+        header.clear();
+        let n = reader
+            .by_ref()
+            .take(MAX_REQUEST_LINE)
+            .read_until(b'\n', &mut header)?;
+        if n == 0 || header == b"\r\n" || header == b"\n" {
+            break;
+        }
+    }
+    let mut conn = reader.into_inner();
+    write!(
+        conn,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{PAGE}",
+        PAGE.len()
+    )?;
+    conn.flush()
+}
+
+#[test]
+fn test_same_origin() {
+    let headers = |pairs: &[(&'static str, &'static str)]| {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.insert(*name, value.parse().unwrap());
+        }
+        map
+    };
+    assert!(same_origin(&headers(&[("host", "127.0.0.1:8080")])));
+    assert!(same_origin(&headers(&[
+        ("host", "127.0.0.1:8080"),
+        ("origin", "http://127.0.0.1:8080")
+    ])));
+    assert!(!same_origin(&headers(&[
+        ("host", "127.0.0.1:8080"),
+        ("origin", "https://evil.example")
+    ])));
+    assert!(!same_origin(&headers(&[(
+        "origin",
+        "http://127.0.0.1:8080"
+    )])));
+}